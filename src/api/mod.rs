@@ -1,16 +1,22 @@
 use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
 use crate::blockchain::Blockchain;
-use crate::block::{PrintableBlock, PrintableContent, PrintableState};
+use crate::block::{Block, PrintableBlock, PrintableContent, PrintableState};
+use crate::crypto::hash::H256;
+use crate::difficulty;
+use crate::config::{BLOCK_SIZE_LIMIT, LONGPOLL_TIMEOUT, MAX_HASHPS_WINDOW, RETARGET_INTERVAL};
 use crate::mempool::MemPool;
+use crate::lock_order::OrderedMutex;
 use crate::transaction::{PrintableTransaction, SignedTransaction};
 use crate::transaction_generator::Handle as TxGeneratorHandle;
+use crate::node::{Handle as NodeHandle, TxStatus};
 use crate::peers::Peers;
 use crate::network::estimator::{start_first_timestamp_estimate};
 
 use log::info;
 use std::collections::HashMap;
 use std::thread;
+use std::time::{Duration, SystemTime};
 use tiny_http::Header;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
@@ -23,8 +29,9 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     transaction_generator: TxGeneratorHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<MemPool>>,
+    node: NodeHandle,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
     peers : Arc<Mutex<Peers>>,
 }
 
@@ -34,6 +41,91 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct ChainTip {
+    hash: String,
+    height: usize,
+    status: String,
+}
+
+#[derive(Serialize)]
+struct BlockCountRes {
+    success: bool,
+    count: usize,
+    initial_block_download: bool,  // tip shouldn't be trusted while syncing
+}
+
+#[derive(Serialize)]
+struct ChainTipsRes {
+    success: bool,
+    tips: Vec<ChainTip>,
+    orphans: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ReorgStatsRes {
+    success: bool,
+    count: usize,
+    max_depth: usize,
+    since_last_reorg_ms: Option<u64>,  // none if no reorg happened since start
+}
+
+#[derive(Serialize)]
+struct UtxoSetHashRes {
+    success: bool,
+    tip: String,
+    height: usize,
+    utxo_set_hash: String,  // commitment of the UTXO set at tip, to verify snapshots against
+}
+
+#[derive(Serialize)]
+struct DifficultyRes {
+    success: bool,
+    target: String,
+    bits: u32,
+    expected_hashes: String,
+    difficulty: f64,  // multiple of the easiest target's difficulty
+}
+
+#[derive(Serialize)]
+struct RawTransactionRes {
+    success: bool,
+    tx: String,  // hex of the serialized signed transaction, as /transaction/submit takes
+    confirmed: bool,
+    block: Option<String>,
+    confirmations: usize,
+}
+
+#[derive(Serialize)]
+struct BlockTemplateRes {
+    success: bool,
+    longpollid: String,  // pass back to wait until this template is outdated
+    parent: String,
+    height: usize,
+    difficulty: String,
+    min_timestamp: u64,
+    coinbase_value: u64,
+    transactions: Vec<String>,  // hex of the serialized signed transactions, in block order
+}
+
+#[derive(Serialize)]
+struct FeeRate {
+    hash: String,
+    fee_rate: f64,  // fee per byte
+}
+
+#[derive(Serialize)]
+struct TopFeeRateRes {
+    success: bool,
+    transactions: Vec<FeeRate>,
+}
+
+#[derive(Serialize)]
+struct HashListRes {
+    success: bool,
+    hashes: Vec<String>,
+}
+
 #[derive(Serialize)]
 struct EstimatorRes {
     success: bool,
@@ -44,13 +136,17 @@ struct EstimatorRes {
 
 macro_rules! respond_json {
     ($req:expr, $success:expr, $message:expr ) => {{
+        respond_json!($req, $success, $message, 200);
+    }};
+    ($req:expr, $success:expr, $message:expr, $status:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
         let payload = ApiResponse {
             success: $success,
             message: $message.to_string(),
         };
         let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
-            .with_header(content_type);
+            .with_header(content_type)
+            .with_status_code($status);
         $req.respond(resp).unwrap();
     }};
 }
@@ -90,8 +186,9 @@ impl Server {
         addr: std::net::SocketAddr,
         miner: MinerHandle,
         transaction_generator: TxGeneratorHandle,
-        blockchain: Arc<Mutex<Blockchain>>,
-        mempool: Arc<Mutex<MemPool>>,
+        node: NodeHandle,
+        blockchain: Arc<OrderedMutex<Blockchain>>,
+        mempool: Arc<OrderedMutex<MemPool>>,
         peers : Arc<Mutex<Peers>>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
@@ -99,6 +196,7 @@ impl Server {
             handle,
             miner,
             transaction_generator,
+            node,
             blockchain,
             mempool,
             peers,
@@ -107,6 +205,7 @@ impl Server {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let transaction_generator = server.transaction_generator.clone();
+                let node = server.node.clone();
                 let blockchain = Arc::clone(&server.blockchain);
                 let mempool = Arc::clone(&server.mempool);
                 let peers = server.peers.clone();
@@ -153,9 +252,74 @@ impl Server {
                             miner.pause();
                             respond_json!(req, true, "ok");
                         }
+                        "/miner/throttle" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let percent = match params.get("percent") {
+                                Some(v) => v,
+                                None => {
+                                    respond_json!(req, false, "missing percent");
+                                    return;
+                                }
+                            };
+                            let percent = match percent.parse::<u32>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_json!(
+                                        req,
+                                        false,
+                                        format!("error parsing percent: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            miner.set_throttle(percent);
+                            respond_json!(req, true, "ok");
+                        }
+                        "/miner/getblocktemplate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let timeout = match params.get("timeout").map(|v| v.parse::<u64>()) {
+                                None => LONGPOLL_TIMEOUT,
+                                Some(Ok(v)) => std::cmp::min(v, LONGPOLL_TIMEOUT),
+                                Some(Err(e)) => {
+                                    respond_json!(req, false, format!("error parsing timeout: {}", e));
+                                    return;
+                                }
+                            };
+                            // with a longpollid, wait until the template it identifies is outdated
+                            let template = match params.get("longpollid").map(|v| v.parse::<H256>()) {
+                                None => node.get_block_template(),
+                                Some(Ok(parent)) => node.wait_block_template(&parent, Duration::from_millis(timeout)),
+                                Some(Err(e)) => {
+                                    respond_json!(req, false, format!("error parsing longpollid: {:?}", e));
+                                    return;
+                                }
+                            };
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = BlockTemplateRes {
+                                success: true,
+                                longpollid: format!("{}", template.parent),
+                                parent: format!("{}", template.parent),
+                                height: template.height,
+                                difficulty: format!("{}", template.difficulty),
+                                min_timestamp: template.min_timestamp,
+                                coinbase_value: template.coinbase_value,
+                                transactions: template.transactions.iter()
+                                    .map(|t| hex::encode(bincode::serialize(t).unwrap()))
+                                    .collect(),
+                            };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
                         "/blockchain/showheader" => {
-                            let blocks = blockchain.lock().unwrap().block_chain();
-                            let pblock = PrintableBlock::from_block_vec(&blocks);
+                            // one block at a time, tip first
+                            let chain = blockchain.lock().unwrap();
+                            let pblock: Vec<PrintableBlock> = chain.iter_canonical().rev()
+                                .map(|b| PrintableBlock::from_block(&b))
+                                .collect();
+                            drop(chain);
                             let mut context = Context::new();
                             context.insert("blocks", &pblock);
 
@@ -189,6 +353,217 @@ impl Server {
                                 .with_header(content_type);
                             req.respond(resp).unwrap();
                         }
+                        "/bestblockhash" => {
+                            respond_json!(req, true, format!("{}", node.get_best_block_hash()));
+                        }
+                        path if path.starts_with("/blockhash/") => {
+                            let height = match path["/blockhash/".len()..].parse::<usize>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing height: {}", e));
+                                    return;
+                                }
+                            };
+                            match node.get_block_hash(height) {
+                                Some(hash) => respond_json!(req, true, format!("{}", hash)),
+                                None => respond_json!(req, false, "height out of range"),
+                            }
+                        }
+                        "/blockchain/getchaintips" => {
+                            let chain = blockchain.lock().unwrap();
+                            let mut tips = vec![ChainTip {
+                                hash: format!("{}", chain.tip()),
+                                height: chain.length() - 1,
+                                status: "active".to_string(),
+                            }];
+                            for (hash, height) in chain.side_branch_tips() {
+                                tips.push(ChainTip { hash: format!("{}", hash), height, status: "side-branch".to_string() });
+                            }
+                            let orphans = chain.orphan_hashes().iter().map(|h| format!("{}", h)).collect();
+                            drop(chain);
+
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = ChainTipsRes { success: true, tips, orphans };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/blockchain/getreorgstats" => {
+                            let stats = blockchain.lock().unwrap().reorg_stats();
+                            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = ReorgStatsRes {
+                                success: true,
+                                count: stats.count,
+                                max_depth: stats.max_depth,
+                                since_last_reorg_ms: stats.last_time.map(|t| now.saturating_sub(t)),
+                            };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/blockchain/getutxosethash" => {
+                            let chain = blockchain.lock().unwrap();
+                            let payload = UtxoSetHashRes {
+                                success: true,
+                                tip: format!("{}", chain.tip()),
+                                height: chain.length() - 1,
+                                utxo_set_hash: format!("{}", chain.utxo_set_hash()),
+                            };
+                            drop(chain);
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/blockchain/getblockcount" => {
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = BlockCountRes {
+                                success: true,
+                                count: node.get_block_count(),
+                                initial_block_download: node.is_initial_block_download(),
+                            };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/blockchain/getdifficulty" => {
+                            // difficulty of the next block on tip
+                            let target = blockchain.lock().unwrap().difficulty();
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = DifficultyRes {
+                                success: true,
+                                target: format!("{}", target),
+                                bits: target.leading_zero_bits(),
+                                expected_hashes: difficulty::block_work(&target).to_string(),
+                                difficulty: node.get_difficulty(),
+                            };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/blockchain/getnetworkhashps" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let window = match params.get("window").map(|v| v.parse::<usize>()) {
+                                None => RETARGET_INTERVAL,
+                                Some(Ok(v)) => std::cmp::min(v, MAX_HASHPS_WINDOW),
+                                Some(Err(e)) => {
+                                    respond_json!(req, false, format!("error parsing window: {}", e));
+                                    return;
+                                }
+                            };
+                            match node.get_network_hashps(window) {
+                                Some(hashps) => respond_json!(req, true, format!("{:.0}", hashps)),
+                                None => respond_json!(req, false, "not enough blocks to estimate"),
+                            }
+                        }
+                        "/blockchain/getrawblock" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_json!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let hash = match hash.parse::<H256>() {
+                                Ok(h) => h,
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing hash: {:?}", e));
+                                    return;
+                                }
+                            };
+                            match blockchain.lock().unwrap().get_block(&hash) {
+                                Some(block) => respond_json!(req, true, block.to_hex()),
+                                None => respond_json!(req, false, "block not found"),
+                            }
+                        }
+                        "/blockchain/submitrawblock" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let raw = match params.get("block") {
+                                Some(v) => v,
+                                None => {
+                                    respond_json!(req, false, "missing block");
+                                    return;
+                                }
+                            };
+                            let block = match Block::from_hex(raw) {
+                                Ok(b) => b,
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing block: {:?}", e));
+                                    return;
+                                }
+                            };
+                            match node.submit_block(&block) {
+                                Ok(hash) => respond_json!(req, true, format!("{}", hash)),
+                                Err(e) => respond_json!(req, false, format!("block rejected: {:?}", e)),
+                            }
+                        }
+                        "/mempool/getancestors" | "/mempool/getdescendants" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_json!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let hash = match hash.parse::<H256>() {
+                                Ok(h) => h,
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing hash: {:?}", e));
+                                    return;
+                                }
+                            };
+                            let pool = mempool.lock().unwrap();
+                            if !pool.exist(&hash) {
+                                respond_json!(req, false, "transaction not in mempool");
+                                return;
+                            }
+                            let hashes = if url.path() == "/mempool/getancestors" {
+                                pool.ancestors(&hash)
+                            } else {
+                                pool.descendants(&hash)
+                            };
+                            drop(pool);
+
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = HashListRes {
+                                success: true,
+                                hashes: hashes.iter().map(|h| format!("{}", h)).collect(),
+                            };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/mempool/gettop" => {
+                            // preview of what the next block template takes first
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let n = match params.get("n").map(|v| v.parse::<usize>()) {
+                                None => BLOCK_SIZE_LIMIT,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_json!(req, false, format!("error parsing n: {}", e));
+                                    return;
+                                }
+                            };
+                            let top = mempool.lock().unwrap().top_n_by_feerate(n);
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = TopFeeRateRes {
+                                success: true,
+                                transactions: top.into_iter()
+                                    .map(|(hash, fee_rate)| FeeRate { hash: format!("{}", hash), fee_rate })
+                                    .collect(),
+                            };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
                         "/mempool/showtx" => {
                             let trans_map = &mempool.lock().unwrap().transactions;
                             let trans: Vec<SignedTransaction> = trans_map.values().cloned().collect();
@@ -203,6 +578,69 @@ impl Server {
                                 .with_header(content_type);
                             req.respond(resp).unwrap();
                         }
+                        "/transaction/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let raw = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_json!(req, false, "missing tx");
+                                    return;
+                                }
+                            };
+                            let tran = match hex::decode(raw).map_err(|e| e.to_string())
+                                .and_then(|b| bincode::deserialize::<SignedTransaction>(&b).map_err(|e| e.to_string())) {
+                                Ok(t) => t,
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing tx: {}", e));
+                                    return;
+                                }
+                            };
+                            match node.submit_transaction(&tran) {
+                                Ok(hash) => respond_json!(req, true, format!("{}", hash)),
+                                Err(e) => respond_json!(req, false, format!("transaction rejected: {:?}", e), 400),
+                            }
+                        }
+                        "/transaction/getraw" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_json!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let hash = match hash.parse::<H256>() {
+                                Ok(h) => h,
+                                Err(e) => {
+                                    respond_json!(req, false, format!("error parsing hash: {:?}", e));
+                                    return;
+                                }
+                            };
+                            let (tran, status) = match node.get_raw_transaction(&hash) {
+                                Some(v) => v,
+                                None => {
+                                    respond_json!(req, false, "transaction not found");
+                                    return;
+                                }
+                            };
+                            let (block, confirmations) = match status {
+                                TxStatus::Unconfirmed => (None, 0),
+                                TxStatus::Confirmed { block, confirmations } => (Some(format!("{}", block)), confirmations),
+                            };
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = RawTransactionRes {
+                                success: true,
+                                tx: hex::encode(bincode::serialize(&tran).unwrap()),
+                                confirmed: block.is_some(),
+                                block,
+                                confirmations,
+                            };
+                            let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
                         "/txgenerator/stop" => {
                             transaction_generator.stop();
                             respond_json!(req, true, "ok");
@@ -6,9 +6,10 @@ use chrono::Utc;
 use std::time::{UNIX_EPOCH, Duration};
 use std::collections::HashMap;
 use crate::crypto::hash::{H256, H160, Hashable};
-use crate::transaction::{SignedTransaction, TxInput, PrintableTransaction, PrintableTxInput, PrintableTxOutput, TxOutput};
-use crate::crypto::merkle::MerkleTree;
-use crate::config::DIFFICULTY;
+use crate::transaction::{Lock, SignedTransaction, TxInput, PrintableTransaction, PrintableTxInput, PrintableTxOutput, TxOutput};
+use crate::crypto::merkle::{MerkleTree, IncrementalMerkle};
+use crate::config::{DIFFICULTY, WITNESS_SCALE_FACTOR};
+use crate::difficulty;
 use crate::helper::gen_difficulty_array;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -19,6 +20,12 @@ pub struct Block {
     pub content: Content,   // transaction in this block
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseBlockError {
+    InvalidHex,
+    InvalidEncoding,  // hex decoded, but bytes are not a bincode block
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PrintableBlock {
     pub hash: String,
@@ -26,8 +33,11 @@ pub struct PrintableBlock {
     pub index: usize,
     pub nonce: u32,
     pub difficulty: String,
+    pub difficulty_bits: u32,  // leading zero bits of the target
+    pub expected_hashes: String,  // expected number of hash attempts to meet the target
     pub timestamp: String,
     pub merkle_root: String,
+    pub size: usize,  // serialized bytes of the whole block
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,9 +60,16 @@ pub struct PrintableContent {
     pub index: usize,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct State (pub HashMap<(H256, u32), (u64, H160)>);
 
+// Changes connecting a block made to a UTXO set, enough to disconnect it exactly
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockUndo {
+    pub block: H256,
+    changes: Vec<((H256, u32), Option<(u64, H160)>)>,  // entry before each change, in the order of changes
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PrintableState {
     pub inputs: Vec<PrintableTxInput>,
@@ -60,6 +77,13 @@ pub struct PrintableState {
     pub pair_num: usize,
 }
 
+impl BlockUndo {
+    // Record of a block that changed nothing
+    pub fn new(block: &H256) -> Self {
+        Self { block: *block, changes: vec![] }
+    }
+}
+
 impl State {
     pub fn new() -> Self {
         let map: HashMap<(H256, u32), (u64, H160)> = HashMap::new();
@@ -82,10 +106,58 @@ impl State {
         return self.0.get(key);
     }
 
+    // Spending condition of an unspent output, None if it isn't in the state
+    pub fn lock_of(&self, key: &(H256, u32)) -> Option<Lock> {
+        self.0.get(key).map(|(_, owner_addr)| Lock::PayToPubkeyHash(*owner_addr))
+    }
+
     pub fn clear(&mut self) {
         self.0.clear();
     }
 
+    // Apply a block on top of this state, i.e. try_generate_state in place, and return what it takes to
+    // disconnect it. On any failed check the state is left unchanged and None is returned
+    pub fn connect_block(&mut self, block: &Block, subsidy: u64) -> Option<BlockUndo> {
+        let mut undo = BlockUndo { block: block.hash, changes: vec![] };
+        if block.apply_to(self, subsidy, &mut undo).is_none() {
+            self.undo_changes(&undo);
+            return None;
+        }
+        Some(undo)
+    }
+
+    // Inverse of connect_block: restore outputs the block spent and remove those it created, including
+    // ones created and spent within the block. Return false if undo was captured for another block
+    pub fn disconnect_block(&mut self, block: &Block, undo: &BlockUndo) -> bool {
+        if undo.block != block.hash {
+            return false;
+        }
+        self.undo_changes(undo);
+        true
+    }
+
+    fn undo_changes(&mut self, undo: &BlockUndo) {
+        for (key, prev) in undo.changes.iter().rev() {
+            match prev {
+                Some(val) => self.0.insert(*key, *val),
+                None => self.0.remove(key),
+            };
+        }
+    }
+
+    fn insert_logged(&mut self, key: (H256, u32), val: (u64, H160), undo: &mut BlockUndo) {
+        let prev = self.0.insert(key, val);
+        undo.changes.push((key, prev));
+    }
+
+    fn remove_logged(&mut self, key: &(H256, u32), undo: &mut BlockUndo) -> Option<(u64, H160)> {
+        let prev = self.0.remove(key);
+        if prev.is_some() {
+            undo.changes.push((*key, prev));
+        }
+        prev
+    }
+
     pub fn coins_of(&self, addr: &H160) -> (HashMap<TxInput, u64>, u64) {
         let mut coins: HashMap<TxInput, u64> = HashMap::new();
         let mut balance = 0u64;
@@ -99,6 +171,19 @@ impl State {
     }
 }
 
+// Digest of the whole UTXO set, independent of insertion order (used to verify snapshots)
+impl Hashable for State {
+    fn hash(&self) -> H256 {
+        let mut entries: Vec<(&(H256, u32), &(u64, H160))> = self.0.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        for entry in entries.iter() {
+            ctx.update(&bincode::serialize(entry).unwrap());
+        }
+        ctx.finish().into()
+    }
+}
+
 impl std::convert::AsRef<HashMap<(H256, u32), (u64, H160)>> for State {
     fn as_ref(&self) -> &HashMap<(H256, u32), (u64, H160)> {
         &self.0
@@ -157,12 +242,51 @@ impl Block {
         self.hash.clone()
     }
 
+    // Length of serialized block
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    // Consensus weight, see SignedTransaction::weight; header and transaction counts are base bytes
+    pub fn weight(&self) -> usize {
+        let witness: usize = self.content.trans.iter().map(|t| t.witness_size()).sum();
+        (self.size() - witness) * WITNESS_SCALE_FACTOR + witness
+    }
+
+    // Hex of the bincode serialized block, for external tools and scripts
+    pub fn to_hex(&self) -> String {
+        hex::encode(bincode::serialize(self).unwrap())
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, ParseBlockError> {
+        let bytes = hex::decode(s).map_err(|_| ParseBlockError::InvalidHex)?;
+        bincode::deserialize(&bytes).map_err(|_| ParseBlockError::InvalidEncoding)
+    }
+
     // Check transaction signature in content; if anyone fails, the whole block fails
     pub fn validate_signature(&self) -> bool {
-        let trans = &self.content.trans;
-        for t in trans.iter() {
-            if !t.sign_check() {
-                return false;
+        self.invalid_signature().is_none()
+    }
+
+    // First transaction in content whose signature fails, to tell which one made a block invalid.
+    // Verified one by one: ring has no batch verification to check them all together
+    pub fn invalid_signature(&self) -> Option<&SignedTransaction> {
+        self.content.trans.iter().find(|t| !t.sign_check())
+    }
+
+    // First transaction in content whose lock time doesn't allow it in this block at the given height
+    pub fn non_final_tran(&self, height: usize) -> Option<&SignedTransaction> {
+        self.content.trans.iter().find(|t| !t.transaction.is_final(height, self.header.timestamp))
+    }
+
+    // Check the commitment of coinbase, if it has one, against the auxiliary root of content,
+    // other transactions may not carry a commitment
+    pub fn validate_commitment(&self) -> bool {
+        for (i, t) in self.content.trans.iter().enumerate() {
+            if let Some(commitment) = &t.transaction.commitment {
+                if i != 0 || *commitment != self.content.aux_root() {
+                    return false;
+                }
             }
         }
         true
@@ -170,10 +294,17 @@ impl Block {
 
     // Try to generate a new state based on the parent_state
     // Validate all transactions, such as coinbase transaction and double-spend issue
-    // return None if any check fails
-    pub fn try_generate_state(&self, parent_state: &State) -> Option<State> {
+    // Coinbase may claim at most subsidy plus fees of the block, return None if any check fails
+    pub fn try_generate_state(&self, parent_state: &State, subsidy: u64) -> Option<State> {
         let mut state = parent_state.clone();
+        state.connect_block(self, subsidy)?;
+        Some(state)
+    }
+
+    // Checks & changes of try_generate_state applied to state, every change logged into undo
+    fn apply_to(&self, state: &mut State, subsidy: u64, undo: &mut BlockUndo) -> Option<()> {
         let mut trans_iter = self.content.trans.iter();
+        let reward: u64;
 
         // check coinbase transaction
         if let Some(coinbase_tran) = trans_iter.next() {
@@ -181,44 +312,55 @@ impl Block {
                 return None;
             }
             let output = coinbase_tran.transaction.outputs[0].clone();
-            state.insert((coinbase_tran.hash.clone(), 0),
-                (output.val, output.rec_address));
+            reward = output.val;
+            state.insert_logged((coinbase_tran.hash, 0),
+                (output.val, output.rec_address), undo);
         } else {
             return None;
         }
+        let mut fees = 0u64;
 
         // check non-coinbase transactions
         while let Some(tran) = trans_iter.next() {
-            let mut balance = 0i64;
-            let sender_addr: H160 = tran.sender_addr();
+            // summed in u128 so that no values of u64 can overflow
+            let mut input_sum = 0u128;
+            let mut output_sum = 0u128;
 
             // remove inputs from state
             for input in tran.transaction.inputs.iter() {
-                match state.remove(&(input.pre_hash, input.index)) {
-                    Some((val, owner_addr)) => {
-                        if owner_addr != sender_addr {
+                let key = (input.pre_hash, input.index);
+                match state.lock_of(&key) {
+                    Some(lock) => {
+                        if !lock.unlocked_by(tran) {
                             return None;
                         }
-                        balance += val as i64;
                     }
                     // double spend check
                     None => return None
                 }
+                let (val, _) = state.remove_logged(&key, undo)?;
+                input_sum += val as u128;
             }
 
             // add output to state
             for (index, output) in tran.transaction.outputs.iter().enumerate() {
-                state.insert((tran.hash.clone(), index as u32),
-                             (output.val, output.rec_address));
-                balance -= output.val as i64;
+                state.insert_logged((tran.hash, index as u32),
+                                    (output.val, output.rec_address), undo);
+                output_sum += output.val as u128;
             }
 
-            // check balance
-            if balance < 0 {
+            // check balance, the fee is what inputs leave over outputs at most
+            if output_sum + tran.transaction.fee as u128 > input_sum {
                 return None;
             }
+            fees = fees.checked_add(tran.transaction.fee)?;
         }
-        return Some(state);
+
+        // check coinbase reward
+        if reward > subsidy.checked_add(fees)? {
+            return None;
+        }
+        Some(())
     }
 
     #[cfg(any(test, test_utilities))]
@@ -229,23 +371,25 @@ impl Block {
 
 impl PrintableBlock {
     pub fn from_block_vec(blocks: &Vec<Block>) -> Vec<PrintableBlock> {
-        let mut pblocks = Vec::<PrintableBlock>::new();
-        for b in blocks {
-            let t = UNIX_EPOCH + Duration::from_millis(b.header.timestamp);
-            let datetime = DateTime::<Utc>::from(t);
-            let ts_str = datetime.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
-            let p = PrintableBlock {
-                hash: hex::encode(&b.hash),
-                parent_hash: hex::encode(&b.header.parent),
-                index: b.index,
-                nonce: b.header.nonce,
-                difficulty: hex::encode(&b.header.difficulty),
-                timestamp: ts_str,
-                merkle_root: hex::encode(&b.header.merkle_root),
-            };
-            pblocks.push(p);
+        blocks.iter().map(Self::from_block).collect()
+    }
+
+    pub fn from_block(b: &Block) -> PrintableBlock {
+        let t = UNIX_EPOCH + Duration::from_millis(b.header.timestamp);
+        let datetime = DateTime::<Utc>::from(t);
+        let ts_str = datetime.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+        PrintableBlock {
+            hash: hex::encode(&b.hash),
+            parent_hash: hex::encode(&b.header.parent),
+            index: b.index,
+            nonce: b.header.nonce,
+            difficulty: hex::encode(&b.header.difficulty),
+            difficulty_bits: b.header.difficulty.leading_zero_bits(),
+            expected_hashes: difficulty::block_work(&b.header.difficulty).to_string(),
+            timestamp: ts_str,
+            merkle_root: hex::encode(&b.header.merkle_root),
+            size: b.size(),
         }
-        pblocks
     }
 }
 
@@ -298,6 +442,26 @@ impl Content {
         tree.root()
     }
 
+    // Auxiliary root committed by coinbase, over the transactions after it
+    pub fn aux_root(&self) -> H256 {
+        let start = std::cmp::min(1, self.trans.len());
+        Self::aux_root_of(&self.trans[start..])
+    }
+
+    // Auxiliary root of the transactions following a coinbase, zero if there is none
+    pub fn aux_root_of(trans: &[SignedTransaction]) -> H256 {
+        let mut tree = IncrementalMerkle::new();
+        for t in trans.iter() {
+            tree.push(t);
+        }
+        tree.root()
+    }
+
+    // Leaf index of a transaction in the merkle tree, which is built in insertion order
+    pub fn index_of(&self, tran_hash: &H256) -> Option<usize> {
+        self.trans.iter().position(|t| t.hash == *tran_hash)
+    }
+
     // Return a vector of hash for all transactions inside
     pub fn get_trans_hashes(&self) -> Vec<H256> {
         let hashes: Vec<H256> = self.trans.iter()
@@ -344,6 +508,7 @@ pub mod test {
     use crate::crypto::key_pair;
     use crate::config::COINBASE_REWARD;
     use crate::transaction::{TxInput, TxOutput};
+    use crate::crypto::merkle::verify;
 
     #[test]
     fn test_genesis() {
@@ -364,6 +529,23 @@ pub mod test {
         let _content = Content::new_with_trans(&trans);
     }
 
+    #[test]
+    fn test_index_of() {
+        let mut content = Content::new();
+        for _ in 0..5 {
+            content.add_tran(generate_random_signed_transaction());
+        }
+        let tree = MerkleTree::new(&content.trans);
+        let root = content.merkle_root();
+        for tran in content.trans.iter() {
+            let index = content.index_of(&tran.hash).unwrap();
+            assert!(verify(&root, &tran.hash, &tree.proof(index), index, content.trans.len()));
+            let wrong = (index + 1) % content.trans.len();
+            assert!(!verify(&root, &tran.hash, &tree.proof(wrong), wrong, content.trans.len()));
+        }
+        assert_eq!(None, content.index_of(&generate_random_hash()));
+    }
+
     #[test]
     fn test_difficulty() {
         let test_array1 = gen_difficulty_array(8);
@@ -419,6 +601,16 @@ pub mod test {
         assert_eq!(block_1, block_5);
     }
 
+    #[test]
+    fn test_printable_block_size() {
+        let content = generate_random_content();
+        let block = Block::new(generate_header(&generate_random_hash(), &content, 0, &generate_random_hash()), content);
+        let pblocks = PrintableBlock::from_block_vec(&vec![block.clone(), Block::genesis()]);
+        assert_eq!(bincode::serialize(&block).unwrap().len(), pblocks[0].size);
+        assert_eq!(bincode::serialize(&Block::genesis()).unwrap().len(), pblocks[1].size);
+        assert!(pblocks[1].size < pblocks[0].size);
+    }
+
     #[test]
     fn test_get_trans_hashed() {
         let t_1 = generate_random_signed_transaction();
@@ -440,7 +632,7 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let new_state = block.try_generate_state(&State::new());
+        let new_state = block.try_generate_state(&State::new(), COINBASE_REWARD);
         if let Some(state) = new_state.clone() {
             assert!(state.contains_key(&(signed_coinbase_tran.hash.clone(), 0)));
             let value = state.get(&(signed_coinbase_tran.hash.clone(), 0)).unwrap().clone();
@@ -453,7 +645,7 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran_2.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let state_2 = block.try_generate_state(&new_state.unwrap());
+        let state_2 = block.try_generate_state(&new_state.unwrap(), COINBASE_REWARD);
         if let Some(state) = state_2.clone() {
             assert!(state.contains_key(&(signed_coinbase_tran.hash.clone(), 0)));
             let value = state.get(&(signed_coinbase_tran.hash.clone(), 0)).unwrap().clone();
@@ -475,7 +667,7 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran_3.clone(), valid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), COINBASE_REWARD);
         if let Some(state) = non_state.clone() {
             assert!(!state.contains_key(&(signed_coinbase_tran_2.hash.clone(), 0)));
             assert!(state.contains_key(&(valid_tran.hash.clone(), 0)));
@@ -497,7 +689,7 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone(), invalid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), COINBASE_REWARD);
         if let Some(_) = non_state {
             assert!(false);
         }
@@ -512,7 +704,7 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone(), invalid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), COINBASE_REWARD);
         if let Some(_) = non_state {
             assert!(false);
         }
@@ -526,12 +718,125 @@ pub mod test {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone(), invalid_tran.clone()]);
         let header = generate_header(&random_h256, &content, 0, &random_h256);
         let block = Block::new(header, content.clone());
-        let non_state = block.try_generate_state(&state_2.clone().unwrap());
+        let non_state = block.try_generate_state(&state_2.clone().unwrap(), COINBASE_REWARD);
         if let Some(_) = non_state {
             assert!(false);
         }
     }
 
+    #[test]
+    fn test_fee_overflow() {
+        let key = key_pair::random();
+        let coinbase = generate_signed_coinbase_transaction(&key);
+        let random_h256 = generate_random_hash();
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let block = Block::new(generate_header(&random_h256, &content, 0, &random_h256), content);
+        let state = block.try_generate_state(&State::new(), COINBASE_REWARD).unwrap();
+
+        let spend_in_block = |fee: u64, reward: u64| {
+            let coin = TxInput::new(coinbase.hash.clone(), 0);
+            let outputs = vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD - 1)];
+            let tran = generate_signed_transaction_with_fee(&key, vec![coin], outputs, fee);
+            let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction_with_reward(&key, reward), tran]);
+            let block = Block::new(generate_header(&random_h256, &content, 0, &random_h256), content);
+            block.try_generate_state(&state, COINBASE_REWARD)
+        };
+        assert!(spend_in_block(1, COINBASE_REWARD + 1).is_some());
+        // fee bigger than what inputs leave over outputs, negative as i64
+        assert!(spend_in_block(u64::MAX / 2 + 1, COINBASE_REWARD).is_none());
+        assert!(spend_in_block(2, COINBASE_REWARD).is_none());
+        // coinbase claiming more than subsidy plus fees
+        assert!(spend_in_block(1, COINBASE_REWARD + 2).is_none());
+        assert!(spend_in_block(1, u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_spend_with_wrong_key() {
+        let owner = key_pair::random();
+        let thief = key_pair::random();
+        let coinbase = generate_signed_coinbase_transaction(&owner);
+        let random_h256 = generate_random_hash();
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let block = Block::new(generate_header(&random_h256, &content, 0, &random_h256), content);
+        let state = block.try_generate_state(&State::new(), COINBASE_REWARD).unwrap();
+
+        let coin = TxInput::new(coinbase.hash.clone(), 0);
+        let outputs = vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD)];
+        let stolen = generate_signed_transaction(&thief, vec![coin.clone()], outputs.clone());
+        let spent = generate_signed_transaction(&owner, vec![coin.clone()], outputs);
+        let lock = state.lock_of(&(coin.pre_hash, coin.index)).unwrap();
+        assert_eq!(Lock::PayToPubkeyHash(address_of(&owner)), lock);
+        assert_eq!(coinbase.transaction.outputs[0].lock(), lock);
+        // both carry a valid signature, only the owner's key unlocks the output
+        assert!(stolen.sign_check() && spent.sign_check());
+        assert!(!lock.unlocked_by(&stolen));
+        assert!(lock.unlocked_by(&spent));
+
+        let spend_in_block = |tran: &SignedTransaction| {
+            let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&thief), tran.clone()]);
+            let block = Block::new(generate_header(&random_h256, &content, 0, &random_h256), content);
+            block.try_generate_state(&state, COINBASE_REWARD)
+        };
+        assert!(spend_in_block(&stolen).is_none());
+        assert!(spend_in_block(&spent).is_some());
+    }
+
+    #[test]
+    fn test_disconnect_block() {
+        let key = key_pair::random();
+        let addr = address_of(&key);
+        let random_h256 = generate_random_hash();
+        let coinbase = generate_signed_coinbase_transaction(&key);
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let block_1 = Block::new(generate_header(&random_h256, &content, 0, &random_h256), content);
+        let mut state = block_1.try_generate_state(&State::new(), COINBASE_REWARD).unwrap();
+        let prior = state.clone();
+
+        // spends a coin from block_1, and an output created & spent within the block
+        let pay = generate_signed_transaction(&key, vec![TxInput::new(coinbase.hash, 0)],
+            vec![TxOutput::new(addr, 30), TxOutput::new(generate_random_h160(), 20)]);
+        let respend = generate_signed_transaction(&key, vec![TxInput::new(pay.hash, 0)],
+            vec![TxOutput::new(generate_random_h160(), 30)]);
+        let trans = vec![generate_signed_coinbase_transaction(&key), pay, respend];
+        let content = Content::new_with_trans(&trans);
+        let block_2 = Block::new(generate_header(&block_1.hash, &content, 0, &random_h256), content);
+
+        let undo = state.connect_block(&block_2, COINBASE_REWARD).unwrap();
+        assert_eq!(block_2.try_generate_state(&prior, COINBASE_REWARD).unwrap().0, state.0);
+        assert!(!state.contains_key(&(coinbase.hash, 0)));
+        assert!(!state.disconnect_block(&block_1, &undo));
+        assert!(state.disconnect_block(&block_2, &undo));
+        assert_eq!(prior.0, state.0);
+        assert_eq!(prior.hash(), state.hash());
+
+        // a failed connect leaves the state as it was
+        let double_spend = Content::new_with_trans(&vec![trans[0].clone(), trans[1].clone(), trans[1].clone()]);
+        let block_3 = Block::new(generate_header(&block_1.hash, &double_spend, 0, &random_h256), double_spend);
+        assert!(state.connect_block(&block_3, COINBASE_REWARD).is_none());
+        assert_eq!(prior.0, state.0);
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let mut trans: Vec<SignedTransaction> = (0..8).map(|_| generate_random_signed_transaction()).collect();
+        let random_h256 = generate_random_hash();
+        let content = Content::new_with_trans(&trans);
+        let block = Block::new(generate_header(&random_h256, &content, 0, &random_h256), content);
+        assert!(block.invalid_signature().is_none());
+        assert!(block.validate_signature());
+
+        // one tampered signature among valid ones is the one reported
+        let mut signature = trans[5].signature.to_vec();
+        signature[0] ^= 1;
+        trans[5].signature = signature.into();
+        let content = Content::new_with_trans(&trans);
+        let block = Block::new(generate_header(&random_h256, &content, 0, &random_h256), content);
+        assert_eq!(Some(trans[5].hash), block.invalid_signature().map(|t| t.hash));
+        assert!(!block.validate_signature());
+        let sequential: Vec<bool> = trans.iter().map(|t| t.sign_check()).collect();
+        assert_eq!(vec![true, true, true, true, true, false, true, true], sequential);
+    }
+
     #[test]
     fn test_state_coins_of() {
         let mut state = State::new();
@@ -1,18 +1,222 @@
-use std::collections::HashMap;
-use log::info;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use crossbeam::atomic::AtomicCell;
+use crossbeam::channel::Receiver;
+use log::{debug, info, warn};
 
-use crate::block::{Block, Header, Content, State};
-use crate::crypto::hash::H256;
+use serde::{Serialize, Deserialize};
+
+use crate::block::{Block, BlockUndo, Header, Content, State};
+use crate::transaction::{SignedTransaction, TxInput};
+use crate::config::{COINBASE_REWARD, EMA_WINDOW, HALVING_INTERVAL, HEADER_CHUNK_SIZE, IBD_MAX_BEHIND, MAX_BLOCK_WEIGHT,
+                    MAX_FUTURE_BLOCKS, MAX_FUTURE_DRIFT, MAX_ORPHAN_BLOCKS, MAX_TIP_AGE, MEDIAN_TIME_SPAN,
+                    ORPHAN_TTL, RETARGET_INTERVAL, RETARGET_MODE, TARGET_BLOCK_TIME};
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::difficulty::{self, RetargetError, RetargetMode};
+use crate::mempool::MemPool;
+use crate::events::{Event, EventBus};
+
+// Why a block was rejected by the blockchain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    AlreadyExists,
+    HashMismatch,
+    DifficultyMismatch,
+    InsufficientPow,
+    InvalidSignature,
+    InvalidTransactions,
+    SnapshotMismatch,  // snapshot doesn't match the trusted checkpoint
+    WorkMismatch,  // stored chain work differs from the one recomputed from headers
+    CommitmentMismatch,  // coinbase commitment differs from the auxiliary root of content
+    TimeTooNew,  // timestamp too far ahead of the local clock, block is held until the clock catches up
+    TimeTooOld,  // timestamp not later than the median time past of its parent
+    NotContiguous,  // a batch block doesn't extend the previous one, or the first one's parent is unknown
+    WeightTooLarge,  // block weight exceeds MAX_BLOCK_WEIGHT
+    NonFinalTransaction,  // a transaction's lock time isn't reached at the block's height or timestamp
+}
+
+// Why a hash prefix doesn't resolve to a single block or transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrefixError {
+    InvalidPrefix,  // empty, longer than a hash or not hex
+    NotFound,
+    Ambiguous(usize),  // number of matches
+}
+
+// Where an accepted block ended up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Extended,  // block extends the previous tip
+    SideBranch,  // block is stored but doesn't change the tip
+    Reorg { depth: usize },  // block becomes tip of another branch, depth: number of blocks disconnected
+    Orphaned,  // parent unknown, block is buffered
+    AlreadyHave,  // block is already stored or buffered, nothing changed and nothing to relay
+}
+
+// Which transaction checks a block goes through, PoW & structural checks always apply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckPolicy {
+    Full,
+    SkipSignatures,  // transactions are still checked against the parent state
+    SkipAll,  // neither signatures nor state, can only be used in test
+}
+
+// Trusted point of longest-chain, from which state can be installed without replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: usize,
+    pub block_hash: H256,
+    pub utxo_set_hash: H256,
+}
+
+// Tip summary readable without locking blockchain, updated on every tip/difficulty change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationTip {
+    pub tip: H256,
+    pub height: usize,
+    pub difficulty: H256,  // difficulty of tip block
+    pub next_difficulty: H256,  // difficulty the next block on tip must have
+    pub median_time_past: u64,  // timestamp the next block on tip must be later than
+}
+
+// Part of a chunk of longest-chain headers, see Blockchain::headers_between.
+// Full chunks are built once and shared, so cloning a slice never clones the headers
+#[derive(Debug, Clone)]
+pub struct HeaderSlice {
+    chunk: Arc<[Header]>,
+    range: Range<usize>,
+}
+
+impl HeaderSlice {
+    // Whether both slices are parts of the same shared chunk
+    pub fn shares_chunk(&self, other: &HeaderSlice) -> bool {
+        Arc::ptr_eq(&self.chunk, &other.chunk)
+    }
+}
+
+impl Deref for HeaderSlice {
+    type Target = [Header];
+
+    fn deref(&self) -> &[Header] {
+        &self.chunk[self.range.clone()]
+    }
+}
+
+// On-disk form of the blockchain written by save_to, orphans are not kept
+#[derive(Serialize, Deserialize)]
+struct SavedChain {
+    blocks: Vec<Block>,
+    state: State,
+    undos: Vec<BlockUndo>,
+    chain_work: Vec<(H256, u128)>,
+    longest_hash: H256,
+}
+
+// Where a block's content lies in the bodies file written by save_index
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct BodyPos {
+    offset: u64,
+    len: u64,
+}
+
+// Compact metadata of a block, enough for tip, difficulty and locators without its body
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    hash: H256,
+    height: usize,
+    header: Header,
+    chain_work: u128,
+    body: BodyPos,
+}
+
+// On-disk form of the block index written by save_index, only the tip's state is kept
+#[derive(Serialize, Deserialize)]
+struct SavedIndex {
+    entries: Vec<IndexEntry>,
+    longest_hash: H256,
+    tip_state: State,
+    spenders: HashMap<TxInput, (H256, H256)>,  // saved so loading needn't read every body of longest-chain
+    tx_index: HashMap<H256, (H256, usize)>,
+}
+
+// Blocks of longest-chain from genesis (or the installed snapshot) to tip, see Blockchain::iter_canonical.
+// Only the hashes are collected up front, each block is cloned (or read from the bodies file) when reached
+pub struct CanonicalIter<'a> {
+    blockchain: &'a Blockchain,
+    hashes: std::vec::IntoIter<H256>,
+}
+
+impl<'a> Iterator for CanonicalIter<'a> {
+    type Item = Block;
+
+    // Ends early if a body can't be read from the bodies file
+    fn next(&mut self) -> Option<Block> {
+        let hash = self.hashes.next()?;
+        self.blockchain.get_block(&hash)
+    }
+}
+
+impl<'a> DoubleEndedIterator for CanonicalIter<'a> {
+    fn next_back(&mut self) -> Option<Block> {
+        let hash = self.hashes.next_back()?;
+        self.blockchain.get_block(&hash)
+    }
+}
+
+// Reorgs of longest-chain since the node started, frequent or deep ones mean the network is unstable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReorgStats {
+    pub count: usize,
+    pub max_depth: usize,
+    pub last_time: Option<u64>,  // time(ms since epoch) of the last reorg
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+}
 
 pub struct Blockchain {
     blocks: HashMap<H256, Block>,
     orphans_map: HashMap<H256, Vec<Block>>, // key is the hash of the parent
     orphans: HashMap<H256, Block>,
+    unchecked_difficulty: HashSet<H256>,  // orphans whose difficulty & median time past can be checked only when parent arrives
+    orphan_times: HashMap<H256, u64>,  // time(ms) each orphan was buffered
+    max_orphans: usize,
+    orphan_ttl: u64,  // time(ms) after which an orphan is dropped
     longest_hash: H256,
     max_index: usize,
-    difficulty: H256,  // assume difficulty is consistent
-    states: HashMap<H256, State>,
-    check_trans: bool,  // can only be false in test
+    canonical: Vec<H256>,  // hashes of longest-chain by height, from canonical_base up to tip
+    canonical_base: usize,  // height of canonical[0], above 0 after installing a snapshot
+    header_chunks: Vec<Arc<[Header]>>,  // headers of canonical in full chunks of header_chunk_size
+    header_chunk_size: usize,
+    difficulty: H256,  // difficulty of the next block on tip
+    initial_difficulty: H256,  // difficulty of the first block after genesis, can only differ from genesis' in test
+    state: State,  // UTXO set at the tip of longest-chain
+    undos: HashMap<H256, BlockUndo>,  // changes connecting each block made, to get the state of others from tip's
+    chain_work: HashMap<H256, u128>,  // cumulative work from genesis of each block
+    bodies_path: Option<PathBuf>,  // bodies file of a loaded index
+    lazy_bodies: HashMap<H256, BodyPos>,  // blocks whose content is still in the bodies file
+    body_reads: AtomicUsize,  // number of bodies read from the bodies file
+    check_policy: CheckPolicy,  // can only be other than Full in test
+    retarget_mode: RetargetMode,
+    #[cfg(any(test, test_utilities))]
+    check_pow: bool,  // not compiled outside test
+    checkpoint: Option<Checkpoint>,
+    best_header_height: usize,  // highest height of a header seen from peers, its block may not be here yet
+    validation_tip: Arc<AtomicCell<ValidationTip>>,
+    events: EventBus,
+    spenders: HashMap<TxInput, (H256, H256)>,  // outpoint -> (spending transaction, its block) in longest-chain
+    tx_index: HashMap<H256, (H256, usize)>,  // transaction -> (its block, position in the block) in longest-chain
+    future_blocks: HashMap<H256, Block>,  // otherwise valid blocks rejected only for a timestamp too far ahead
+    max_future_drift: u64,  // time(ms) a block timestamp may be ahead of the local clock
+    max_block_weight: usize,
+    reorg_stats: ReorgStats,
 }
 
 impl Blockchain {
@@ -25,59 +229,126 @@ impl Blockchain {
         let mut map: HashMap<H256, Block> = HashMap::new();
         let orphans_map: HashMap<H256, Vec<Block>> = HashMap::new();
         map.insert(genesis.get_hash(), genesis);
-        let mut states: HashMap<H256, State> = HashMap::new();
-        let genesis_state = State::new();
-        states.insert(genesis_hash, genesis_state);
+        let mut chain_work: HashMap<H256, u128> = HashMap::new();
+        chain_work.insert(genesis_hash, difficulty::block_work(&difficulty));
+        let validation_tip = ValidationTip {
+            tip: longest_hash.clone(),
+            height: 0,
+            difficulty: difficulty.clone(),
+            next_difficulty: difficulty.clone(),
+            median_time_past: 0,
+        };
         Self {
             blocks: map,
             orphans_map,
             orphans: HashMap::new(),
+            unchecked_difficulty: HashSet::new(),
+            orphan_times: HashMap::new(),
+            max_orphans: MAX_ORPHAN_BLOCKS,
+            orphan_ttl: ORPHAN_TTL,
+            canonical: vec![longest_hash.clone()],
+            canonical_base: 0,
+            header_chunks: vec![],
+            header_chunk_size: HEADER_CHUNK_SIZE,
             longest_hash,
             max_index: 0,
+            initial_difficulty: difficulty,
             difficulty,
-            states,
-            check_trans: true,
+            state: State::new(),
+            undos: HashMap::new(),
+            chain_work,
+            bodies_path: None,
+            lazy_bodies: HashMap::new(),
+            body_reads: AtomicUsize::new(0),
+            check_policy: CheckPolicy::Full,
+            retarget_mode: RETARGET_MODE,
+            #[cfg(any(test, test_utilities))]
+            check_pow: true,
+            checkpoint: None,
+            best_header_height: 0,
+            validation_tip: Arc::new(AtomicCell::new(validation_tip)),
+            events: EventBus::new(),
+            spenders: HashMap::new(),
+            tx_index: HashMap::new(),
+            future_blocks: HashMap::new(),
+            max_future_drift: MAX_FUTURE_DRIFT,
+            max_block_weight: MAX_BLOCK_WEIGHT,
+            reorg_stats: ReorgStats::default(),
         }
     }
 
+    // Receive BlockAccepted as blocks are stored, TxConfirmed/TxUnconfirmed as they join or leave longest-chain
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
     // Insert a block with existence & validation check (used in inter-miner blocks broadcast)
-    pub fn insert_with_check(&mut self, block: &Block) -> bool {
-        if self.exist(&block.hash) || !self.validate_block_meta(block) {
-            return false;
+    pub fn insert_with_check(&mut self, block: &Block) -> Result<InsertOutcome, BlockError> {
+        if self.exist(&block.hash) {
+            return Err(BlockError::AlreadyExists);
         }
-        return self.insert(block);
+        match self.validate_block_meta(block) {
+            Err(BlockError::TimeTooNew) => {
+                self.hold_future_block(block);
+                return Err(BlockError::TimeTooNew);
+            }
+            result => result?,
+        }
+        let outcome = self.insert(block)?;
+        if outcome == InsertOutcome::Orphaned && self.is_orphan(&block.hash) {
+            self.unchecked_difficulty.insert(block.hash);
+        }
+        Ok(outcome)
     }
 
-    // Insert a block into blockchain if parent exists; otherwise, put it into orphan buffer
-    pub fn insert(&mut self, block: &Block) -> bool {
-        let mut b = block.clone();
+    // Whether insert_with_check would accept the block, without changing the blockchain
+    pub fn would_accept(&self, block: &Block) -> Result<(), BlockError> {
+        if self.exist(&block.hash) {
+            return Err(BlockError::AlreadyExists);
+        }
+        self.validate_block_meta(block)?;
+        // orphans are buffered, their transactions are checked when the parent arrives
+        if let Some(parent) = self.blocks.get(&block.header.parent) {
+            self.check_final(block, parent.index + 1)?;
+            if self.try_generate_new_state(block).is_none() {
+                return Err(BlockError::InvalidTransactions);
+            }
+        }
+        return Ok(());
+    }
+
+    // Insert a block into blockchain if parent exists; otherwise, put it into orphan buffer.
+    // Inserting a known block again is a no-op reporting AlreadyHave
+    pub fn insert(&mut self, block: &Block) -> Result<InsertOutcome, BlockError> {
+        if self.exist(&block.hash) {
+            return Ok(InsertOutcome::AlreadyHave);
+        }
+        let b = block.clone();
         let parent_hash = &b.header.parent;
+        let outcome: InsertOutcome;
 
         match self.blocks.get(parent_hash) {
-            Some(prev_block) => {
+            Some(parent) => {
+                self.check_final(block, parent.index + 1)?;
                 // validate transaction and generate new state
-                if let Some(new_state) = self.try_generate_new_state(block) {
-                    self.states.insert(block.hash.clone(), new_state);
-                } else {
-                    return false;
+                let (new_state, undo) = match self.try_generate_new_state(block) {
+                    Some(result) => result,
+                    None => return Err(BlockError::InvalidTransactions),
+                };
+                let old_tip = self.longest_hash.clone();
+                outcome = self.connect(b, undo);
+                if outcome != InsertOutcome::SideBranch {
+                    self.state = new_state;
+                    self.publish_tip();
+                    self.publish_confirmations(&old_tip);
+                    self.update_chain_indexes(&old_tip);
                 }
-                let cur_index = prev_block.index + 1;
-                b.index = cur_index;
-                let longest_block = self.blocks.get(&self.longest_hash).unwrap();
-                if cur_index > longest_block.index {
-                    self.longest_hash = b.hash.clone();
-                    self.max_index = cur_index;
-                }
-                let new_parent_hash = b.hash.clone();
-                info!("Insert block with index {:?}: {:?}, nonce: {}, parent: {:?}",
-                      &b.index, &b.hash, b.header.nonce, parent_hash);
-
-                self.blocks.insert(b.hash.clone(), b);
                 info!("Length of longest chain is {:?}, Total number of blocks is {:?}", self.length(), self.blocks.len());
 
-                self.handle_orphan(&new_parent_hash);
+                self.handle_orphan(&block.hash);
             },
             None => {
+                outcome = InsertOutcome::Orphaned;
                 self.orphans.insert(b.hash.clone(), b.clone());
                 match self.orphans_map.get_mut(parent_hash) {
                     Some(children_vec) => {
@@ -90,9 +361,353 @@ impl Blockchain {
                         self.orphans_map.insert(parent_hash_copy, children_vec);
                     }
                 }
+                self.orphan_times.insert(block.hash.clone(), now_ms());
+                self.expire_orphans();
+            }
+        }
+        return Ok(outcome);
+    }
+
+    // Insert a contiguous run of blocks for catching up, each the parent of the next and the first on a known block.
+    // All or nothing: the whole run is validated before anything changes, then indexed in one pass
+    pub fn insert_batch(&mut self, blocks: &[Block]) -> Result<Vec<InsertOutcome>, BlockError> {
+        let first = match blocks.first() {
+            Some(b) => b,
+            None => return Ok(vec![]),
+        };
+        let mut parent = match self.blocks.get(&first.header.parent) {
+            Some(b) => b.clone(),
+            None => return Err(BlockError::NotContiguous),
+        };
+        let mut staged: HashMap<H256, Block> = HashMap::new();
+        let mut state: Option<State> = None;  // after the last staged block
+        let mut undos: Vec<BlockUndo> = vec![];
+        for block in blocks.iter() {
+            // not exist, a block of the run buffered as an orphan is connected below
+            if self.blocks.contains_key(&block.hash) || staged.contains_key(&block.hash) {
+                return Err(BlockError::AlreadyExists);
+            }
+            if block.header.parent != parent.hash {
+                return Err(BlockError::NotContiguous);
+            }
+            let expected = if parent.hash == self.longest_hash {
+                self.difficulty.clone()
+            } else {
+                self.next_difficulty_in(&parent, &staged)?
+            };
+            self.validate_block_with(block, &expected)?;
+            let height = parent.index + 1;
+            self.check_final(block, height)?;
+            let undo = if self.check_policy == CheckPolicy::SkipAll {
+                state = Some(State::new());  // skip in test
+                BlockUndo::new(&block.hash)
+            } else {
+                if state.is_none() {
+                    state = Some(self.state_at(&parent.hash).ok_or(BlockError::InvalidTransactions)?);
+                }
+                match state.as_mut().unwrap().connect_block(block, Self::block_subsidy(height)) {
+                    Some(undo) => undo,
+                    None => return Err(BlockError::InvalidTransactions),
+                }
+            };
+            undos.push(undo);
+            let mut b = block.clone();
+            b.index = height;
+            staged.insert(b.hash.clone(), b.clone());
+            parent = b;
+        }
+
+        let old_tip = self.longest_hash.clone();
+        let mut outcomes = vec![];
+        for (block, undo) in blocks.iter().zip(undos.into_iter()) {
+            // a block of the run may have arrived before as an orphan
+            self.remove_orphan(&block.hash);
+            outcomes.push(self.connect(block.clone(), undo));
+        }
+        // more work at any block of the run means more at the last one, so the tip is either that or unchanged
+        if self.longest_hash != old_tip {
+            self.state = state.unwrap();
+            self.publish_tip();
+            self.publish_confirmations(&old_tip);
+            self.update_chain_indexes(&old_tip);
+        }
+        info!("Length of longest chain is {:?}, Total number of blocks is {:?}", self.length(), self.blocks.len());
+        for block in blocks.iter() {
+            self.handle_orphan(&block.hash);
+        }
+        return Ok(outcomes);
+    }
+
+    // Store a validated block whose parent is known together with its undo record, moving the tip if
+    // its branch now has the most work. The tip state, subscribers & spenders are left for the caller to update
+    fn connect(&mut self, mut b: Block, undo: BlockUndo) -> InsertOutcome {
+        let parent_hash = b.header.parent.clone();
+        let cur_index = self.blocks.get(&parent_hash).expect("connect a block without parent").index + 1;
+        let outcome: InsertOutcome;
+        self.undos.insert(b.hash, undo);
+        b.index = cur_index;
+        let parent_work = self.chain_work.get(&parent_hash).cloned().unwrap_or(0);
+        let work = parent_work.saturating_add(difficulty::block_work(&b.header.difficulty));
+        self.chain_work.insert(b.hash.clone(), work);
+        // a shorter branch of harder blocks beats a longer one of easier blocks, on a tie the first one stays
+        let more_work = work > self.tip_work();
+        let longest_block = self.blocks.get(&self.longest_hash).unwrap();
+        if more_work {
+            if parent_hash == self.longest_hash {
+                outcome = InsertOutcome::Extended;
+            } else {
+                let fork = self.fork_point(&self.longest_hash, &parent_hash);
+                let depth = longest_block.index - self.blocks.get(&fork).unwrap().index;
+                outcome = InsertOutcome::Reorg { depth };
+                self.reorg_stats.count += 1;
+                self.reorg_stats.max_depth = std::cmp::max(self.reorg_stats.max_depth, depth);
+                self.reorg_stats.last_time = Some(now_ms());
+                self.events.publish(Event::Reorg { tip: b.hash.clone(), depth });
+            }
+            self.longest_hash = b.hash.clone();
+            self.max_index = cur_index;
+            self.difficulty = self.difficulty_on_tip(&b);
+        } else {
+            outcome = InsertOutcome::SideBranch;
+        }
+        info!("Insert block with index {:?}: {:?}, nonce: {}, parent: {:?}, outcome: {:?}",
+              &b.index, &b.hash, b.header.nonce, parent_hash, outcome);
+
+        self.events.publish(Event::BlockAccepted {
+            hash: b.hash.clone(),
+            height: b.index,
+            tx_count: b.content.trans.len(),
+            total_fees: b.content.trans.iter().skip(1).map(|t| t.transaction.fee).sum(),
+            block_bytes: b.size(),
+        });
+        self.blocks.insert(b.hash.clone(), b);
+        if more_work {
+            self.update_canonical();
+        }
+        outcome
+    }
+
+    // Bring the height index of longest-chain up to the current tip, keeping the part shared with the new branch
+    fn update_canonical(&mut self) {
+        let mut branch = vec![];
+        let mut kept = None;  // height of the highest block the index already holds
+        let mut cur = self.blocks.get(&self.longest_hash);
+        while let Some(block) = cur {
+            if self.canonical_hash(block.index) == Some(&block.hash) {
+                kept = Some(block.index);
+                break;
+            }
+            branch.push(block.hash.clone());
+            if block.index == 0 {
+                break;
+            }
+            cur = self.blocks.get(&block.header.parent);
+        }
+        match kept {
+            Some(height) => {
+                self.canonical.truncate(height + 1 - self.canonical_base);
+                self.header_chunks.truncate(self.canonical.len() / self.header_chunk_size);
+            }
+            None => {
+                self.canonical.clear();
+                self.header_chunks.clear();
+                self.canonical_base = self.max_index + 1 - branch.len();
+            }
+        }
+        self.canonical.extend(branch.into_iter().rev());
+        self.update_header_chunks();
+    }
+
+    // Build the header chunks filled since the last update
+    fn update_header_chunks(&mut self) {
+        let size = self.header_chunk_size;
+        while (self.header_chunks.len() + 1) * size <= self.canonical.len() {
+            let start = self.header_chunks.len() * size;
+            let chunk = self.canonical[start..start + size].iter()
+                .map(|h| self.blocks.get(h).unwrap().header.clone())
+                .collect();
+            self.header_chunks.push(chunk);
+        }
+    }
+
+    // Hash of the block at a given height of longest-chain
+    fn canonical_hash(&self, height: usize) -> Option<&H256> {
+        self.canonical.get(height.checked_sub(self.canonical_base)?)
+    }
+
+    // Mempool transactions invalidated by a block, i.e. spending the same inputs as its transactions,
+    // including the ones held for their lock time
+    pub fn mempool_conflicts(&self, hash: &H256, mempool: &MemPool) -> Vec<H256> {
+        let mut conflicts = Vec::<H256>::new();
+        let block = match self.get_block(hash) {
+            Some(b) => b,
+            None => return conflicts,
+        };
+        for tran in block.content.trans.iter() {
+            for input in tran.transaction.inputs.iter() {
+                for conf_hash in mempool.spender_of(input).into_iter().chain(mempool.held_spender_of(input)) {
+                    if conf_hash != tran.hash && !conflicts.contains(&conf_hash) {
+                        conflicts.push(conf_hash);
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+
+    // Trust a checkpoint, so that a snapshot matching it can be installed
+    pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    // Get a block together with the state after it, for peers doing fast sync
+    pub fn snapshot(&self, hash: &H256) -> Option<(Block, State)> {
+        let state = self.state_at(hash)?;
+        let block = self.get_block(hash)?;
+        Some((block, state))
+    }
+
+    // Install state of the checkpoint block without replaying blocks before it
+    pub fn install_snapshot(&mut self, block: &Block, state: State) -> Result<(), BlockError> {
+        let checkpoint = match &self.checkpoint {
+            Some(c) => c.clone(),
+            None => return Err(BlockError::SnapshotMismatch),
+        };
+        if block.hash != checkpoint.block_hash
+            || block.header.hash() != block.hash
+            || state.hash() != checkpoint.utxo_set_hash {
+            return Err(BlockError::SnapshotMismatch);
+        }
+        if self.max_index >= checkpoint.height {
+            return Err(BlockError::AlreadyExists);
+        }
+        let mut b = block.clone();
+        b.index = checkpoint.height;
+        info!("Install snapshot at height {}: {:?}", b.index, b.hash);
+        self.remove_orphan(&b.hash);
+        self.state = state;
+        // ancestors are unknown, assume they all had the snapshot's difficulty
+        let work = difficulty::block_work(&b.header.difficulty).saturating_mul(b.index as u128 + 1);
+        self.chain_work.insert(b.hash.clone(), work);
+        self.blocks.insert(b.hash.clone(), b);
+        self.longest_hash = checkpoint.block_hash.clone();
+        self.max_index = checkpoint.height;
+        self.canonical.clear();
+        self.update_canonical();
+        self.difficulty = self.difficulty_on_tip(block);
+        self.reindex_chain();
+        self.publish_tip();
+        self.handle_orphan(&checkpoint.block_hash);
+        Ok(())
+    }
+
+    // Find the latest common ancestor of two blocks in chain
+    fn fork_point(&self, a: &H256, b: &H256) -> H256 {
+        let mut a = self.blocks.get(a).unwrap();
+        let mut b = self.blocks.get(b).unwrap();
+        while a.hash != b.hash {
+            // chain below an installed snapshot is unknown
+            let next = if a.index >= b.index { &mut a } else { &mut b };
+            match self.blocks.get(&next.header.parent) {
+                Some(parent) => *next = parent,
+                None => break,
+            }
+        }
+        a.hash.clone()
+    }
+
+    // Blocks from `from` back to (excluding) its ancestor `to`, `from` first
+    fn branch(&self, from: &H256, to: &H256) -> Vec<H256> {
+        let mut hashes = vec![];
+        let mut cur = self.blocks.get(from);
+        while let Some(block) = cur {
+            if block.hash == *to || block.index == 0 {
+                break;
+            }
+            hashes.push(block.hash.clone());
+            cur = self.blocks.get(&block.header.parent);
+        }
+        hashes
+    }
+
+    // Tell subscribers which transactions left and entered longest-chain after tip moved from old_tip:
+    // disconnected blocks first (old tip first), then connected ones (fork point first)
+    fn publish_confirmations(&mut self, old_tip: &H256) {
+        let fork = self.fork_point(old_tip, &self.longest_hash);
+        let mut events = vec![];
+        for hash in self.branch(old_tip, &fork) {
+            if let Some(block) = self.get_block(&hash) {
+                events.extend(block.content.trans.iter().map(|t| Event::TxUnconfirmed(t.hash.clone())));
+            }
+        }
+        for hash in self.branch(&self.longest_hash, &fork).iter().rev() {
+            if let Some(block) = self.get_block(hash) {
+                events.extend(block.content.trans.iter().map(|t| Event::TxConfirmed(t.hash.clone(), block.index)));
+            }
+        }
+        for event in events {
+            self.events.publish(event);
+        }
+    }
+
+    // Move the spent-output and transaction indexes from the branch of old_tip to the current longest-chain
+    fn update_chain_indexes(&mut self, old_tip: &H256) {
+        let fork = self.fork_point(old_tip, &self.longest_hash);
+        for hash in self.branch(old_tip, &fork) {
+            if let Some(block) = self.get_block(&hash) {
+                for tran in block.content.trans.iter() {
+                    for input in tran.transaction.inputs.iter() {
+                        self.spenders.remove(input);
+                    }
+                    self.tx_index.remove(&tran.hash);
+                }
+            }
+        }
+        for hash in self.branch(&self.longest_hash, &fork).into_iter().rev() {
+            if let Some(block) = self.get_block(&hash) {
+                for (position, tran) in block.content.trans.iter().enumerate() {
+                    for input in tran.transaction.inputs.iter() {
+                        self.spenders.insert(input.clone(), (tran.hash.clone(), hash.clone()));
+                    }
+                    self.tx_index.insert(tran.hash.clone(), (hash.clone(), position));
+                }
+            }
+        }
+    }
+
+    // Rebuild the spent-output and transaction indexes from the whole longest-chain
+    fn reindex_chain(&mut self) {
+        self.spenders.clear();
+        self.tx_index.clear();
+        self.update_chain_indexes(&Block::genesis().hash);
+    }
+
+    // The transaction spending an outpoint in longest-chain and the block containing it
+    pub fn spending_tx(&self, outpoint: &TxInput) -> Option<(H256, H256)> {
+        self.spenders.get(outpoint).cloned()
+    }
+
+    // Non-coinbase transactions of blocks that left longest-chain since tip was old_tip,
+    // except those confirmed again in the new branch
+    pub fn disconnected_trans(&self, old_tip: &H256) -> Vec<SignedTransaction> {
+        let fork = self.fork_point(old_tip, &self.longest_hash);
+        let disconnected = self.branch(old_tip, &fork);
+        if disconnected.is_empty() {
+            return vec![];
+        }
+        let mut confirmed = HashSet::new();
+        for hash in self.branch(&self.longest_hash, &fork) {
+            if let Some(block) = self.get_block(&hash) {
+                confirmed.extend(block.content.get_trans_hashes());
+            }
+        }
+        let mut trans = vec![];
+        for hash in disconnected.iter().rev() {
+            if let Some(block) = self.get_block(hash) {
+                trans.extend(block.content.trans.into_iter().skip(1).filter(|t| !confirmed.contains(&t.hash)));
             }
         }
-        return true;
+        trans
     }
 
     // Deal with a newly-arrived parent block's orphans
@@ -100,7 +715,66 @@ impl Blockchain {
         if let Some(children_vec) = self.orphans_map.remove(new_parent) {
             for child in children_vec.iter() {
                 self.orphans.remove(&child.hash);
-                self.insert(child);
+                self.orphan_times.remove(&child.hash);
+                if self.unchecked_difficulty.remove(&child.hash) {
+                    let parent = self.blocks.get(new_parent).unwrap();
+                    let checked = self.next_difficulty(parent).and_then(|d| self.validate_block_with(child, &d));
+                    match checked {
+                        Ok(()) => {}
+                        Err(BlockError::TimeTooNew) => {
+                            self.hold_future_block(child);
+                            continue;
+                        }
+                        Err(e) => {
+                            info!("Drop orphan {:?} after its parent arrived: {:?}", child.hash, e);
+                            continue;
+                        }
+                    }
+                }
+                if let Err(e) = self.insert(child) {
+                    info!("Drop orphan {:?} after its parent arrived: {:?}", child.hash, e);
+                }
+            }
+        }
+    }
+
+    // Drop a buffered orphan, return it if it was buffered
+    fn remove_orphan(&mut self, hash: &H256) -> Option<Block> {
+        let block = self.orphans.remove(hash)?;
+        let parent = &block.header.parent;
+        if let Some(children) = self.orphans_map.get_mut(parent) {
+            children.retain(|c| c.hash != *hash);
+            if children.is_empty() {
+                self.orphans_map.remove(parent);
+            }
+        }
+        self.unchecked_difficulty.remove(hash);
+        self.orphan_times.remove(hash);
+        Some(block)
+    }
+
+    // Drop orphans buffered for longer than orphan_ttl, then the oldest ones beyond max_orphans,
+    // so peers can't pin memory with blocks whose parent never comes
+    fn expire_orphans(&mut self) {
+        let now = now_ms();
+        let ttl = self.orphan_ttl;
+        let expired: Vec<H256> = self.orphan_times.iter()
+            .filter(|(_, since)| now.saturating_sub(**since) > ttl)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in expired.iter() {
+            debug!("Drop orphan {:?}, its parent didn't arrive in {} ms", hash, ttl);
+            self.remove_orphan(hash);
+        }
+        if self.orphans.len() > self.max_orphans {
+            let mut by_age: Vec<(u64, H256)> = self.orphan_times.iter()
+                .map(|(hash, since)| (*since, hash.clone()))
+                .collect();
+            by_age.sort();
+            let excess = self.orphans.len() - self.max_orphans;
+            for (_, hash) in by_age.into_iter().take(excess) {
+                debug!("Drop orphan {:?}, more than {} orphans are buffered", hash, self.max_orphans);
+                self.remove_orphan(&hash);
             }
         }
     }
@@ -110,6 +784,24 @@ impl Blockchain {
         self.orphans.contains_key(hash)
     }
 
+    // Hashes of buffered orphans, whose ancestors are still missing
+    pub fn orphan_hashes(&self) -> Vec<H256> {
+        let mut hashes: Vec<H256> = self.orphans.keys().cloned().collect();
+        hashes.sort();
+        hashes
+    }
+
+    // Tips (blocks without children) of all non-canonical branches with their heights, highest first
+    pub fn side_branch_tips(&self) -> Vec<(H256, usize)> {
+        let parents: HashSet<&H256> = self.blocks.values().map(|b| &b.header.parent).collect();
+        let mut tips: Vec<(H256, usize)> = self.blocks.values()
+            .filter(|b| b.hash != self.longest_hash && !parents.contains(&b.hash))
+            .map(|b| (b.hash.clone(), b.index))
+            .collect();
+        tips.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        tips
+    }
+
     // Trace back the very-first missing block of a block's hash
     pub fn missing_parent(&self, orphan_hash: &H256) -> Option<H256> {
         if !self.is_orphan(orphan_hash) {
@@ -122,25 +814,226 @@ impl Blockchain {
         Some(cur.clone())
     }
 
-    // Try to create new state for the new block
-    pub fn try_generate_new_state(&self, block: &Block) -> Option<State> {
-        if !self.check_trans {
-            return Some(State::new());  // skip in test
+    // Number of buffered orphans from a block down to its very-first missing ancestor, 0 if not orphan
+    pub fn orphan_depth(&self, orphan_hash: &H256) -> usize {
+        let mut depth = 0;
+        let mut cur = orphan_hash;
+        while let Some(orphan) = self.orphans.get(cur) {
+            depth += 1;
+            cur = &orphan.header.parent;
+        }
+        depth
+    }
+
+    // Try to create new state for the new block, together with the undo record of connecting it
+    pub fn try_generate_new_state(&self, block: &Block) -> Option<(State, BlockUndo)> {
+        if self.check_policy == CheckPolicy::SkipAll {
+            return Some((State::new(), BlockUndo::new(&block.hash)));  // skip in test
+        }
+        let mut state = match self.state_at(&block.header.parent) {
+            Some(s) => s,
+            None => {
+                warn!("No state of parent {:?}, it was loaded from an index", block.header.parent);
+                return None;
+            }
+        };
+        let height = self.blocks.get(&block.header.parent).unwrap().index + 1;
+        let undo = state.connect_block(block, Self::block_subsidy(height))?;
+        Some((state, undo))
+    }
+
+    // State after a block, from the tip state by disconnecting longest-chain down to the fork point
+    // and connecting the block's branch. None if an undo record or a body on the way is missing,
+    // e.g. for blocks loaded from an index or below an installed snapshot
+    fn state_at(&self, hash: &H256) -> Option<State> {
+        if *hash == self.longest_hash {
+            return Some(self.state.clone());
+        }
+        if !self.blocks.contains_key(hash) {
+            return None;
+        }
+        let fork = self.fork_point(&self.longest_hash, hash);
+        let mut state = self.state.clone();
+        for disconnected in self.branch(&self.longest_hash, &fork) {
+            let block = self.get_block(&disconnected)?;
+            if !state.disconnect_block(&block, self.undos.get(&disconnected)?) {
+                return None;
+            }
+        }
+        for connected in self.branch(hash, &fork).into_iter().rev() {
+            let block = self.get_block(&connected)?;
+            state.connect_block(&block, Self::block_subsidy(block.index))?;
+        }
+        Some(state)
+    }
+
+    // Every transaction of a block at a given height must have reached its lock time
+    fn check_final(&self, block: &Block, height: usize) -> Result<(), BlockError> {
+        if let Some(tran) = block.non_final_tran(height) {
+            debug!("Block {:?} has a transaction before its lock time: {:?}", block.hash, tran.hash);
+            return Err(BlockError::NonFinalTransaction);
+        }
+        Ok(())
+    }
+
+    // Coinbase reward of the block at a given height, halving every HALVING_INTERVAL blocks
+    pub fn block_subsidy(height: usize) -> u64 {
+        let halvings = height / HALVING_INTERVAL;
+        if halvings >= 64 {
+            return 0;
+        }
+        return COINBASE_REWARD >> halvings;
+    }
+
+    // Difficulty of the block following parent, adjusted every RETARGET_INTERVAL blocks or, in
+    // RetargetMode::Ema, every block
+    fn next_difficulty(&self, parent: &Block) -> Result<H256, BlockError> {
+        self.next_difficulty_in(parent, &HashMap::new())
+    }
+
+    // Same as next_difficulty, with ancestors also looked up in blocks not inserted yet.
+    // No difficulty matches a child whose parent can't be retargeted, so the error is DifficultyMismatch
+    fn next_difficulty_in(&self, parent: &Block, staged: &HashMap<H256, Block>) -> Result<H256, BlockError> {
+        if parent.index == 0 {
+            return Ok(self.initial_difficulty);
+        }
+        if self.retarget_mode == RetargetMode::Ema {
+            // solvetime of parent, genesis timestamp is meaningless
+            if parent.index < 2 {
+                return Ok(parent.header.difficulty.clone());
+            }
+            let grandparent = match staged.get(&parent.header.parent).or_else(|| self.blocks.get(&parent.header.parent)) {
+                Some(b) => b,
+                None => return Ok(parent.header.difficulty.clone()),  // below an installed snapshot
+            };
+            let solvetime = parent.header.timestamp.saturating_sub(grandparent.header.timestamp);
+            return Ok(difficulty::retarget_ema(&parent.header.difficulty, solvetime, TARGET_BLOCK_TIME, EMA_WINDOW));
+        }
+        let index = parent.index + 1;
+        if index % RETARGET_INTERVAL != 0 {
+            return Ok(parent.header.difficulty);
+        }
+        // first block of the interval, genesis timestamp is meaningless
+        let first_index = std::cmp::max(index - RETARGET_INTERVAL, 1);
+        let mut first = parent;
+        while first.index > first_index {
+            match staged.get(&first.header.parent).or_else(|| self.blocks.get(&first.header.parent)) {
+                Some(b) => first = b,
+                None => return Ok(parent.header.difficulty),  // below an installed snapshot
+            }
+        }
+        difficulty::retarget(&parent.header.difficulty, first.header.timestamp, parent.header.timestamp,
+                             (parent.index - first.index) as u64, TARGET_BLOCK_TIME)
+            .map_err(|e: RetargetError| {
+                debug!("Fail to retarget after block {:?}: {:?}", parent.hash, e);
+                BlockError::DifficultyMismatch
+            })
+    }
+
+    // Difficulty of the next block on a new tip, the tip's own one if it can't be retargeted
+    fn difficulty_on_tip(&self, tip: &Block) -> H256 {
+        match self.next_difficulty(tip) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Fail to retarget on block {:?}: {:?}", tip.hash, e);
+                tip.header.difficulty
+            }
         }
-        let parent_state = self.states.get(&block.header.parent).unwrap();
-        return block.try_generate_state(parent_state);
     }
 
     // Perform validation checks on PoW & difficulty & all transactions within it
-    pub fn validate_block_meta(&self, block: &Block) -> bool {
+    pub fn validate_block_meta(&self, block: &Block) -> Result<(), BlockError> {
+        let expected = if block.header.parent == self.longest_hash {
+            self.difficulty
+        } else if let Some(parent) = self.blocks.get(&block.header.parent) {
+            self.next_difficulty(parent)?
+        } else {
+            block.header.difficulty  // orphan, checked when its parent arrives
+        };
+        self.validate_block_with(block, &expected)
+    }
+
+    // Checks of validate_block_meta given the difficulty the block must have
+    fn validate_block_with(&self, block: &Block, expected: &H256) -> Result<(), BlockError> {
         let header_hash = block.header.hash();
-        if header_hash == block.hash
-            && block.header.difficulty == self.difficulty
-            && header_hash < self.difficulty
-            && block.validate_signature() {
-            return true;
+        if header_hash != block.hash {
+            return Err(BlockError::HashMismatch);
+        }
+        let expected = expected.clone();
+        if block.header.difficulty != expected {
+            return Err(BlockError::DifficultyMismatch);
+        }
+        if self.pow_enabled() && !header_hash.meets_difficulty(&expected) {
+            return Err(BlockError::InsufficientPow);
+        }
+        if block.weight() > self.max_block_weight {
+            return Err(BlockError::WeightTooLarge);
+        }
+        if self.check_policy == CheckPolicy::Full {
+            if let Some(tran) = block.invalid_signature() {
+                debug!("Block {:?} has a transaction with invalid signature: {:?}", block.hash, tran.hash);
+                return Err(BlockError::InvalidSignature);
+            }
+        }
+        if !block.validate_commitment() {
+            return Err(BlockError::CommitmentMismatch);
+        }
+        // a miner can't push its timestamps back without most of the recent blocks agreeing
+        if self.blocks.contains_key(&block.header.parent)
+            && block.header.timestamp <= self.median_time_past(&block.header.parent) {
+            return Err(BlockError::TimeTooOld);
+        }
+        // last, so a block held for its timestamp is otherwise valid
+        if block.header.timestamp > now_ms().saturating_add(self.max_future_drift) {
+            return Err(BlockError::TimeTooNew);
+        }
+        return Ok(());
+    }
+
+    // Hold a block rejected for its timestamp, dropping the furthest in the future beyond MAX_FUTURE_BLOCKS
+    fn hold_future_block(&mut self, block: &Block) {
+        self.future_blocks.insert(block.hash.clone(), block.clone());
+        if self.future_blocks.len() > MAX_FUTURE_BLOCKS {
+            let furthest = self.future_blocks.values()
+                .max_by_key(|b| (b.header.timestamp, b.hash.clone()))
+                .map(|b| b.hash.clone())
+                .unwrap();
+            self.future_blocks.remove(&furthest);
+        }
+    }
+
+    // Remove & return held blocks whose timestamp the clock has caught up with, earliest first,
+    // to be inserted again
+    pub fn take_due_future_blocks(&mut self) -> Vec<Block> {
+        let limit = now_ms().saturating_add(self.max_future_drift);
+        let due: Vec<H256> = self.future_blocks.values()
+            .filter(|b| b.header.timestamp <= limit)
+            .map(|b| b.hash.clone())
+            .collect();
+        let mut blocks: Vec<Block> = due.iter().filter_map(|h| self.future_blocks.remove(h)).collect();
+        blocks.sort_by_key(|b| b.header.timestamp);
+        blocks
+    }
+
+    // Median timestamp of a block and the blocks before it, MEDIAN_TIME_SPAN in all or fewer near genesis
+    // (or a snapshot); a block on top of it must be later. 0 for an unknown block
+    pub fn median_time_past(&self, hash: &H256) -> u64 {
+        let mut timestamps = vec![];
+        let mut cur = self.blocks.get(hash);
+        while let Some(block) = cur {
+            timestamps.push(block.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN || block.index == 0 {
+                break;
+            }
+            cur = self.blocks.get(&block.header.parent);
         }
-        return false;
+        timestamps.sort();
+        timestamps.get(timestamps.len() / 2).cloned().unwrap_or(0)
+    }
+
+    // Number of blocks held for a timestamp too far ahead
+    pub fn future_block_count(&self) -> usize {
+        self.future_blocks.len()
     }
 
     // Get the last block's hash of the longest chain
@@ -148,9 +1041,75 @@ impl Blockchain {
         self.longest_hash.clone()
     }
 
+    // Header of the tip block, without cloning its content
+    pub fn tip_header(&self) -> Header {
+        self.blocks.get(&self.longest_hash).unwrap().header.clone()
+    }
+
+    // Hash of the genesis header, which tells networks apart (the genesis block's own hash is all zeros)
+    pub fn genesis_id(&self) -> H256 {
+        let mut header = Block::genesis().header;
+        // a test network with another initial difficulty is another network
+        header.difficulty = self.initial_difficulty;
+        header.hash()
+    }
+
+    // Header of a block or orphan, without cloning or reading its content
+    pub fn get_header(&self, hash: &H256) -> Option<Header> {
+        self.blocks.get(hash).or_else(|| self.orphans.get(hash)).map(|b| b.header.clone())
+    }
+
     // Get state of the longest chain(tip)
     pub fn tip_block_state(&self) -> State {
-        self.states.get(&self.longest_hash).unwrap().clone()
+        self.state.clone()
+    }
+
+    // Confirmed balance of an address at the tip of longest-chain
+    pub fn balance(&self, addr: &H160) -> u64 {
+        self.state.coins_of(addr).1
+    }
+
+    // Digest of the UTXO set at the tip of longest-chain, the same on every node with the same set,
+    // to be committed in a checkpoint that snapshots are verified against
+    pub fn utxo_set_hash(&self) -> H256 {
+        self.state.hash()
+    }
+
+    // Height of the tip, genesis being 0
+    pub fn get_block_count(&self) -> usize {
+        self.length() - 1
+    }
+
+    // Raise the best known height by headers announced by a peer. A header counts if it has proof of work
+    // and its parent is a known block or an earlier header of the same batch, which tells its height
+    pub fn note_headers(&mut self, headers: &[Header]) {
+        let mut heights = HashMap::<H256, usize>::new();
+        for header in headers.iter() {
+            let hash = header.hash();
+            if !hash.meets_difficulty(&header.difficulty) {
+                continue;
+            }
+            let parent_height = match self.blocks.get(&header.parent) {
+                Some(parent) => parent.index,
+                None => match heights.get(&header.parent) {
+                    Some(height) => *height,
+                    None => continue,
+                },
+            };
+            heights.insert(hash, parent_height + 1);
+            self.best_header_height = std::cmp::max(self.best_header_height, parent_height + 1);
+        }
+    }
+
+    // Still catching up: tip is more than IBD_MAX_BEHIND blocks below the best known height
+    // (that of a trusted checkpoint or a header seen from peers), or older than MAX_TIP_AGE
+    pub fn is_initial_block_download(&self) -> bool {
+        let best_known = std::cmp::max(self.checkpoint.as_ref().map_or(0, |c| c.height), self.best_header_height);
+        if best_known > self.max_index + IBD_MAX_BEHIND {
+            return true;
+        }
+        let tip_ts = self.blocks.get(&self.longest_hash).unwrap().header.timestamp;
+        now_ms().saturating_sub(tip_ts) > MAX_TIP_AGE
     }
 
     // include genesis block
@@ -162,6 +1121,38 @@ impl Blockchain {
         self.difficulty.clone()
     }
 
+    // Difficulty a child of the given block must have, NotContiguous if the block is unknown
+    pub fn difficulty_after(&self, parent: &H256) -> Result<H256, BlockError> {
+        if *parent == self.longest_hash {
+            return Ok(self.difficulty);
+        }
+        match self.blocks.get(parent) {
+            Some(block) => self.next_difficulty(block),
+            None => Err(BlockError::NotContiguous),
+        }
+    }
+
+    // Reorgs seen since the node started
+    pub fn reorg_stats(&self) -> ReorgStats {
+        self.reorg_stats
+    }
+
+    // Shared tip summary, for miner & worker to read without blockchain lock
+    pub fn validation_tip(&self) -> Arc<AtomicCell<ValidationTip>> {
+        self.validation_tip.clone()
+    }
+
+    fn publish_tip(&self) {
+        let tip_block = self.blocks.get(&self.longest_hash).unwrap();
+        self.validation_tip.store(ValidationTip {
+            tip: self.longest_hash.clone(),
+            height: tip_block.index,
+            difficulty: tip_block.header.difficulty.clone(),
+            next_difficulty: self.difficulty.clone(),
+            median_time_past: self.median_time_past(&self.longest_hash),
+        });
+    }
+
     // check existence, including orphans_map
     pub fn exist(&self, hash: &H256) -> bool {
         self.blocks.contains_key(hash)
@@ -172,10 +1163,8 @@ impl Blockchain {
     pub fn get_blocks(&self, hashes: &Vec<H256>) -> Vec<Block> {
         let mut blocks = Vec::<Block>::new();
         for h in hashes.iter() {
-            if let Some(b) = self.blocks.get(&h) {
-                blocks.push(b.clone());
-            } else if let Some(b) = self.orphans.get(&h) {
-                blocks.push(b.clone());
+            if let Some(b) = self.get_block(h) {
+                blocks.push(b);
             }
         }
         blocks
@@ -184,7 +1173,7 @@ impl Blockchain {
     // Given hash, get a block from chain or orphan buffer
     pub fn get_block(&self, hash: &H256) -> Option<Block> {
         if let Some(b) = self.blocks.get(hash) {
-            Some(b.clone())
+            self.with_body(b)
         } else if let Some(b) = self.orphans.get(hash) {
             Some(b.clone())
         } else {
@@ -192,48 +1181,407 @@ impl Blockchain {
         }
     }
 
-    // Get a vector of hashes in longest-chain from tip to genesis
-    pub fn hash_chain(&self) -> Vec<H256> {
-        let mut cur_hash = self.tip();
-        let mut cur_block = self.blocks.get(&cur_hash).unwrap();
-        let mut index = cur_block.index as i32;
-        let mut result = Vec::<H256>::new();
-        while index >= 0 {
-            result.push(cur_hash);
-            cur_hash = cur_block.header.parent.clone();
-            cur_block = self.blocks.get(&cur_hash).unwrap();
-            index -= 1;
+    // Block in chain or orphan buffer whose hash starts with a hex prefix, e.g. for explorer searches
+    pub fn get_block_by_prefix(&self, prefix: &str) -> Result<Block, PrefixError> {
+        Self::check_prefix(prefix)?;
+        let matches: Vec<&H256> = self.blocks.keys().chain(self.orphans.keys())
+            .filter(|h| h.has_hex_prefix(prefix))
+            .collect();
+        match matches[..] {
+            [hash] => self.get_block(hash).ok_or(PrefixError::NotFound),
+            [] => Err(PrefixError::NotFound),
+            _ => Err(PrefixError::Ambiguous(matches.len())),
         }
-        result
+    }
+
+    // Transaction in longest-chain whose hash starts with a hex prefix, with the hash of its block
+    pub fn get_transaction_by_prefix(&self, prefix: &str) -> Result<(SignedTransaction, H256), PrefixError> {
+        Self::check_prefix(prefix)?;
+        let matches: Vec<&H256> = self.tx_index.keys()
+            .filter(|h| h.has_hex_prefix(prefix))
+            .collect();
+        match matches[..] {
+            [hash] => self.get_transaction(hash).map(|(tran, block, _)| (tran, block)).ok_or(PrefixError::NotFound),
+            [] => Err(PrefixError::NotFound),
+            _ => Err(PrefixError::Ambiguous(matches.len())),
+        }
+    }
+
+    // Transaction in longest-chain with a given hash, with the hash of its block and its number of confirmations
+    pub fn get_transaction(&self, hash: &H256) -> Option<(SignedTransaction, H256, usize)> {
+        let (block_hash, position) = self.tx_index.get(hash)?;
+        let block = self.blocks.get(block_hash)?;
+        let tran = if self.lazy_bodies.contains_key(block_hash) {
+            self.with_body(block)?.content.trans.into_iter().nth(*position)?
+        } else {
+            block.content.trans.get(*position)?.clone()
+        };
+        Some((tran, block_hash.clone(), self.max_index - block.index + 1))
+    }
+
+    fn check_prefix(prefix: &str) -> Result<(), PrefixError> {
+        if prefix.is_empty() || prefix.len() > 64 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(PrefixError::InvalidPrefix);
+        }
+        Ok(())
+    }
+
+    // Difficulty stored in the header of the canonical block at a given height
+    pub fn difficulty_at(&self, height: usize) -> Option<H256> {
+        self.canonical_block(height).map(|b| b.header.difficulty.clone())
+    }
+
+    // Estimated network hashes per second: work of the last `window` blocks of longest-chain divided by
+    // the time since the block before them. Genesis timestamp is meaningless, so the window starts after it.
+    // None if there are fewer than two blocks after genesis or no time passed
+    pub fn network_hashps(&self, window: usize) -> Option<f64> {
+        let window = std::cmp::min(window, self.max_index.saturating_sub(1));
+        if window == 0 {
+            return None;
+        }
+        let last = self.blocks.get(&self.longest_hash)?;
+        let mut first = last;
+        for _ in 0..window {
+            first = self.blocks.get(&first.header.parent)?;
+        }
+        let timespan = last.header.timestamp.checked_sub(first.header.timestamp)?;
+        if timespan == 0 {
+            return None;
+        }
+        // cumulative work tells the work of the window without walking it again
+        let work = self.chain_work.get(&last.hash)?.saturating_sub(*self.chain_work.get(&first.hash)?);
+        Some(work as f64 * 1000.0 / timespan as f64)
+    }
+
+    // Get the block at a given height of the longest chain
+    pub fn get_block_by_index(&self, index: usize) -> Option<Block> {
+        self.with_body(self.canonical_block(index)?)
+    }
+
+    fn canonical_block(&self, height: usize) -> Option<&Block> {
+        self.blocks.get(self.canonical_hash(height)?)  // none below an installed snapshot
+    }
+
+    // Get a vector of hashes in longest-chain from tip to genesis (or to the installed snapshot)
+    pub fn hash_chain(&self) -> Vec<H256> {
+        self.canonical.iter().rev().cloned().collect()
     }
 
     // Get a vector of headers in longest-chain from tip to genesis
     pub fn header_chain(&self) -> Vec<Header> {
         let hash_chain = self.hash_chain();
         let header_chain = hash_chain.iter()
-                .map(|h| self.get_block(h).unwrap().header.clone())
+                .map(|h| self.get_header(h).unwrap())
                 .collect();
         header_chain
     }
 
-    // Get a vector of blocks in longest-chain from tip to genesis
+    // Iterate blocks of longest-chain from genesis to tip without cloning them all at once
+    pub fn iter_canonical(&self) -> CanonicalIter<'_> {
+        let mut hashes = self.hash_chain();
+        hashes.reverse();
+        CanonicalIter {
+            blockchain: self,
+            hashes: hashes.into_iter(),
+        }
+    }
+
+    // Get a vector of blocks in longest-chain from tip to genesis, skipping those whose body can't be read
     pub fn block_chain(&self) -> Vec<Block> {
         let hash_chain = self.hash_chain();
         let block_chain = hash_chain.iter()
-                .map(|h| self.get_block(h).unwrap().clone())
+                .filter_map(|h| self.get_block(h))
                 .collect();
         block_chain
     }
 
-    // Get a vector of contents in longest-chain from tip to genesis
+    // Get a vector of contents in longest-chain from tip to genesis, skipping those that can't be read
     pub fn content_chain(&self) -> Vec<Content> {
         let hash_chain = self.hash_chain();
         let content_chain = hash_chain.iter()
-                .map(|h| self.get_block(h).unwrap().content)
+                .filter_map(|h| self.get_block(h))
+                .map(|b| b.content)
                 .collect();
         content_chain
     }
 
+    // Cumulative work from genesis to a block
+    pub fn chain_work(&self, hash: &H256) -> Option<u128> {
+        self.chain_work.get(hash).cloned()
+    }
+
+    // Cumulative work of the longest chain
+    pub fn tip_work(&self) -> u128 {
+        self.chain_work.get(&self.longest_hash).cloned().unwrap_or(0)
+    }
+
+    // Recompute the work of every block from its parent's and check it against the stored one
+    pub fn verify_chain(&self) -> Result<(), BlockError> {
+        for (hash, block) in self.blocks.iter() {
+            let stored = match self.chain_work.get(hash) {
+                Some(w) => *w,
+                None => return Err(BlockError::WorkMismatch),
+            };
+            let parent_work = if block.index == 0 {
+                0  // genesis, whose parent field is its own hash
+            } else {
+                match self.chain_work.get(&block.header.parent) {
+                    Some(w) => *w,
+                    None => continue,  // installed from a snapshot, ancestors unknown
+                }
+            };
+            if stored != parent_work.saturating_add(difficulty::block_work(&block.header.difficulty)) {
+                return Err(BlockError::WorkMismatch);
+            }
+        }
+        Ok(())
+    }
+
+    // Write blocks with the tip state, undo records & chain work to a file, return the number of blocks written
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let saved = SavedChain {
+            blocks: self.blocks.keys().filter_map(|h| self.get_block(h)).collect(),
+            state: self.state.clone(),
+            undos: self.undos.values().cloned().collect(),
+            chain_work: self.chain_work.iter().map(|(h, w)| (h.clone(), *w)).collect(),
+            longest_hash: self.longest_hash.clone(),
+        };
+        let writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(writer, &saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(saved.blocks.len())
+    }
+
+    // Replace the blockchain with one written by save_to, without replaying blocks.
+    // Return the number of blocks loaded, call verify_chain to check the stored work
+    pub fn load_from<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let reader = BufReader::new(File::open(path)?);
+        let saved: SavedChain = bincode::deserialize_from(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tip = match saved.blocks.iter().find(|b| b.hash == saved.longest_hash) {
+            Some(b) => b.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "tip block missing")),
+        };
+        let loaded = saved.blocks.len();
+        self.blocks = saved.blocks.into_iter().map(|b| (b.hash.clone(), b)).collect();
+        self.state = saved.state;
+        self.undos = saved.undos.into_iter().map(|u| (u.block, u)).collect();
+        self.chain_work = saved.chain_work.into_iter().collect();
+        self.lazy_bodies.clear();
+        self.bodies_path = None;
+        self.orphans.clear();
+        self.orphans_map.clear();
+        self.unchecked_difficulty.clear();
+        self.orphan_times.clear();
+        self.longest_hash = tip.hash.clone();
+        self.max_index = tip.index;
+        self.canonical.clear();
+        self.update_canonical();
+        self.difficulty = self.difficulty_on_tip(&tip);
+        self.reindex_chain();
+        self.publish_tip();
+        Ok(loaded)
+    }
+
+    // Write the block index to index_path and block contents to bodies_path,
+    // return the number of blocks written
+    pub fn save_index<P: AsRef<Path>, Q: AsRef<Path>>(&self, index_path: P, bodies_path: Q) -> io::Result<usize> {
+        let mut bodies = BufWriter::new(File::create(bodies_path)?);
+        let mut entries = vec![];
+        let mut offset = 0u64;
+        for hash in self.blocks.keys() {
+            let block = match self.get_block(hash) {
+                Some(b) => b,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("body of {:?} missing", hash))),
+            };
+            let body = bincode::serialize(&block.content)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            bodies.write_all(&body)?;
+            entries.push(IndexEntry {
+                hash: *hash,
+                height: block.index,
+                header: block.header,
+                chain_work: self.chain_work.get(hash).cloned().unwrap_or(0),
+                body: BodyPos { offset, len: body.len() as u64 },
+            });
+            offset += body.len() as u64;
+        }
+        bodies.flush()?;
+        let saved = SavedIndex {
+            entries,
+            longest_hash: self.longest_hash,
+            tip_state: self.tip_block_state(),
+            spenders: self.spenders.clone(),
+            tx_index: self.tx_index.clone(),
+        };
+        let writer = BufWriter::new(File::create(index_path)?);
+        bincode::serialize_into(writer, &saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(saved.entries.len())
+    }
+
+    // Replace the blockchain with a block index written by save_index, block contents are read
+    // from bodies_path only when a block is requested. Return the number of blocks indexed
+    pub fn load_index<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, index_path: P, bodies_path: Q) -> io::Result<usize> {
+        let reader = BufReader::new(File::open(index_path)?);
+        let saved: SavedIndex = bincode::deserialize_from(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tip = match saved.entries.iter().find(|e| e.hash == saved.longest_hash) {
+            Some(e) => Block { hash: e.hash, index: e.height, header: e.header.clone(), content: Content::new() },
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "tip block missing")),
+        };
+        let indexed = saved.entries.len();
+        self.blocks.clear();
+        self.chain_work.clear();
+        self.lazy_bodies.clear();
+        for e in saved.entries.into_iter() {
+            self.chain_work.insert(e.hash, e.chain_work);
+            self.lazy_bodies.insert(e.hash, e.body);
+            let block = Block { hash: e.hash, index: e.height, header: e.header, content: Content::new() };
+            self.blocks.insert(e.hash, block);
+        }
+        self.bodies_path = Some(bodies_path.as_ref().to_path_buf());
+        self.state = saved.tip_state;
+        self.undos.clear();
+        self.orphans.clear();
+        self.orphans_map.clear();
+        self.unchecked_difficulty.clear();
+        self.orphan_times.clear();
+        self.longest_hash = tip.hash;
+        self.max_index = tip.index;
+        self.canonical.clear();
+        self.update_canonical();
+        self.difficulty = self.difficulty_on_tip(&tip);
+        self.spenders = saved.spenders;
+        self.tx_index = saved.tx_index;
+        self.publish_tip();
+        Ok(indexed)
+    }
+
+    // The block with its content, read from the bodies file if it was loaded from an index
+    fn with_body(&self, block: &Block) -> Option<Block> {
+        let pos = match self.lazy_bodies.get(&block.hash) {
+            Some(pos) => pos,
+            None => return Some(block.clone()),
+        };
+        let path = self.bodies_path.as_ref()?;
+        self.body_reads.fetch_add(1, Ordering::Relaxed);
+        let read_body = || -> io::Result<Content> {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(pos.offset))?;
+            let mut buf = vec![0u8; pos.len as usize];
+            file.read_exact(&mut buf)?;
+            bincode::deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        match read_body() {
+            Ok(content) => {
+                let mut b = block.clone();
+                b.content = content;
+                Some(b)
+            }
+            Err(e) => {
+                warn!("Fail to read body of block {:?} from {:?}: {}", block.hash, path, e);
+                None
+            }
+        }
+    }
+
+    // Hashes of longest-chain from tip back to genesis, dense near tip then exponentially sparse,
+    // for a peer to find the fork point with us
+    pub fn block_locator(&self) -> Vec<H256> {
+        let chain = self.hash_chain();
+        let mut locator = vec![];
+        let mut i = 0;
+        let mut step = 1;
+        while i < chain.len() {
+            locator.push(chain[i]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            i += step;
+        }
+        let last = chain.last().unwrap();
+        if locator.last() != Some(last) {
+            locator.push(*last);
+        }
+        locator
+    }
+
+    // Headers of longest-chain a peer lacks given its block locator: oldest first, from just after the first
+    // locator hash on longest-chain toward tip, at most max. With no hash in common, from just after genesis
+    pub fn get_block_headers_for_sync(&self, locator: &[H256], max: usize) -> Vec<HeaderSlice> {
+        // a locator hash is on longest-chain if the height index holds it at its height
+        let common = locator.iter()
+            .filter_map(|h| self.blocks.get(h))
+            .find(|b| self.canonical_hash(b.index) == Some(&b.hash))
+            .map_or(self.canonical_base, |b| b.index);
+        self.headers_between(common + 1, common + 1 + max)
+    }
+
+    // Headers of longest-chain at heights from..to, oldest first. Heights in full chunks share them,
+    // only the headers after the last full chunk are cloned
+    pub fn headers_between(&self, from: usize, to: usize) -> Vec<HeaderSlice> {
+        let size = self.header_chunk_size;
+        let mut pos = from.saturating_sub(self.canonical_base);
+        let end = std::cmp::min(to.saturating_sub(self.canonical_base), self.canonical.len());
+        let mut slices = vec![];
+        while pos < end {
+            let k = pos / size;
+            if let Some(chunk) = self.header_chunks.get(k) {
+                let chunk_end = std::cmp::min((k + 1) * size, end);
+                slices.push(HeaderSlice { chunk: chunk.clone(), range: pos - k * size..chunk_end - k * size });
+                pos = chunk_end;
+            } else {
+                let chunk: Arc<[Header]> = self.canonical[pos..end].iter()
+                    .map(|h| self.blocks.get(h).unwrap().header.clone())
+                    .collect();
+                slices.push(HeaderSlice { range: 0..chunk.len(), chunk });
+                pos = end;
+            }
+        }
+        slices
+    }
+
+    // Write all blocks of longest-chain (genesis excluded) in order to a bootstrap file. After installing a snapshot
+    // they start at the snapshot block, for nodes synced up to its parent
+    // Blocks are written one by one in the encoding of a Vec<Block>, so the chain is never held in memory
+    pub fn export_bootstrap<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let mut blocks = self.iter_canonical();
+        if self.canonical.first() == Some(&Block::genesis().hash) {
+            blocks.next();  // every node already has genesis
+        }
+        let count = blocks.hashes.len();
+        let mut writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(&mut writer, &(count as u64))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let mut written = 0;
+        for block in blocks {
+            bincode::serialize_into(&mut writer, &block)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            written += 1;
+        }
+        if written != count {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("only {} of {} blocks have a readable body", written, count)));
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    // Validate and insert blocks from a bootstrap file one by one, return number of imported blocks
+    pub fn import_bootstrap<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let reader = BufReader::new(File::open(path)?);
+        let blocks: Vec<Block> = bincode::deserialize_from(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for b in blocks.iter() {
+            if let Err(e) = self.insert_with_check(b) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("bootstrap block {:?} rejected: {:?}", b.hash, e)));
+            }
+        }
+        Ok(blocks.len())
+    }
+
     #[cfg(any(test, test_utilities))]
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut cur_hash = self.tip();
@@ -249,8 +1597,15 @@ impl Blockchain {
         result
     }
 
+    // Change difficulty of the next block, also of the first interval if chain only has genesis.
+    // Genesis itself is left untouched, its hash is a key of blocks
+    #[cfg(any(test, test_utilities))]
     pub fn change_difficulty(&mut self, difficulty: &H256) {
         self.difficulty = difficulty.clone();
+        if self.max_index == 0 {
+            self.initial_difficulty = *difficulty;
+        }
+        self.publish_tip();
     }
 
     #[cfg(any(test, test_utilities))]
@@ -260,8 +1615,57 @@ impl Blockchain {
     }
 
     #[cfg(any(test, test_utilities))]
-    pub fn set_check_trans(&mut self, b: bool) {
-        self.check_trans = b;
+    pub fn set_max_future_drift(&mut self, max_future_drift: u64) {
+        self.max_future_drift = max_future_drift;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_orphan_limits(&mut self, max_orphans: usize, orphan_ttl: u64) {
+        self.max_orphans = max_orphans;
+        self.orphan_ttl = orphan_ttl;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn body_reads(&self) -> usize {
+        self.body_reads.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_header_chunk_size(&mut self, header_chunk_size: usize) {
+        self.header_chunk_size = header_chunk_size;
+        self.header_chunks.clear();
+        self.update_header_chunks();
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_max_block_weight(&mut self, max_block_weight: usize) {
+        self.max_block_weight = max_block_weight;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_retarget_mode(&mut self, retarget_mode: RetargetMode) {
+        self.retarget_mode = retarget_mode;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_check_policy(&mut self, policy: CheckPolicy) {
+        self.check_policy = policy;
+    }
+
+    // Accept blocks regardless of PoW, so tests can build long chains without mining
+    #[cfg(any(test, test_utilities))]
+    pub fn set_check_pow(&mut self, b: bool) {
+        self.check_pow = b;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    fn pow_enabled(&self) -> bool {
+        self.check_pow
+    }
+
+    #[cfg(not(any(test, test_utilities)))]
+    fn pow_enabled(&self) -> bool {
+        true
     }
 }
 
@@ -273,22 +1677,29 @@ mod tests {
     use crate::spread::Spreader;
     use crate::crypto::key_pair;
     use crate::network::message::Message;
+    use crate::config::{EASIEST_DIF, MIN_TARGET_DIF};
+    use crate::miner;
+    use crate::crypto::hash::H160;
+    use crate::transaction::{LockTime, SignedTransaction, TxInput, TxOutput};
+    use ring::digest;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
 
     use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+    use std::sync::Mutex;
     use std::time;
     use std::thread;
 
     #[test]
     fn test_insert() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         assert_eq!(&genesis_hash, &H256::from([0u8; 32]));
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
         assert_eq!(blockchain.tip_difficulty(), block.header.difficulty);
-        assert!(!blockchain.insert_with_check(&block));
+        assert_eq!(Err(BlockError::AlreadyExists), blockchain.insert_with_check(&block));
 
         let mut blockchain = Blockchain::new();
         let key = key_pair::random();
@@ -296,13 +1707,13 @@ mod tests {
         let content = Content::new_with_trans(&vec![signed_coinbase_tran.clone()]);
         let header = generate_header(&genesis_hash, &content, 0, &generate_random_hash());
         let block = Block::new(header, content);
-        assert!(blockchain.insert(&block));
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block));
 
         let invalid_signed_tran = generate_random_signed_transaction();
         let content = Content::new_with_trans(&vec![invalid_signed_tran.clone()]);
         let header = generate_header(&block.hash, &content, 0, &generate_random_hash());
         let block = Block::new(header, content);
-        assert!(!blockchain.insert(&block));
+        assert_eq!(Err(BlockError::InvalidTransactions), blockchain.insert(&block));
     }
 
     #[test]
@@ -314,45 +1725,45 @@ mod tests {
          *              ---------  block_2_1 <- block_2_2
          */
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block_1_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1_1);
+        blockchain.insert(&block_1_1).unwrap();
         let block_1_2 = generate_random_block(&block_1_1.hash());
-        blockchain.insert(&block_1_2);
+        blockchain.insert(&block_1_2).unwrap();
         assert_eq!(blockchain.tip(), block_1_2.hash());
         let block_2_1 = generate_random_block(&block_1_1.hash());
-        blockchain.insert(&block_2_1);
+        blockchain.insert(&block_2_1).unwrap();
         assert_eq!(blockchain.tip(), block_1_2.hash());
         let block_2_2 = generate_random_block(&block_2_1.hash());
-        blockchain.insert(&block_2_2);
+        blockchain.insert(&block_2_2).unwrap();
         assert_eq!(blockchain.tip(), block_2_2.hash());
         let block_1_3 = generate_random_block(&block_1_2.hash());
-        blockchain.insert(&block_1_3);
+        blockchain.insert(&block_1_3).unwrap();
         assert_eq!(blockchain.tip(), block_2_2.hash());
         let block_1_4 = generate_random_block(&block_1_3.hash());
-        blockchain.insert(&block_1_4);
+        blockchain.insert(&block_1_4).unwrap();
         assert_eq!(blockchain.tip(), block_1_4.hash());
     }
 
     #[test]
     fn handle_orphan() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         assert_eq!(1, blockchain.length());
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash());
         let block3 = generate_random_block(&block2.hash());
-        blockchain.insert(&block3);
-        blockchain.insert(&block2);
-        blockchain.insert(&block1);
+        blockchain.insert(&block3).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block1).unwrap();
         assert_eq!(blockchain.tip(), block3.hash());
         assert_eq!(4, blockchain.length());
 
         // naming rule: block_<branch>_<index>
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block_1_1 = generate_random_block(&genesis_hash);
         let block_1_2 = generate_random_block(&block_1_1.hash());
@@ -361,14 +1772,14 @@ mod tests {
         let block_2_3 = generate_random_block(&block_2_2.hash());
         let block_2_4 = generate_random_block(&block_2_3.hash());
         let block_2_5 = generate_random_block(&block_2_4.hash());
-        blockchain.insert(&block_2_5);
-        blockchain.insert(&block_2_4);
-        blockchain.insert(&block_2_3);
-        blockchain.insert(&block_2_2);
-        blockchain.insert(&block_1_3);
-        blockchain.insert(&block_1_2);
+        blockchain.insert(&block_2_5).unwrap();
+        blockchain.insert(&block_2_4).unwrap();
+        blockchain.insert(&block_2_3).unwrap();
+        blockchain.insert(&block_2_2).unwrap();
+        blockchain.insert(&block_1_3).unwrap();
+        blockchain.insert(&block_1_2).unwrap();
         assert_eq!(blockchain.tip(), genesis_hash);
-        blockchain.insert(&block_1_1);
+        blockchain.insert(&block_1_1).unwrap();
         assert_eq!(blockchain.tip(), block_2_5.hash());
         assert_eq!(6, blockchain.length());
     }
@@ -376,14 +1787,14 @@ mod tests {
     #[test]
     fn longest_chain_hash() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash());
         let block3 = generate_random_block(&block2.hash());
-        blockchain.insert(&block3);
-        blockchain.insert(&block2);
-        blockchain.insert(&block1);
+        blockchain.insert(&block3).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block1).unwrap();
         assert_eq!(blockchain.tip(), block3.hash());
         let chain_hash = blockchain.all_blocks_in_longest_chain();
         assert_eq!(chain_hash[0], block3.hash);
@@ -393,30 +1804,30 @@ mod tests {
     #[test]
     fn test_exist() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         assert!(blockchain.exist(&genesis_hash));
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash());
         let block3 = generate_random_block(&block2.hash());
         assert!(!blockchain.exist(&block3.hash));
-        blockchain.insert(&block3);
+        blockchain.insert(&block3).unwrap();
         assert!(blockchain.exist(&block3.hash));
         assert!(!blockchain.exist(&block1.hash));
-        blockchain.insert(&block1);
+        blockchain.insert(&block1).unwrap();
         assert!(blockchain.exist(&block1.hash));
     }
 
     #[test]
     fn test_get_blocks() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
         let hashes = vec![block1.hash(), block2.hash(), block3.hash()];
         let blocks = blockchain.get_blocks(&hashes);
         assert_eq!(2, blocks.len());
@@ -427,29 +1838,53 @@ mod tests {
     #[test]
     fn test_get_block() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block3);
+        blockchain.insert(&block3).unwrap();
         assert_eq!(block3, blockchain.get_block(&block3.hash).unwrap());
         assert_eq!(None, blockchain.get_block(&block1.hash));
-        blockchain.insert(&block2);
+        blockchain.insert(&block2).unwrap();
         assert_eq!(block2, blockchain.get_block(&block2.hash).unwrap());
     }
 
+    #[test]
+    fn test_tip_header() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&genesis_hash);
+        let block2 = generate_random_block(&block1.hash);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+
+        let tip_block = blockchain.get_block(&blockchain.tip()).unwrap();
+        let header = blockchain.tip_header();
+        assert_eq!(tip_block.header.hash(), header.hash());
+        assert_eq!(tip_block.header.parent, header.parent);
+        assert_eq!(tip_block.header.timestamp, header.timestamp);
+        assert_eq!(block1.hash, blockchain.get_header(&block1.hash).unwrap().hash());
+
+        // orphans have headers too
+        let orphan = generate_random_block(&generate_random_hash());
+        blockchain.insert(&orphan).unwrap();
+        assert_eq!(orphan.hash, blockchain.get_header(&orphan.hash).unwrap().hash());
+        assert!(blockchain.get_header(&generate_random_hash()).is_none());
+    }
+
     #[test]
     fn test_get_hash_chain() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         let hashes = blockchain.hash_chain();
         assert_eq!(genesis_hash, hashes[3]);
         assert_eq!(block1.hash, hashes[2]);
@@ -460,14 +1895,14 @@ mod tests {
     #[test]
     fn test_get_header_chain() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         let headers = blockchain.header_chain();
         assert_eq!(genesis_hash, headers[2].parent);
         assert_eq!(block1.hash, headers[1].parent);
@@ -477,14 +1912,14 @@ mod tests {
     #[test]
     fn test_get_block_chain() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
-        blockchain.insert(&block1);
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block1).unwrap();
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         let blocks = blockchain.block_chain();
         assert_eq!(block1.hash, blocks[2].hash);
         assert_eq!(block2.hash, blocks[1].hash);
@@ -494,21 +1929,21 @@ mod tests {
     #[test]
     fn test_orphan() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block1 = generate_random_block(&genesis_hash);
         let block2 = generate_random_block(&block1.hash);
         let block3 = generate_random_block(&block2.hash);
         assert!(!blockchain.is_orphan(&block3.hash));
         assert!(!blockchain.is_orphan(&block1.hash));
-        blockchain.insert(&block2);
-        blockchain.insert(&block3);
+        blockchain.insert(&block2).unwrap();
+        blockchain.insert(&block3).unwrap();
         assert!(blockchain.is_orphan(&block2.hash));
         assert!(blockchain.is_orphan(&block3.hash));
         assert!(!blockchain.is_orphan(&block1.hash));
         assert_eq!(block1.hash, blockchain.missing_parent(&block3.hash).unwrap());
         assert_eq!(block1.hash, blockchain.missing_parent(&block2.hash).unwrap());
-        blockchain.insert(&block1);
+        blockchain.insert(&block1).unwrap();
         assert!(!blockchain.is_orphan(&block3.hash));
         assert!(!blockchain.is_orphan(&block2.hash));
         assert!(!blockchain.is_orphan(&block1.hash));
@@ -517,6 +1952,34 @@ mod tests {
         assert_eq!(None, blockchain.missing_parent(&block1.hash));
     }
 
+    #[test]
+    fn test_orphan_expiry() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.set_orphan_limits(3, 60000);
+        let mut orphans = vec![];
+        for _ in 0..5 {
+            let orphan = generate_random_block(&generate_random_hash());
+            assert_eq!(Ok(InsertOutcome::Orphaned), blockchain.insert(&orphan));
+            orphans.push(orphan.hash);
+            thread::sleep(time::Duration::from_millis(2));
+        }
+
+        // the oldest are dropped beyond the cap
+        let mut kept = orphans[2..].to_vec();
+        kept.sort();
+        assert_eq!(kept, blockchain.orphan_hashes());
+        assert!(!blockchain.exist(&orphans[0]) && !blockchain.exist(&orphans[1]));
+
+        // and all of them once they outlive the ttl
+        blockchain.set_orphan_limits(3, 50);
+        thread::sleep(time::Duration::from_millis(100));
+        let recent = generate_random_block(&generate_random_hash());
+        blockchain.insert(&recent).unwrap();
+        assert_eq!(vec![recent.hash], blockchain.orphan_hashes());
+        assert_eq!(1, blockchain.orphans_map.len());
+    }
+
     #[test]
     fn test_sync_longest_chain() {
         let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17051);
@@ -537,8 +2000,8 @@ mod tests {
 
         server_1.broadcast(Message::Introduce((addr_1, pub_key1, port_1)), None);
         thread::sleep(time::Duration::from_millis(100));
-        blockchain_1.lock().unwrap().set_check_trans(false);
-        blockchain_2.lock().unwrap().set_check_trans(false);
+        blockchain_1.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain_2.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
 
         let mut chain_1 = blockchain_1.lock().unwrap();
         let mut chain_2 = blockchain_2.lock().unwrap();
@@ -546,9 +2009,9 @@ mod tests {
         let genesis = chain_1.tip();
         let difficulty = chain_1.difficulty();
         let block_1 = generate_mined_block(&genesis, &difficulty);
-        chain_1.insert(&block_1);
+        chain_1.insert(&block_1).unwrap();
         let block_2 = generate_mined_block(&block_1.hash, &difficulty);
-        chain_1.insert(&block_2);
+        chain_1.insert(&block_2).unwrap();
         drop(chain_1);
         drop(chain_2);
 
@@ -571,117 +2034,900 @@ mod tests {
     #[test]
     fn midtermproject1_insert_one() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
     }
     #[test]
     fn midtermproject1_insert_3_2() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let block_3 = generate_random_block(&block_2.hash());
-        blockchain.insert(&block_3);
+        blockchain.insert(&block_3).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
     }
     #[test]
     fn midtermproject1_insert_2_3() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let fork_block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         //assert_eq!(blockchain.tip(), block_2.hash());
         let fork_block_3 = generate_random_block(&fork_block_2.hash());
-        blockchain.insert(&fork_block_3);
+        blockchain.insert(&fork_block_3).unwrap();
         assert_eq!(blockchain.tip(), fork_block_3.hash());
     }
     #[test]
     fn midtermproject1_insert_3_fork_and_back() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let block_3 = generate_random_block(&block_2.hash());
-        blockchain.insert(&block_3);
+        blockchain.insert(&block_3).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_1 = generate_random_block(&block_2.hash());
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let block_4 = generate_random_block(&block_3.hash());
-        blockchain.insert(&block_4);
+        blockchain.insert(&block_4).unwrap();
         let block_5 = generate_random_block(&block_4.hash());
-        blockchain.insert(&block_5);
+        blockchain.insert(&block_5).unwrap();
         assert_eq!(blockchain.tip(), block_5.hash());
     }
     #[test]
     fn midtermproject1_insert_3_fork_and_6() {
         let mut blockchain = Blockchain::new();
-        blockchain.set_check_trans(false);
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
         let genesis_hash = blockchain.tip();
         let block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&block_1);
+        blockchain.insert(&block_1).unwrap();
         assert_eq!(blockchain.tip(), block_1.hash());
         let block_2 = generate_random_block(&block_1.hash());
-        blockchain.insert(&block_2);
+        blockchain.insert(&block_2).unwrap();
         assert_eq!(blockchain.tip(), block_2.hash());
         let block_3 = generate_random_block(&block_2.hash());
-        blockchain.insert(&block_3);
+        blockchain.insert(&block_3).unwrap();
         assert_eq!(blockchain.tip(), block_3.hash());
         let fork_block_1 = generate_random_block(&block_2.hash());
-        blockchain.insert(&fork_block_1);
+        blockchain.insert(&fork_block_1).unwrap();
         let fork_block_2 = generate_random_block(&fork_block_1.hash());
-        blockchain.insert(&fork_block_2);
+        blockchain.insert(&fork_block_2).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_1 = generate_random_block(&genesis_hash);
-        blockchain.insert(&another_block_1);
+        blockchain.insert(&another_block_1).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_2 = generate_random_block(&another_block_1.hash());
-        blockchain.insert(&another_block_2);
+        blockchain.insert(&another_block_2).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_3 = generate_random_block(&another_block_2.hash());
-        blockchain.insert(&another_block_3);
+        blockchain.insert(&another_block_3).unwrap();
         assert_eq!(blockchain.tip(), fork_block_2.hash());
         let another_block_4 = generate_random_block(&another_block_3.hash());
-        blockchain.insert(&another_block_4);
+        blockchain.insert(&another_block_4).unwrap();
         let another_block_5 = generate_random_block(&another_block_4.hash());
-        blockchain.insert(&another_block_5);
+        blockchain.insert(&another_block_5).unwrap();
         assert_eq!(blockchain.tip(), another_block_5.hash());
         let another_block_6 = generate_random_block(&another_block_5.hash());
-        blockchain.insert(&another_block_6);
+        blockchain.insert(&another_block_6).unwrap();
         assert_eq!(blockchain.tip(), another_block_6.hash());
     }
 
+    #[test]
+    fn test_insert_outcome() {
+        /*
+         * structure:
+         * genesis <- block_1_1 <- block_1_2
+         *    ^
+         *    ------ block_2_1 <- block_2_2 <- block_2_3
+         */
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let genesis_hash = blockchain.tip();
+        let block_1_1 = generate_random_block(&genesis_hash);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block_1_1));
+        let block_1_2 = generate_random_block(&block_1_1.hash);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block_1_2));
+        let block_2_1 = generate_random_block(&genesis_hash);
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert(&block_2_1));
+        let block_2_2 = generate_random_block(&block_2_1.hash);
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert(&block_2_2));
+        let block_2_3 = generate_random_block(&block_2_2.hash);
+        assert_eq!(Ok(InsertOutcome::Reorg { depth: 2 }), blockchain.insert(&block_2_3));
+        assert_eq!(blockchain.tip(), block_2_3.hash);
+
+        let missing = generate_random_block(&block_2_3.hash);
+        let orphan = generate_random_block(&missing.hash);
+        assert_eq!(Ok(InsertOutcome::Orphaned), blockchain.insert(&orphan));
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&missing));
+        assert_eq!(blockchain.tip(), orphan.hash);
+    }
+
+    #[test]
+    fn test_reorg_stats() {
+        /*
+         * structure:
+         * genesis <- block_1_1
+         *    ^
+         *    ------ block_2_1 <- block_2_2 <- block_2_3
+         *              ^
+         *              ------ block_3_2 <- block_3_3 <- block_3_4
+         */
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let events = blockchain.subscribe();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(ReorgStats::default(), blockchain.reorg_stats());
+        let mut insert = |parent: &H256| {
+            let block = generate_random_block(parent);
+            blockchain.insert(&block).unwrap();
+            block.hash
+        };
+
+        insert(&genesis_hash);
+        let block_2_1 = insert(&genesis_hash);
+        let block_2_2 = insert(&block_2_1);
+        insert(&block_2_2);
+        let block_3_2 = insert(&block_2_1);
+        let block_3_3 = insert(&block_3_2);
+        let block_3_4 = insert(&block_3_3);
+
+        let stats = blockchain.reorg_stats();
+        assert_eq!(2, stats.count);
+        assert_eq!(2, stats.max_depth);
+        assert!(now_ms() - stats.last_time.unwrap() < 1000);
+        let reorgs: Vec<Event> = events.try_iter().filter(|e| match e {
+            Event::Reorg { .. } => true,
+            _ => false,
+        }).collect();
+        assert_eq!(vec![Event::Reorg { tip: block_2_2, depth: 1 }, Event::Reorg { tip: block_3_4, depth: 2 }],
+                   reorgs);
+
+        // extending the tip is no reorg
+        let tip = blockchain.tip();
+        let block = generate_random_block(&tip);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block));
+        assert_eq!(stats, blockchain.reorg_stats());
+    }
+
+    #[test]
+    fn test_insert_already_have() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let events = blockchain.subscribe();
+        let block = generate_random_block(&blockchain.tip());
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block));
+        assert_eq!(Ok(InsertOutcome::AlreadyHave), blockchain.insert(&block));
+        assert_eq!(2, blockchain.length());
+        let accepted = events.try_iter().filter(|e| match e {
+            Event::BlockAccepted { .. } => true,
+            _ => false,
+        }).count();
+        assert_eq!(1, accepted);
+
+        // a buffered orphan is known too
+        let orphan = generate_random_block(&generate_random_hash());
+        assert_eq!(Ok(InsertOutcome::Orphaned), blockchain.insert(&orphan));
+        assert_eq!(Ok(InsertOutcome::AlreadyHave), blockchain.insert(&orphan));
+        assert_eq!(vec![orphan.hash], blockchain.orphan_hashes());
+    }
+
+    #[test]
+    fn test_insert_error() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let difficulty: H256 = gen_difficulty_array(0).into();
+        blockchain.change_difficulty(&difficulty);
+
+        let block = generate_block(&genesis_hash, 0, &difficulty);
+        assert_eq!(Err(BlockError::InvalidTransactions), blockchain.insert_with_check(&block));
+
+        let key = key_pair::random();
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key)]);
+        let header = generate_header(&genesis_hash, &content, 0, &difficulty);
+        let block = Block::new(header, content);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&block));
+        assert_eq!(Err(BlockError::AlreadyExists), blockchain.insert_with_check(&block));
+
+        let mut block = generate_block(&genesis_hash, 0, &difficulty);
+        block.change_hash(&generate_random_hash());
+        assert_eq!(Err(BlockError::HashMismatch), blockchain.insert_with_check(&block));
+
+        let wrong_difficulty: H256 = gen_difficulty_array(1).into();
+        let block = generate_block(&genesis_hash, 0, &wrong_difficulty);
+        assert_eq!(Err(BlockError::DifficultyMismatch), blockchain.insert_with_check(&block));
+
+        let mut tran = generate_signed_coinbase_transaction(&key);
+        tran.signature = generate_random_signed_transaction().signature;
+        let content = Content::new_with_trans(&vec![tran]);
+        let header = generate_header(&genesis_hash, &content, 0, &difficulty);
+        let block = Block::new(header, content);
+        assert_eq!(Err(BlockError::InvalidSignature), blockchain.insert_with_check(&block));
+
+        let hard_difficulty: H256 = gen_difficulty_array(256).into();
+        blockchain.change_difficulty(&hard_difficulty);
+        let block = generate_block(&blockchain.tip(), 0, &hard_difficulty);
+        assert_eq!(Err(BlockError::InsufficientPow), blockchain.insert_with_check(&block));
+    }
+
+    #[test]
+    fn test_check_policy() {
+        let difficulty: H256 = gen_difficulty_array(0).into();
+        let key = key_pair::random();
+        // block with an invalid spend, one with a bad signature, one with the wrong difficulty
+        let blocks = |genesis_hash: &H256| {
+            let bad_spend = generate_block(genesis_hash, 0, &difficulty);
+            let mut tran = generate_signed_coinbase_transaction(&key);
+            tran.signature = generate_random_signed_transaction().signature;
+            let content = Content::new_with_trans(&vec![tran]);
+            let header = generate_header(genesis_hash, &content, 0, &difficulty);
+            let bad_signature = Block::new(header, content);
+            let wrong_difficulty: H256 = gen_difficulty_array(1).into();
+            let bad_difficulty = generate_block(genesis_hash, 0, &wrong_difficulty);
+            (bad_spend, bad_signature, bad_difficulty)
+        };
+        let check = |policy: CheckPolicy, spend: Result<(), BlockError>, signature: Result<(), BlockError>| {
+            let mut blockchain = Blockchain::new();
+            blockchain.change_difficulty(&difficulty);
+            blockchain.set_check_policy(policy);
+            let (bad_spend, bad_signature, bad_difficulty) = blocks(&blockchain.tip());
+            assert_eq!(spend, blockchain.insert_with_check(&bad_spend).map(|_| ()));
+            assert_eq!(signature, blockchain.insert_with_check(&bad_signature).map(|_| ()));
+            // structural checks apply under every policy
+            assert_eq!(Err(BlockError::DifficultyMismatch), blockchain.insert_with_check(&bad_difficulty));
+        };
+
+        check(CheckPolicy::Full, Err(BlockError::InvalidTransactions), Err(BlockError::InvalidSignature));
+        check(CheckPolicy::SkipSignatures, Err(BlockError::InvalidTransactions), Ok(()));
+        check(CheckPolicy::SkipAll, Ok(()), Ok(()));
+    }
+
+    #[test]
+    fn test_lookup_by_prefix() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        for _ in 0..16 {
+            let block = generate_random_block(&blockchain.tip());
+            assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block));
+        }
+        // more than 16 hashes, so some of them share the first hex digit
+        let ambiguous = |hashes: &Vec<H256>| {
+            let first = |h: &H256| h.to_string()[..1].to_string();
+            let digit = hashes.iter().map(first).find(|d| hashes.iter().filter(|h| first(h) == *d).count() > 1).unwrap();
+            let count = hashes.iter().filter(|h| first(h) == digit).count();
+            (digit, count)
+        };
+
+        let block_hashes = blockchain.hash_chain();
+        let tip = blockchain.tip();
+        assert_eq!(Ok(tip), blockchain.get_block_by_prefix(&tip.to_string()[..16]).map(|b| b.hash));
+        assert_eq!(Ok(tip), blockchain.get_block_by_prefix(&tip.to_string().to_ascii_uppercase()).map(|b| b.hash));
+        let (digit, count) = ambiguous(&block_hashes);
+        assert_eq!(Err(PrefixError::Ambiguous(count)), blockchain.get_block_by_prefix(&digit).map(|b| b.hash));
+        assert_eq!(Err(PrefixError::NotFound), blockchain.get_block_by_prefix(&generate_random_hash().to_string()).map(|b| b.hash));
+        for bad in ["", "xyz", &"0".repeat(65)].iter() {
+            assert_eq!(Err(PrefixError::InvalidPrefix), blockchain.get_block_by_prefix(bad).map(|b| b.hash));
+        }
+
+        let block = blockchain.get_block(&block_hashes[3]).unwrap();
+        let tran = &block.content.trans[1];
+        assert_eq!(Ok((tran.hash, block.hash)), blockchain.get_transaction_by_prefix(&tran.hash.to_string()[..16])
+            .map(|(t, b)| (t.hash, b)));
+        let tran_hashes: Vec<H256> = blockchain.block_chain().iter()
+            .flat_map(|b| b.content.get_trans_hashes())
+            .collect();
+        let (digit, count) = ambiguous(&tran_hashes);
+        assert_eq!(Err(PrefixError::Ambiguous(count)), blockchain.get_transaction_by_prefix(&digit).map(|(t, _)| t.hash));
+        // blocks are looked up, their hashes aren't transactions
+        assert_eq!(Err(PrefixError::NotFound), blockchain.get_transaction_by_prefix(&tip.to_string()).map(|(t, _)| t.hash));
+    }
+
+    #[test]
+    fn test_would_accept() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let difficulty: H256 = gen_difficulty_array(0).into();
+        blockchain.change_difficulty(&difficulty);
+        let key = key_pair::random();
+        // would_accept must agree with insert_with_check and leave the blockchain unchanged
+        let check = |blockchain: &mut Blockchain, block: &Block, expected: Result<(), BlockError>| {
+            let (tip, length) = (blockchain.tip(), blockchain.length());
+            assert_eq!(expected, blockchain.would_accept(block));
+            assert_eq!((tip, length), (blockchain.tip(), blockchain.length()));
+            assert!(!blockchain.exist(&block.hash) || expected == Err(BlockError::AlreadyExists));
+            assert_eq!(expected, blockchain.insert_with_check(block).map(|_| ()));
+        };
+
+        let block = generate_block(&genesis_hash, 0, &difficulty);
+        check(&mut blockchain, &block, Err(BlockError::InvalidTransactions));
+
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key)]);
+        let header = generate_header(&genesis_hash, &content, 0, &difficulty);
+        let valid = Block::new(header, content);
+        check(&mut blockchain, &valid, Ok(()));
+        check(&mut blockchain, &valid, Err(BlockError::AlreadyExists));
+
+        // orphan is accepted into the buffer
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key)]);
+        let header = generate_header(&generate_random_hash(), &content, 0, &difficulty);
+        check(&mut blockchain, &Block::new(header, content), Ok(()));
+
+        let mut block = generate_block(&genesis_hash, 0, &difficulty);
+        block.change_hash(&generate_random_hash());
+        check(&mut blockchain, &block, Err(BlockError::HashMismatch));
+
+        let wrong_difficulty: H256 = gen_difficulty_array(1).into();
+        let block = generate_block(&valid.hash, 0, &wrong_difficulty);
+        check(&mut blockchain, &block, Err(BlockError::DifficultyMismatch));
+
+        let mut tran = generate_signed_coinbase_transaction(&key);
+        tran.signature = generate_random_signed_transaction().signature;
+        let content = Content::new_with_trans(&vec![tran]);
+        let header = generate_header(&valid.hash, &content, 0, &difficulty);
+        check(&mut blockchain, &Block::new(header, content), Err(BlockError::InvalidSignature));
+
+        let hard_difficulty: H256 = gen_difficulty_array(256).into();
+        blockchain.change_difficulty(&hard_difficulty);
+        let block = generate_block(&blockchain.tip(), 0, &hard_difficulty);
+        check(&mut blockchain, &block, Err(BlockError::InsufficientPow));
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&difficulty);
+        for _ in 0..10 {
+            let block = generate_mined_block(&blockchain.tip(), &difficulty);
+            blockchain.insert_with_check(&block).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("bootstrap_{}.dat", generate_random_str()));
+        assert_eq!(10, blockchain.export_bootstrap(&path).unwrap());
+
+        let mut fresh = Blockchain::new();
+        fresh.set_check_policy(CheckPolicy::SkipAll);
+        fresh.change_difficulty(&difficulty);
+        assert_eq!(10, fresh.import_bootstrap(&path).unwrap());
+        assert_eq!(blockchain.tip(), fresh.tip());
+        assert_eq!(blockchain.length(), fresh.length());
+
+        // blocks are re-validated on import
+        let mut strict = Blockchain::new();
+        strict.set_check_policy(CheckPolicy::SkipAll);
+        assert!(strict.import_bootstrap(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_iter_canonical() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let genesis_hash = blockchain.tip();
+        let mut parent = genesis_hash.clone();
+        for _ in 0..10 {
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash;
+        }
+        // a side branch is never yielded
+        blockchain.insert(&generate_random_block(&genesis_hash)).unwrap();
+
+        let blocks: Vec<Block> = blockchain.iter_canonical().collect();
+        assert_eq!(blockchain.length(), blocks.len());
+        assert_eq!(genesis_hash, blocks[0].hash);
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[0].hash, pair[1].header.parent);
+            assert_eq!(pair[0].index + 1, pair[1].index);
+        }
+        assert_eq!(blockchain.tip(), blocks.last().unwrap().hash);
+
+        // from tip back, as block_chain
+        let hashes: Vec<H256> = blockchain.iter_canonical().rev().map(|b| b.hash).collect();
+        assert_eq!(blockchain.hash_chain(), hashes);
+    }
+
+    #[test]
+    fn test_chain_work() {
+        let easy: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let harder: H256 = gen_difficulty_array(2).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&easy);
+        for i in 0..6 {
+            let difficulty = if i < 3 { &easy } else { &harder };
+            let block = generate_mined_block(&blockchain.tip(), difficulty);
+            blockchain.insert(&block).unwrap();
+        }
+        // a side branch
+        let fork = generate_mined_block(&blockchain.get_block_by_index(4).unwrap().hash, &easy);
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert(&fork));
+
+        // fresh sum of work along the ancestors
+        let computed = |chain: &Blockchain, hash: &H256| -> u128 {
+            let mut work = 0;
+            let mut block = chain.get_block(hash).unwrap();
+            loop {
+                work += difficulty::block_work(&block.header.difficulty);
+                if block.index == 0 {
+                    return work;
+                }
+                block = chain.get_block(&block.header.parent).unwrap();
+            }
+        };
+        // genesis keeps its own difficulty
+        let genesis_work = difficulty::block_work(&Block::genesis().header.difficulty);
+        assert_eq!(genesis_work + 3 + 3 * 4, blockchain.tip_work());
+        assert_eq!(Ok(()), blockchain.verify_chain());
+
+        let path = std::env::temp_dir().join(format!("chain_{}.dat", generate_random_str()));
+        assert_eq!(8, blockchain.save_to(&path).unwrap());
+        let mut loaded = Blockchain::new();
+        assert_eq!(8, loaded.load_from(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(blockchain.tip(), loaded.tip());
+        assert_eq!(blockchain.length(), loaded.length());
+        assert_eq!(blockchain.difficulty(), loaded.difficulty());
+        for hash in vec![loaded.tip(), fork.hash] {
+            assert_eq!(Some(computed(&loaded, &hash)), loaded.chain_work(&hash));
+        }
+        assert_eq!(Ok(()), loaded.verify_chain());
+
+        // tampered work is caught
+        loaded.chain_work.insert(fork.hash, 1);
+        assert_eq!(Err(BlockError::WorkMismatch), loaded.verify_chain());
+    }
+
+    #[test]
+    fn test_fork_choice_by_work() {
+        let easy: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let harder: H256 = gen_difficulty_array(2).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let genesis = blockchain.tip();
+        let mut parent = genesis.clone();
+        for _ in 0..3 {
+            let block = generate_mined_block(&parent, &easy);
+            assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block));
+            parent = block.hash;
+        }
+
+        // one block of 4 times the work beats three easy ones
+        let heavy = generate_mined_block(&genesis, &harder);
+        assert_eq!(Ok(InsertOutcome::Reorg { depth: 3 }), blockchain.insert(&heavy));
+        assert_eq!(heavy.hash, blockchain.tip());
+        assert_eq!(1, blockchain.get_block_count());
+
+        // a longer branch with as much work doesn't take the tip back
+        let block = generate_mined_block(&parent, &easy);
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert(&block));
+        assert_eq!(heavy.hash, blockchain.tip());
+        let block = generate_mined_block(&block.hash, &easy);
+        assert_eq!(Ok(InsertOutcome::Reorg { depth: 1 }), blockchain.insert(&block));
+        assert_eq!(5, blockchain.get_block_count());
+    }
+
+    #[test]
+    fn test_headers_for_sync() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let mut requester = Blockchain::new();
+        requester.set_check_policy(CheckPolicy::SkipAll);
+        let mut blocks = vec![];
+        for _ in 0..20 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block).unwrap();
+            blocks.push(block);
+        }
+        // the requester forked off after block 8 and mined a branch of its own
+        for block in blocks[..8].iter() {
+            requester.insert(block).unwrap();
+        }
+        for _ in 0..3 {
+            let block = generate_random_block(&requester.tip());
+            requester.insert(&block).unwrap();
+        }
+        let hashes_of = |blocks: &[Block]| -> Vec<H256> { blocks.iter().map(|b| b.hash).collect() };
+        let sync = |blockchain: &Blockchain, locator: &[H256], max| -> Vec<H256> {
+            blockchain.get_block_headers_for_sync(locator, max).iter().flat_map(|s| s.iter().map(|h| h.hash())).collect()
+        };
+
+        let locator = requester.block_locator();
+        assert_eq!(hashes_of(&blocks[8..]), sync(&blockchain, &locator, 100));
+        assert_eq!(hashes_of(&blocks[8..13]), sync(&blockchain, &locator, 5));
+
+        // up to date peer gets nothing, an unrelated locator gets the chain after genesis
+        assert!(sync(&blockchain, &blockchain.block_locator(), 100).is_empty());
+        assert_eq!(hashes_of(&blocks), sync(&blockchain, &[generate_random_hash()], 100));
+
+        // hashes of a known side branch don't count as common, even once the branch takes over by reorg
+        let mut branch: Vec<Block> = requester.block_chain()[..3].iter().rev().cloned().collect();
+        for block in branch.iter() {
+            blockchain.insert(block).unwrap();
+        }
+        assert_eq!(hashes_of(&blocks[8..]), sync(&blockchain, &locator, 100));
+        for _ in 0..10 {
+            let block = generate_random_block(&branch.last().unwrap().hash);
+            blockchain.insert(&block).unwrap();
+            branch.push(block);
+        }
+        assert_eq!(branch.last().unwrap().hash, blockchain.tip());
+        for (i, block) in branch.iter().enumerate() {
+            assert_eq!(Some(block.hash), blockchain.get_block_by_index(9 + i).map(|b| b.hash));
+        }
+        assert!(blockchain.get_block_by_index(9 + branch.len()).is_none());
+        assert_eq!(hashes_of(&branch[3..]), sync(&blockchain, &locator, 100));
+        assert_eq!(hashes_of(&branch), sync(&blockchain, &[blocks[7].hash], 100));
+    }
+
+    #[test]
+    fn test_headers_between_shared() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.set_header_chunk_size(5);
+        let mut blocks = vec![];
+        for _ in 0..25 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block).unwrap();
+            blocks.push(block);
+        }
+        // heights 0..25 fill 5 chunks, the tip at 25 is the incomplete one
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let handles: Vec<_> = (0..8).map(|i| {
+            let blockchain = blockchain.clone();
+            thread::spawn(move || blockchain.lock().unwrap().headers_between(1 + i, 21 + i))
+        }).collect();
+        let results: Vec<Vec<HeaderSlice>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        for (i, slices) in results.iter().enumerate() {
+            let hashes: Vec<H256> = slices.iter().flat_map(|s| s.iter().map(|h| h.hash())).collect();
+            let expected: Vec<H256> = blocks[i..std::cmp::min(20 + i, 25)].iter().map(|b| b.hash).collect();
+            assert_eq!(expected, hashes);
+        }
+        // every request got the same chunks for heights 0..25 instead of its own copy of the headers,
+        // only the tip is cloned for those reaching it
+        for (i, slices) in results.iter().enumerate() {
+            let shared = slices.iter().filter(|s| results[0].iter().any(|o| s.shares_chunk(o))).count();
+            let reaches_tip = 21 + i > 25;
+            assert_eq!(slices.len() - reaches_tip as usize, shared);
+        }
+        assert!(!results[5][4].shares_chunk(&results[6][4]));
+
+        // a reorg drops the chunks above the fork point, the ones below stay shared
+        let mut blockchain = blockchain.lock().unwrap();
+        let mut parent = blocks[11].hash;
+        for _ in 0..20 {
+            let block = generate_random_block(&parent);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash;
+        }
+        assert_eq!(parent, blockchain.tip());
+        let slices = blockchain.headers_between(1, 40);  // genesis hash isn't the hash of its header
+        assert_eq!(7, slices.len());
+        assert!(slices[1].shares_chunk(&results[0][1]));
+        assert!(!slices[2].shares_chunk(&results[0][2]));
+        let hashes: Vec<H256> = slices.iter().flat_map(|s| s.iter().map(|h| h.hash())).collect();
+        let expected: Vec<H256> = blockchain.hash_chain().into_iter().rev().skip(1).collect();
+        assert_eq!(expected, hashes);
+    }
+
+    #[test]
+    fn test_block_index() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.change_difficulty(&difficulty);
+        let key = key_pair::random();
+        for _ in 0..30 {
+            let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key)]);
+            let header = generate_header(&blockchain.tip(), &content, 0, &difficulty);
+            blockchain.insert_with_check(&Block::new(header, content)).unwrap();
+        }
+        let locator = blockchain.block_locator();
+        let chain = blockchain.hash_chain();
+        // 10 latest blocks, then every 2nd, 4th... block, ending at genesis
+        assert_eq!(chain[..10].to_vec(), locator[..10].to_vec());
+        assert_eq!(vec![chain[11], chain[15], chain[23], chain[30]],
+                   locator[10..].to_vec());
+
+        let name = generate_random_str();
+        let index_path = std::env::temp_dir().join(format!("index_{}.dat", name));
+        let bodies_path = std::env::temp_dir().join(format!("bodies_{}.dat", name));
+        assert_eq!(31, blockchain.save_index(&index_path, &bodies_path).unwrap());
+
+        // the index alone is enough for tip, difficulty, locator and the transaction indexes
+        let mut indexed = Blockchain::new();
+        assert_eq!(31, indexed.load_index(&index_path, &bodies_path).unwrap());
+        assert_eq!(0, indexed.body_reads());
+        let coinbase = blockchain.get_block(&chain[5]).unwrap().content.trans[0].hash;
+        assert_eq!(blockchain.get_transaction(&coinbase).map(|t| t.1), indexed.tx_index.get(&coinbase).map(|e| e.0));
+        assert_eq!(blockchain.spenders, indexed.spenders);
+        assert_eq!(blockchain.tip(), indexed.tip());
+        assert_eq!(blockchain.length(), indexed.length());
+        assert_eq!(blockchain.difficulty(), indexed.difficulty());
+        assert_eq!(blockchain.tip_work(), indexed.tip_work());
+        assert_eq!(locator, indexed.block_locator());
+        assert_eq!(0, indexed.body_reads());
+
+        // without the bodies file no content is available
+        let missing_bodies = std::env::temp_dir().join(format!("missing_{}.dat", name));
+        let mut indexed = Blockchain::new();
+        assert_eq!(31, indexed.load_index(&index_path, &missing_bodies).unwrap());
+        assert!(indexed.get_block(&indexed.tip()).is_none());
+        assert!(indexed.block_chain().is_empty());
+        assert!(indexed.content_chain().is_empty());
+
+        // bodies are read on demand, and new blocks extend the loaded tip
+        let mut indexed = Blockchain::new();
+        indexed.load_index(&index_path, &bodies_path).unwrap();
+        for hash in chain.iter() {
+            assert_eq!(blockchain.get_block(hash), indexed.get_block(hash));
+        }
+        assert!(indexed.body_reads() > 0);
+        assert!(indexed.get_transaction(&coinbase).is_some());
+        assert_eq!(blockchain.get_transaction(&coinbase), indexed.get_transaction(&coinbase));
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key)]);
+        let header = generate_header(&indexed.tip(), &content, 0, &difficulty);
+        assert_eq!(Ok(InsertOutcome::Extended), indexed.insert_with_check(&Block::new(header, content)));
+
+        // blocks of a truncated bodies file are skipped
+        let bodies_len = std::fs::metadata(&bodies_path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&bodies_path).unwrap().set_len(bodies_len / 2).unwrap();
+        let mut indexed = Blockchain::new();
+        indexed.load_index(&index_path, &bodies_path).unwrap();
+        let blocks = indexed.block_chain();
+        assert!(blocks.len() > 0 && blocks.len() < chain.len());
+        for block in blocks.iter() {
+            assert_eq!(blockchain.get_block(&block.hash).as_ref(), Some(block));
+        }
+        assert_eq!(blocks.len(), indexed.content_chain().len());
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::remove_file(&bodies_path).unwrap();
+    }
+
+    #[test]
+    fn test_install_snapshot() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let mut full = Blockchain::new();
+        full.change_difficulty(&difficulty);
+        let mut blocks = Vec::<Block>::new();
+        let mut first_coinbase: Option<SignedTransaction> = None;
+        for i in 0..8 {
+            let coinbase = generate_signed_coinbase_transaction(&keys[i % 3]);
+            let mut trans = vec![coinbase.clone()];
+            if i == 6 {
+                // spend the very first coinbase above the snapshot
+                let prev = first_coinbase.clone().unwrap();
+                let rec_addr: H160 = digest::digest(&digest::SHA256, keys[1].public_key().as_ref()).into();
+                let output = TxOutput::new(rec_addr, COINBASE_REWARD);
+                trans.push(generate_signed_transaction(&keys[0], vec![TxInput::new(prev.hash, 0)], vec![output]));
+            }
+            if first_coinbase.is_none() {
+                first_coinbase = Some(coinbase);
+            }
+            let content = Content::new_with_trans(&trans);
+            let header = generate_header(&full.tip(), &content, 0, &difficulty);
+            let block = Block::new(header, content);
+            full.insert_with_check(&block).unwrap();
+            blocks.push(block);
+        }
+
+        let height = 4;
+        let (base, state) = full.snapshot(&blocks[height - 1].hash).unwrap();
+        let mut fast = Blockchain::new();
+        fast.change_difficulty(&difficulty);
+        assert_eq!(Err(BlockError::SnapshotMismatch), fast.install_snapshot(&base, state.clone()));
+        fast.set_checkpoint(Checkpoint { height, block_hash: base.hash, utxo_set_hash: state.hash() });
+        assert_eq!(Err(BlockError::SnapshotMismatch), fast.install_snapshot(&base, State::new()));
+        fast.install_snapshot(&base, state).unwrap();
+        assert_eq!(height + 1, fast.length());
+        for block in blocks[height..].iter() {
+            fast.insert_with_check(block).unwrap();
+        }
+        assert_eq!(full.tip(), fast.tip());
+        assert_eq!(full.length(), fast.length());
+        assert_eq!(5, fast.hash_chain().len());
+        for key in keys.iter() {
+            let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
+            assert_eq!(full.tip_block_state().coins_of(&addr).1, fast.tip_block_state().coins_of(&addr).1);
+        }
+
+        // the export starts at the snapshot block, a node synced up to its parent can import it
+        let path = std::env::temp_dir().join(format!("bootstrap_{}.dat", generate_random_str()));
+        assert_eq!(5, fast.export_bootstrap(&path).unwrap());
+        let mut partial = Blockchain::new();
+        partial.change_difficulty(&difficulty);
+        for block in blocks[..height - 1].iter() {
+            partial.insert_with_check(block).unwrap();
+        }
+        assert_eq!(5, partial.import_bootstrap(&path).unwrap());
+        assert_eq!(full.tip(), partial.tip());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_retarget_on_boundary() {
+        let difficulty: H256 = gen_difficulty_array(4).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&difficulty);
+        let mine = |parent: &H256, ts: u128, difficulty: &H256| {
+            let content = generate_random_content();
+            let mut header = Header::new(parent, 0, ts, difficulty, &content.merkle_root());
+            assert!(miner::mining_base(&mut header, *difficulty));
+            Block::new(header, content)
+        };
+        // blocks come twice as slow as expected
+        for i in 1..RETARGET_INTERVAL {
+            let block = mine(&blockchain.tip(), (i as u64 * 2 * TARGET_BLOCK_TIME) as u128, &difficulty);
+            blockchain.insert_with_check(&block).unwrap();
+        }
+        let fork_parent = blockchain.tip();
+        let new_difficulty = difficulty::retarget_timespan(&difficulty, 2 * TARGET_BLOCK_TIME * (RETARGET_INTERVAL as u64 - 2),
+                                                           TARGET_BLOCK_TIME * (RETARGET_INTERVAL as u64 - 2));
+        assert_eq!(new_difficulty, blockchain.difficulty());
+        assert!(new_difficulty > difficulty);
+
+        let ts = (RETARGET_INTERVAL as u64 * 2 * TARGET_BLOCK_TIME) as u128;
+        let stale = mine(&fork_parent, ts, &difficulty);
+        assert_eq!(Err(BlockError::DifficultyMismatch), blockchain.insert_with_check(&stale));
+        let block = mine(&fork_parent, ts, &new_difficulty);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&block));
+        assert_eq!(new_difficulty, blockchain.difficulty());
+
+        // a side branch at the boundary is checked against its own parent, orphans once parent arrives
+        let side = mine(&fork_parent, ts + 1, &new_difficulty);
+        let orphan = mine(&side.hash, ts + 2, &difficulty);
+        assert_eq!(Ok(InsertOutcome::Orphaned), blockchain.insert_with_check(&orphan));
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert_with_check(&side));
+        assert!(!blockchain.exist(&orphan.hash));
+    }
+
+    #[test]
+    fn test_retarget_ema() {
+        let difficulty: H256 = gen_difficulty_array(4).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.set_retarget_mode(RetargetMode::Ema);
+        blockchain.change_difficulty(&difficulty);
+        let mine = |parent: &H256, ts: u128, difficulty: &H256| {
+            let content = generate_random_content();
+            let mut header = Header::new(parent, 0, ts, difficulty, &content.merkle_root());
+            assert!(miner::mining_base(&mut header, difficulty.clone()));
+            Block::new(header, content)
+        };
+        // the first block's solvetime is unknown, after that every block retargets
+        let block = mine(&blockchain.tip(), TARGET_BLOCK_TIME as u128, &difficulty);
+        blockchain.insert_with_check(&block).unwrap();
+        assert_eq!(difficulty, blockchain.difficulty());
+
+        // blocks come twice as slow as expected, the target eases a little every block
+        let mut expected = difficulty.clone();
+        for i in 2..6u64 {
+            let ts = (i * 2 * TARGET_BLOCK_TIME) as u128;
+            let stale = mine(&blockchain.tip(), ts, &difficulty::retarget_ema(&expected, 0, TARGET_BLOCK_TIME, EMA_WINDOW));
+            assert_eq!(Err(BlockError::DifficultyMismatch), blockchain.insert_with_check(&stale));
+            let block = mine(&blockchain.tip(), ts, &expected);
+            assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&block));
+            let solvetime = if i == 2 { 3 * TARGET_BLOCK_TIME } else { 2 * TARGET_BLOCK_TIME };
+            let next = difficulty::retarget_ema(&expected, solvetime, TARGET_BLOCK_TIME, EMA_WINDOW);
+            assert!(next > expected);
+            assert_eq!(next, blockchain.difficulty());
+            expected = next;
+        }
+    }
+
+    #[test]
+    fn test_validation_tip() {
+        let blockchain = Arc::new(crate::lock_order::OrderedMutex::new(Blockchain::new()));
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain.lock().unwrap().change_difficulty(&difficulty);
+        let validation_tip = blockchain.lock().unwrap().validation_tip();
+        assert_eq!(difficulty, validation_tip.load().next_difficulty);
+
+        let writer_chain = blockchain.clone();
+        let writer = thread::spawn(move || {
+            let mut side_parent = writer_chain.lock().unwrap().tip();
+            for i in 0..(RETARGET_INTERVAL - 1) {
+                let mut chain = writer_chain.lock().unwrap();
+                let parent = if i % 10 == 9 { side_parent } else { chain.tip() };
+                let block = generate_mined_block(&parent, &chain.difficulty());
+                chain.insert_with_check(&block).unwrap();
+                side_parent = parent;
+            }
+        });
+        let mut readers = Vec::new();
+        for _ in 0..2 {
+            let chain = blockchain.clone();
+            let validation_tip = validation_tip.clone();
+            readers.push(thread::spawn(move || {
+                for _ in 0..500 {
+                    // lock-free read is a consistent tip
+                    let snapshot = validation_tip.load();
+                    let locked = chain.lock().unwrap();
+                    assert_eq!(snapshot.height, locked.get_block(&snapshot.tip).unwrap().index);
+                    // published under lock, so equal to locked values
+                    let snapshot = validation_tip.load();
+                    assert_eq!(locked.tip(), snapshot.tip);
+                    assert_eq!(locked.length(), snapshot.height + 1);
+                    assert_eq!(locked.difficulty(), snapshot.next_difficulty);
+                    assert_eq!(locked.median_time_past(&locked.tip()), snapshot.median_time_past);
+                    assert_eq!(locked.get_block(&locked.tip()).unwrap().header.difficulty, snapshot.difficulty);
+                }
+            }));
+        }
+        writer.join().unwrap();
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(blockchain.lock().unwrap().tip(), validation_tip.load().tip);
+    }
+
+    #[test]
+    fn test_mempool_conflicts() {
+        let key = key_pair::random();
+        let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
+        let mut blockchain = Blockchain::new();
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        let coinbase = generate_signed_coinbase_transaction(&key);
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let block_1 = Block::new(generate_header(&blockchain.tip(), &content, 0, &difficulty), content);
+        blockchain.insert_with_check(&block_1).unwrap();
+
+        // two transactions spending the same coinbase output
+        let input = TxInput::new(coinbase.hash, 0);
+        let confirmed = generate_signed_transaction(&key, vec![input.clone()], vec![TxOutput::new(addr, 20)]);
+        let conflicting = generate_signed_transaction(&key, vec![input], vec![TxOutput::new(addr, 30)]);
+        let unrelated = generate_random_signed_transaction();
+        let mut mempool = MemPool::new();
+        assert!(mempool.add_with_check(&conflicting));
+        assert!(mempool.add_with_check(&unrelated));
+
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key), confirmed]);
+        let block_2 = Block::new(generate_header(&blockchain.tip(), &content, 0, &difficulty), content);
+        blockchain.insert_with_check(&block_2).unwrap();
+        let conflicts = blockchain.mempool_conflicts(&block_2.hash, &mempool);
+        assert_eq!(vec![conflicting.hash], conflicts);
+        assert!(blockchain.mempool_conflicts(&block_1.hash, &mempool).is_empty());
+
+        mempool.remove_conflicts(&conflicts);
+        assert!(!mempool.exist(&conflicting.hash));
+        assert!(mempool.exist(&unrelated.hash));
+    }
+
     #[test]
     fn test_validate_block_meta() {
         let mut blockchain = Blockchain::new();
@@ -689,17 +2935,748 @@ mod tests {
         let difficulty: H256 = gen_difficulty_array(0).into();
         blockchain.change_difficulty(&difficulty);
         let mut block = generate_block(&genesis_hash, 40, &difficulty);
-        assert!(blockchain.validate_block_meta(&block));
+        assert_eq!(Ok(()), blockchain.validate_block_meta(&block));
 
         // Hash Validate
         let hash: H256 = gen_difficulty_array(20).into();
         block.change_hash(&hash);
-        assert!(!blockchain.validate_block_meta(&block));
+        assert_eq!(Err(BlockError::HashMismatch), blockchain.validate_block_meta(&block));
 
         //POW validate
         let difficulty: H256 = gen_difficulty_array(20).into();
         blockchain.change_difficulty(&difficulty);
         let block = generate_block(&genesis_hash, 1, &difficulty);
-        assert!(!blockchain.validate_block_meta(&block));
+        assert_eq!(Err(BlockError::InsufficientPow), blockchain.validate_block_meta(&block));
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(COINBASE_REWARD, Blockchain::block_subsidy(0));
+        assert_eq!(COINBASE_REWARD, Blockchain::block_subsidy(HALVING_INTERVAL - 1));
+        assert_eq!(COINBASE_REWARD / 2, Blockchain::block_subsidy(HALVING_INTERVAL));
+        assert_eq!(COINBASE_REWARD / 4, Blockchain::block_subsidy(2 * HALVING_INTERVAL));
+        assert_eq!(0, Blockchain::block_subsidy(64 * HALVING_INTERVAL));
+
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.change_difficulty(&difficulty);
+        let key = key_pair::random();
+        let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
+
+        // over-claiming coinbase
+        let coinbase = generate_signed_coinbase_transaction_with_reward(&key, COINBASE_REWARD + 1);
+        let content = Content::new_with_trans(&vec![coinbase]);
+        let header = generate_header(&blockchain.tip(), &content, 0, &difficulty);
+        let block = Block::new(header, content);
+        assert_eq!(Err(BlockError::InvalidTransactions), blockchain.insert_with_check(&block));
+
+        let coinbase = generate_signed_coinbase_transaction(&key);
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let header = generate_header(&blockchain.tip(), &content, 0, &difficulty);
+        blockchain.insert_with_check(&Block::new(header, content)).unwrap();
+
+        // coinbase may also collect fees, but not more
+        let input = TxInput::new(coinbase.hash.clone(), 0);
+        let output = TxOutput::new(addr, COINBASE_REWARD - 3);
+        let paying = generate_signed_transaction_with_fee(&key, vec![input], vec![output], 3);
+        for (reward, expected) in vec![(COINBASE_REWARD + 4, Err(BlockError::InvalidTransactions)),
+                                       (COINBASE_REWARD + 3, Ok(InsertOutcome::Extended))] {
+            let coinbase = generate_signed_coinbase_transaction_with_reward(&key, reward);
+            let content = Content::new_with_trans(&vec![coinbase, paying.clone()]);
+            let header = generate_header(&blockchain.tip(), &content, 0, &difficulty);
+            assert_eq!(expected, blockchain.insert_with_check(&Block::new(header, content)));
+        }
+    }
+
+    #[test]
+    fn test_side_branch_tips() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&difficulty);
+        let genesis_hash = blockchain.tip();
+        assert!(blockchain.side_branch_tips().is_empty());
+
+        // main chain: genesis <- 1 <- 2 <- 3, side branch: 1 <- 2'
+        let mut main_chain = vec![];
+        for _ in 0..3 {
+            let block = generate_mined_block(&blockchain.tip(), &difficulty);
+            blockchain.insert_with_check(&block).unwrap();
+            main_chain.push(block.hash);
+        }
+        let side = generate_mined_block(&main_chain[0], &difficulty);
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert_with_check(&side));
+        assert_eq!(vec![(side.hash.clone(), 2)], blockchain.side_branch_tips());
+
+        // a second branch from genesis
+        let side_2 = generate_mined_block(&genesis_hash, &difficulty);
+        blockchain.insert_with_check(&side_2).unwrap();
+        assert_eq!(vec![(side.hash.clone(), 2), (side_2.hash.clone(), 1)], blockchain.side_branch_tips());
+
+        // extending a side branch moves its tip
+        let side_child = generate_mined_block(&side.hash, &difficulty);
+        blockchain.insert_with_check(&side_child).unwrap();
+        assert_eq!(vec![(side_child.hash.clone(), 3), (side_2.hash.clone(), 1)], blockchain.side_branch_tips());
+
+        let orphan = generate_mined_block(&generate_random_hash(), &difficulty);
+        assert_eq!(Ok(InsertOutcome::Orphaned), blockchain.insert_with_check(&orphan));
+        assert_eq!(vec![orphan.hash.clone()], blockchain.orphan_hashes());
+        assert_eq!(2, blockchain.side_branch_tips().len());
+    }
+
+    #[test]
+    fn test_change_difficulty_keeps_genesis() {
+        let easy: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&easy);
+        let genesis = Block::genesis();
+        assert_eq!(genesis.header.hash(), blockchain.get_block(&genesis.hash).unwrap().header.hash());
+        assert_ne!(Blockchain::new().genesis_id(), blockchain.genesis_id());
+        assert_eq!(Ok(easy), blockchain.difficulty_after(&genesis.hash));
+
+        // a branch off genesis still has the changed difficulty once genesis is no longer tip
+        let block = generate_mined_block(&genesis.hash, &easy);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&block));
+        assert_eq!(Ok(easy), blockchain.difficulty_after(&genesis.hash));
+        let fork = generate_mined_block(&genesis.hash, &easy);
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert_with_check(&fork));
+        assert_eq!(Err(BlockError::NotContiguous), blockchain.difficulty_after(&generate_random_hash()));
+    }
+
+    #[test]
+    fn test_difficulty_at() {
+        let difficulty: H256 = gen_difficulty_array(4).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&difficulty);
+        // blocks come twice as fast as expected
+        for i in 1..(RETARGET_INTERVAL + 2) {
+            let content = generate_random_content();
+            let next_difficulty = blockchain.difficulty();
+            let mut header = Header::new(&blockchain.tip(), 0, (i as u64 * TARGET_BLOCK_TIME / 2) as u128,
+                                         &next_difficulty, &content.merkle_root());
+            assert!(miner::mining_base(&mut header, next_difficulty.clone()));
+            blockchain.insert_with_check(&Block::new(header, content)).unwrap();
+        }
+        let new_difficulty = difficulty::retarget_timespan(&difficulty, TARGET_BLOCK_TIME / 2 * (RETARGET_INTERVAL as u64 - 2),
+                                                           TARGET_BLOCK_TIME * (RETARGET_INTERVAL as u64 - 2));
+        assert!(new_difficulty < difficulty);
+
+        assert_eq!(Some(Block::genesis().header.difficulty), blockchain.difficulty_at(0));
+        assert_eq!(Some(difficulty), blockchain.difficulty_at(1));
+        assert_eq!(Some(difficulty), blockchain.difficulty_at(RETARGET_INTERVAL - 1));
+        assert_eq!(Some(new_difficulty), blockchain.difficulty_at(RETARGET_INTERVAL));
+        assert_eq!(Some(new_difficulty), blockchain.difficulty_at(RETARGET_INTERVAL + 1));
+        assert_eq!(None, blockchain.difficulty_at(RETARGET_INTERVAL + 2));
+    }
+    #[test]
+    fn test_insert_batch() {
+        let key = key_pair::random();
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut single = Blockchain::new();
+        single.change_difficulty(&difficulty);
+        let mine = |chain: &Blockchain, trans: Vec<SignedTransaction>, payout: &H160| {
+            let mut all = vec![generate_signed_coinbase_transaction_to(&key, payout, COINBASE_REWARD)];
+            all.extend(trans);
+            let content = Content::new_with_trans(&all);
+            let ts = (chain.length() as u64 * TARGET_BLOCK_TIME) as u128;
+            Block::new(Header::new(&chain.tip(), 0, ts, &chain.difficulty(), &content.merkle_root()), content)
+        };
+        // 100 blocks crossing a retarget, one spending an earlier coinbase; random payouts keep coinbases apart
+        let addr = address_of(&key);
+        let mut blocks: Vec<Block> = vec![];
+        for i in 0..100 {
+            let trans = if i == 10 {
+                let coin = TxInput::new(blocks[0].content.trans[0].hash, 0);
+                vec![generate_signed_transaction(&key, vec![coin], vec![TxOutput::new(generate_random_h160(), 1)])]
+            } else {
+                vec![]
+            };
+            let payout = if i == 0 { addr } else { generate_random_h160() };
+            let block = mine(&single, trans, &payout);
+            assert_eq!(Ok(InsertOutcome::Extended), single.insert_with_check(&block));
+            blocks.push(block);
+        }
+        assert_eq!(RETARGET_INTERVAL + 1, single.length());
+
+        let mut batch = Blockchain::new();
+        batch.change_difficulty(&difficulty);
+        assert_eq!(vec![InsertOutcome::Extended; 100], batch.insert_batch(&blocks).unwrap());
+        assert_eq!(single.tip(), batch.tip());
+        assert_eq!(single.length(), batch.length());
+        assert_eq!(single.difficulty(), batch.difficulty());
+        assert_eq!(single.tip_work(), batch.tip_work());
+        assert_eq!(single.tip_block_state().hash(), batch.tip_block_state().hash());
+        let coin = TxInput::new(blocks[0].content.trans[0].hash, 0);
+        assert_eq!(single.spending_tx(&coin), batch.spending_tx(&coin));
+        assert!(batch.spending_tx(&coin).is_some());
+        assert_eq!(single.validation_tip().load(), batch.validation_tip().load());
+
+        // a bad block anywhere leaves the blockchain untouched
+        let mut partial = Blockchain::new();
+        partial.change_difficulty(&difficulty);
+        partial.insert_batch(&blocks[..50]).unwrap();
+        let bad_spend = generate_signed_transaction(&key, vec![generate_random_txinput()],
+                                                    vec![TxOutput::new(generate_random_h160(), 1)]);
+        let bad = mine(&partial, vec![bad_spend], &addr);
+        let mut fresh = Blockchain::new();
+        fresh.change_difficulty(&difficulty);
+        let genesis = fresh.tip();
+        let mut with_bad = blocks[..50].to_vec();
+        with_bad.push(bad);
+        assert_eq!(Err(BlockError::InvalidTransactions), fresh.insert_batch(&with_bad));
+        let mut swapped = blocks.clone();
+        swapped.swap(60, 61);
+        assert_eq!(Err(BlockError::NotContiguous), fresh.insert_batch(&swapped));
+        assert_eq!(Err(BlockError::NotContiguous), fresh.insert_batch(&blocks[1..]));
+        assert_eq!(genesis, fresh.tip());
+        assert_eq!(1, fresh.length());
+        assert!(!fresh.exist(&blocks[0].hash));
+        assert!(fresh.tip_block_state().0.is_empty());
+
+        // the rest of a partial sync goes on from where it stopped, taking in a block of it buffered as an orphan
+        assert_eq!(Err(BlockError::AlreadyExists), partial.insert_batch(&blocks[49..]));
+        assert_eq!(Ok(InsertOutcome::Orphaned), partial.insert_with_check(&blocks[60]));
+        assert_eq!(vec![InsertOutcome::Extended; 50], partial.insert_batch(&blocks[50..]).unwrap());
+        assert!(!partial.is_orphan(&blocks[60].hash));
+        assert!(partial.orphan_hashes().is_empty());
+        assert_eq!(single.tip(), partial.tip());
+        assert_eq!(single.tip_block_state().hash(), partial.tip_block_state().hash());
+    }
+
+    #[test]
+    fn test_initial_block_download() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&difficulty);
+        let mine = |chain: &Blockchain, ts: u64| {
+            let content = generate_random_content();
+            Block::new(Header::new(&chain.tip(), 0, ts as u128, &difficulty, &content.merkle_root()), content)
+        };
+        assert_eq!(0, blockchain.get_block_count());
+        assert!(blockchain.is_initial_block_download());
+
+        // stale tip
+        let block = mine(&blockchain, now_ms() - MAX_TIP_AGE - 1000);
+        blockchain.insert_with_check(&block).unwrap();
+        assert_eq!(1, blockchain.get_block_count());
+        assert!(blockchain.is_initial_block_download());
+
+        // caught up with the clock
+        let block = mine(&blockchain, now_ms());
+        blockchain.insert_with_check(&block).unwrap();
+        assert_eq!(2, blockchain.get_block_count());
+        assert!(!blockchain.is_initial_block_download());
+
+        // far behind a known checkpoint, until close enough
+        blockchain.set_checkpoint(Checkpoint {
+            height: 3 + IBD_MAX_BEHIND,
+            block_hash: generate_random_hash(),
+            utxo_set_hash: generate_random_hash(),
+        });
+        assert!(blockchain.is_initial_block_download());
+        let block = mine(&blockchain, now_ms());
+        blockchain.insert_with_check(&block).unwrap();
+        assert!(!blockchain.is_initial_block_download());
+
+        // far behind headers announced by peers, until close enough
+        let mut blocks = vec![];
+        let mut parent = blockchain.tip();
+        for _ in 0..IBD_MAX_BEHIND + 1 {
+            let content = generate_random_content();
+            let mut header = Header::new(&parent, 0, now_ms() as u128, &difficulty, &content.merkle_root());
+            assert!(miner::mining_base(&mut header, difficulty.clone()));
+            parent = header.hash();
+            blocks.push(Block::new(header, content));
+        }
+        let headers: Vec<Header> = blocks.iter().map(|b| b.header.clone()).collect();
+        blockchain.note_headers(&headers[1..]);  // unknown height without the first one
+        assert!(!blockchain.is_initial_block_download());
+        blockchain.note_headers(&headers);
+        assert!(blockchain.is_initial_block_download());
+        blockchain.insert_with_check(&blocks[0]).unwrap();
+        assert!(!blockchain.is_initial_block_download());
+    }
+
+    #[test]
+    fn test_future_block_held() {
+        let difficulty: H256 = gen_difficulty_array(4).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&difficulty);
+        blockchain.set_max_future_drift(100);
+        let ts = now_ms() + 400;
+        let content = generate_random_content();
+        let mut header = Header::new(&blockchain.tip(), 0, ts as u128, &difficulty, &content.merkle_root());
+        assert!(miner::mining_base(&mut header, difficulty.clone()));
+        let block = Block::new(header, content);
+
+        // just beyond the drift: held, not inserted
+        assert_eq!(Err(BlockError::TimeTooNew), blockchain.insert_with_check(&block));
+        assert!(!blockchain.exist(&block.hash));
+        assert_eq!(1, blockchain.future_block_count());
+        assert!(blockchain.take_due_future_blocks().is_empty());
+
+        // bad PoW is dropped right away, whatever its timestamp
+        let content = generate_random_content();
+        let mut header = Header::new(&blockchain.tip(), 0, ts as u128, &difficulty, &content.merkle_root());
+        while header.hash().meets_difficulty(&difficulty) {
+            header.change_nonce();
+        }
+        assert_eq!(Err(BlockError::InsufficientPow), blockchain.insert_with_check(&Block::new(header, content)));
+        assert_eq!(1, blockchain.future_block_count());
+
+        // accepted once the clock catches up
+        thread::sleep(time::Duration::from_millis((ts - 100).saturating_sub(now_ms()) + 10));
+        let due = blockchain.take_due_future_blocks();
+        assert_eq!(vec![block.hash], due.iter().map(|b| b.hash).collect::<Vec<H256>>());
+        assert_eq!(0, blockchain.future_block_count());
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&due[0]));
+        assert_eq!(block.hash, blockchain.tip());
+    }
+
+    #[test]
+    fn test_network_hashps() {
+        let difficulty: H256 = gen_difficulty_array(4).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.change_difficulty(&difficulty);
+        let block_time = TARGET_BLOCK_TIME / 2;
+        for i in 1..(RETARGET_INTERVAL + 2) {
+            if i == 2 {
+                assert_eq!(None, blockchain.network_hashps(10));
+            }
+            let content = generate_random_content();
+            let next_difficulty = blockchain.difficulty();
+            let mut header = Header::new(&blockchain.tip(), 0, (i as u64 * block_time) as u128,
+                                         &next_difficulty, &content.merkle_root());
+            assert!(miner::mining_base(&mut header, next_difficulty.clone()));
+            blockchain.insert_with_check(&Block::new(header, content)).unwrap();
+        }
+        let old_work = difficulty::block_work(&difficulty) as f64;
+        let new_work = difficulty::block_work(&blockchain.difficulty_at(RETARGET_INTERVAL).unwrap()) as f64;
+        assert!(new_work > old_work);
+        let within = |estimate: Option<f64>, expected: f64| (estimate.unwrap() - expected).abs() / expected < 0.001;
+
+        // last 10 blocks: 8 before the retarget, 2 after
+        let expected = (8.0 * old_work + 2.0 * new_work) * 1000.0 / (10 * block_time) as f64;
+        assert!(within(blockchain.network_hashps(10), expected));
+        // window is capped at the blocks after genesis
+        let blocks = RETARGET_INTERVAL;
+        let expected = ((blocks - 2) as f64 * old_work + 2.0 * new_work) * 1000.0 / (blocks as u64 * block_time) as f64;
+        assert!(within(blockchain.network_hashps(10000), expected));
+        assert_eq!(None, blockchain.network_hashps(0));
+    }
+
+    #[test]
+    fn test_confirmation_events() {
+        /*
+         * structure:
+         * genesis <- block_1_1
+         *    ^
+         *    ------ block_2_1 <- block_2_2
+         */
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let events = blockchain.subscribe();
+        let genesis_hash = blockchain.tip();
+        let confirmed = |block: &Block| -> Vec<Event> {
+            block.content.trans.iter().map(|t| Event::TxConfirmed(t.hash, block.index)).collect()
+        };
+        let tx_events = || -> Vec<Event> {
+            events.try_iter().filter(|e| match e {
+                Event::BlockAccepted { .. } | Event::Reorg { .. } => false,
+                _ => true,
+            }).collect()
+        };
+
+        let mut block_1_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1_1).unwrap();
+        block_1_1.index = 1;
+        assert_eq!(confirmed(&block_1_1), tx_events());
+
+        // side branch confirms nothing
+        let mut block_2_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_2_1).unwrap();
+        assert!(tx_events().is_empty());
+
+        // reorg un-confirms block_1_1 before confirming the new branch
+        let mut block_2_2 = generate_random_block(&block_2_1.hash);
+        assert_eq!(Ok(InsertOutcome::Reorg { depth: 1 }), blockchain.insert(&block_2_2));
+        block_2_1.index = 1;
+        block_2_2.index = 2;
+        let mut expected: Vec<Event> = block_1_1.content.trans.iter().map(|t| Event::TxUnconfirmed(t.hash)).collect();
+        expected.extend(confirmed(&block_2_1));
+        expected.extend(confirmed(&block_2_2));
+        assert_eq!(expected, tx_events());
+    }
+
+    #[test]
+    fn test_skip_pow() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let difficulty: H256 = gen_difficulty_array(MIN_TARGET_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        let block = generate_block(&blockchain.tip(), 0, &difficulty);
+        assert_eq!(Err(BlockError::InsufficientPow), blockchain.insert_with_check(&block));
+
+        blockchain.set_check_pow(false);
+        for _ in 0..2 * RETARGET_INTERVAL {
+            let block = generate_block(&blockchain.tip(), 0, &blockchain.difficulty());
+            assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&block));
+        }
+        assert_eq!(2 * RETARGET_INTERVAL + 1, blockchain.length());
+
+        // other checks still apply
+        let block = generate_block(&blockchain.tip(), 0, &generate_random_hash());
+        assert_eq!(Err(BlockError::DifficultyMismatch), blockchain.insert_with_check(&block));
+    }
+
+    #[test]
+    fn test_block_weight() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.set_check_pow(false);
+        let block = generate_block(&blockchain.tip(), 0, &blockchain.difficulty());
+        let witness: usize = block.content.trans.iter().map(|t| t.witness_size()).sum();
+        assert!(witness > 0);
+        assert_eq!((block.size() - witness) * 4 + witness, block.weight());
+        assert!(block.weight() < 4 * block.size());
+
+        // rejected beyond the limit even though its byte size is fine
+        blockchain.set_max_block_weight(block.weight() - 1);
+        assert_eq!(Err(BlockError::WeightTooLarge), blockchain.insert_with_check(&block));
+        assert!(!blockchain.exist(&block.hash));
+        blockchain.set_max_block_weight(block.weight());
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&block));
+    }
+
+    #[test]
+    fn test_lock_time() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        blockchain.set_check_pow(false);
+        let difficulty = blockchain.difficulty();
+        let key = key_pair::random();
+        let locked = generate_signed_locked_transaction(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], LockTime::Height(2));
+        let block_with = |parent: &H256, trans: Vec<SignedTransaction>| {
+            let content = Content::new_with_trans(&trans);
+            Block::new(generate_header(parent, &content, 0, &difficulty), content)
+        };
+        let coinbase = || generate_signed_coinbase_transaction(&key);
+
+        // not at height 1
+        let too_early = block_with(&blockchain.tip(), vec![coinbase(), locked.clone()]);
+        assert_eq!(Err(BlockError::NonFinalTransaction), blockchain.insert(&too_early));
+        assert_eq!(Err(BlockError::NonFinalTransaction), blockchain.insert_batch(std::slice::from_ref(&too_early)));
+        assert!(!blockchain.exist(&too_early.hash));
+
+        // at height 2
+        let block_1 = block_with(&blockchain.tip(), vec![coinbase()]);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block_1));
+        let block_2 = block_with(&block_1.hash, vec![coinbase(), locked.clone()]);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert(&block_2));
+
+        // by timestamp, against the block's own
+        let time_locked = generate_signed_locked_transaction(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], LockTime::Time(now_ms() + 60000));
+        let block_3 = block_with(&block_2.hash, vec![coinbase(), time_locked]);
+        assert_eq!(Err(BlockError::NonFinalTransaction), blockchain.insert(&block_3));
+    }
+
+    #[test]
+    fn test_coinbase_commitment() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_check_policy(CheckPolicy::SkipAll);
+        let genesis_hash = blockchain.tip();
+        let difficulty: H256 = gen_difficulty_array(0).into();
+        blockchain.change_difficulty(&difficulty);
+        let key = key_pair::random();
+        let trans = vec![generate_random_signed_transaction(), generate_random_signed_transaction()];
+        let block_with = |commitment: &H256| {
+            let mut all = vec![generate_signed_coinbase_transaction_with_commitment(
+                &key, &address_of(&key), COINBASE_REWARD, commitment)];
+            all.extend(trans.clone());
+            let content = Content::new_with_trans(&all);
+            let header = generate_header(&genesis_hash, &content, 0, &difficulty);
+            Block::new(header, content)
+        };
+
+        let block = block_with(&generate_random_hash());
+        assert_eq!(Err(BlockError::CommitmentMismatch), blockchain.insert_with_check(&block));
+        // only coinbase may carry a commitment
+        let committed = generate_signed_coinbase_transaction_with_commitment(
+            &key, &address_of(&key), COINBASE_REWARD, &Content::aux_root_of(&vec![]));
+        let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key), committed]);
+        let header = generate_header(&genesis_hash, &content, 0, &difficulty);
+        assert_eq!(Err(BlockError::CommitmentMismatch), blockchain.insert_with_check(&Block::new(header, content)));
+        let block = block_with(&Content::aux_root_of(&trans));
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&block));
+
+        // miner's content always commits
+        let mut mempool = MemPool::new();
+        for tran in trans.iter() {
+            mempool.add_with_check(tran);
+        }
+        let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 10);
+        assert_eq!(Some(content.aux_root()), content.trans[0].transaction.commitment);
+        let header = generate_header(&blockchain.tip(), &content, 0, &blockchain.difficulty());
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&Block::new(header, content)));
+    }
+
+    #[test]
+    fn test_spending_tx() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_a (spends block_1's coinbase)
+         *               ^
+         *               ------ block_b_1 <- block_b_2 <- block_b_3 (spends it again)
+         */
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::new();
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        // distinct rewards, so coinbases created in the same instant don't share a hash
+        let mut reward = COINBASE_REWARD;
+        let mut block_on = |parent: &H256, trans: Vec<SignedTransaction>| {
+            let mut all = vec![generate_signed_coinbase_transaction_with_reward(&key, reward)];
+            reward -= 1;
+            all.extend(trans);
+            let content = Content::new_with_trans(&all);
+            Block::new(generate_header(parent, &content, 0, &difficulty), content)
+        };
+        let block_1 = block_on(&blockchain.tip(), vec![]);
+        let coin = TxInput::new(block_1.content.trans[0].hash, 0);
+        let paid = generate_signed_transaction_with_fee(&key, vec![coin.clone()],
+            vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD - 5)], 5);
+        let block_a = block_on(&block_1.hash, vec![paid.clone()]);
+        let block_b_1 = block_on(&block_1.hash, vec![]);
+        let block_b_2 = block_on(&block_b_1.hash, vec![]);
+        let block_b_3 = block_on(&block_b_2.hash, vec![paid.clone()]);
+
+        blockchain.insert_with_check(&block_1).unwrap();
+        assert_eq!(None, blockchain.spending_tx(&coin));
+        blockchain.insert_with_check(&block_a).unwrap();
+        assert_eq!(Some((paid.hash, block_a.hash)), blockchain.spending_tx(&coin));
+
+        // side branch doesn't count, reorg away from the spend forgets it
+        blockchain.insert_with_check(&block_b_1).unwrap();
+        assert_eq!(Some((paid.hash, block_a.hash)), blockchain.spending_tx(&coin));
+        blockchain.insert_with_check(&block_b_2).unwrap();
+        assert_eq!(None, blockchain.spending_tx(&coin));
+        assert!(blockchain.get_transaction(&paid.hash).is_none());
+
+        blockchain.insert_with_check(&block_b_3).unwrap();
+        assert_eq!(Some((paid.hash, block_b_3.hash)), blockchain.spending_tx(&coin));
+        let found = |chain: &Blockchain| chain.get_transaction(&paid.hash).map(|(t, b, n)| (t.hash, b, n));
+        assert_eq!(Some((paid.hash, block_b_3.hash, 1)), found(&blockchain));
+        assert_eq!(Some((block_1.content.trans[0].hash, block_1.hash, 4)),
+                   blockchain.get_transaction(&coin.pre_hash).map(|(t, b, n)| (t.hash, b, n)));
+        let unspent = TxInput::new(paid.hash, 0);
+        assert_eq!(None, blockchain.spending_tx(&unspent));
+
+        // the index is rebuilt for a loaded chain
+        let path = std::env::temp_dir().join(format!("chain_{}.dat", generate_random_str()));
+        blockchain.save_to(&path).unwrap();
+        let mut loaded = Blockchain::new();
+        loaded.load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some((paid.hash, block_b_3.hash)), loaded.spending_tx(&coin));
+        assert_eq!(Some((paid.hash, block_b_3.hash, 1)), found(&loaded));
+    }
+
+    #[test]
+    fn test_state_from_undo() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_a (spends block_1's coinbase) <- block_a_2
+         *               ^
+         *               ------ block_b_1 <- block_b_2
+         */
+        let key = key_pair::random();
+        let mut blockchain = Blockchain::new();
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        let mut reward = COINBASE_REWARD;
+        let mut block_on = |parent: &H256, trans: Vec<SignedTransaction>| {
+            let mut all = vec![generate_signed_coinbase_transaction_with_reward(&key, reward)];
+            reward -= 1;
+            all.extend(trans);
+            let content = Content::new_with_trans(&all);
+            Block::new(generate_header(parent, &content, 0, &difficulty), content)
+        };
+        let block_1 = block_on(&blockchain.tip(), vec![]);
+        let coin = TxInput::new(block_1.content.trans[0].hash, 0);
+        let paid = generate_signed_transaction_with_fee(&key, vec![coin.clone()],
+            vec![TxOutput::new(address_of(&key), COINBASE_REWARD - 5)], 5);
+        let block_a = block_on(&block_1.hash, vec![paid.clone()]);
+        let block_b_1 = block_on(&block_1.hash, vec![]);
+        let block_b_2 = block_on(&block_b_1.hash, vec![]);
+        let replay = |blocks: &[&Block]| {
+            let mut state = State::new();
+            for (height, block) in blocks.iter().enumerate() {
+                state.connect_block(block, Blockchain::block_subsidy(height + 1)).unwrap();
+            }
+            state
+        };
+        let unspent = |state: &State, input: &TxInput| state.contains_key(&(input.pre_hash, input.index));
+        for block in vec![&block_1, &block_a, &block_b_1] {
+            blockchain.insert_with_check(block).unwrap();
+        }
+        assert!(!unspent(&blockchain.tip_block_state(), &coin));
+
+        // a side block's state is rebuilt from the tip's by undoing block_a
+        let (_, side_state) = blockchain.snapshot(&block_b_1.hash).unwrap();
+        assert_eq!(replay(&[&block_1, &block_b_1]).hash(), side_state.hash());
+        assert!(unspent(&side_state, &coin));
+
+        // after a reorg the tip state is the replayed one, and block_a's is rebuilt the other way
+        blockchain.insert_with_check(&block_b_2).unwrap();
+        assert_eq!(block_b_2.hash, blockchain.tip());
+        assert_eq!(replay(&[&block_1, &block_b_1, &block_b_2]).hash(), blockchain.utxo_set_hash());
+        let (_, a_state) = blockchain.snapshot(&block_a.hash).unwrap();
+        assert_eq!(replay(&[&block_1, &block_a]).hash(), a_state.hash());
+
+        // blocks on the side branch are checked against its own state
+        let respend = generate_signed_transaction(&key, vec![TxInput::new(paid.hash, 0)],
+            vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD - 5)]);
+        let block_a_2 = block_on(&block_a.hash, vec![respend]);
+        let double_spend = block_on(&block_a.hash, vec![paid.clone()]);
+        assert_eq!(Err(BlockError::InvalidTransactions), blockchain.insert_with_check(&double_spend));
+        assert_eq!(Ok(InsertOutcome::SideBranch), blockchain.insert_with_check(&block_a_2));
+
+        // undo records are saved with the chain
+        let path = std::env::temp_dir().join(format!("chain_{}.dat", generate_random_str()));
+        blockchain.save_to(&path).unwrap();
+        let mut loaded = Blockchain::new();
+        loaded.load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(blockchain.utxo_set_hash(), loaded.utxo_set_hash());
+        let (_, loaded_state) = loaded.snapshot(&block_a_2.hash).unwrap();
+        assert_eq!(replay(&[&block_1, &block_a, &block_a_2]).hash(), loaded_state.hash());
+    }
+
+    #[test]
+    fn test_utxo_set_hash() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_2 (spends block_1's coinbase)
+         *    ^
+         *    ------ block_b
+         */
+        let key = key_pair::random();
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut reward = COINBASE_REWARD;
+        let mut block_on = |parent: &H256, trans: Vec<SignedTransaction>| {
+            let mut all = vec![generate_signed_coinbase_transaction_with_reward(&key, reward)];
+            reward -= 1;
+            all.extend(trans);
+            let content = Content::new_with_trans(&all);
+            Block::new(generate_header(parent, &content, 0, &difficulty), content)
+        };
+        let genesis = Blockchain::new().tip();
+        let block_1 = block_on(&genesis, vec![]);
+        let coin = TxInput::new(block_1.content.trans[0].hash, 0);
+        let paid = generate_signed_transaction_with_fee(&key, vec![coin],
+            vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD - 5)], 5);
+        let block_2 = block_on(&block_1.hash, vec![paid]);
+        let block_b = block_on(&genesis, vec![]);
+
+        // blocks in order
+        let mut in_order = Blockchain::new();
+        in_order.change_difficulty(&difficulty);
+        let genesis_hash = in_order.utxo_set_hash();
+        in_order.insert_with_check(&block_1).unwrap();
+        let block_1_hash = in_order.utxo_set_hash();
+        assert_ne!(genesis_hash, block_1_hash);
+        in_order.insert_with_check(&block_2).unwrap();
+
+        // a side branch first, then block_2 as an orphan, reorged to by its parent
+        let mut reorged = Blockchain::new();
+        reorged.change_difficulty(&difficulty);
+        reorged.insert_with_check(&block_b).unwrap();
+        assert_ne!(block_1_hash, reorged.utxo_set_hash());
+        let _ = reorged.insert_with_check(&block_2);
+        reorged.insert_with_check(&block_1).unwrap();
+        assert_eq!(block_2.hash, reorged.tip());
+        assert_eq!(in_order.utxo_set_hash(), reorged.utxo_set_hash());
+        assert_ne!(block_1_hash, reorged.utxo_set_hash());
+
+        // the same entries inserted in reverse order
+        let mut entries: Vec<_> = in_order.tip_block_state().0.into_iter().collect();
+        entries.sort_by(|a, b| b.0.cmp(&a.0));
+        let mut state = State::new();
+        for (key, val) in entries.into_iter() {
+            state.insert(key, val);
+        }
+        assert_eq!(in_order.utxo_set_hash(), state.hash());
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.change_difficulty(&difficulty);
+        let key = key_pair::random();
+        let mut reward = COINBASE_REWARD;
+        let mut block_at = |parent: &H256, ts: u128| {
+            let coinbase = generate_signed_coinbase_transaction_with_reward(&key, reward);
+            reward -= 1;
+            let content = Content::new_with_trans(&vec![coinbase]);
+            Block::new(Header::new(parent, 0, ts, &difficulty, &content.merkle_root()), content)
+        };
+        assert_eq!(0, blockchain.median_time_past(&blockchain.tip()));
+        assert_eq!(0, blockchain.median_time_past(&generate_random_hash()));
+        let mut hashes = vec![];
+        for i in 1..=12 {
+            let block = block_at(&blockchain.tip(), i * 1000);
+            blockchain.insert_with_check(&block).unwrap();
+            hashes.push(block.hash);
+        }
+        // fewer blocks near genesis, whose timestamp counts too
+        assert_eq!(1000, blockchain.median_time_past(&hashes[1]));
+        // the 11 latest blocks, 2000..12000
+        assert_eq!(7000, blockchain.median_time_past(&blockchain.tip()));
+
+        // a block may be earlier than its parent, as long as it's later than the median
+        let tip = blockchain.tip();
+        assert_eq!(Ok(()), blockchain.would_accept(&block_at(&tip, 7001)));
+        // a timestamp pushed back to the median is rejected, however much work the block has
+        let manipulated = block_at(&tip, 7000);
+        assert_eq!(Err(BlockError::TimeTooOld), blockchain.would_accept(&manipulated));
+        assert_eq!(Err(BlockError::TimeTooOld), blockchain.insert_with_check(&manipulated));
+        assert_eq!(tip, blockchain.tip());
+    }
+
+    #[test]
+    fn test_orphan_median_time_past() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.change_difficulty(&difficulty);
+        let key = key_pair::random();
+        let mut reward = COINBASE_REWARD;
+        let mut block_at = |parent: &H256, ts: u128| {
+            let coinbase = generate_signed_coinbase_transaction_with_reward(&key, reward);
+            reward -= 1;
+            let content = Content::new_with_trans(&vec![coinbase]);
+            Block::new(Header::new(parent, 0, ts, &difficulty, &content.merkle_root()), content)
+        };
+        for i in 1..=12 {
+            let block = block_at(&blockchain.tip(), i * 1000);
+            blockchain.insert_with_check(&block).unwrap();
+        }
+        // the median of 3000..13000 once the parent is in
+        let parent = block_at(&blockchain.tip(), 13000);
+        let child = block_at(&parent.hash, 8000);
+        assert_eq!(Ok(InsertOutcome::Orphaned), blockchain.insert_with_check(&child));
+
+        // sent before its parent, the child is checked once the parent connects
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&parent));
+        assert_eq!(parent.hash, blockchain.tip());
+        assert!(!blockchain.exist(&child.hash));
+        assert!(blockchain.orphan_hashes().is_empty());
     }
 }
@@ -1,13 +1,72 @@
+use crate::difficulty::RetargetMode;
 use crate::spread::Spreader;
 
 pub static DIFFICULTY: i32 = 17; // number of leading zero
 
+pub static RETARGET_INTERVAL: usize = 100; // number of blocks between two difficulty adjustments
+
+pub static TARGET_BLOCK_TIME: u64 = 10000; // expected time(ms) between two blocks
+
+pub static MAX_RETARGET_FACTOR: u64 = 4; // difficulty changes at most by this factor in one adjustment
+
+pub static RETARGET_MODE: RetargetMode = RetargetMode::Step; // how difficulty is adjusted, the same on all nodes
+
+pub static EMA_WINDOW: u64 = 20; // number of blocks the moving average of block times spans in RetargetMode::Ema
+
+pub static EMA_MAX_SOLVETIME: u64 = 6; // block times one block can count at most in RetargetMode::Ema
+
+pub static MAX_HASHPS_WINDOW: usize = 1000; // number of blocks getnetworkhashps averages over at most, 10 retarget intervals
+
+pub static MAX_FUTURE_DRIFT: u64 = 2 * 3600 * 1000; // time(ms) a block timestamp may be ahead of the local clock
+
+pub static MEDIAN_TIME_SPAN: usize = 11; // number of blocks whose median timestamp a block on top of them must be later than
+
+pub static MAX_FUTURE_BLOCKS: usize = 100; // number of blocks too far in the future held until the clock catches up
+
+pub static FUTURE_BLOCK_RETRY_INTERVAL: u64 = 1000; // time(ms) between retries of held blocks, whether or not other blocks arrive
+
+pub static MAX_TIP_AGE: u64 = 24 * 3600 * 1000; // time(ms) after which an old tip means the node is still syncing
+
+pub static IBD_MAX_BEHIND: usize = 6; // number of blocks the tip may be behind the best known height without syncing
+
+pub static MAX_TARGET_DIF: i32 = 0; // number of leading zero of the easiest difficulty after adjustment
+
+pub static MIN_TARGET_DIF: i32 = 64; // number of leading zero of the hardest difficulty after adjustment
+
 pub static MINING_STEP: u32 = 8192; // number of mining step
 
+pub static MINER_IDLE_BACKOFF: u64 = 1000; // max time(ms) miner waits for a transaction when mempool is empty
+
+pub static MIN_PEERS: usize = 0; // connections needed before the miner starts, so an isolated node doesn't build a private chain
+
 pub static BLOCK_SIZE_LIMIT: usize = 256; // size limit of transactions in a block
 
+pub static MAX_BLOCK_BYTES: usize = 1024 * 1024; // size limit(bytes) of serialized transactions in a block
+
+pub static WITNESS_SCALE_FACTOR: usize = 4; // weight of a non-witness byte, a witness(signature & public key) byte weighs 1
+
+pub static MAX_BLOCK_WEIGHT: usize = 4 * 1024 * 1024 + 4 * 4096; // consensus limit of block weight, MAX_BLOCK_BYTES of transactions plus room for header & coinbase
+
 pub static POOL_SIZE_LIMIT: usize = 100000; // size limit of mempool
 
+pub static MAX_TX_PER_ADDRESS: usize = 100; // unconfirmed transactions of one sender address mempool accepts
+
+pub static MEMPOOL_SAVE_INTERVAL: u64 = 60000; // time interval(ms) to save mempool to the file given by --mempool-file
+
+pub static MAX_MEMPOOL_BYTES: usize = 64 * 1024 * 1024; // size limit(bytes) of serialized transactions in mempool
+
+pub static NON_FINAL_TTL: u64 = 24 * 3600 * 1000; // time(ms) after which a transaction held for its lock time is dropped
+
+pub static DUST_THRESHOLD: u64 = 1; // outputs worth less than this are dust, not relayed by mempool
+
+pub static MAX_FEE_RATE: u64 = 1000; // fee per serialized byte above which a transaction is not relayed by mempool
+
+pub static INCREMENTAL_RELAY_FEE: u64 = 1; // fee a replacement pays on top of the total fee of the transactions it replaces
+
+pub static LONGPOLL_NEW_TXS: usize = 100; // transactions entering mempool after which a long-polled block template is refreshed
+
+pub static LONGPOLL_TIMEOUT: u64 = 60000; // max time(ms) a block template long-poll waits for the template to change
+
 pub static TRANSACTION_GENERATE_INTERVAL: u64 = 8000; // time interval(ms) to add a new-created transaction to mempool
 
 pub static TEST_DIF: i32 = 4; // difficulty used for mod test
@@ -16,6 +75,8 @@ pub static EASIEST_DIF: i32 = 0; // all-1-difficulty
 
 pub static COINBASE_REWARD: u64 = 50; // reward for miner
 
+pub static HALVING_INTERVAL: usize = 10000; // number of blocks after which the coinbase reward halves
+
 pub static RAND_INPUTS_NUM: usize = 4; // number of inputs in generate_random_txinput
 
 pub static RAND_OUTPUTS_NUM: usize = 4; // number of outputs in generate_random_txoutput
@@ -24,6 +85,40 @@ pub static VALID_OUTPUTS_NUM: usize = 3; // number of valid tx_outputs in transa
 
 pub static REPEAT_TEST_TIME: usize = 20; // used for test repeatedly
 
+pub static STALE_TIP_TIMEOUT: u64 = 120000; // time(ms) without a new tip before the watchdog alerts
+
+pub static CONNECT_TIMEOUT: u64 = 5000; // time(ms) to wait for an outgoing peer connection
+
+pub static BLOCK_REQUEST_TIMEOUT: u64 = 5000; // time(ms) before a requested block that hasn't arrived is requested again
+
+pub static ADDRMAN_CAPACITY: usize = 1000; // number of peer addresses remembered in the file given by --peers-file
+
+pub static ADDR_MAX_AGE: u64 = 7 * 24 * 3600 * 1000; // time(ms) since the last connection after which a peer address is dropped
+
+pub static MAX_ORPHAN_BLOCKS: usize = 10000; // number of buffered orphan blocks, the oldest are dropped beyond it
+
+pub static ORPHAN_TTL: u64 = 20 * 60 * 1000; // time(ms) after which an orphan block whose parent never arrived is dropped
+
+pub static MAX_ORPHAN_FETCH_DEPTH: usize = 1000; // missing ancestors of an orphan are no longer fetched once it is this many orphans deep
+
+pub static MAX_HEADERS_RESULTS: usize = 2000; // number of headers sent in answer to GetHeaders
+
+pub static HEADER_CHUNK_SIZE: usize = 500; // headers of longest-chain per chunk shared by concurrent GetHeaders answers
+
+pub static INV_FLUSH_INTERVAL: u64 = 100; // time(ms) new transaction hashes wait to be announced together in one message
+
+pub static INV_BATCH_SIZE: usize = 1000; // number of transaction hashes announced in one message, a full batch is sent at once
+
+pub static MEMPOOL_SYNC_LIMIT: usize = 10000; // number of transaction hashes sent in answer to GetMempool
+
+pub static MAX_IN_FLIGHT_BLOCKS: usize = 10000; // number of requested blocks remembered to avoid duplicate requests
+
+pub static WORKER_QUEUE_CAPACITY: usize = 10000; // number of received messages waiting for p2p workers, extra ones are dropped
+
+pub static MAX_MESSAGE_BYTES: usize = 32 * 1024 * 1024; // size limit(bytes) of a message from peers, before and after decompression
+
+pub static COMPRESSION_THRESHOLD: usize = 4096; // block/transaction messages larger than this(bytes) are compressed on the wire
+
 pub static SPREADER: Spreader = Spreader::DandelionPlus;
 
 /*  Trickle Configuration */
@@ -45,4 +140,108 @@ pub static DANDELION_PLUS_DEST_NUM: usize = 2; // number of destination peer of
 
 pub static IS_DIFFUSER_PROB: u64 = 10; // probability(%) for a node to be a diffuser in this epoch
 
-pub const T_BASE: f32 = 10.0;
\ No newline at end of file
+pub const T_BASE: f32 = 10.0;
+
+// Constants the other ones must agree with, so a bad edit fails at startup instead of making a subtly broken node
+#[derive(Debug, Clone)]
+pub struct ConsensusParams {
+    pub difficulty: i32,
+    pub max_target_dif: i32,
+    pub min_target_dif: i32,
+    pub block_size_limit: usize,
+    pub pool_size_limit: usize,
+    pub retarget_interval: usize,
+    pub target_block_time: u64,
+    pub max_retarget_factor: u64,
+}
+
+// Why the consensus constants can't make a working node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    DifficultyOutOfRange(i32),  // number of leading zero must be within (0, 256), [0, 256) for the bounds
+    DifficultyOutsideBounds(i32),  // DIFFICULTY must be within [MAX_TARGET_DIF, MIN_TARGET_DIF]
+    ZeroBlockSizeLimit,
+    PoolSmallerThanBlock(usize, usize),  // POOL_SIZE_LIMIT below BLOCK_SIZE_LIMIT
+    ZeroRetargetParam(&'static str),  // name of the constant difficulty adjustment divides by
+}
+
+impl ConsensusParams {
+    // Params of the constants above
+    pub fn configured() -> Self {
+        Self {
+            difficulty: DIFFICULTY,
+            max_target_dif: MAX_TARGET_DIF,
+            min_target_dif: MIN_TARGET_DIF,
+            block_size_limit: BLOCK_SIZE_LIMIT,
+            pool_size_limit: POOL_SIZE_LIMIT,
+            retarget_interval: RETARGET_INTERVAL,
+            target_block_time: TARGET_BLOCK_TIME,
+            max_retarget_factor: MAX_RETARGET_FACTOR,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.difficulty <= 0 || self.difficulty >= 256 {
+            return Err(ConfigError::DifficultyOutOfRange(self.difficulty));
+        }
+        // the easiest target (0) is a valid bound
+        for dif in [self.max_target_dif, self.min_target_dif].iter() {
+            if *dif < 0 || *dif >= 256 {
+                return Err(ConfigError::DifficultyOutOfRange(*dif));
+            }
+        }
+        if self.difficulty < self.max_target_dif || self.difficulty > self.min_target_dif {
+            return Err(ConfigError::DifficultyOutsideBounds(self.difficulty));
+        }
+        if self.block_size_limit == 0 {
+            return Err(ConfigError::ZeroBlockSizeLimit);
+        }
+        if self.pool_size_limit < self.block_size_limit {
+            return Err(ConfigError::PoolSmallerThanBlock(self.pool_size_limit, self.block_size_limit));
+        }
+        if self.retarget_interval == 0 {
+            return Err(ConfigError::ZeroRetargetParam("RETARGET_INTERVAL"));
+        }
+        if self.target_block_time == 0 {
+            return Err(ConfigError::ZeroRetargetParam("TARGET_BLOCK_TIME"));
+        }
+        if self.max_retarget_factor == 0 {
+            return Err(ConfigError::ZeroRetargetParam("MAX_RETARGET_FACTOR"));
+        }
+        Ok(())
+    }
+}
+
+// Check the consensus constants, run once at startup
+pub fn validate_config() -> Result<(), ConfigError> {
+    ConsensusParams::configured().validate()
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_config() {
+        assert_eq!(Ok(()), validate_config());
+        let check = |edit: &dyn Fn(&mut ConsensusParams)| {
+            let mut params = ConsensusParams::configured();
+            edit(&mut params);
+            params.validate()
+        };
+        assert_eq!(Err(ConfigError::DifficultyOutOfRange(0)), check(&|p| p.difficulty = 0));
+        assert_eq!(Err(ConfigError::DifficultyOutOfRange(256)), check(&|p| p.difficulty = 256));
+        assert_eq!(Err(ConfigError::DifficultyOutOfRange(-1)), check(&|p| p.max_target_dif = -1));
+        assert_eq!(Err(ConfigError::DifficultyOutsideBounds(65)), check(&|p| p.difficulty = 65));
+        assert_eq!(Err(ConfigError::DifficultyOutsideBounds(DIFFICULTY)), check(&|p| p.max_target_dif = 20));
+        assert_eq!(Err(ConfigError::ZeroBlockSizeLimit), check(&|p| p.block_size_limit = 0));
+        assert_eq!(Err(ConfigError::PoolSmallerThanBlock(100, 256)), check(&|p| p.pool_size_limit = 100));
+        assert_eq!(Err(ConfigError::ZeroRetargetParam("RETARGET_INTERVAL")), check(&|p| p.retarget_interval = 0));
+        assert_eq!(Err(ConfigError::ZeroRetargetParam("TARGET_BLOCK_TIME")), check(&|p| p.target_block_time = 0));
+        assert_eq!(Err(ConfigError::ZeroRetargetParam("MAX_RETARGET_FACTOR")),
+                   check(&|p| p.max_retarget_factor = 0));
+
+        // the bounds may be the easiest target, the genesis difficulty may not
+        assert_eq!(Ok(()), check(&|p| p.max_target_dif = 0));
+    }
+}
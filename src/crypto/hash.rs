@@ -38,6 +38,28 @@ impl H256 {
         buffer[..].copy_from_slice(bytes[12..32].as_ref());
         H160(buffer)
     }
+
+    // Number of leading zero bits, 256 for the all-zero hash
+    pub fn leading_zero_bits(&self) -> u32 {
+        let mut bits = 0;
+        for byte in self.0.iter() {
+            bits += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        bits
+    }
+
+    // Whether the hash satisfies a difficulty target, i.e. hash < target
+    pub fn meets_difficulty(&self, target: &H256) -> bool {
+        self < target
+    }
+
+    // Whether the hex encoding (as by Display) starts with prefix, in any case
+    pub fn has_hex_prefix(&self, prefix: &str) -> bool {
+        self.to_string().starts_with(&prefix.to_ascii_lowercase())
+    }
 }
 
 impl Hashable for H256 {
@@ -64,6 +86,28 @@ impl std::fmt::Display for H256 {
     }
 }
 
+// Why a string can't be parsed into a hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseHashError {
+    InvalidLength(usize),  // number of hex digits given
+    InvalidHex,
+}
+
+// Parse 64 hex digits, same encoding as Display and PrintableBlock
+impl std::str::FromStr for H256 {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 {
+            return Err(ParseHashError::InvalidLength(s.len()));
+        }
+        let bytes = hex::decode(s).map_err(|_| ParseHashError::InvalidHex)?;
+        let mut buffer: [u8; 32] = [0; 32];
+        buffer[..].copy_from_slice(&bytes);
+        Ok(H256(buffer))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -220,6 +264,29 @@ pub mod tests {
     use ring;
     use super::*;
 
+    #[test]
+    fn test_h256_from_str() {
+        let hashes: Vec<H256> = vec![
+            [0u8; 32].into(),
+            [0xffu8; 32].into(),
+            hex!("0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d").into(),
+            ring::digest::digest(&ring::digest::SHA256, b"hash").into(),
+        ];
+        for h in hashes.iter() {
+            let s = h.to_string();
+            assert_eq!(hex::encode(h), s);
+            assert_eq!(Ok(*h), s.parse::<H256>());
+        }
+        let upper = "0A0B0C0D0E0F0E0D0A0B0C0D0E0F0E0D0A0B0C0D0E0F0E0D0A0B0C0D0E0F0E0D";
+        assert_eq!(Ok(hashes[2]), upper.parse::<H256>());
+
+        assert_eq!(Err(ParseHashError::InvalidLength(0)), "".parse::<H256>());
+        assert_eq!(Err(ParseHashError::InvalidLength(62)), "00".repeat(31).parse::<H256>());
+        assert_eq!(Err(ParseHashError::InvalidLength(66)), "00".repeat(33).parse::<H256>());
+        assert_eq!(Err(ParseHashError::InvalidHex), "0g".repeat(32).parse::<H256>());
+        assert_eq!(Err(ParseHashError::InvalidHex), format!("0x{}", "00".repeat(31)).parse::<H256>());
+    }
+
     #[test]
     fn test_convert_h256_to_h160() {
         let mut arr: [u8; 32] = [0; 32];
@@ -240,4 +307,24 @@ pub mod tests {
         assert_eq!(81, h160.0[1]);
         assert_eq!(160, h160.0[19]);
     }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        let zero: H256 = [0u8; 32].into();
+        let ones: H256 = [0xffu8; 32].into();
+        assert_eq!(256, zero.leading_zero_bits());
+        assert_eq!(0, ones.leading_zero_bits());
+        let h: H256 = hex!("0000000000000000000000000000000000000000000000000000000000000001").into();
+        assert_eq!(255, h.leading_zero_bits());
+        let h: H256 = hex!("00000fff0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d").into();
+        assert_eq!(20, h.leading_zero_bits());
+        let h: H256 = hex!("0080000000000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(8, h.leading_zero_bits());
+
+        // meets_difficulty is hash < target
+        assert!(zero.meets_difficulty(&ones));
+        assert!(!ones.meets_difficulty(&ones));
+        assert!(!ones.meets_difficulty(&zero));
+        assert!(h.meets_difficulty(&ones));
+    }
 }
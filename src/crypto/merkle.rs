@@ -22,10 +22,7 @@ impl Node {
     }
 
     fn new_non_leaf(left: Node, right: Node) -> Self {
-        let mut ctx = digest::Context::new(&digest::SHA256);
-        ctx.update(left.hash.as_ref());
-        ctx.update(right.hash.as_ref());
-        let h: H256 = ctx.finish().into();
+        let h = hash_pair(&left.hash, &right.hash);
         let i = ((left.index as f32 + right.index as f32)/2.0).ceil() as usize;
         Node {hash: h, left: Some(Box::new(left)), right: Some(Box::new(right)),
                 is_leaf: false, index: i}
@@ -37,6 +34,13 @@ impl Node {
     }
 }
 
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(left.as_ref());
+    ctx.update(right.as_ref());
+    ctx.finish().into()
+}
+
 fn ensure_even(nodes: &mut Vec<Node>, step: usize) {
     if nodes.len() % 2 != 0 {
         nodes.push(nodes.last().unwrap().copy(step));
@@ -104,6 +108,60 @@ impl MerkleTree {
     }
 }
 
+/// A Merkle tree built by appending leaves one at a time. Each push only recomputes the path
+/// from the new leaf to the root, and the root equals `MerkleTree::new` over the same leaves.
+#[derive(Debug, Default, Clone)]
+pub struct IncrementalMerkle {
+    levels: Vec<Vec<H256>>,  // levels[0] are leaf hashes, the last level holds the root
+}
+
+impl IncrementalMerkle {
+    pub fn new() -> Self {
+        Self { levels: vec![] }
+    }
+
+    pub fn push<T>(&mut self, data: &T) where T: Hashable {
+        if self.levels.is_empty() {
+            self.levels.push(vec![]);
+        }
+        self.levels[0].push(data.hash());
+        let mut l = 0;
+        while self.levels[l].len() > 1 {
+            // the last node of each level changed, odd one is paired with itself as in MerkleTree
+            let level = &self.levels[l];
+            let i = level.len() - 1;
+            let parent = if i % 2 == 0 {
+                hash_pair(&level[i], &level[i])
+            } else {
+                hash_pair(&level[i - 1], &level[i])
+            };
+            if self.levels.len() == l + 1 {
+                self.levels.push(vec![]);
+            }
+            let upper = &mut self.levels[l + 1];
+            if upper.len() > i / 2 {
+                upper[i / 2] = parent;
+            } else {
+                upper.push(parent);
+            }
+            l += 1;
+        }
+    }
+
+    /// Merkle root of the leaves pushed so far, all zero if there is none
+    pub fn root(&self) -> H256 {
+        match self.levels.last() {
+            Some(top) => top[0],
+            None => H256::default(),
+        }
+    }
+
+    /// Number of leaves pushed
+    pub fn len(&self) -> usize {
+        self.levels.first().map_or(0, |leaves| leaves.len())
+    }
+}
+
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, _leaf_size: usize) -> bool {
@@ -369,4 +427,20 @@ mod tests {
         let p: H256 = (hex!("1e28fb71415f259bd4b0b3b98d67a1240b4f3bed5923aa222c5fdbd97c8fb002")).into();
         assert!(proof.contains(&p));
     }
+
+    #[test]
+    fn incremental_root() {
+        let mut rng = rand::thread_rng();
+        let data: Vec<H256> = (0..40).map(|_| {
+            let bytes: [u8; 32] = rng.gen();
+            bytes.into()
+        }).collect();
+        let mut incremental = IncrementalMerkle::new();
+        assert_eq!(H256::default(), incremental.root());
+        for i in 0..data.len() {
+            incremental.push(&data[i]);
+            assert_eq!(i + 1, incremental.len());
+            assert_eq!(MerkleTree::new(&data[..i + 1]).root(), incremental.root());
+        }
+    }
 }
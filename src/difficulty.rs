@@ -0,0 +1,258 @@
+use std::convert::TryInto;
+
+use crate::config::{EMA_MAX_SOLVETIME, MAX_RETARGET_FACTOR, MAX_TARGET_DIF, MIN_TARGET_DIF};
+use crate::crypto::hash::H256;
+use crate::helper::gen_difficulty_array;
+
+// How the target of the next block is derived, all nodes of a network must use the same mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetargetMode {
+    Step,  // once every RETARGET_INTERVAL blocks from the interval's timespan
+    Ema,  // every block from an exponential moving average of block times over EMA_WINDOW blocks
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetargetError {
+    NonMonotonicTimestamps,  // last block of the interval is older than the first one
+}
+
+// Easiest target a retarget can reach
+pub fn max_target() -> H256 {
+    gen_difficulty_array(MAX_TARGET_DIF).into()
+}
+
+// Hardest target a retarget can reach
+pub fn min_target() -> H256 {
+    gen_difficulty_array(MIN_TARGET_DIF).into()
+}
+
+// New target from timestamps(ms) of the first & last block of an interval spanning `blocks` blocks
+pub fn retarget(target: &H256, first_ts: u64, last_ts: u64, blocks: u64, block_time: u64)
+        -> Result<H256, RetargetError> {
+    if last_ts < first_ts {
+        return Err(RetargetError::NonMonotonicTimestamps);
+    }
+    Ok(retarget_timespan(target, last_ts - first_ts, blocks * block_time))
+}
+
+// Scale target by actual/expected timespan, changing at most MAX_RETARGET_FACTOR times and staying in bounds
+pub fn retarget_timespan(target: &H256, actual: u64, expected: u64) -> H256 {
+    let expected = expected.max(1);
+    let actual = actual
+        .max(expected / MAX_RETARGET_FACTOR)
+        .min(expected * MAX_RETARGET_FACTOR);
+    let new_target = match mul_div(target, actual, expected) {
+        Some(t) => t,
+        None => return max_target(),  // overflow
+    };
+    clamp(new_target)
+}
+
+// New target after a block solved in solvetime(ms): the moving average of block times moves by
+// (solvetime - block_time) / window, and the target with it. Solvetime is capped at EMA_MAX_SOLVETIME
+// block times, so one late timestamp can't ease the target much; integer only, so every node agrees
+pub fn retarget_ema(target: &H256, solvetime: u64, block_time: u64, window: u64) -> H256 {
+    let block_time = block_time.max(1);
+    let window = window.max(2);
+    let solvetime = solvetime.min(block_time * EMA_MAX_SOLVETIME);
+    let expected = window * block_time;
+    let new_target = match mul_div(target, expected + solvetime - block_time, expected) {
+        Some(t) => t,
+        None => return max_target(),  // overflow
+    };
+    clamp(new_target)
+}
+
+// Keep target within [min_target, max_target]
+pub fn clamp(target: H256) -> H256 {
+    let (lo, hi) = (min_target(), max_target());
+    if target < lo {
+        lo
+    } else if target > hi {
+        hi
+    } else {
+        target
+    }
+}
+
+// Expected number of hashes to meet target, about 2^256 / (target + 1), computed on its top 128 bits
+pub fn block_work(target: &H256) -> u128 {
+    let bytes: [u8; 32] = target.into();
+    let high = u128::from_be_bytes(bytes[0..16].try_into().unwrap());
+    if high == u128::MAX {
+        return 1;
+    }
+    (!high / (high + 1)).saturating_add(1)
+}
+
+// Compute target * mul / div in 256 bits, None if the result overflows
+fn mul_div(target: &H256, mul: u64, div: u64) -> Option<H256> {
+    let bytes: [u8; 32] = target.into();
+    // big endian limbs, with an extra limb for overflow
+    let mut limbs = [0u64; 5];
+    for i in 0..4 {
+        limbs[i + 1] = u64::from_be_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+    }
+    let mut carry = 0u128;
+    for i in (0..5).rev() {
+        let v = limbs[i] as u128 * mul as u128 + carry;
+        limbs[i] = v as u64;
+        carry = v >> 64;
+    }
+    let mut rem = 0u128;
+    for i in 0..5 {
+        let v = (rem << 64) | limbs[i] as u128;
+        limbs[i] = (v / div as u128) as u64;
+        rem = v % div as u128;
+    }
+    if limbs[0] != 0 {
+        return None;
+    }
+    let mut result = [0u8; 32];
+    for i in 0..4 {
+        result[i * 8..i * 8 + 8].copy_from_slice(&limbs[i + 1].to_be_bytes());
+    }
+    Some(result.into())
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::PrintableBlock;
+    use crate::helper::{generate_block, generate_random_hash};
+
+    fn from_hex(s: &str) -> H256 {
+        let bytes: [u8; 32] = hex::decode(s).unwrap()[..].try_into().unwrap();
+        bytes.into()
+    }
+
+    #[test]
+    fn test_block_work() {
+        assert_eq!(1, block_work(&gen_difficulty_array(0).into()));
+        assert_eq!(2, block_work(&gen_difficulty_array(1).into()));
+        assert_eq!(1 << 16, block_work(&gen_difficulty_array(16).into()));
+        assert_eq!(1 << 100, block_work(&gen_difficulty_array(100).into()));
+        assert_eq!(u128::MAX, block_work(&gen_difficulty_array(256).into()));
+    }
+
+    #[test]
+    fn test_display_difficulty() {
+        for zeros in [16u32, 24].iter() {
+            let target: H256 = gen_difficulty_array(*zeros as i32).into();
+            assert_eq!(*zeros, target.leading_zero_bits());
+            let block = generate_block(&generate_random_hash(), 0, &target);
+            let pblock = &PrintableBlock::from_block_vec(&vec![block])[0];
+            assert_eq!(*zeros, pblock.difficulty_bits);
+            assert_eq!((1u128 << zeros).to_string(), pblock.expected_hashes);
+        }
+    }
+
+    #[test]
+    fn test_retarget_vectors() {
+        let target: H256 = gen_difficulty_array(16).into();
+        // (actual timespan, expected timespan, new target)
+        let vectors = vec![
+            (1000, 1000, "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+            (2000, 1000, "0001fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe"),
+            (500, 1000, "00007fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+            (1500, 1000, "00017ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe"),
+            (0, 1000, "00003fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+        ];
+        for (actual, expected, new_target) in vectors {
+            assert_eq!(from_hex(new_target), retarget_timespan(&target, actual, expected));
+        }
+    }
+
+    #[test]
+    fn test_retarget_factor_limit() {
+        let target: H256 = gen_difficulty_array(16).into();
+        // 4x up: blocks came far too slowly, target grows by 4 at most
+        let up = from_hex("0003fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffc");
+        assert_eq!(up, retarget_timespan(&target, 4000, 1000));
+        assert_eq!(up, retarget_timespan(&target, 100000, 1000));
+        // 4x down: blocks came far too quickly, target shrinks by 4 at most
+        let down = from_hex("00003fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+        assert_eq!(down, retarget_timespan(&target, 250, 1000));
+        assert_eq!(down, retarget_timespan(&target, 1, 1000));
+    }
+
+    #[test]
+    fn test_retarget_bounds() {
+        let easy: H256 = gen_difficulty_array(MAX_TARGET_DIF + 1).into();
+        assert_eq!(max_target(), retarget_timespan(&easy, 4000, 1000));
+        assert_eq!(max_target(), retarget_timespan(&max_target(), 4000, 1000));
+        let hard: H256 = gen_difficulty_array(MIN_TARGET_DIF - 1).into();
+        assert_eq!(min_target(), retarget_timespan(&hard, 250, 1000));
+        assert_eq!(min_target(), retarget_timespan(&min_target(), 250, 1000));
+    }
+
+    #[test]
+    fn test_retarget_timestamps() {
+        let target: H256 = gen_difficulty_array(16).into();
+        assert_eq!(Err(RetargetError::NonMonotonicTimestamps), retarget(&target, 2000, 1000, 10, 100));
+        assert_eq!(Ok(target), retarget(&target, 1000, 2000, 10, 100));
+        assert_eq!(Ok(retarget_timespan(&target, 0, 1000)), retarget(&target, 1000, 1000, 10, 100));
+    }
+
+    #[test]
+    fn test_retarget_ema_vectors() {
+        let target: H256 = gen_difficulty_array(16).into();
+        // (solvetime, new target) with a window of 10 blocks of 1000ms
+        let vectors = vec![
+            (1000, "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+            (2000, "0001199999999999999999999999999999999999999999999999999999999998"),
+            (500, "0000f33333333333333333333333333333333333333333333333333333333332"),
+            (0, "0000e66666666666666666666666666666666666666666666666666666666665"),
+            // capped at EMA_MAX_SOLVETIME block times
+            (100000, "00017ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe"),
+        ];
+        for (solvetime, new_target) in vectors {
+            assert_eq!(from_hex(new_target), retarget_ema(&target, solvetime, 1000, 10));
+        }
+        assert_eq!(max_target(), retarget_ema(&max_target(), 2000, 1000, 10));
+        assert_eq!(min_target(), retarget_ema(&min_target(), 0, 1000, 10));
+    }
+
+    #[test]
+    fn test_ema_smoother_than_step() {
+        let (block_time, interval, window) = (1000u64, 20u64, 20u64);
+        // hashrate quadruples at block 100; a block takes its expected number of hashes
+        let simulate = |mode: RetargetMode| -> Vec<u128> {
+            let mut target: H256 = gen_difficulty_array(16).into();
+            let (mut ts, mut first_ts) = (0u64, 0u64);
+            let mut works = vec![];
+            for height in 1..=300u64 {
+                let hashrate: u128 = if height < 100 { 1 << 16 } else { 1 << 18 };
+                let solvetime = (block_work(&target) * block_time as u128 / hashrate) as u64;
+                ts += solvetime;
+                works.push(block_work(&target));
+                target = match mode {
+                    RetargetMode::Ema => retarget_ema(&target, solvetime, block_time, window),
+                    RetargetMode::Step if height % interval == 0 => {
+                        let new_target = retarget(&target, first_ts, ts, interval, block_time).unwrap();
+                        first_ts = ts;
+                        new_target
+                    }
+                    RetargetMode::Step => target,
+                };
+            }
+            works
+        };
+        // largest change of difficulty from one block to the next
+        let max_jump = |works: &Vec<u128>| works.windows(2)
+            .map(|w| std::cmp::max(w[0], w[1]) as f64 / std::cmp::min(w[0], w[1]) as f64)
+            .fold(1.0, f64::max);
+
+        let step = simulate(RetargetMode::Step);
+        let ema = simulate(RetargetMode::Ema);
+        assert!(max_jump(&ema) < 1.1);
+        assert!(max_jump(&step) > 3.0);
+        // both settle at the new hashrate
+        for works in [&step, &ema].iter() {
+            let settled = works[280] as f64 / (1u128 << 18) as f64;
+            assert!(settled > 0.8 && settled < 1.25, "settled at {}", settled);
+        }
+        // and the same inputs give the same targets
+        assert_eq!(ema, simulate(RetargetMode::Ema));
+    }
+}
@@ -0,0 +1,62 @@
+use crossbeam::channel::{unbounded, Receiver, Sender};
+
+use crate::crypto::hash::H256;
+
+// Notifications published to interested components (e.g. transaction generator)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    TxAdded(H256),  // transaction accepted into mempool
+    TxEvicted(H256),  // transaction dropped from mempool without being confirmed
+    TxReplaced { old: H256, new: H256 },  // conflicting transaction took its place in mempool
+    StaleTip { tip: H256, idle_ms: u64 },  // no new block extends the chain for a while
+    TxConfirmed(H256, usize),  // transaction entered longest-chain in the block of this height
+    TxUnconfirmed(H256),  // a reorg took the block of a confirmed transaction out of longest-chain
+    // block stored with its parent known, on longest-chain or not; tx_count includes coinbase
+    BlockAccepted { hash: H256, height: usize, tx_count: usize, total_fees: u64, block_bytes: usize },
+    Reorg { tip: H256, depth: usize },  // tip moved to another branch, disconnecting depth blocks
+}
+
+// Fan out events to every subscriber; disconnected subscribers are dropped on publish
+pub struct EventBus {
+    subscribers: Vec<Sender<Event>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn publish(&mut self, event: Event) {
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::helper::generate_random_hash;
+
+    #[test]
+    fn test_publish() {
+        let mut bus = EventBus::new();
+        let rx_1 = bus.subscribe();
+        let rx_2 = bus.subscribe();
+        let hash = generate_random_hash();
+        bus.publish(Event::TxEvicted(hash));
+        assert_eq!(Ok(Event::TxEvicted(hash)), rx_1.try_recv());
+        assert_eq!(Ok(Event::TxEvicted(hash)), rx_2.try_recv());
+
+        drop(rx_1);
+        bus.publish(Event::TxEvicted(hash));
+        assert_eq!(1, bus.subscribers.len());
+        assert_eq!(Ok(Event::TxEvicted(hash)), rx_2.try_recv());
+    }
+}
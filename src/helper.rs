@@ -1,51 +1,98 @@
 use crate::transaction::*;
-use crate::blockchain::Blockchain;
 use crate::block::*;
 use crate::crypto::hash::{H256, H160};
 use crate::crypto::key_pair;
 use crate::config::*;
 use crate::miner;
-use crate::mempool::MemPool;
-use crate::transaction_generator;
-use crate::network::{worker, server};
+use crate::network::server;
 use crate::account::Account;
-use crate::peers::Peers;
-use crate::spread::Spreader;
 
 use log::{info, error};
-use rand::{Rng,thread_rng};
+use rand::{Rng, RngCore, SeedableRng, thread_rng};
+use rand::rngs::StdRng;
 use rand::distributions::Distribution;
 use rand::seq::SliceRandom;
-use ring::signature::{KeyPair, Ed25519KeyPair};
+use ring::signature::{KeyPair, Ed25519KeyPair, EcdsaKeyPair};
 use ring::digest;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use chrono::prelude::*;
 use std::net::SocketAddr;
-use crossbeam::channel;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, BufRead};
+#[cfg(any(test, test_utilities))]
+use std::sync::{Arc, Mutex};
+#[cfg(any(test, test_utilities))]
+use crossbeam::channel;
+#[cfg(any(test, test_utilities))]
+use crate::blockchain::Blockchain;
+#[cfg(any(test, test_utilities))]
+use crate::mempool::MemPool;
+#[cfg(any(test, test_utilities))]
+use crate::lock_order::OrderedMutex;
+#[cfg(any(test, test_utilities))]
+use crate::transaction_generator;
+#[cfg(any(test, test_utilities))]
+use crate::network::worker;
+#[cfg(any(test, test_utilities))]
+use crate::peers::Peers;
+#[cfg(any(test, test_utilities))]
+use crate::spread::Spreader;
 use std::path::Path;
 use std::iter::FromIterator;
+use std::cell::RefCell;
+
+thread_local! {
+    // PRNG of the random generators below when a test fixes the seed, thread_rng otherwise
+    static SEEDED_RNG: RefCell<Option<StdRng>> = RefCell::new(None);
+}
+
+// Make random blocks/transactions generated by this thread reproducible, None goes back to thread_rng
+pub fn set_random_seed(seed: Option<u64>) {
+    SEEDED_RNG.with(|r| *r.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
+
+fn is_seeded() -> bool {
+    SEEDED_RNG.with(|r| r.borrow().is_some())
+}
+
+fn with_rng<T, F: FnOnce(&mut dyn RngCore) -> T>(f: F) -> T {
+    SEEDED_RNG.with(|r| match r.borrow_mut().as_mut() {
+        Some(rng) => f(rng),
+        None => f(&mut thread_rng()),
+    })
+}
+
+// Random key pair, reproducible when seeded
+fn random_key_pair() -> Ed25519KeyPair {
+    if !is_seeded() {
+        return key_pair::random();
+    }
+    let mut seed = [0u8; 32];
+    with_rng(|rng| rng.fill_bytes(&mut seed));
+    Ed25519KeyPair::from_seed_unchecked(&seed).unwrap()
+}
 
 ///Network
+#[cfg(any(test, test_utilities))]
 pub fn new_server_env(ipv4_addr: SocketAddr, spreader_type : Spreader, is_supernode: bool) -> (server::Handle, miner::Context, transaction_generator::Context,
-                                                Arc<Mutex<Blockchain>>, Arc<Mutex<MemPool>>, Arc<Mutex<Peers>>,
+                                                Arc<OrderedMutex<Blockchain>>, Arc<OrderedMutex<MemPool>>, Arc<Mutex<Peers>>,
                                                 Arc<Account>) {
-    let (sender, receiver) = channel::unbounded();
+    let (sender, receiver) = channel::bounded(WORKER_QUEUE_CAPACITY);
 
     let peers = Arc::new(Mutex::new(Peers::new()));
 
     let mut blockchain = Blockchain::new();
     let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
     blockchain.change_difficulty(&difficulty);
-    let blockchain =  Arc::new(Mutex::new(blockchain));
+    let blockchain =  Arc::new(OrderedMutex::new(blockchain));
 
-    let mempool = Arc::new(Mutex::new(MemPool::new()));
+    let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
 
     let using_dandelion =  spreader_type == Spreader::Dandelion || spreader_type == Spreader::DandelionPlus;
 
-    let (server_ctx, server, spreader_ctx) = server::new(ipv4_addr, sender, spreader_type, mempool.clone()).unwrap();
+    let (mut server_ctx, server, spreader_ctx) = server::new(ipv4_addr, sender, spreader_type, mempool.clone()).unwrap();
+    server_ctx.set_genesis(blockchain.lock().unwrap().genesis_id());
     server_ctx.start().unwrap();
     spreader_ctx.start();
 
@@ -72,11 +119,16 @@ pub fn new_server_env(ipv4_addr: SocketAddr, spreader_type : Spreader, is_supern
     (server, miner_ctx, transaction_generator_ctx, blockchain, mempool, peers, account)
 }
 
-pub fn connect_peers(server: &server::Handle, known_peers: &Vec<SocketAddr>) {
-    for peer_addr in known_peers {
-        match server.connect(*peer_addr) {
+// Return the peers connected to
+pub fn connect_peers(server: &server::Handle, known_peers: &Vec<SocketAddr>) -> Vec<SocketAddr> {
+    // connect to all peers at once, so a slow peer doesn't hold up the others
+    let pending: Vec<_> = known_peers.iter().map(|addr| server.connect_async(*addr)).collect();
+    let mut connected = vec![];
+    for (peer_addr, result) in known_peers.iter().zip(pending) {
+        match result.recv().unwrap() {
             Ok(_) => {
                 info!("Connected to outgoing peer {}", &peer_addr);
+                connected.push(*peer_addr);
             }
             Err(e) => {
                 error!(
@@ -86,6 +138,7 @@ pub fn connect_peers(server: &server::Handle, known_peers: &Vec<SocketAddr>) {
             }
         }
     }
+    connected
 }
 
 ///Block
@@ -104,10 +157,9 @@ pub fn generate_random_block(parent: &H256) -> Block {
 }
 
 pub fn generate_random_header(parent: &H256, content: &Content) -> Header {
-    let mut rng = rand::thread_rng();
-    let nonce: u32 = rng.gen();
-    let timestamp: u128 = rng.gen();
-    let difficulty = generate_random_hash();
+    let (nonce, timestamp): (u32, u128) = with_rng(|rng| (rng.gen(), rng.gen()));
+    // same work in every random block, so a longer branch of them has more work
+    let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
     let merkle_root = content.merkle_root();
     Header::new(
         parent, nonce, timestamp,
@@ -117,8 +169,7 @@ pub fn generate_random_header(parent: &H256, content: &Content) -> Header {
 
 pub fn generate_random_content() -> Content {
     let mut content = Content::new();
-    let mut rng = rand::thread_rng();
-    let size: u32 = rng.gen_range(10, 20);
+    let size: u32 = with_rng(|rng| rng.gen_range(10, 20));
     for _ in 0..size {
         content.add_tran(generate_random_signed_transaction());
     }
@@ -132,9 +183,13 @@ pub fn generate_block(parent: &H256, nonce: u32, difficulty: &H256)
     Block::new(header, content)
 }
 
+// Timestamp of the next header from generate_header
+static NEXT_HEADER_TS: AtomicU64 = AtomicU64::new(100);
+
+// Header with a timestamp later than all generated before, so a chain of them passes the median time past check
 pub fn generate_header(parent: &H256, content: &Content, nonce: u32,
                    difficulty: &H256) -> Header {
-    let ts = 100u128;
+    let ts = NEXT_HEADER_TS.fetch_add(1, Ordering::Relaxed) as u128;
     let merkle_root = content.merkle_root();
     Header::new(
         parent, nonce, ts,
@@ -152,45 +207,96 @@ fn generate_content() -> Content {
 /// Transaction
 
 // Create valid transactions under current state (For now: Send to one peer & myself)
+// Spend one coin at a time, so a burst of transactions doesn't lock the whole balance in the first one
 pub fn generate_valid_tran(state: &State, account: &Account, rec_addr: &H160) -> Option<SignedTransaction> {
-    let (coins, balance) = state.coins_of(&account.addr);
-    if balance > 0 {
-        let transfer_val = gen_random_num(1, balance);
-        let mut acc = 0u64;
-        let mut tx_inputs = Vec::<TxInput>::new();
-        for (input, val) in coins.iter() {
-            tx_inputs.push(input.clone());
-            acc += val;
-            if acc >= transfer_val {
-                break;
-            }
-        }
-        let mut tx_outputs = Vec::<TxOutput>::new();
-        tx_outputs.push(TxOutput::new(rec_addr.clone(), transfer_val));
-        if acc > transfer_val {
-            tx_outputs.push(TxOutput::new(account.addr.clone(), acc-transfer_val));
-        }
-        let new_tran = generate_signed_transaction(&account.key_pair, tx_inputs, tx_outputs);
-        return Some(new_tran);
+    let (coins, _) = state.coins_of(&account.addr);
+    if coins.is_empty() {
+        return None;
+    }
+    let pick = gen_random_num(0, coins.len() as u64 - 1) as usize;
+    let (input, val) = coins.iter().nth(pick).unwrap();
+    let transfer_val = gen_random_num(1, *val);
+    let mut tx_outputs = Vec::<TxOutput>::new();
+    tx_outputs.push(TxOutput::new(rec_addr.clone(), transfer_val));
+    if *val > transfer_val {
+        tx_outputs.push(TxOutput::new(account.addr.clone(), val-transfer_val));
     }
-    return None;
+    let new_tran = generate_signed_transaction(&account.key_pair, vec![input.clone()], tx_outputs);
+    return Some(new_tran);
 }
 
 pub fn generate_signed_transaction(key: &Ed25519KeyPair,
         inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> SignedTransaction {
+    generate_signed_transaction_with_fee(key, inputs, outputs, 0)
+}
+
+pub fn generate_signed_transaction_with_fee(key: &Ed25519KeyPair,
+        inputs: Vec<TxInput>, outputs: Vec<TxOutput>, fee: u64) -> SignedTransaction {
     let pub_key_bytes: Box<[u8]> = key.public_key().as_ref().into();
-    let tran = Transaction::new(inputs, outputs);
+    let tran = Transaction::new_with_fee(inputs, outputs, fee);
     let signature = sign(&tran, &key);
     let sig_bytes: Box<[u8]> = signature.as_ref().into();
     return SignedTransaction::new(tran, sig_bytes, pub_key_bytes);
 }
 
+// Transaction opting in to be replaced in mempool by a conflicting one paying more
+pub fn generate_signed_replaceable_transaction(key: &Ed25519KeyPair,
+        inputs: Vec<TxInput>, outputs: Vec<TxOutput>, fee: u64) -> SignedTransaction {
+    let pub_key_bytes: Box<[u8]> = key.public_key().as_ref().into();
+    let mut tran = Transaction::new_with_fee(inputs, outputs, fee);
+    tran.replaceable = true;
+    let sig_bytes: Box<[u8]> = sign(&tran, &key).as_ref().into();
+    return SignedTransaction::new(tran, sig_bytes, pub_key_bytes);
+}
+
+// Transaction that may be in a block only once its lock time is reached
+pub fn generate_signed_locked_transaction(key: &Ed25519KeyPair,
+        inputs: Vec<TxInput>, outputs: Vec<TxOutput>, lock_time: LockTime) -> SignedTransaction {
+    let pub_key_bytes: Box<[u8]> = key.public_key().as_ref().into();
+    let mut tran = Transaction::new(inputs, outputs);
+    tran.lock_time = Some(lock_time);
+    let sig_bytes: Box<[u8]> = sign(&tran, &key).as_ref().into();
+    SignedTransaction::new(tran, sig_bytes, pub_key_bytes)
+}
+
+pub fn generate_ecdsa_signed_transaction(key: &EcdsaKeyPair,
+        inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> SignedTransaction {
+    let pub_key_bytes: Box<[u8]> = key.public_key().as_ref().into();
+    let tran = Transaction::new(inputs, outputs);
+    let sig_bytes: Box<[u8]> = sign_ecdsa(&tran, &key).as_ref().into();
+    return SignedTransaction::new_with_scheme(tran, sig_bytes, pub_key_bytes, SignatureScheme::EcdsaP256);
+}
+
 pub fn generate_signed_coinbase_transaction(key: &Ed25519KeyPair) -> SignedTransaction {
-    let addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
-    let txoutput = TxOutput {rec_address: addr.clone(), val: COINBASE_REWARD};
+    generate_signed_coinbase_transaction_with_reward(key, COINBASE_REWARD)
+}
+
+pub fn generate_signed_coinbase_transaction_with_reward(key: &Ed25519KeyPair, reward: u64) -> SignedTransaction {
+    generate_signed_coinbase_transaction_to(key, &address_of(key), reward)
+}
+
+// Address owned by a key pair
+pub fn address_of(key: &Ed25519KeyPair) -> H160 {
+    digest::digest(&digest::SHA256, key.public_key().as_ref()).into()
+}
+
+// Coinbase signed by key, paying the reward to another address
+pub fn generate_signed_coinbase_transaction_to(key: &Ed25519KeyPair, addr: &H160, reward: u64) -> SignedTransaction {
+    let txoutput = TxOutput {rec_address: addr.clone(), val: reward};
     return generate_signed_transaction(key, Vec::new(), vec![txoutput]);
 }
 
+// Coinbase carrying a commitment to the auxiliary root of its block
+pub fn generate_signed_coinbase_transaction_with_commitment(key: &Ed25519KeyPair, addr: &H160, reward: u64,
+        commitment: &H256) -> SignedTransaction {
+    let txoutput = TxOutput {rec_address: addr.clone(), val: reward};
+    let mut transaction = Transaction::new(Vec::new(), vec![txoutput]);
+    transaction.commitment = Some(commitment.clone());
+    let pub_key_bytes: Box<[u8]> = key.public_key().as_ref().into();
+    let sig_bytes: Box<[u8]> = sign(&transaction, key).as_ref().into();
+    SignedTransaction::new(transaction, sig_bytes, pub_key_bytes)
+}
+
 pub fn generate_random_signed_transaction_from_keypair(key: &Ed25519KeyPair) -> SignedTransaction {
     let transaction = generate_random_transaction();
     let public_key: Box<[u8]> = key.public_key().as_ref().into();
@@ -200,7 +306,7 @@ pub fn generate_random_signed_transaction_from_keypair(key: &Ed25519KeyPair) ->
 
 pub fn generate_random_signed_transaction() -> SignedTransaction {
     let transaction = generate_random_transaction();
-    let key = key_pair::random();
+    let key = random_key_pair();
     let public_key: Box<[u8]> = key.public_key().as_ref().into();
     let signature: Box<[u8]> = sign(&transaction, &key).as_ref().into();
     SignedTransaction::new(transaction, signature, public_key)
@@ -215,35 +321,35 @@ pub fn generate_random_transaction() -> Transaction {
     for _ in 0..RAND_OUTPUTS_NUM {
         outputs.push(generate_random_txoutput());
     }
-    Transaction::new(inputs, outputs)
+    let mut transaction = Transaction::new(inputs, outputs);
+    if is_seeded() {
+        transaction.ts = with_rng(|rng| rng.gen());  // wall clock would break reproducibility
+    }
+    transaction
 }
 
 pub fn generate_random_txinput() -> TxInput {
     let pre_hash = generate_random_hash();
-    let mut rng = rand::thread_rng();
-    let index: u32 = rng.gen_range(0, 10);
+    let index: u32 = with_rng(|rng| rng.gen_range(0, 10));
     TxInput {pre_hash, index}
 }
 
 pub fn generate_random_txoutput() -> TxOutput {
     let rec_address = generate_random_h160();
-    let mut rng = rand::thread_rng();
-    let val: u64 = rng.gen_range(0, 256);
+    let val: u64 = with_rng(|rng| rng.gen_range(DUST_THRESHOLD, 256));
     TxOutput {rec_address, val}
 }
 
 /// Hash
 pub fn generate_random_hash() -> H256 {
-    let mut rng = rand::thread_rng();
-    let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
+    let random_bytes: Vec<u8> = with_rng(|rng| (0..32).map(|_| rng.gen()).collect());
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
 }
 
 pub fn generate_random_h160() -> H160 {
-    let mut rng = rand::thread_rng();
-    let random_bytes: Vec<u8> = (0..20).map(|_| rng.gen()).collect();
+    let random_bytes: Vec<u8> = with_rng(|rng| (0..20).map(|_| rng.gen()).collect());
     let mut raw_bytes = [0; 20];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
@@ -492,4 +598,19 @@ pub mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_random_seed() {
+        let run = |seed: Option<u64>| {
+            set_random_seed(seed);
+            let trans: Vec<H256> = (0..3).map(|_| generate_random_signed_transaction().hash).collect();
+            let block = generate_random_block(&generate_random_hash());
+            (trans, block.hash)
+        };
+        let first = run(Some(42));
+        assert_eq!(first, run(Some(42)));
+        assert_ne!(first, run(Some(43)));
+        // unseeded generators stay random
+        assert_ne!(run(None), run(None));
+    }
 }
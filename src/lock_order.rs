@@ -0,0 +1,243 @@
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LockResult, Mutex, MutexGuard, PoisonError};
+use log::warn;
+
+use crate::blockchain::Blockchain;
+use crate::mempool::MemPool;
+
+// Lock acquisition order: a thread holding a lock may only take locks of a higher rank,
+// i.e. blockchain before mempool everywhere. Checked in debug builds; locks of the same rank belong
+// to different nodes (tests run several in one process) and may be held together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockRank {
+    Blockchain = 1,
+    MemPool = 2,
+}
+
+pub trait Ranked {
+    const RANK: LockRank;
+}
+
+impl Ranked for Blockchain {
+    const RANK: LockRank = LockRank::Blockchain;
+}
+
+impl Ranked for MemPool {
+    const RANK: LockRank = LockRank::MemPool;
+}
+
+thread_local! {
+    // ranks of the ordered locks held by this thread
+    static HELD: RefCell<Vec<LockRank>> = RefCell::new(Vec::new());
+}
+
+fn acquire(rank: LockRank) {
+    if cfg!(debug_assertions) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(top) = held.iter().max() {
+                if *top > rank {
+                    panic!("lock order violation: acquiring {:?} while holding {:?}", rank, held);
+                }
+            }
+            held.push(rank);
+        });
+    }
+}
+
+fn release(rank: LockRank) {
+    if cfg!(debug_assertions) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(pos) = held.iter().rposition(|r| *r == rank) {
+                held.remove(pos);
+            }
+        });
+    }
+}
+
+// A thread panicked while holding the lock, so the data it guards may be half-updated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Poisoned(pub LockRank);
+
+// Mutex that checks the lock acquisition order of its thread before locking
+pub struct OrderedMutex<T: Ranked> {
+    inner: Mutex<T>,
+    recovered: AtomicBool,  // a poisoned lock has been recovered, so it is logged only once
+}
+
+pub struct OrderedMutexGuard<'a, T: Ranked> {
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T: Ranked> OrderedMutex<T> {
+    pub fn new(t: T) -> Self {
+        Self { inner: Mutex::new(t), recovered: AtomicBool::new(false) }
+    }
+
+    // Same as Mutex::lock, panics in debug builds if the lock order is violated
+    pub fn lock(&self) -> LockResult<OrderedMutexGuard<'_, T>> {
+        acquire(T::RANK);
+        match self.inner.lock() {
+            Ok(guard) => Ok(OrderedMutexGuard { guard }),
+            Err(e) => Err(PoisonError::new(OrderedMutexGuard { guard: e.into_inner() })),
+        }
+    }
+
+    // Lock even if a thread panicked holding it, for reads and for data that stays consistent
+    // wherever an update stops, so one panic doesn't cascade to every other thread
+    pub fn lock_or_recover(&self) -> OrderedMutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|e| {
+            if !self.recovered.swap(true, Ordering::Relaxed) {
+                warn!("Recovered {:?} lock poisoned by a thread that panicked holding it", T::RANK);
+            }
+            e.into_inner()
+        })
+    }
+
+    // Lock for updates that must not build on a half-updated state, fail if a thread panicked holding it
+    pub fn lock_healthy(&self) -> Result<OrderedMutexGuard<'_, T>, Poisoned> {
+        self.lock().map_err(|_| Poisoned(T::RANK))
+    }
+}
+
+impl<'a, T: Ranked> Deref for OrderedMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: Ranked> DerefMut for OrderedMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: Ranked> Drop for OrderedMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        release(T::RANK);
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::blockchain::CheckPolicy;
+    use crate::helper::*;
+    use crate::miner;
+    use crate::crypto::key_pair;
+    use crate::spread::Spreader;
+    use crate::network::message::Message;
+
+    use crossbeam::channel;
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    #[test]
+    #[should_panic(expected = "lock order violation")]
+    fn test_lock_order_violation() {
+        let blockchain = OrderedMutex::new(Blockchain::new());
+        let mempool = OrderedMutex::new(MemPool::new());
+        let _pool = mempool.lock().unwrap();
+        let _chain = blockchain.lock().unwrap();
+    }
+
+    #[test]
+    fn test_recover_poisoned_lock() {
+        let blockchain = Arc::new(OrderedMutex::new(Blockchain::new()));
+        let chain = blockchain.clone();
+        assert!(thread::spawn(move || {
+            let _chain = chain.lock().unwrap();
+            panic!("poison the blockchain lock");
+        }).join().is_err());
+
+        // readers recover, writers get a typed error, neither panics
+        let chain = blockchain.clone();
+        let length = thread::spawn(move || chain.lock_or_recover().length()).join().unwrap();
+        assert_eq!(1, length);
+        assert_eq!(Err(Poisoned(LockRank::Blockchain)), blockchain.lock_healthy().map(|_| ()));
+        // the failed lock doesn't count as held
+        let mempool = OrderedMutex::new(MemPool::new());
+        let _pool = mempool.lock_or_recover();
+    }
+
+    #[test]
+    fn test_workers_survive_poisoned_blockchain() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17461);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17462);
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, _, _, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        let chain = blockchain_1.clone();
+        assert!(thread::spawn(move || {
+            let _chain = chain.lock().unwrap();
+            panic!("poison the blockchain lock");
+        }).join().is_err());
+
+        // more blocks than workers are dropped, then every worker is still there to take transactions
+        let genesis = blockchain_1.lock_or_recover().tip();
+        for _ in 0..8 {
+            server_2.broadcast(Message::Blocks(vec![generate_random_block(&genesis)]), None);
+        }
+        let tran = generate_random_signed_transaction();
+        server_2.broadcast(Message::Transactions(vec![tran.clone()]), None);
+        let start = time::Instant::now();
+        while !mempool_1.lock().unwrap().exist(&tran.hash) {
+            assert!(start.elapsed() < time::Duration::from_secs(5), "workers are gone");
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(1, blockchain_1.lock_or_recover().length());
+        drop(server_1);
+    }
+
+    #[test]
+    fn test_no_deadlock_under_load() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17411);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17412);
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, blockchain_2, mempool_2, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        let nodes = vec![(server_1, blockchain_1, mempool_1), (server_2, blockchain_2, mempool_2)];
+
+        // both nodes mine and relay blocks while transactions keep arriving
+        let mut miners = vec![];
+        for (server, blockchain, mempool) in nodes.iter() {
+            blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+            let (miner_ctx, miner_handle) = miner::new(server.clone(), blockchain.clone(), mempool.clone(),
+                                                       Arc::new(key_pair::random()));
+            miner_ctx.start();
+            miner_handle.start(0);
+            miners.push(miner_handle);
+        }
+        let start = time::Instant::now();
+        while start.elapsed() < time::Duration::from_millis(1000) {
+            for (_, _, mempool) in nodes.iter() {
+                mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction());
+            }
+        }
+
+        // every lock can still be taken in order
+        let (sender, receiver) = channel::unbounded();
+        let locks: Vec<_> = nodes.iter().map(|(_, b, m)| (b.clone(), m.clone())).collect();
+        thread::spawn(move || {
+            for (blockchain, mempool) in locks {
+                let chain = blockchain.lock().unwrap();
+                let pool = mempool.lock().unwrap();
+                sender.send((chain.length(), pool.size())).unwrap();
+            }
+        });
+        for _ in 0..nodes.len() {
+            let (length, _) = receiver.recv_timeout(time::Duration::from_secs(5)).expect("deadlock");
+            assert!(length > 1);
+        }
+        for miner in miners {
+            miner.exit();
+        }
+    }
+}
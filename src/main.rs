@@ -10,14 +10,19 @@ pub mod api;
 pub mod block;
 pub mod blockchain;
 pub mod crypto;
+pub mod difficulty;
 pub mod miner;
 pub mod network;
 pub mod transaction;
 pub mod config;
 pub mod helper;
 pub mod mempool;
+pub mod node;
 pub mod transaction_generator;
 pub mod peers;
+pub mod events;
+pub mod watchdog;
+pub mod lock_order;
 #[allow(unused_variables)] // TODO: remove
 #[allow(dead_code)] // TODO: remove
 pub mod spread;
@@ -37,12 +42,14 @@ use net::SocketAddr;
 
 use crate::blockchain::Blockchain;
 use crate::mempool::MemPool;
+use crate::lock_order::OrderedMutex;
 use crate::account::Account;
 use crate::peers::Peers;
 use crate::network::message::Message;
+use crate::network::addrman::AddrMan;
 use crate::crypto::key_pair;
+use crate::node::Node;
 use ring::signature::KeyPair;
-use crate::spread::Spreader;
 
 fn run_regular_server(matches: ArgMatches) {
     // parse p2p server address
@@ -66,23 +73,42 @@ fn run_regular_server(matches: ArgMatches) {
         });
 
     // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::unbounded();
-
-    // create peer(for transaction)
-    let peers = Arc::new(Mutex::new(Peers::new()));
+    let queue_capacity = match matches.value_of("p2p_queue") {
+        Some(v) => v.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing P2P queue capacity: {}", e);
+            process::exit(1);
+        }),
+        None => config::WORKER_QUEUE_CAPACITY,
+    };
     // create blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    // create mempool
-    let mempool = Arc::new(Mutex::new(MemPool::new()));
-
-    let spreader_type = config::SPREADER;
-    let using_dandelion = spreader_type == Spreader::Dandelion || spreader_type == Spreader::DandelionPlus;
-    // start the p2p server
-    let (server_ctx, server, spreader_ctx) = server::new(p2p_addr, msg_tx, spreader_type, mempool.clone()).unwrap();
-    server_ctx.start().unwrap();
-    spreader_ctx.start();
-
-    // start the worker
+    let blockchain = Arc::new(OrderedMutex::new(Blockchain::new()));
+    // create mempool, restoring unconfirmed transactions saved by a previous run
+    let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+    let state = blockchain.lock().unwrap().tip_block_state();
+    mempool.lock().unwrap().set_tip_state(state.clone());
+    {
+        // lock times are checked against the next block from the start, not only after the first new tip
+        let blockchain = blockchain.lock().unwrap();
+        mempool.lock().unwrap().update_tip(blockchain.get_block_count(), blockchain.tip_header().timestamp);
+    }
+    if let Some(path) = matches.value_of("mempool_file") {
+        if std::path::Path::new(path).exists() {
+            match mempool.lock().unwrap().load_from(path, &state) {
+                Ok(n) => info!("Loaded {} transactions into mempool from {}", n, path),
+                Err(e) => error!("Error loading mempool from {}: {}", path, e),
+            }
+        }
+        let path = path.to_string();
+        let mempool = mempool.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_millis(config::MEMPOOL_SAVE_INTERVAL));
+            if let Err(e) = mempool.lock().unwrap().save_to(&path) {
+                error!("Error saving mempool to {}: {}", path, e);
+            }
+        });
+    }
+
+    // parse the number of p2p workers
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -92,66 +118,101 @@ fn run_regular_server(matches: ArgMatches) {
             process::exit(1);
         });
 
-    // create user account
-    let port = p2p_addr.port();
+    // create user account and wire up the node
     let key_pair = Arc::new(key_pair::random());
-    let account  = Arc::new(Account::new(port, key_pair.clone()));
-    let addr = account.addr;
-    let pub_key = account.get_pub_key();
-    info!("Client get started: address is {:?}, {:?}", addr, &key_pair.public_key());
-
-    // start the transaction_generator
-    let (transaction_generator_ctx, transaction_generator) = transaction_generator::new(
-        server.clone(),
-        mempool.clone(),
-        blockchain.clone(),
-        peers.clone(),
-        account.clone(),
-        using_dandelion,
-    );
-    transaction_generator_ctx.start();
-
-    // start server worker
-    let worker_ctx = worker::new(
-        p2p_workers,
-        msg_rx,
-        server.clone(),
-        blockchain.clone(),
-        mempool.clone(),
-        peers.clone(),
-        account.addr,
-        pub_key.clone(),
-        port,
-    );
-    worker_ctx.start();
-
-    // start the miner
-    let (miner_ctx, miner) = miner::new(
-        server.clone(),
-        blockchain.clone(),
-        mempool.clone(),
-        key_pair.clone(),
-    );
-    miner_ctx.start();
+    let mut node = Node::new(p2p_addr, config::SPREADER, p2p_workers, queue_capacity,
+                             blockchain.clone(), mempool.clone(), key_pair.clone()).unwrap();
+    let account = node.account().clone();
+    info!("Client get started: address is {:?}, {:?}", account.addr, &key_pair.public_key());
+    if let Some(v) = matches.value_of("connect_timeout") {
+        node.set_connect_timeout(v.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing connect timeout: {}", e);
+            process::exit(1);
+        }));
+    }
+    if let Some(v) = matches.value_of("max_block_trans") {
+        node.set_max_block_trans(v.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing max block transactions: {}", e);
+            process::exit(1);
+        }));
+    }
+    if matches.is_present("log_fees") {
+        node.set_log_fees(true);
+    }
+    if let Some(v) = matches.value_of("min_peers") {
+        node.set_min_peers(v.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing min peers: {}", e);
+            process::exit(1);
+        }));
+    }
+    if let Some(v) = matches.value_of("miner_throttle") {
+        node.miner().set_throttle(v.parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing miner throttle: {}", e);
+            process::exit(1);
+        }));
+    }
+    if matches.is_present("high_bandwidth") {
+        node.set_high_bandwidth(true);
+    }
+    if let Some(v) = matches.value_of("fee_filter") {
+        node.set_fee_filter(v.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing fee filter: {}", e);
+            process::exit(1);
+        }));
+    }
 
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<SocketAddr> = known_peers.map(|x| x.parse::<SocketAddr>().unwrap()).collect();
-        helper::connect_peers(&server, &known_peers);
+    // start the stale-tip watchdog
+    let (watchdog_ctx, _watchdog) = watchdog::new(blockchain.clone(), config::STALE_TIP_TIMEOUT);
+    watchdog_ctx.start();
+
+    // connect to known peers, and to peers remembered by a previous run
+    let mut known_peers: Vec<SocketAddr> = match matches.values_of("known_peer") {
+        Some(known_peers) => known_peers.map(|x| x.parse::<SocketAddr>().unwrap()).collect(),
+        None => vec![],
+    };
+    let mut addrman = AddrMan::new(config::ADDRMAN_CAPACITY, config::ADDR_MAX_AGE);
+    if let Some(path) = matches.value_of("peers_file") {
+        if std::path::Path::new(path).exists() {
+            match addrman.load_from(path) {
+                Ok(n) => info!("Loaded {} peer addresses from {}", n, path),
+                Err(e) => error!("Error loading peer addresses from {}: {}", path, e),
+            }
+        }
+        for peer_addr in addrman.addrs() {
+            if !known_peers.contains(&peer_addr) {
+                known_peers.push(peer_addr);
+            }
+        }
+    }
+    node.set_known_peers(known_peers);
+
+    // start the node
+    let connected = node.start().unwrap_or_else(|e| {
+        error!("Error starting node: {}", e);
+        process::exit(1);
+    });
+    for peer_addr in connected {
+        addrman.record(peer_addr);
+    }
+    if let Some(path) = matches.value_of("peers_file") {
+        if let Err(e) = addrman.save_to(path) {
+            error!("Error saving peer addresses to {}: {}", path, e);
+        }
     }
 
     thread::sleep(time::Duration::from_millis(200));
     // introduce myself to network_peers
-    server.broadcast(Message::Introduce((addr, pub_key, port)), None);
+    node.server().broadcast(Message::Introduce((account.addr, account.get_pub_key(), account.port)), None);
 
     // start the API server
     ApiServer::start(
         api_addr,
-        miner.clone(),
-        transaction_generator.clone(),
+        node.miner().clone(),
+        node.transaction_generator().clone(),
+        node.handle(),
         blockchain.clone(),
         mempool.clone(),
-        peers.clone(),
+        node.peers().clone(),
     );
 
     loop {
@@ -209,11 +270,11 @@ fn run_supernode(matches: ArgMatches) {
     }
 
     let peers = Arc::new(Mutex::new(Peers::new()));
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    let mempool = Arc::new(Mutex::new(MemPool::new()));
+    let blockchain = Arc::new(OrderedMutex::new(Blockchain::new()));
+    let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
 
     for addr in nodes_addr.iter() {
-        let (msg_tx, msg_rx) = channel::unbounded();
+        let (msg_tx, msg_rx) = channel::bounded(config::WORKER_QUEUE_CAPACITY);
 
         let key_pair = Arc::new(key_pair::random());
         let account  = Arc::new(Account::new(addr.port(), key_pair.clone()));
@@ -268,6 +329,7 @@ fn run_supernode(matches: ArgMatches) {
         api_addr,
         miner.clone(),  // Fake
         transaction_generator.clone(),  //Fake
+        node::new(server.clone(), blockchain.clone(), mempool.clone(), false),  // Fake
         blockchain.clone(),  // Fake
         mempool.clone(),
         peers.clone(),
@@ -287,7 +349,17 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg connect_timeout: --("connect-timeout") [MS] "Sets the time(ms) to wait for connecting to a peer")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg p2p_queue: --("p2p-queue") [INT] "Sets the number of received messages queued for P2P workers")
+     (@arg mempool_file: --("mempool-file") [FILE] "Sets the file mempool is periodically saved to and restored from at start")
+     (@arg peers_file: --("peers-file") [FILE] "Sets the file connected peer addresses are saved to and reconnected from at start")
+     (@arg max_block_trans: --("max-block-trans") [INT] "Sets the max number of transactions in a mined block")
+     (@arg log_fees: --("log-fees") "Logs the total fee of every block template the miner builds")
+     (@arg min_peers: --("min-peers") [INT] "Sets the number of connections needed before the miner starts")
+     (@arg miner_throttle: --("miner-throttle") [PERCENT] "Caps the miner at roughly this percent of a core, 100 for no throttle")
+     (@arg fee_filter: --("fee-filter") [INT] "Asks peers not to announce transactions paying less than this fee per byte")
+     (@arg high_bandwidth: --("high-bandwidth") "Asks peers to push new blocks instead of announcing them")
      (@arg supernode: --supernode "Run as a super node")
      (@arg probe: -p --probe [INT] default_value("2") "Number of connect to each regular server for supernode")
     )
@@ -296,6 +368,11 @@ fn main() {
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
+    if let Err(e) = config::validate_config() {
+        error!("Invalid consensus constants in config: {:?}", e);
+        process::exit(1);
+    }
+
     if matches.is_present("supernode") {
         run_supernode(matches);
     } else {
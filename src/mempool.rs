@@ -1,20 +1,70 @@
-use crate::crypto::hash::H256;
+use crate::crypto::hash::{H160, H256};
 use crate::transaction::{SignedTransaction, TxInput};
-use crate::block::Content;
-use crate::config::POOL_SIZE_LIMIT;
+use crate::block::{Content, State};
+use crate::config::{POOL_SIZE_LIMIT, MAX_MEMPOOL_BYTES, MAX_BLOCK_BYTES, MAX_TX_PER_ADDRESS, INCREMENTAL_RELAY_FEE,
+                    NON_FINAL_TTL};
 use crate::helper;
+use crate::events::{Event, EventBus};
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
 use std::net::SocketAddr;
+use std::path::Path;
+use std::time::SystemTime;
 use log::debug;
+use crossbeam::channel::Receiver;
 use ring::signature::Ed25519KeyPair;
-use crate::helper::generate_signed_coinbase_transaction;
+use crate::helper::generate_signed_coinbase_transaction_with_commitment;
+
+// Custom acceptance policy, returns false to reject a transaction
+pub type Policy = Box<dyn Fn(&SignedTransaction) -> bool + Send>;
+
+// Why a transaction was not accepted into mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    AlreadyExists,
+    InvalidSignature,
+    PoolFull,  // mempool already holds its maximum number of transactions
+    NonStandard,  // dust output or absurd fee rate, see SignedTransaction::is_standard
+    PolicyRejected,
+    DoubleSpend,  // spends an input of a pool or held transaction it can't replace
+    FeeTooLow,  // mempool is over its byte budget and the fee rate is the lowest, evicted right away
+    TooManyFromSender,  // the sender address already has MAX_TX_PER_ADDRESS transactions in mempool
+    InputsNotCovered,  // inputs unknown at tip and in mempool, not owned by the signer or worth less than outputs and fee
+}
+
+// Where an accepted transaction is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accepted {
+    Pooled,  // in the pool, may be in the next block
+    Held,  // lock time not reached by the next block, held until it is, see MemPool::update_tip
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+}
 
 pub struct MemPool {
     pub transactions: HashMap<H256, SignedTransaction>,
     pub input_tran_map: HashMap<TxInput, (H256, u64)>, //Key: TxInput, Val: (hash, timestamp)
     pub ts_addr_map: HashMap<H256, Vec<(SocketAddr, i64)>>,
     dandelion_buffer: HashMap<H256, SignedTransaction>,
+    events: EventBus,
+    bytes_used: usize,  // total serialized size of transactions, held ones included
+    max_bytes: usize,
+    max_count: usize,
+    sender_counts: HashMap<H160, usize>,  // number of pool transactions of each sender address
+    max_per_sender: usize,
+    policy: Option<Policy>,  // None accepts all
+    reorged: HashSet<H256>,  // transactions put back after a reorg took their block out of longest-chain
+    non_final: HashMap<H256, (SignedTransaction, u64)>,  // transactions held until their lock time is reached,
+                                                         // with the time(ms) they were held at
+    non_final_inputs: HashMap<TxInput, H256>,  // inputs spent by held transactions
+    non_final_ttl: u64,  // time(ms) after which a held transaction is dropped
+    tip_state: Option<State>,  // UTXO set of longest-chain tip new transactions are checked against, see set_tip_state
+    lock_point: (usize, u64),  // height & timestamp(ms) a transaction must be final at: the next block's, tip's time
 }
 
 impl MemPool {
@@ -25,6 +75,19 @@ impl MemPool {
             input_tran_map: HashMap::new(),
             ts_addr_map: HashMap::new(),
             dandelion_buffer: HashMap::new(),
+            events: EventBus::new(),
+            bytes_used: 0,
+            max_bytes: MAX_MEMPOOL_BYTES,
+            max_count: POOL_SIZE_LIMIT,
+            sender_counts: HashMap::new(),
+            max_per_sender: MAX_TX_PER_ADDRESS,
+            policy: None,
+            reorged: HashSet::new(),
+            non_final: HashMap::new(),
+            non_final_inputs: HashMap::new(),
+            non_final_ttl: NON_FINAL_TTL,
+            tip_state: None,
+            lock_point: (1, 0),
         }
     }
 
@@ -37,12 +100,289 @@ impl MemPool {
         return mempool;
     }
 
-    // Add a valid transaction after signature check && double-spend txinput check
+    // Add a valid & standard transaction after signature check && double-spend txinput check
+    // Return whether it is kept, held ones included
     pub fn add_with_check(&mut self, tran: &SignedTransaction) -> bool {
-        if self.exist(&tran.hash) || !tran.sign_check() || self.size() >= POOL_SIZE_LIMIT {
-            return false;
+        self.accept(tran).is_ok()
+    }
+
+    // Same as add_with_check, but tell whether a transaction is held or why it is rejected
+    pub fn accept(&mut self, tran: &SignedTransaction) -> Result<Accepted, TransactionError> {
+        if self.exist(&tran.hash) {
+            return Err(TransactionError::AlreadyExists);
+        }
+        if !tran.sign_check() {
+            return Err(TransactionError::InvalidSignature);
+        }
+        if self.size() >= self.max_count {
+            return Err(TransactionError::PoolFull);
+        }
+        if !tran.is_standard() {
+            debug!("Transaction {:?} is non-standard, not relayed", tran.hash);
+            return Err(TransactionError::NonStandard);
+        }
+        if let Some(policy) = &self.policy {
+            if !policy(tran) {
+                debug!("Transaction {:?} is rejected by mempool policy", tran.hash);
+                return Err(TransactionError::PolicyRejected);
+            }
+        }
+        if !self.inputs_cover(tran) {
+            debug!("Inputs of transaction {:?} don't cover its outputs and fee {}", tran.hash, tran.transaction.fee);
+            return Err(TransactionError::InputsNotCovered);
+        }
+        if self.sender_at_cap(tran) {
+            debug!("Sender of transaction {:?} has {} transactions in mempool", tran.hash, self.max_per_sender);
+            return Err(TransactionError::TooManyFromSender);
+        }
+        let (height, timestamp) = self.lock_point;
+        if !tran.transaction.is_final(height, timestamp) {
+            return self.hold(tran);
+        }
+        if !self.try_insert(tran) {
+            return Err(TransactionError::DoubleSpend);
+        }
+        self.evict_over_budget();
+        if !self.exist(&tran.hash) {
+            return Err(TransactionError::FeeTooLow);
+        }
+        return Ok(Accepted::Pooled);
+    }
+
+    // Write all transactions to a file, return the number of transactions written
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let trans: Vec<&SignedTransaction> = self.transactions.values().collect();
+        let writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(writer, &trans)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(trans.len())
+    }
+
+    // Read transactions saved by save_to, dropping those whose inputs are no longer unspent in state
+    // Return the number of transactions put into mempool
+    pub fn load_from<P: AsRef<Path>>(&mut self, path: P, state: &State) -> io::Result<usize> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut trans: Vec<SignedTransaction> = bincode::deserialize_from(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        trans.sort_by_key(|t| t.transaction.ts);  // older one wins on conflict, as when first received
+        let mut loaded = 0;
+        for tran in trans.iter() {
+            if !Self::spendable(tran, state) {
+                debug!("Drop stale transaction {:?} from saved mempool", tran.hash);
+                continue;
+            }
+            if self.add_with_check(tran) {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    // Put back transactions of blocks a reorg took out of longest-chain (e.g. Blockchain::disconnected_trans),
+    // dropping those whose inputs are no longer unspent in state of the new tip
+    // They keep their original fee and are marked, so create_content prefers them among equal fee rates
+    // Return the number of transactions put back
+    pub fn apply_reorg(&mut self, trans: &Vec<SignedTransaction>, state: &State) -> usize {
+        let mut readded = 0;
+        for tran in trans.iter() {
+            if !Self::spendable(tran, state) {
+                debug!("Drop transaction {:?} of disconnected block, its inputs are spent", tran.hash);
+                continue;
+            }
+            if self.add_with_check(tran) {
+                self.reorged.insert(tran.hash);
+                readded += 1;
+            }
+        }
+        readded
+    }
+
+    // Follow a new tip of longest-chain at a given height & timestamp(ms): held transactions whose lock time
+    // the next block reaches enter the pool, pool transactions it no longer reaches (after a reorg) are held.
+    // Return the number of transactions that entered the pool
+    pub fn update_tip(&mut self, height: usize, timestamp: u64) -> usize {
+        self.lock_point = (height + 1, timestamp);
+        let (height, timestamp) = self.lock_point;
+        let locked: Vec<H256> = self.transactions.values()
+            .filter(|t| !t.transaction.is_final(height, timestamp))
+            .map(|t| t.hash)
+            .collect();
+        for hash in locked.iter() {
+            if let Some(tran) = self.remove_tran_internel(hash) {
+                self.insert_non_final(tran);
+            }
+        }
+        self.expire_non_final();
+        let due: Vec<H256> = self.non_final.values()
+            .filter(|(t, _)| t.transaction.is_final(height, timestamp))
+            .map(|(t, _)| t.hash)
+            .collect();
+        let mut entered = 0;
+        for hash in due.iter() {
+            if let Some(tran) = self.remove_non_final(hash) {
+                if self.add_with_check(&tran) {
+                    entered += 1;
+                }
+            }
+        }
+        entered
+    }
+
+    // Whether a transaction is held until its lock time
+    pub fn is_non_final(&self, hash: &H256) -> bool {
+        self.non_final.contains_key(hash)
+    }
+
+    // Hold a transaction not final at the next block, with the checks it would pass entering the pool now:
+    // no conflict it can't replace, pool or held, and a fee rate high enough to fit in the byte budget
+    fn hold(&mut self, tran: &SignedTransaction) -> Result<Accepted, TransactionError> {
+        self.expire_non_final();
+        if self.non_final.len() >= self.max_count {
+            return Err(TransactionError::PoolFull);
+        }
+        let held_conflict = tran.transaction.inputs.iter().any(|input| self.non_final_inputs.contains_key(input));
+        if held_conflict || self.conflicts_to_replace(tran).is_none() {
+            return Err(TransactionError::DoubleSpend);
+        }
+        self.insert_non_final(tran.clone());
+        self.evict_over_budget();
+        if !self.is_non_final(&tran.hash) {
+            return Err(TransactionError::FeeTooLow);
         }
-        return self.try_insert(tran);
+        debug!("Transaction {:?} is held until its lock time {:?}", tran.hash, tran.transaction.lock_time);
+        Ok(Accepted::Held)
+    }
+
+    fn insert_non_final(&mut self, tran: SignedTransaction) {
+        for input in tran.transaction.inputs.iter() {
+            self.non_final_inputs.insert(input.clone(), tran.hash);
+        }
+        self.bytes_used += tran.size();
+        self.non_final.insert(tran.hash, (tran, now_ms()));
+    }
+
+    fn remove_non_final(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let (tran, _) = self.non_final.remove(hash)?;
+        self.bytes_used -= tran.size();
+        for input in tran.transaction.inputs.iter() {
+            if self.non_final_inputs.get(input) == Some(hash) {
+                self.non_final_inputs.remove(input);
+            }
+        }
+        Some(tran)
+    }
+
+    // Drop transactions held for longer than non_final_ttl, so lock times far ahead can't pin memory
+    fn expire_non_final(&mut self) {
+        let now = now_ms();
+        let ttl = self.non_final_ttl;
+        let expired: Vec<H256> = self.non_final.iter()
+            .filter(|(_, (_, since))| now.saturating_sub(*since) > ttl)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            debug!("Drop transaction {:?}, held for its lock time longer than {} ms", hash, ttl);
+            self.remove_non_final(hash);
+            self.events.publish(Event::TxEvicted(*hash));
+        }
+    }
+
+    // Whether a transaction was put back by apply_reorg
+    pub fn is_reorged(&self, hash: &H256) -> bool {
+        self.reorged.contains(hash)
+    }
+
+    // All inputs of a transaction are unspent in state and owned by its signer
+    fn spendable(tran: &SignedTransaction, state: &State) -> bool {
+        tran.transaction.inputs.iter().all(|input| {
+            match state.lock_of(&(input.pre_hash, input.index)) {
+                Some(lock) => lock.unlocked_by(tran),
+                None => false,
+            }
+        })
+    }
+
+    // Check new transactions against the UTXO set of a new tip of longest-chain, see inputs_cover
+    pub fn set_tip_state(&mut self, state: State) {
+        self.tip_state = Some(state);
+    }
+
+    // Whether the inputs of a transaction are worth at least its outputs plus the fee it declares, so that a block
+    // can include it. Without a tip state set, the declared fee is trusted
+    fn inputs_cover(&self, tran: &SignedTransaction) -> bool {
+        self.tip_state.is_none() || self.verified_fee(tran).is_some()
+    }
+
+    // Fee of a transaction if its inputs are known to pay it, None without a tip state
+    fn verified_fee(&self, tran: &SignedTransaction) -> Option<u64> {
+        let output_sum: u128 = tran.transaction.outputs.iter().map(|o| o.val as u128).sum();
+        if output_sum + tran.transaction.fee as u128 <= self.input_value(tran)? {
+            Some(tran.transaction.fee)
+        } else {
+            None
+        }
+    }
+
+    // Value of the inputs of a transaction, unspent at tip or outputs of pool transactions, None if one is unknown
+    // or not owned by its signer, or without a tip state
+    fn input_value(&self, tran: &SignedTransaction) -> Option<u128> {
+        let state = self.tip_state.as_ref()?;
+        let mut input_sum = 0u128;
+        for input in tran.transaction.inputs.iter() {
+            let key = (input.pre_hash, input.index);
+            let (val, lock) = match state.get(&key) {
+                Some((val, _)) => (*val, state.lock_of(&key)?),
+                None => match self.transactions.get(&input.pre_hash)
+                    .and_then(|t| t.transaction.outputs.get(input.index as usize)) {
+                    Some(output) => (output.val, output.lock()),
+                    None => return None,
+                },
+            };
+            if !lock.unlocked_by(tran) {
+                return None;
+            }
+            input_sum += val as u128;
+        }
+        Some(input_sum)
+    }
+
+    // Install a policy consulted for every new transaction, None goes back to accepting all
+    pub fn set_policy(&mut self, policy: Option<Policy>) {
+        self.policy = policy;
+    }
+
+    // Evict transactions with the lowest fee rate (fee / vsize) until mempool fits in its byte budget
+    fn evict_over_budget(&mut self) {
+        let (max_count, max_bytes) = (self.max_count, self.max_bytes);
+        self.trim_to(max_count, max_bytes);
+    }
+
+    // Evict the lowest fee rate transactions until at most max_count of them using at most max_bytes are left,
+    // e.g. after the limits are lowered; return the number evicted
+    pub fn trim_to(&mut self, max_count: usize, max_bytes: usize) -> usize {
+        let mut evicted = 0;
+        while self.transactions.len() > max_count || self.bytes_used > max_bytes {
+            // held transactions count in bytes only; newer one goes first on tie, then the greater hash,
+            // the reverse of create_content
+            let over_bytes = self.bytes_used > max_bytes;
+            let held = self.non_final.values().filter(|_| over_bytes).map(|(t, _)| t);
+            let lowest = self.transactions.values().chain(held)
+                .min_by(|x, y| x.cmp_fee_rate(y).then(y.transaction.ts.cmp(&x.transaction.ts))
+                    .then(y.hash.cmp(&x.hash)))
+                .map(|t| t.hash.clone());
+            match lowest {
+                Some(hash) => {
+                    debug!("Mempool is over {} transactions or {} bytes, evict {:?}", max_count, max_bytes, hash);
+                    if self.remove_non_final(&hash).is_none() {
+                        self.remove_tran_internel(&hash);
+                    }
+                    self.events.publish(Event::TxEvicted(hash));
+                    evicted += 1;
+                }
+                None => break,
+            }
+        }
+        evicted
     }
 
     pub fn insert_buffer_tran(&mut self, tran: SignedTransaction) {
@@ -59,36 +399,95 @@ impl MemPool {
     }
 
     // try insert transaction if no conflict input
-    // or the transaction has the minimal timestamp among conflict trans
+    // or the transaction has the minimal timestamp among conflict trans that opted in to be replaced
     fn try_insert(&mut self, tran: &SignedTransaction) -> bool {
         debug!("Try to add {:?} into mempool", tran);
+        self.remove_buffered_tran(&tran.hash);
+        let to_remove_hash = match self.conflicts_to_replace(tran) {
+            Some(conflicts) => conflicts,
+            None => return false, // conflict and has bigger timestamp & doesn't replace by fee
+        };
+        // remove conflict trans
+        for conf_hash in to_remove_hash.iter() {
+            if self.remove_tran_internel(conf_hash).is_some() {
+                self.events.publish(Event::TxReplaced { old: *conf_hash, new: tran.hash });
+            }
+        }
+
+        for input in tran.transaction.inputs.iter() {
+            self.input_tran_map.insert(input.clone(), (tran.hash, tran.transaction.ts));
+        }
+        self.bytes_used += tran.size();
+        *self.sender_counts.entry(tran.sender_addr()).or_insert(0) += 1;
+        self.transactions.insert(tran.hash, tran.clone());
+        self.events.publish(Event::TxAdded(tran.hash));
+        true
+    }
+
+    // Pool transactions conflicting with tran that it replaces, None if it may not replace all of them
+    fn conflicts_to_replace(&self, tran: &SignedTransaction) -> Option<Vec<H256>> {
         let mut to_remove_hash: Vec<H256> = Vec::new();
+        let mut by_fee: Vec<H256> = Vec::new();
         let ts = tran.transaction.ts;
-        self.remove_buffered_tran(&tran.hash);
+        // all conflicts are collected before any is removed, tran replaces either all of them or none
         for input in tran.transaction.inputs.iter() {
             if let Some((conf_hash, conf_ts)) = self.input_tran_map.get(input) {
-                if ts < *conf_ts {
-                    to_remove_hash.push(conf_hash.clone());
-                } else {
-                    return false; // conflict and has bigger timestamp
+                if to_remove_hash.contains(conf_hash) {
+                    continue;
+                }
+                // an earlier timestamp only wins against a replaceable conflict, pays_more_than rejects the others
+                let replaceable = self.transactions.get(conf_hash).map_or(false, |c| c.transaction.replaceable);
+                if ts >= *conf_ts || !replaceable {
+                    by_fee.push(conf_hash.clone());
                 }
+                to_remove_hash.push(conf_hash.clone());
             }
         }
-        // remove conflict trans
-        for conf_hash in to_remove_hash.iter() {
-            self.remove_tran_internel(conf_hash);
+        if !by_fee.is_empty() && !self.pays_more_than(tran, &by_fee) {
+            return None;
         }
+        Some(to_remove_hash)
+    }
 
-        for input in tran.transaction.inputs.iter() {
-            self.input_tran_map.insert(input.clone(), (tran.hash, ts));
+    // Whether the sender of tran already has max_per_sender pool transactions, not counting the ones tran replaces
+    fn sender_at_cap(&self, tran: &SignedTransaction) -> bool {
+        let sender = tran.sender_addr();
+        let count = match self.sender_counts.get(&sender) {
+            Some(count) => *count,
+            None => return false,
+        };
+        let replaced: HashSet<&H256> = tran.transaction.inputs.iter()
+            .filter_map(|input| self.input_tran_map.get(input))
+            .map(|(hash, _)| hash)
+            .filter(|hash| self.transactions.get(hash).map_or(false, |t| t.sender_addr() == sender))
+            .collect();
+        count.saturating_sub(replaced.len()) >= self.max_per_sender
+    }
+
+    // Whether tran may replace conflicting pool transactions: each must have opted in and have a lower fee rate,
+    // and tran must pay their total fee plus INCREMENTAL_RELAY_FEE. Only fees the inputs are known to pay count,
+    // a declared one could be more than the inputs leave
+    fn pays_more_than(&self, tran: &SignedTransaction, conflicts: &[H256]) -> bool {
+        let fee = match self.verified_fee(tran) {
+            Some(fee) => fee,
+            None => return false,
+        };
+        let mut total_fee: u64 = 0;
+        for conf_hash in conflicts.iter() {
+            match self.transactions.get(conf_hash) {
+                Some(conf) if conf.transaction.replaceable && tran.cmp_fee_rate(conf) == Ordering::Greater => {
+                    total_fee = total_fee.saturating_add(self.verified_fee(conf).unwrap_or(0));
+                }
+                _ => return false,
+            }
         }
-        self.transactions.insert(tran.hash.clone(), tran.clone());
-        return true;
+        fee >= total_fee.saturating_add(INCREMENTAL_RELAY_FEE)
     }
 
     // Remove transactions from pool
     pub fn remove_trans(&mut self, trans: &Vec<H256>) {
         for hash in trans.iter() {
+            self.remove_non_final(hash);
             if let Some(_) = self.transactions.get(&hash) {
                 self.remove_tran_internel(&hash);
             } else {
@@ -100,9 +499,41 @@ impl MemPool {
         }
     }
 
-    fn remove_tran_internel(&mut self, hash: &H256) {
-        self.transactions.remove(hash);
+    // Remove transactions invalidated by a block, pooled or held, e.g. found by Blockchain::mempool_conflicts
+    pub fn remove_conflicts(&mut self, hashes: &Vec<H256>) {
+        for hash in hashes.iter() {
+            if self.remove_tran_internel(hash).or_else(|| self.remove_non_final(hash)).is_some() {
+                debug!("Remove conflicting transaction from mempool {:?}", hash);
+                self.events.publish(Event::TxEvicted(hash.clone()));
+            }
+        }
+    }
+
+    fn remove_tran_internel(&mut self, hash: &H256) -> Option<SignedTransaction> {
         self.dandelion_buffer.remove(hash);
+        self.reorged.remove(hash);
+        let tran = self.transactions.remove(hash)?;
+        self.bytes_used -= tran.size();
+        let sender = tran.sender_addr();
+        if let Some(count) = self.sender_counts.get_mut(&sender) {
+            *count -= 1;
+            if *count == 0 {
+                self.sender_counts.remove(&sender);
+            }
+        }
+        for input in tran.transaction.inputs.iter() {
+            if let Some((h, _)) = self.input_tran_map.get(input) {
+                if h == hash {
+                    self.input_tran_map.remove(input);
+                }
+            }
+        }
+        Some(tran)
+    }
+
+    // Receive eviction/replacement events of this mempool
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        self.events.subscribe()
     }
 
     pub fn contains_buffered_tran(&self, hash: &H256) -> bool {
@@ -120,23 +551,109 @@ impl MemPool {
             for input in inputs.iter() {
                 if let Some((tx_hash,_)) = self.input_tran_map.remove(input) {
                     debug!("Remove conflicting input from mempool {:?}", input);
-                    self.remove_tran_internel(&tx_hash);
+                    if tx_hash != trans.hash && self.remove_tran_internel(&tx_hash).is_some() {
+                        self.events.publish(Event::TxEvicted(tx_hash));
+                    }
                 }
             }
         }
     }
 
-    // Create content for miner's block to include as many transactions as possible
-    pub fn create_content(&self, key_pair: &Ed25519KeyPair) -> Content {
-        let mut trans = Vec::<SignedTransaction>::new();
+    // Create content for miner's block with at most max_trans transactions besides coinbase,
+    // highest fee rate first (transactions put back by a reorg first among equals) and within MAX_BLOCK_BYTES.
+    // Remaining ties go by timestamp then hash, never by arrival, so nodes with the same mempool build the same block
+    // Coinbase claims the block subsidy plus fees of included transactions, and commits to their auxiliary root
+    pub fn create_content(&self, key_pair: &Ed25519KeyPair, payout: &H160, subsidy: u64, max_trans: usize) -> Content {
+        self.create_content_within(key_pair, payout, subsidy, max_trans, MAX_BLOCK_BYTES)
+    }
+
+    // Same as create_content within max_bytes instead of MAX_BLOCK_BYTES. Under contention the fee rate order
+    // may leave fees behind, e.g. small transactions displacing a big one paying more in total, so transactions
+    // are also picked by fee and whichever selection pays more is kept (the fee rate one on tie). Either way
+    // a transaction is picked only after its pooled parents, and the block keeps them in block_order
+    pub fn create_content_within(&self, key_pair: &Ed25519KeyPair, payout: &H160, subsidy: u64, max_trans: usize,
+                                 max_bytes: usize) -> Content {
+        let selected = self.select_transactions(max_trans, max_bytes);
+        let fees: u64 = selected.iter().map(|t| t.transaction.fee).sum();
 
-        let coinbase_trans = generate_signed_coinbase_transaction(key_pair);
+        let mut trans = Vec::<SignedTransaction>::new();
+        let commitment = Content::aux_root_of(&selected);
+        let coinbase_trans = generate_signed_coinbase_transaction_with_commitment(key_pair, payout, subsidy + fees, &commitment);
         trans.push(coinbase_trans);
+        trans.extend(selected);
+        Content::new_with_trans(&trans)
+    }
+
+    // Transactions create_content_within puts into a block besides coinbase, in block order
+    pub fn select_transactions(&self, max_trans: usize, max_bytes: usize) -> Vec<SignedTransaction> {
+        let ordered = self.block_order();
+        let by_rate = self.select(&ordered, max_trans, max_bytes);
+        // without contention nothing is left behind to pick by fee instead
+        let chosen = if by_rate.len() == ordered.len() {
+            by_rate
+        } else {
+            let mut by_fee = ordered.clone();
+            by_fee.sort_by(|x, y| y.transaction.fee.cmp(&x.transaction.fee));  // stable, ties stay in block order
+            let by_fee = self.select(&by_fee, max_trans, max_bytes);
+            let fees_of = |hashes: &HashSet<H256>| -> u64 {
+                ordered.iter().filter(|t| hashes.contains(&t.hash)).map(|t| t.transaction.fee).sum()
+            };
+            if fees_of(&by_fee) > fees_of(&by_rate) { by_fee } else { by_rate }
+        };
+        ordered.iter()
+            .filter(|t| chosen.contains(&t.hash))
+            .map(|t| (*t).clone())
+            .collect()
+    }
 
-        for (_, tran) in self.transactions.iter() {
-            trans.push(tran.clone());
+    // Pool transactions by fee rate, each moved right after its pooled ancestors so a block can spend them in order
+    fn block_order(&self) -> Vec<&SignedTransaction> {
+        let mut by_rate: Vec<&SignedTransaction> = self.transactions.values().collect();
+        by_rate.sort_by(|x, y| y.cmp_fee_rate(x)
+            .then(self.is_reorged(&y.hash).cmp(&self.is_reorged(&x.hash)))
+            .then(x.transaction.ts.cmp(&y.transaction.ts))
+            .then(x.hash.cmp(&y.hash)));
+        let mut placed = HashSet::new();
+        let mut ordered = Vec::with_capacity(by_rate.len());
+        for tran in by_rate.into_iter() {
+            self.place_after_parents(tran, &mut placed, &mut ordered);
         }
-        Content::new_with_trans(&trans)
+        ordered
+    }
+
+    // Push tran to ordered unless placed already, after pushing its pooled parents the same way
+    fn place_after_parents<'a>(&'a self, tran: &'a SignedTransaction, placed: &mut HashSet<H256>,
+                               ordered: &mut Vec<&'a SignedTransaction>) {
+        if !placed.insert(tran.hash.clone()) {
+            return;
+        }
+        for input in tran.transaction.inputs.iter() {
+            if let Some(parent) = self.transactions.get(&input.pre_hash) {
+                self.place_after_parents(parent, placed, ordered);
+            }
+        }
+        ordered.push(tran);
+    }
+
+    // Take transactions in the given order, skipping those that don't fit into max_bytes
+    // or whose pooled parents weren't taken before them
+    fn select(&self, ordered: &[&SignedTransaction], max_trans: usize, max_bytes: usize) -> HashSet<H256> {
+        let mut selected = HashSet::new();
+        let mut bytes = 0;
+        for tran in ordered.iter() {
+            if selected.len() >= max_trans {
+                break;
+            }
+            if bytes + tran.size() > max_bytes {
+                continue;
+            }
+            if tran.transaction.inputs.iter().any(|i| self.exist(&i.pre_hash) && !selected.contains(&i.pre_hash)) {
+                continue;
+            }
+            bytes += tran.size();
+            selected.insert(tran.hash.clone());
+        }
+        selected
     }
 
     // check existence of a hash
@@ -144,6 +661,90 @@ impl MemPool {
         self.transactions.contains_key(hash)
     }
 
+    // Hash of the pooled transaction spending an outpoint
+    pub fn spender_of(&self, input: &TxInput) -> Option<H256> {
+        self.input_tran_map.get(input).map(|(hash, _)| hash.clone())
+    }
+
+    // Hash of the transaction held for its lock time spending an outpoint
+    pub fn held_spender_of(&self, input: &TxInput) -> Option<H256> {
+        self.non_final_inputs.get(input).cloned()
+    }
+
+    // Pooled transactions a transaction spends from, directly or transitively, sorted
+    pub fn ancestors(&self, hash: &H256) -> Vec<H256> {
+        self.walk(hash, |h| match self.transactions.get(h) {
+            Some(tran) => tran.transaction.inputs.iter()
+                .map(|input| input.pre_hash.clone())
+                .filter(|parent| self.exist(parent))
+                .collect(),
+            None => vec![],
+        })
+    }
+
+    // Pooled transactions spending from a transaction, directly or transitively, sorted
+    pub fn descendants(&self, hash: &H256) -> Vec<H256> {
+        self.walk(hash, |h| self.input_tran_map.iter()
+            .filter(|(input, (child, _))| input.pre_hash == *h && self.exist(child))
+            .map(|(_, (child, _))| child.clone())
+            .collect())
+    }
+
+    // Transactions reachable from hash through next, each visited once so a cycle can't hang
+    fn walk<F: Fn(&H256) -> Vec<H256>>(&self, hash: &H256, next: F) -> Vec<H256> {
+        let mut visited: HashSet<H256> = HashSet::new();
+        visited.insert(hash.clone());
+        let mut stack = next(hash);
+        let mut found = vec![];
+        while let Some(h) = stack.pop() {
+            if visited.insert(h.clone()) {
+                stack.extend(next(&h));
+                found.push(h);
+            }
+        }
+        found.sort();
+        found
+    }
+
+    // Hashes of at most limit transactions, highest fee rate first, ties by timestamp then hash
+    pub fn hashes(&self, limit: usize) -> Vec<H256> {
+        self.top_by_fee_rate(limit).into_iter().map(|t| t.hash.clone()).collect()
+    }
+
+    // Hashes and fee rates(fee per byte) of the n transactions of highest fee rate, in the same order as hashes,
+    // e.g. to preview a block template or estimate fees
+    pub fn top_n_by_feerate(&self, n: usize) -> Vec<(H256, f64)> {
+        self.top_by_fee_rate(n).into_iter()
+            .map(|t| (t.hash.clone(), t.transaction.fee as f64 / t.vsize() as f64))
+            .collect()
+    }
+
+    // At most n transactions, highest fee rate first. Only those n are sorted, the rest of the pool is
+    // partitioned off by a linear-time selection
+    fn top_by_fee_rate(&self, n: usize) -> Vec<&SignedTransaction> {
+        if n == 0 {
+            return vec![];
+        }
+        let order = |x: &&SignedTransaction, y: &&SignedTransaction| y.cmp_fee_rate(x)
+            .then(x.transaction.ts.cmp(&y.transaction.ts))
+            .then(x.hash.cmp(&y.hash));
+        let mut trans: Vec<&SignedTransaction> = self.transactions.values().collect();
+        if n < trans.len() {
+            trans.select_nth_unstable_by(n - 1, order);
+            trans.truncate(n);
+        }
+        trans.sort_by(order);
+        trans
+    }
+
+    // Keep the hashes of pool transactions paying at least min_fee_rate(fee per byte), e.g. to honor a peer's
+    // fee filter; hashes not in pool are dropped
+    pub fn filter_fee_rate(&self, hashes: Vec<H256>, min_fee_rate: u64) -> Vec<H256> {
+        hashes.into_iter()
+            .filter(|h| self.transactions.get(h).map_or(false, |t| t.pays_fee_rate(min_fee_rate)))
+            .collect()
+    }
+
     // Given hashes, get transactions from mempool
     pub fn get_trans(&self, hashes: &Vec<H256>) -> Vec<SignedTransaction> {
         let mut trans = Vec::<SignedTransaction>::new();
@@ -160,6 +761,31 @@ impl MemPool {
         self.transactions.len()
     }
 
+    // Total serialized size of available transactions and the ones held for their lock time
+    pub fn bytes_used(&self) -> usize {
+        self.bytes_used
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_max_count(&mut self, max_count: usize) {
+        self.max_count = max_count;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_max_per_sender(&mut self, max_per_sender: usize) {
+        self.max_per_sender = max_per_sender;
+    }
+
+    #[cfg(any(test, test_utilities))]
+    pub fn set_non_final_ttl(&mut self, non_final_ttl: u64) {
+        self.non_final_ttl = non_final_ttl;
+    }
+
     // Check if no transaction in pool
     pub fn empty(&self) -> bool {
         self.transactions.is_empty()
@@ -171,10 +797,15 @@ mod tests {
     use super::*;
     use crate::helper::*;
     use crate::block::{Block, Content};
+    use crate::blockchain::{Blockchain, CheckPolicy, InsertOutcome};
+    use crate::config::MAX_FEE_RATE;
     use crate::network::message::Message;
     use crate::spread::Spreader;
-    use crate::config::EASIEST_DIF;
+    use crate::config::{BLOCK_SIZE_LIMIT, EASIEST_DIF, COINBASE_REWARD, INV_FLUSH_INTERVAL};
     use crate::crypto::{key_pair, hash::Hashable};
+    use crate::transaction::{sign, LockTime, Transaction, TxOutput};
+    use ring::signature::KeyPair;
+    use std::cmp::Ordering;
     use std::net::{SocketAddr, IpAddr, Ipv4Addr};
     use std::thread::sleep;
     use std::time;
@@ -195,6 +826,277 @@ mod tests {
         assert_eq!(mempool.get_trans(&vec![t.hash(), t_2.hash()]).len(), 2);
     }
 
+    #[test]
+    fn test_policy() {
+        let mut mempool = MemPool::new();
+        let blocked = generate_random_signed_transaction();
+        let blocked_addr = blocked.sender_addr();
+        mempool.set_policy(Some(Box::new(move |t: &SignedTransaction| t.sender_addr() != blocked_addr)));
+
+        let t = generate_random_signed_transaction();
+        assert!(!mempool.add_with_check(&blocked));
+        assert!(!mempool.exist(&blocked.hash));
+        assert!(mempool.add_with_check(&t));
+
+        mempool.set_policy(None);
+        assert!(mempool.add_with_check(&blocked));
+        assert_eq!(2, mempool.size());
+    }
+
+    // Tip state where each input is an output owned by key worth enough for any fee of these tests,
+    // replacement only counts fees the inputs are known to pay
+    fn fund(mempool: &mut MemPool, key: &Ed25519KeyPair, inputs: &[TxInput]) {
+        let outpoints = inputs.iter().map(|i| (i.pre_hash, i.index)).collect();
+        mempool.set_tip_state(generate_random_state(outpoints, vec![(1000, address_of(key)); inputs.len()]));
+    }
+
+    #[test]
+    fn test_max_tx_per_address() {
+        let mut mempool = MemPool::new();
+        let key = key_pair::random();
+        let new_tran = |key: &Ed25519KeyPair, fee| generate_signed_replaceable_transaction(key,
+            vec![TxInput::new(generate_random_hash(), 0)], vec![TxOutput::new(generate_random_h160(), 10)], fee);
+        let mut accepted = vec![];
+        for _ in 0..MAX_TX_PER_ADDRESS {
+            let tran = new_tran(&key, 1);
+            assert_eq!(Ok(Accepted::Pooled), mempool.accept(&tran));
+            accepted.push(tran);
+        }
+        assert_eq!(Err(TransactionError::TooManyFromSender), mempool.accept(&new_tran(&key, 1)));
+
+        // other addresses are unaffected
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&new_tran(&key_pair::random(), 1)));
+
+        // replacing one of its own still works at the cap
+        let original = &accepted[0];
+        let later = new_tran(&key, 1);
+        fund(&mut mempool, &key, &[original.transaction.inputs[0].clone(), later.transaction.inputs[0].clone()]);
+        let bumped = generate_signed_transaction_with_fee(&key, original.transaction.inputs.clone(),
+            original.transaction.outputs.clone(), 100);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&bumped));
+        assert!(!mempool.exist(&original.hash));
+
+        // room is made once one leaves mempool
+        mempool.remove_trans(&vec![bumped.hash]);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&later));
+        assert_eq!(MAX_TX_PER_ADDRESS + 1, mempool.size());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let key = key_pair::random();
+        let addr: H160 = ring::digest::digest(&ring::digest::SHA256, key.public_key().as_ref()).into();
+        let mut state = State::new();
+        let mut mempool = MemPool::new();
+        let mut trans = vec![];
+        for i in 0..3 {
+            let input = TxInput::new(generate_random_hash(), i);
+            state.insert((input.pre_hash, input.index), (10, addr));
+            let output = TxOutput::new(generate_random_h160(), 9);
+            let tran = generate_signed_transaction_with_fee(&key, vec![input], vec![output], 1);
+            assert!(mempool.add_with_check(&tran));
+            trans.push(tran);
+        }
+        let path = std::env::temp_dir().join(format!("mempool_{}.dat", generate_random_str()));
+        assert_eq!(3, mempool.save_to(&path).unwrap());
+
+        // the second one's input got spent by a block while the node was down
+        let spent = &trans[1].transaction.inputs[0];
+        state.remove(&(spent.pre_hash, spent.index));
+        let mut reloaded = MemPool::new();
+        assert_eq!(2, reloaded.load_from(&path, &state).unwrap());
+        assert!(reloaded.exist(&trans[0].hash));
+        assert!(!reloaded.exist(&trans[1].hash));
+        assert!(reloaded.exist(&trans[2].hash));
+        assert_eq!(1, reloaded.transactions.get(&trans[2].hash).unwrap().transaction.fee);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_top_n_by_feerate() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let fees = [3, 9, 1, 7, 5, 8, 2, 6];
+        let mut trans = vec![];
+        for fee in fees.iter() {
+            let tran = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+                vec![generate_random_txoutput()], *fee);
+            assert!(mempool.add_with_check(&tran));
+            trans.push(tran);
+        }
+        // same shape, so fee rate follows fee
+        let expected = |fees: &[u64]| -> Vec<(H256, f64)> {
+            fees.iter().map(|fee| {
+                let tran = trans.iter().find(|t| t.transaction.fee == *fee).unwrap();
+                (tran.hash, *fee as f64 / tran.vsize() as f64)
+            }).collect()
+        };
+        assert_eq!(expected(&[9, 8, 7]), mempool.top_n_by_feerate(3));
+        assert_eq!(expected(&[9, 8, 7, 6, 5, 3, 2, 1]), mempool.top_n_by_feerate(100));
+        assert!(mempool.top_n_by_feerate(0).is_empty());
+        let hashes: Vec<H256> = expected(&[9, 8, 7, 6]).into_iter().map(|(h, _)| h).collect();
+        assert_eq!(hashes, mempool.hashes(4));
+    }
+
+    #[test]
+    fn test_fee_rate_order() {
+        let key = key_pair::random();
+        let gen_tran = |outputs_num: usize, fee: u64| {
+            let outputs = (0..outputs_num).map(|_| generate_random_txoutput()).collect();
+            generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()], outputs, fee)
+        };
+        // the bigger fee of big doesn't make up for its size
+        let small = gen_tran(1, 10);
+        let big = gen_tran(40, 30);
+        let medium = gen_tran(10, 30);
+        assert_eq!(Ordering::Greater, small.cmp_fee_rate(&big));
+
+        let mut mempool = MemPool::new();
+        let rx = mempool.subscribe();
+        for tran in [&big, &small, &medium].iter() {
+            assert!(mempool.add_with_check(tran));
+        }
+        let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 10);
+        let selected: Vec<H256> = content.trans[1..].iter().map(|t| t.hash).collect();
+        assert_eq!(vec![medium.hash, small.hash, big.hash], selected);
+
+        // eviction goes in exactly the reverse order
+        mempool.set_max_bytes(0);
+        mempool.evict_over_budget();
+        let evicted: Vec<H256> = rx.try_iter().filter_map(|e| match e {
+            Event::TxEvicted(hash) => Some(hash),
+            _ => None,
+        }).collect();
+        assert_eq!(selected.into_iter().rev().collect::<Vec<_>>(), evicted);
+    }
+
+    #[test]
+    fn test_content_max_fee() {
+        let key = key_pair::random();
+        let gen_tran = |outputs_num: usize, fee: u64| {
+            let outputs = (0..outputs_num).map(|_| generate_random_txoutput()).collect();
+            generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()], outputs, fee)
+        };
+        // small ones have the better fee rate, but big alone pays more than both of them
+        let trans = vec![gen_tran(1, 12), gen_tran(1, 11), gen_tran(20, 30)];
+        let (small, big) = (&trans[0], &trans[2]);
+        assert_eq!(Ordering::Greater, trans[1].cmp_fee_rate(big));
+        let max_bytes = big.size();
+        assert!(small.size() + trans[1].size() <= max_bytes && small.size() + big.size() > max_bytes);
+
+        let mut mempool = MemPool::new();
+        for tran in trans.iter() {
+            assert!(mempool.add_with_check(tran));
+        }
+        // best fee of any selection within the limits
+        let max_fee = |max_trans: usize| (0..1usize << trans.len())
+            .map(|mask| trans.iter().enumerate().filter(|(i, _)| mask & (1 << i) != 0).map(|(_, t)| t).collect::<Vec<_>>())
+            .filter(|picked| picked.len() <= max_trans && picked.iter().map(|t| t.size()).sum::<usize>() <= max_bytes)
+            .map(|picked| picked.iter().map(|t| t.transaction.fee).sum::<u64>())
+            .max()
+            .unwrap();
+        for max_trans in 0..4 {
+            let content = mempool.create_content_within(&key, &address_of(&key), COINBASE_REWARD, max_trans, max_bytes);
+            let fees: u64 = content.trans[1..].iter().map(|t| t.transaction.fee).sum();
+            assert_eq!(max_fee(max_trans), fees);
+            assert_eq!(COINBASE_REWARD + fees, content.trans[0].transaction.outputs[0].val);
+        }
+        let content = mempool.create_content_within(&key, &address_of(&key), COINBASE_REWARD, 10, max_bytes);
+        assert_eq!(vec![big.hash], content.get_trans_hashes()[1..].to_vec());
+
+        // without contention every transaction goes in, by fee rate
+        let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 10);
+        assert_eq!(vec![trans[0].hash, trans[1].hash, big.hash], content.get_trans_hashes()[1..].to_vec());
+    }
+
+    #[test]
+    fn test_content_independent_of_arrival() {
+        // same fee rate & timestamp, only hashes tell them apart
+        let key = key_pair::random();
+        let outputs = vec![TxOutput::new(generate_random_h160(), 5)];
+        let mut tran = Transaction::new_with_fee(vec![generate_random_txinput()], outputs.clone(), 3);
+        let mut trans = vec![];
+        for i in 0..30 {
+            tran.inputs = vec![TxInput::new(generate_random_hash(), i)];
+            let signature: Box<[u8]> = sign(&tran, &key).as_ref().into();
+            trans.push(SignedTransaction::new(tran.clone(), signature, key.public_key().as_ref().into()));
+        }
+        let mut mempool_1 = MemPool::new();
+        let mut mempool_2 = MemPool::new();
+        for tran in trans.iter() {
+            assert!(mempool_1.add_with_check(tran));
+        }
+        for tran in trans.iter().rev() {
+            assert!(mempool_2.add_with_check(tran));
+        }
+
+        // coinbases are created apart, compare what follows them
+        let root_of = |mempool: &MemPool| {
+            let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 20);
+            Content::new_with_trans(&content.trans[1..].to_vec()).merkle_root()
+        };
+        assert_eq!(root_of(&mempool_1), root_of(&mempool_2));
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let key = key_pair::random();
+        let gen_tran = |outputs_num: usize, fee: u64| {
+            let outputs = (0..outputs_num).map(|_| generate_random_txoutput()).collect();
+            generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()], outputs, fee)
+        };
+        let small = gen_tran(1, 10);
+        let big = gen_tran(40, 10);
+        let medium = gen_tran(10, 100);
+        assert!(small.size() < medium.size() && medium.size() < big.size());
+
+        let mut mempool = MemPool::new();
+        mempool.set_max_bytes(small.size() + big.size());
+        let rx = mempool.subscribe();
+        assert!(mempool.add_with_check(&small));
+        assert!(mempool.add_with_check(&big));
+        assert_eq!(small.size() + big.size(), mempool.bytes_used());
+
+        // big has the lowest fee rate, so it leaves for medium
+        assert!(mempool.add_with_check(&medium));
+        assert!(!mempool.exist(&big.hash));
+        assert!(rx.try_iter().any(|e| e == Event::TxEvicted(big.hash)));
+        assert_eq!(small.size() + medium.size(), mempool.bytes_used());
+        assert!(mempool.size() < POOL_SIZE_LIMIT);
+
+        // a new transaction with the lowest fee rate doesn't get in
+        let cheap = gen_tran(40, 1);
+        assert!(!mempool.add_with_check(&cheap));
+        assert_eq!(2, mempool.size());
+
+        mempool.remove_trans(&vec![small.hash, medium.hash]);
+        assert_eq!(0, mempool.bytes_used());
+    }
+
+    #[test]
+    fn test_trim_to() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let trans: Vec<SignedTransaction> = (1..=10).map(|fee| generate_signed_transaction_with_fee(&key,
+            vec![generate_random_txinput()], vec![TxOutput::new(generate_random_h160(), 5)], fee)).collect();
+        for tran in trans.iter() {
+            assert!(mempool.add_with_check(tran));
+        }
+        assert_eq!(0, mempool.trim_to(10, mempool.bytes_used()));
+
+        // lowered count: the cheapest go
+        assert_eq!(4, mempool.trim_to(6, mempool.bytes_used()));
+        assert_eq!(6, mempool.size());
+        assert!(trans[..4].iter().all(|t| !mempool.exist(&t.hash)));
+        assert!(trans[4..].iter().all(|t| mempool.exist(&t.hash)));
+
+        // lowered bytes: same sized transactions, so half the bytes keeps the 3 best
+        let half = mempool.bytes_used() / 2;
+        assert_eq!(3, mempool.trim_to(10, half));
+        assert!(mempool.bytes_used() <= half);
+        assert!(trans[7..].iter().all(|t| mempool.exist(&t.hash)));
+    }
+
     #[test]
     fn test_remove_trans() {
         let mut mempool = MemPool::new();
@@ -215,6 +1117,46 @@ mod tests {
         assert!(mempool.exist(&t_3.hash()));
     }
 
+    #[test]
+    fn test_ancestors_and_descendants() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let output = |val| TxOutput {rec_address: generate_random_h160(), val};
+        let spend = |tran: &SignedTransaction, index| TxInput {pre_hash: tran.hash, index};
+        // a <- b <- d, a <- c <- d, e unrelated
+        let a = generate_signed_transaction(&key, vec![TxInput {pre_hash: generate_random_hash(), index: 0}],
+                                            vec![output(2), output(2)]);
+        let b = generate_signed_transaction(&key, vec![spend(&a, 0)], vec![output(1)]);
+        let c = generate_signed_transaction(&key, vec![spend(&a, 1)], vec![output(1)]);
+        let d = generate_signed_transaction(&key, vec![spend(&b, 0), spend(&c, 0)], vec![output(1)]);
+        let e = generate_random_signed_transaction();
+        for t in vec![&a, &b, &c, &d, &e] {
+            assert!(mempool.add_with_check(t));
+        }
+        let sorted = |mut v: Vec<H256>| { v.sort(); v };
+
+        assert!(mempool.ancestors(&a.hash).is_empty());
+        assert_eq!(vec![a.hash], mempool.ancestors(&b.hash));
+        assert_eq!(sorted(vec![a.hash, b.hash, c.hash]), mempool.ancestors(&d.hash));
+        assert_eq!(sorted(vec![b.hash, c.hash, d.hash]), mempool.descendants(&a.hash));
+        assert_eq!(vec![d.hash], mempool.descendants(&c.hash));
+        assert!(mempool.descendants(&d.hash).is_empty());
+        assert!(mempool.ancestors(&e.hash).is_empty() && mempool.descendants(&e.hash).is_empty());
+
+        // a mined parent is no longer an ancestor
+        mempool.remove_trans(&vec![a.hash]);
+        assert!(mempool.ancestors(&b.hash).is_empty());
+        assert_eq!(vec![d.hash], mempool.descendants(&b.hash));
+
+        // a cycle doesn't hang
+        let fake = generate_random_hash();
+        let looped = generate_signed_transaction(&key, vec![TxInput {pre_hash: fake, index: 0}], vec![output(1)]);
+        mempool.transactions.insert(fake, looped);
+        mempool.input_tran_map.insert(TxInput {pre_hash: fake, index: 0}, (fake, 0));
+        assert!(mempool.ancestors(&fake).is_empty());
+        assert!(mempool.descendants(&fake).is_empty());
+    }
+
     #[test]
     fn test_create_trans() {
         let key = key_pair::random();
@@ -226,10 +1168,37 @@ mod tests {
         t = generate_random_signed_transaction();
         mempool.add_with_check(&t);
 
-        let content = mempool.create_content(&key);
+        let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, BLOCK_SIZE_LIMIT);
         assert_eq!(content.trans.len(), 4);
     }
 
+    #[test]
+    fn test_create_content_cap() {
+        let key = key_pair::random();
+        let sender = key_pair::random();
+        let mut mempool = MemPool::new();
+        let mut trans = vec![];
+        for fee in 0..10 {
+            let input = TxInput::new(generate_random_hash(), 0);
+            let output = TxOutput::new(generate_random_h160(), 1);
+            let tran = generate_signed_transaction_with_fee(&sender, vec![input], vec![output], fee);
+            assert!(mempool.add_with_check(&tran));
+            trans.push(tran);
+        }
+
+        // coinbase plus the 3 best paying ones
+        let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 3);
+        assert_eq!(4, content.trans.len());
+        let hashes = content.get_trans_hashes();
+        for tran in trans[7..].iter() {
+            assert!(hashes.contains(&tran.hash));
+        }
+        assert_eq!(COINBASE_REWARD + 7 + 8 + 9, content.trans[0].transaction.outputs[0].val);
+
+        assert_eq!(1, mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 0).trans.len());
+        assert_eq!(11, mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, BLOCK_SIZE_LIMIT).trans.len());
+    }
+
     #[test]
     fn test_mempool_clear() {
         let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17031);
@@ -239,14 +1208,16 @@ mod tests {
         let (_server_1, _miner_ctx_1, mut _generator_1,  _blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
         let (server_2, _miner_ctx_2, mut _generator_2, _blockchain_2, mempool_2, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
         let (server_3, _miner_ctx_3, mut _generator_3, blockchain_3, _mempool_3, _, _) = new_server_env(p2p_addr_3, Spreader::Default, false);
-        _blockchain_1.lock().unwrap().set_check_trans(false);
-        _blockchain_2.lock().unwrap().set_check_trans(false);
-        blockchain_3.lock().unwrap().set_check_trans(false);
+        _blockchain_1.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        _blockchain_2.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain_3.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
 
         let peers_1 = vec![p2p_addr_1];
         connect_peers(&server_2, &peers_1);
         let peers_2 = vec![p2p_addr_2];
         connect_peers(&server_3, &peers_2);
+        // let the handshakes finish, so GetMempool doesn't pull the transactions after the block
+        sleep(time::Duration::from_millis(100));
 
         let t_1 = generate_random_signed_transaction();
         let t_2 = generate_random_signed_transaction();
@@ -269,7 +1240,7 @@ mod tests {
         let content = Content::new_with_trans(&vec![t_1, t_2, t_3]);
         let header = generate_header(&chain_3.tip(), &content, 0, &difficulty);
         let new_block = Block::new(header, content);
-        chain_3.insert(&new_block);
+        chain_3.insert(&new_block).unwrap();
         drop(chain_3);
 
         // Server3 Only broadcasts a new block
@@ -290,9 +1261,10 @@ mod tests {
         let mut mempool = MemPool::new();
         let h256 = generate_random_hash();
         let input = TxInput {pre_hash: h256, index: 0};
-        let signed_tran_1 = generate_signed_transaction(&key, vec![input.clone()], Vec::new());
+        // the earlier timestamp wins only among transactions opting in to be replaced
+        let signed_tran_1 = generate_signed_replaceable_transaction(&key, vec![input.clone()], Vec::new(), 0);
         sleep(time::Duration::from_millis(10));
-        let signed_tran_2 = generate_signed_transaction(&key, vec![input.clone()], Vec::new());
+        let signed_tran_2 = generate_signed_replaceable_transaction(&key, vec![input.clone()], Vec::new(), 0);
         assert!(mempool.try_insert(&signed_tran_2));
         assert!(mempool.exist(&signed_tran_2.hash));
         assert!(mempool.try_insert(&signed_tran_1));
@@ -323,6 +1295,69 @@ mod tests {
         assert!(!mempool.contains_buffered_tran(&signed_tran_2.hash));
     }
 
+    #[test]
+    fn test_opt_in_replacement() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let conflicting = |original: &SignedTransaction, fee| generate_signed_transaction_with_fee(&key,
+            original.transaction.inputs.clone(), original.transaction.outputs.clone(), fee);
+
+        // replaceable transaction gives way to one paying more
+        let inputs = vec![generate_random_txinput(), generate_random_txinput()];
+        fund(&mut mempool, &key, &inputs);
+        let replaceable = generate_signed_replaceable_transaction(&key, vec![inputs[0].clone()],
+            vec![generate_random_txoutput()], 1);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&replaceable));
+        let replacement = conflicting(&replaceable, 5);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&replacement));
+        assert!(!mempool.exist(&replaceable.hash));
+
+        // the replacement itself didn't opt in, so the same fee rule now fails
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&conflicting(&replacement, 10)));
+
+        // final transaction is never replaced, whatever the fee
+        let final_tran = generate_signed_transaction_with_fee(&key, vec![inputs[1].clone()],
+            vec![generate_random_txoutput()], 1);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&final_tran));
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&conflicting(&final_tran, 5)));
+        assert!(mempool.exist(&final_tran.hash));
+        assert_eq!(2, mempool.size());
+    }
+
+    #[test]
+    fn test_lock_time() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let locked = generate_signed_locked_transaction(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], LockTime::Height(3));
+
+        // held while the next block is below its height
+        assert_eq!(Ok(Accepted::Held), mempool.accept(&locked));
+        assert!(mempool.is_non_final(&locked.hash));
+        assert!(!mempool.exist(&locked.hash));
+        assert_eq!(0, mempool.update_tip(1, 0));
+        assert!(!mempool.exist(&locked.hash));
+
+        // enters once the next block may have it
+        assert_eq!(1, mempool.update_tip(2, 0));
+        assert!(mempool.exist(&locked.hash));
+        assert!(!mempool.is_non_final(&locked.hash));
+
+        // held again after a reorg to a shorter chain
+        assert_eq!(0, mempool.update_tip(1, 0));
+        assert!(!mempool.exist(&locked.hash));
+        assert!(mempool.is_non_final(&locked.hash));
+
+        // by timestamp of tip
+        let time_locked = generate_signed_locked_transaction(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], LockTime::Time(1000));
+        assert!(mempool.add_with_check(&time_locked));
+        assert!(mempool.is_non_final(&time_locked.hash));
+        assert_eq!(0, mempool.update_tip(1, 999));
+        assert_eq!(1, mempool.update_tip(1, 1000));
+        assert!(mempool.exist(&time_locked.hash));
+    }
+
     #[test]
     fn test_remove_conflict_tx_inputs() {
         let key = key_pair::random();
@@ -340,6 +1375,54 @@ mod tests {
         assert!(!mempool.exist(&signed_tran_1.hash));
     }
 
+    #[test]
+    fn test_non_standard() {
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        let mut blockchain = Blockchain::new();
+        blockchain.change_difficulty(&difficulty);
+        let key = key_pair::random();
+        let coinbase = generate_signed_coinbase_transaction(&key);
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let header = generate_header(&blockchain.tip(), &content, 0, &difficulty);
+        blockchain.insert_with_check(&Block::new(header, content)).unwrap();
+
+        // dust output and absurd fee are rejected by mempool
+        let mut mempool = MemPool::new();
+        let input = TxInput {pre_hash: coinbase.hash, index: 0};
+        let dust = generate_signed_transaction(&key, vec![input.clone()],
+            vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD), TxOutput::new(address_of(&key), 0)]);
+        assert!(!dust.is_standard());
+        assert!(!mempool.add_with_check(&dust));
+        let absurd = generate_signed_transaction_with_fee(&key, vec![], vec![], u64::MAX);
+        assert!(absurd.transaction.fee / absurd.size() as u64 > MAX_FEE_RATE);
+        assert!(!mempool.add_with_check(&absurd));
+        assert!(mempool.empty());
+        let standard = generate_signed_transaction(&key, vec![input.clone()],
+            vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD)]);
+        assert!(mempool.add_with_check(&standard));
+
+        // but a mined block containing dust is valid
+        let coinbase = generate_signed_coinbase_transaction(&key);
+        let content = Content::new_with_trans(&vec![coinbase, dust]);
+        let header = generate_header(&blockchain.tip(), &content, 0, &difficulty);
+        assert_eq!(Ok(InsertOutcome::Extended), blockchain.insert_with_check(&Block::new(header, content)));
+    }
+
+    #[test]
+    fn test_spender_of() {
+        let mut mempool = MemPool::new();
+        let t = generate_random_signed_transaction();
+        assert!(mempool.add_with_check(&t));
+        for input in t.transaction.inputs.iter() {
+            assert_eq!(Some(t.hash), mempool.spender_of(input));
+        }
+        let unspent = TxInput {pre_hash: generate_random_hash(), index: 0};
+        assert_eq!(None, mempool.spender_of(&unspent));
+
+        mempool.remove_trans(&vec![t.hash]);
+        assert_eq!(None, mempool.spender_of(&t.transaction.inputs[0]));
+    }
+
     #[test]
     fn test_ts_addr_map() {
         let mut mempool = MemPool::new();
@@ -375,10 +1458,243 @@ mod tests {
 
         let hash = generate_random_hash();
         server_1.broadcast(Message::NewTransactionHashes(vec![hash]), None);
-        sleep(time::Duration::from_millis(100));
+        sleep(time::Duration::from_millis(INV_FLUSH_INTERVAL + 100));
         assert_eq!(1, mempool_2.lock().unwrap().ts_addr_map.len());
         server_3.broadcast(Message::NewTransactionHashes(vec![hash]), None);
-        sleep(time::Duration::from_millis(100));
+        sleep(time::Duration::from_millis(INV_FLUSH_INTERVAL + 100));
         assert_eq!(2, mempool_2.lock().unwrap().ts_addr_map.get(&hash).unwrap().len());
     }
+
+    #[test]
+    fn test_apply_reorg() {
+        /*
+         * structure:
+         * genesis <- block_1 <- block_a (spends block_1's coinbase)
+         *               ^
+         *               ------ block_b_1 <- block_b_2
+         */
+        let key = key_pair::random();
+        let addr = address_of(&key);
+        let mut blockchain = Blockchain::new();
+        let difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
+        blockchain.change_difficulty(&difficulty);
+        // distinct rewards, so coinbases created in the same instant don't share a hash
+        let mut reward = COINBASE_REWARD;
+        let mut block_on = |parent: &H256, trans: Vec<SignedTransaction>| {
+            let mut all = vec![generate_signed_coinbase_transaction_with_reward(&key, reward)];
+            reward -= 1;
+            all.extend(trans);
+            let content = Content::new_with_trans(&all);
+            Block::new(generate_header(parent, &content, 0, &difficulty), content)
+        };
+        let block_1 = block_on(&blockchain.tip(), vec![]);
+        let coin = TxInput::new(block_1.content.trans[0].hash, 0);
+        let paid = generate_signed_transaction_with_fee(&key, vec![coin],
+            vec![TxOutput::new(generate_random_h160(), COINBASE_REWARD - 5)], 5);
+        let block_a = block_on(&block_1.hash, vec![paid.clone()]);
+        let block_b_1 = block_on(&block_1.hash, vec![]);
+        let block_b_2 = block_on(&block_b_1.hash, vec![]);
+        for block in [&block_1, &block_a, &block_b_1].iter() {
+            blockchain.insert_with_check(block).unwrap();
+        }
+        let old_tip = blockchain.tip();
+        assert!(blockchain.disconnected_trans(&old_tip).is_empty());
+        assert_eq!(Ok(InsertOutcome::Reorg { depth: 1 }), blockchain.insert_with_check(&block_b_2));
+
+        // the disconnected transaction comes back with its fee
+        let mut mempool = MemPool::new();
+        let cheap = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+            vec![TxOutput::new(generate_random_h160(), 1)], 1);
+        assert!(mempool.add_with_check(&cheap));
+        let disconnected = blockchain.disconnected_trans(&old_tip);
+        assert_eq!(vec![paid.hash], disconnected.iter().map(|t| t.hash).collect::<Vec<_>>());
+        assert_eq!(1, mempool.apply_reorg(&disconnected, &blockchain.tip_block_state()));
+        assert!(mempool.is_reorged(&paid.hash));
+        assert!(!mempool.is_reorged(&cheap.hash));
+        assert_eq!(5, mempool.get_trans(&vec![paid.hash])[0].transaction.fee);
+
+        // and is still selected by its fee rate
+        let content = mempool.create_content(&key, &addr, COINBASE_REWARD, 1);
+        assert_eq!(paid.hash, content.trans[1].hash);
+        assert_eq!(COINBASE_REWARD + 5, content.trans[0].transaction.outputs[0].val);
+
+        // spent inputs are not put back
+        let mut mempool = MemPool::new();
+        assert_eq!(0, mempool.apply_reorg(&disconnected, &State::new()));
+        assert!(mempool.empty());
+    }
+
+    #[test]
+    fn test_replace_all_conflicts() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let inputs = vec![generate_random_txinput(), generate_random_txinput()];
+        fund(&mut mempool, &key, &inputs);
+        let first = generate_signed_replaceable_transaction(&key, vec![inputs[0].clone()],
+            vec![generate_random_txoutput()], 3);
+        let second = generate_signed_replaceable_transaction(&key, vec![inputs[1].clone()],
+            vec![generate_random_txoutput()], 4);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&first));
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&second));
+        let replacement = |fee| generate_signed_transaction_with_fee(&key, inputs.clone(),
+            vec![generate_random_txoutput()], fee);
+
+        // paying more than each conflict isn't enough, nor is their total without the incremental fee
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&replacement(5)));
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&replacement(7 + INCREMENTAL_RELAY_FEE - 1)));
+        assert!(mempool.exist(&first.hash) && mempool.exist(&second.hash));
+
+        let winner = replacement(7 + INCREMENTAL_RELAY_FEE);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&winner));
+        assert!(!mempool.exist(&first.hash) && !mempool.exist(&second.hash));
+        assert_eq!(1, mempool.size());
+    }
+
+    #[test]
+    fn test_content_parents_first() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let parent = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+            vec![TxOutput::new(generate_random_h160(), 10)], 1);
+        let child = generate_signed_transaction_with_fee(&key, vec![TxInput::new(parent.hash, 0)],
+            vec![TxOutput::new(generate_random_h160(), 5)], 50);
+        assert!(mempool.add_with_check(&child));
+        assert!(mempool.add_with_check(&parent));
+        assert_eq!(Ordering::Greater, child.cmp_fee_rate(&parent));
+
+        // the child pays more in fee and fee rate, but can't come before its parent nor without it
+        let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 10);
+        assert_eq!(vec![parent.hash, child.hash], content.get_trans_hashes()[1..].to_vec());
+        for max_bytes in vec![parent.size(), child.size()] {
+            let content = mempool.create_content_within(&key, &address_of(&key), COINBASE_REWARD, 10, max_bytes);
+            assert!(!content.get_trans_hashes().contains(&child.hash));
+        }
+        let content = mempool.create_content_within(&key, &address_of(&key), COINBASE_REWARD, 1, MAX_BLOCK_BYTES);
+        assert_eq!(vec![parent.hash], content.get_trans_hashes()[1..].to_vec());
+    }
+
+    #[test]
+    fn test_earlier_ts_replaces_only_opted_in() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let earlier_than = |tran: &SignedTransaction| {
+            let mut earlier = Transaction::new_with_fee(tran.transaction.inputs.clone(),
+                vec![generate_random_txoutput()], tran.transaction.fee);
+            earlier.ts = tran.transaction.ts - 1;
+            let signature: Box<[u8]> = sign(&earlier, &key).as_ref().into();
+            SignedTransaction::new(earlier, signature, key.public_key().as_ref().into())
+        };
+
+        // a transaction not opting in stays, however early its conflict claims to be
+        let kept = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], 3);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&kept));
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&earlier_than(&kept)));
+        assert!(mempool.exist(&kept.hash));
+
+        let replaceable = generate_signed_replaceable_transaction(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], 3);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&replaceable));
+        let earlier = earlier_than(&replaceable);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&earlier));
+        assert!(!mempool.exist(&replaceable.hash) && mempool.exist(&earlier.hash));
+    }
+
+    #[test]
+    fn test_non_final_checks() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let locked = |key: &Ed25519KeyPair, input: TxInput| generate_signed_locked_transaction(key, vec![input],
+            vec![generate_random_txoutput()], LockTime::Height(10));
+
+        // sender and conflict checks come before holding
+        let pooled = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], 5);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&pooled));
+        mempool.set_max_per_sender(1);
+        let capped = locked(&key, generate_random_txinput());
+        assert_eq!(Err(TransactionError::TooManyFromSender), mempool.accept(&capped));
+        assert!(!mempool.is_non_final(&capped.hash));
+        mempool.set_max_per_sender(MAX_TX_PER_ADDRESS);
+        let double_spend = locked(&key, pooled.transaction.inputs[0].clone());
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&double_spend));
+        assert!(!mempool.is_non_final(&double_spend.hash));
+
+        // held transactions count in bytes and conflict with each other
+        let input = generate_random_txinput();
+        let held = locked(&key, input.clone());
+        assert_eq!(Ok(Accepted::Held), mempool.accept(&held));
+        assert_eq!(pooled.size() + held.size(), mempool.bytes_used());
+        assert_eq!(Some(held.hash), mempool.held_spender_of(&input));
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&locked(&key, input.clone())));
+
+        // a block spending its input drops it
+        mempool.remove_conflicts(&vec![held.hash]);
+        assert!(!mempool.is_non_final(&held.hash));
+        assert_eq!(None, mempool.held_spender_of(&input));
+        assert_eq!(pooled.size(), mempool.bytes_used());
+
+        // evicted first when the budget is exceeded, and dropped once held too long
+        let held = locked(&key, generate_random_txinput());
+        assert_eq!(Ok(Accepted::Held), mempool.accept(&held));
+        assert_eq!(1, mempool.trim_to(10, pooled.size()));
+        assert!(!mempool.is_non_final(&held.hash) && mempool.exist(&pooled.hash));
+        assert_eq!(Ok(Accepted::Held), mempool.accept(&held));
+        mempool.set_non_final_ttl(50);
+        sleep(time::Duration::from_millis(100));
+        assert_eq!(0, mempool.update_tip(1, 0));
+        assert!(!mempool.is_non_final(&held.hash));
+        assert_eq!(pooled.size(), mempool.bytes_used());
+    }
+
+    #[test]
+    fn test_inputs_cover_fee() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let coin = generate_random_txinput();
+        mempool.set_tip_state(generate_random_state(vec![(coin.pre_hash, coin.index)], vec![(10, address_of(&key))]));
+        let spend = |inputs: Vec<TxInput>, value, fee| generate_signed_transaction_with_fee(&key, inputs,
+            vec![TxOutput::new(generate_random_h160(), value)], fee);
+
+        // a fee the inputs can't pay would make every block including it invalid
+        assert_eq!(Err(TransactionError::InputsNotCovered), mempool.accept(&spend(vec![coin.clone()], 5, 6)));
+        assert_eq!(Err(TransactionError::InputsNotCovered), mempool.accept(&spend(vec![generate_random_txinput()], 1, 0)));
+        let other = key_pair::random();
+        let stolen = generate_signed_transaction(&other, vec![coin.clone()], vec![generate_random_txoutput()]);
+        assert_eq!(Err(TransactionError::InputsNotCovered), mempool.accept(&stolen));
+        assert_eq!(0, mempool.size());
+
+        let parent = generate_signed_transaction_with_fee(&key, vec![coin.clone()],
+            vec![TxOutput::new(address_of(&key), 6)], 4);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&parent));
+        // outputs of pool transactions count as inputs
+        assert_eq!(Err(TransactionError::InputsNotCovered), mempool.accept(&spend(vec![TxInput::new(parent.hash, 0)], 5, 2)));
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&spend(vec![TxInput::new(parent.hash, 0)], 5, 1)));
+        let content = mempool.create_content(&key, &address_of(&key), COINBASE_REWARD, 10);
+        assert_eq!(3, content.trans.len());
+    }
+
+    #[test]
+    fn test_replace_by_verified_fee() {
+        let key = key_pair::random();
+        let mut mempool = MemPool::new();
+        let input = generate_random_txinput();
+        let original = generate_signed_replaceable_transaction(&key, vec![input.clone()],
+            vec![generate_random_txoutput()], 1);
+        let overpaying = generate_signed_transaction_with_fee(&key, vec![input.clone()],
+            vec![generate_random_txoutput()], 5000);
+
+        // without a tip state no fee is known to be paid, nothing is replaced
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&original));
+        assert_eq!(Err(TransactionError::DoubleSpend), mempool.accept(&overpaying));
+
+        // a fee the inputs can't pay doesn't displace the original
+        fund(&mut mempool, &key, &[input.clone()]);
+        assert_eq!(Err(TransactionError::InputsNotCovered), mempool.accept(&overpaying));
+        assert!(mempool.exist(&original.hash));
+        let replacement = generate_signed_transaction_with_fee(&key, vec![input.clone()],
+            vec![generate_random_txoutput()], 500);
+        assert_eq!(Ok(Accepted::Pooled), mempool.accept(&replacement));
+        assert!(!mempool.exist(&original.hash));
+    }
 }
\ No newline at end of file
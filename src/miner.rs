@@ -1,26 +1,34 @@
 use crate::network::server::Handle as ServerHandle;
 
-use log::info;
+use log::{debug, info, warn};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{self, unbounded, select, Receiver, Sender, TryRecvError};
+use crossbeam::atomic::AtomicCell;
 use std::time;
 use std::time::SystemTime;
 
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use ring::signature::Ed25519KeyPair;
 
-use crate::blockchain::Blockchain;
-use crate::block::{Header, Block};
-use crate::network::message::{Message};
-use crate::crypto::hash::H256;
-use crate::config::MINING_STEP;
+use crate::blockchain::{Blockchain, InsertOutcome, ValidationTip};
+use crate::block::{Header, Block, Content};
+use crate::crypto::hash::{H160, H256};
+use crate::helper;
+use crate::config::{BLOCK_SIZE_LIMIT, MINING_STEP, MINER_IDLE_BACKOFF, MIN_PEERS};
 use crate::mempool::MemPool;
+use crate::lock_order::OrderedMutex;
+use crate::events::Event;
+
+// Log target of the miner, so mining logs can be filtered apart from network logs
+pub const LOG_TARGET: &str = "miner";
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Exit,
     Paused,
+    Throttle(u32), // percent of a core the miner may use
 }
 
 enum OperatingState {
@@ -34,40 +42,67 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<MemPool>>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    validation_tip: Arc<AtomicCell<ValidationTip>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
+    events: Receiver<Event>,  // mempool events while running, never while paused so that none pile up
+    wakeups: Arc<AtomicUsize>,  // rounds of the mining loop, shared with Handle
     pub nonce: u32,
     pub mined_num: usize,
     key_pair: Arc<Ed25519KeyPair>,
+    max_block_trans: usize,  // max number of transactions in a mined block besides coinbase
+    payout_addrs: Vec<H160>,  // coinbase reward goes to these in turn, one per mined block
+    template: Option<(Header, Content)>,  // block being mined, kept across mining steps while still valid
+    pool_changed: bool,  // a mempool event arrived since the template's content was created
+    log_fees: bool,  // log total fee of every new template
+    min_peers: usize,  // connections needed before mining, the miner stays paused below
+    waiting_for_peers: bool,
+    throttle: u32,  // percent of a core the miner may use, 100 for no throttle
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    wakeups: Arc<AtomicUsize>,
 }
 
 pub fn new(
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<MemPool>>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
     key_pair: Arc<Ed25519KeyPair>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let validation_tip = blockchain.lock_or_recover().validation_tip();
+    let wakeups = Arc::new(AtomicUsize::new(0));
+    let payout_addrs = vec![helper::address_of(&key_pair)];
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server,
         blockchain: blockchain,
+        validation_tip: validation_tip,
         mempool: mempool,
+        events: channel::never(),
+        wakeups: wakeups.clone(),
         nonce: 0,
         mined_num: 0,
         key_pair: key_pair,
+        max_block_trans: BLOCK_SIZE_LIMIT,
+        payout_addrs,
+        template: None,
+        pool_changed: false,
+        log_fees: false,
+        min_peers: MIN_PEERS,
+        waiting_for_peers: false,
+        throttle: 100,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        wakeups,
     };
 
     (ctx, handle)
@@ -95,9 +130,45 @@ impl Handle {
             .send(ControlSignal::Paused)
             .unwrap()
     }
+
+    // Cap the miner at roughly percent of a core by sleeping between mining steps, 100 for no throttle
+    pub fn set_throttle(&self, percent: u32) {
+        self.control_chan
+            .send(ControlSignal::Throttle(percent))
+            .unwrap()
+    }
+
+    // Rounds of the mining loop so far, which keeps still while the miner idles or is paused
+    pub fn wakeups(&self) -> usize {
+        self.wakeups.load(Ordering::Relaxed)
+    }
 }
 
 impl Context {
+    // Produce smaller blocks than BLOCK_SIZE_LIMIT, set before start
+    pub fn set_max_block_trans(&mut self, max_block_trans: usize) {
+        self.max_block_trans = max_block_trans;
+    }
+
+    // Rotate coinbase rewards through several addresses, set before start
+    pub fn set_payout_addrs(&mut self, payout_addrs: Vec<H160>) {
+        if payout_addrs.is_empty() {
+            warn!(target: LOG_TARGET, "No payout address given, keep paying to {:?}", self.payout_addrs);
+            return;
+        }
+        self.payout_addrs = payout_addrs;
+    }
+
+    // Log total fee of every new block template, so operators can see what the miner earns, set before start
+    pub fn set_log_fees(&mut self, log_fees: bool) {
+        self.log_fees = log_fees;
+    }
+
+    // Don't mine with fewer than min_peers connections, set before start
+    pub fn set_min_peers(&mut self, min_peers: usize) {
+        self.min_peers = min_peers;
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
             .name("miner".to_string())
@@ -105,22 +176,33 @@ impl Context {
                 self.miner_loop();
             })
             .unwrap();
-        info!("Miner initialized into paused mode");
+        debug!(target: LOG_TARGET, "Miner initialized into paused mode");
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
         match signal {
             ControlSignal::Exit => {
-                info!("Miner shutting down");
+                info!(target: LOG_TARGET, "Miner shutting down");
                 self.operating_state = OperatingState::ShutDown;
+                self.events = channel::never();
             }
             ControlSignal::Start(i) => {
-                info!("Miner starting in continuous mode with lambda {}", i);
+                info!(target: LOG_TARGET, "Miner starting in continuous mode with lambda {}", i);
+                if let OperatingState::Paused = self.operating_state {
+                    self.events = self.mempool.lock_or_recover().subscribe();
+                    self.pool_changed = true;  // events while paused were missed
+                }
                 self.operating_state = OperatingState::Run(i);
             }
             ControlSignal::Paused => {
-                info!("Miner paused");
+                info!(target: LOG_TARGET, "Miner paused");
                 self.operating_state = OperatingState::Paused;
+                // dropping the receiver unsubscribes on the next event
+                self.events = channel::never();
+            }
+            ControlSignal::Throttle(percent) => {
+                self.throttle = std::cmp::min(std::cmp::max(percent, 1), 100);
+                info!(target: LOG_TARGET, "Miner throttled to {}% of a core", self.throttle);
             }
         }
     }
@@ -128,6 +210,7 @@ impl Context {
     fn miner_loop(&mut self) {
         // main mining loop
         loop {
+            self.wakeups.fetch_add(1, Ordering::Relaxed);
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
@@ -150,6 +233,18 @@ impl Context {
                 return;
             }
 
+            if !self.enough_peers() {
+                self.wait_for_peers();
+                continue;
+            }
+
+            // drain events before checking mempool, so a transaction arriving afterwards wakes us up
+            self.drain_events();
+            if self.mempool.lock_or_recover().empty() {
+                self.wait_for_tran();
+                continue;
+            }
+
             self.mining();
 
             if let OperatingState::Run(i) = self.operating_state {
@@ -161,46 +256,159 @@ impl Context {
         }
     }
 
+    // Note pending mempool events, the template's content is created again on the next mining step
+    fn drain_events(&mut self) {
+        if self.events.try_iter().count() > 0 {
+            self.pool_changed = true;
+        }
+    }
+
+    // Back off on empty mempool until a transaction arrives, a control signal comes or timeout
+    fn wait_for_tran(&mut self) {
+        let control_chan = self.control_chan.clone();
+        let events = self.events.clone();
+        select! {
+            recv(control_chan) -> signal => match signal {
+                Ok(signal) => self.handle_control_signal(signal),
+                Err(_) => panic!("Miner control channel detached"),
+            },
+            recv(events) -> _ => {},
+            default(time::Duration::from_millis(MINER_IDLE_BACKOFF)) => {},
+        }
+    }
+
+    // Whether there are at least min_peers connections, logging when this changes
+    fn enough_peers(&mut self) -> bool {
+        if self.min_peers == 0 {
+            return true;
+        }
+        let peer_count = self.server.peer_count();
+        let enough = peer_count >= self.min_peers;
+        if !enough && !self.waiting_for_peers {
+            info!(target: LOG_TARGET, "Miner stays paused with {} peers, waiting for {}", peer_count, self.min_peers);
+        } else if enough && self.waiting_for_peers {
+            info!(target: LOG_TARGET, "Miner resumes with {} peers", peer_count);
+        }
+        self.waiting_for_peers = !enough;
+        enough
+    }
+
+    // Back off until peers may have connected or a control signal comes
+    fn wait_for_peers(&mut self) {
+        let control_chan = self.control_chan.clone();
+        select! {
+            recv(control_chan) -> signal => match signal {
+                Ok(signal) => self.handle_control_signal(signal),
+                Err(_) => panic!("Miner control channel detached"),
+            },
+            default(time::Duration::from_millis(MINER_IDLE_BACKOFF)) => {},
+        }
+    }
+
     // Procedures when new block found
     pub(crate) fn found(&mut self, block: Block) {
         self.mined_num += 1;
-        info!("Mined a block: {:?}, number of transactions: {:?}. Total mined: {}",
-                block.hash, block.content.trans.len(), self.mined_num);
+        debug!(target: LOG_TARGET, "Mined a block: {:?}, number of transactions: {:?}. Total mined: {}",
+               block.hash, block.content.trans.len(), self.mined_num);
 
         let hash_of_trans = block.content.get_trans_hashes();
-        // insert block into chain
-        let mut blockchain = self.blockchain.lock().unwrap();
-        blockchain.insert(&block);
-        drop(blockchain);
-
-        // remove content's all transactions from mempool
-        let mut mempool = self.mempool.lock().unwrap();
-        mempool.remove_trans(&hash_of_trans);
-        mempool.remove_conflict_tx_inputs(&block.content);
-
-        // broadcast new block
-        let vec = vec![block.hash.clone()];
-        self.server.broadcast(Message::NewBlockHashes(vec), None);
+        // insert block into chain, unless a panic left it half-updated
+        let mut blockchain = match self.blockchain.lock_healthy() {
+            Ok(blockchain) => blockchain,
+            Err(e) => {
+                warn!(target: LOG_TARGET, "Mined block {:?} is dropped: {:?}", block.hash, e);
+                return;
+            }
+        };
+        let mut mempool = match self.mempool.lock_healthy() {
+            Ok(mempool) => mempool,
+            Err(e) => {
+                warn!(target: LOG_TARGET, "Mined block {:?} is dropped: {:?}", block.hash, e);
+                return;
+            }
+        };
+        let old_tip = blockchain.tip();
+        let outcome = blockchain.insert(&block);
+
+        match outcome {
+            Ok(InsertOutcome::Extended) | Ok(InsertOutcome::Reorg { .. }) => {
+                if let Ok(InsertOutcome::Reorg { depth }) = outcome {
+                    info!(target: LOG_TARGET, "Mined block {:?} reorganized the chain, depth: {}", block.hash, depth);
+                }
+                // remove content's all transactions and the ones conflicting with them from mempool
+                let conflicts = blockchain.mempool_conflicts(&block.hash, &mempool);
+                let disconnected = blockchain.disconnected_trans(&old_tip);
+                let tip_state = blockchain.tip_block_state();
+                mempool.set_tip_state(tip_state.clone());
+                if !disconnected.is_empty() {
+                    mempool.apply_reorg(&disconnected, &tip_state);
+                }
+                let (tip_height, tip_time) = (blockchain.get_block_count(), blockchain.tip_header().timestamp);
+                drop(blockchain);
+                mempool.remove_trans(&hash_of_trans);
+                mempool.remove_conflicts(&conflicts);
+                mempool.update_tip(tip_height, tip_time);
+                drop(mempool);
+
+                // broadcast new block
+                self.server.relay_blocks(vec![block.clone()], None);
+            }
+            Ok(outcome) => {
+                debug!(target: LOG_TARGET, "Mined block {:?} is not on the longest chain: {:?}", block.hash, outcome);
+            }
+            Err(e) => {
+                warn!(target: LOG_TARGET, "Mined block {:?} is rejected: {:?}", block.hash, e);
+            }
+        }
     }
 
     // Mining process! Return true: mining a block successfully
     fn mining(&mut self) -> bool {
-        let blockchain = self.blockchain.lock().unwrap();
-        let tip = blockchain.tip();  // previous hash
-        let difficulty = blockchain.difficulty();
-        drop(blockchain);
-
-        let mempool = self.mempool.lock().unwrap();
-
-        // Miner put transactions into block content from mempool!!
-        let content = mempool.create_content(&self.key_pair);
-        drop(mempool);
-
-        let nonce = self.nonce;
-        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap().as_millis();
-        let mut header = Header::new(&tip, nonce, ts,
-                &difficulty, &content.merkle_root());
+        let started = time::Instant::now();
+        let validation_tip = self.validation_tip.load();
+        let tip = validation_tip.tip;  // previous hash
+        let difficulty = validation_tip.next_difficulty;
+        self.drain_events();
+
+        // keep the template, timestamp included, so nonce progress carries over to the next step;
+        // its content is only created again once the mempool changed
+        let template = match self.template.take() {
+            Some((header, template)) if header.parent == tip && header.difficulty == difficulty => {
+                if self.pool_changed {
+                    let content = self.create_content(validation_tip.height + 1);
+                    if Context::same_content(&template, &content) {
+                        Ok((header, template))
+                    } else {
+                        Err(content)
+                    }
+                } else {
+                    Ok((header, template))
+                }
+            }
+            _ => Err(self.create_content(validation_tip.height + 1)),
+        };
+        let (mut header, content) = match template {
+            Ok(template) => template,
+            Err(content) => {
+                self.nonce = 0;
+                if self.log_fees {
+                    let fees: u64 = content.trans[1..].iter().map(|t| t.transaction.fee).sum();
+                    info!(target: LOG_TARGET, "New template on {:?} with {} transactions pays {} in fees",
+                          tip, content.trans.len() - 1, fees);
+                }
+                // later than the median time past, even if blocks come faster than the clock ticks
+                let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap().as_millis().max(validation_tip.median_time_past as u128 + 1);
+                (Header::new(&tip, 0, ts, &difficulty, &content.merkle_root()), content)
+            }
+        };
+        // nonce space used up, a later timestamp gives new headers to try
+        if self.nonce.checked_add(MINING_STEP).is_none() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+            header.timestamp = std::cmp::max(now, header.timestamp + 1);
+            self.nonce = 0;
+        }
+        header.nonce = self.nonce;
 
         let mut bingo = false;
         if mining_base(&mut header, difficulty) {
@@ -210,10 +418,64 @@ impl Context {
             self.nonce = 0;
         } else {
             self.nonce = header.nonce;
+            self.template = Some((header, content));
         }
+        self.throttle_sleep(started.elapsed());
         bingo
     }
 
+    // Content of a block at height from mempool, paying the next payout address
+    fn create_content(&mut self, height: usize) -> Content {
+        self.pool_changed = false;
+        let subsidy = Blockchain::block_subsidy(height);
+        let payout = &self.payout_addrs[self.mined_num % self.payout_addrs.len()];
+        self.mempool.lock_or_recover().create_content(&self.key_pair, payout, subsidy, self.max_block_trans)
+    }
+
+    // Sleep in proportion to the time spent mining, so the miner uses about throttle percent of a core
+    fn throttle_sleep(&self, busy: time::Duration) {
+        if self.throttle < 100 {
+            thread::sleep(busy * (100 - self.throttle) / self.throttle);
+        }
+    }
+
+    // Whether a freshly created content pays the same coinbase outputs and has the same transactions as
+    // the template, in any order; coinbases differ only by timestamp then
+    fn same_content(template: &Content, content: &Content) -> bool {
+        let mut template_hashes = template.get_trans_hashes().split_off(1);
+        let mut hashes = content.get_trans_hashes().split_off(1);
+        template_hashes.sort();
+        hashes.sort();
+        template.trans[0].transaction.outputs == content.trans[0].transaction.outputs && template_hashes == hashes
+    }
+
+    // Mine a block on a given parent instead of tip and process it as found, for building forks in tests.
+    // Return None if the parent is unknown
+    #[cfg(any(test, test_utilities))]
+    pub fn mine_on(&mut self, parent: &H256) -> Option<Block> {
+        let blockchain = self.blockchain.lock().unwrap();
+        let difficulty = blockchain.difficulty_after(parent).ok()?;
+        let height = blockchain.get_block(parent)?.index + 1;
+        let median_time_past = blockchain.median_time_past(parent);
+        drop(blockchain);
+
+        let mempool = self.mempool.lock().unwrap();
+        let subsidy = Blockchain::block_subsidy(height);
+        let payout = &self.payout_addrs[self.mined_num % self.payout_addrs.len()];
+        let content = mempool.create_content(&self.key_pair, payout, subsidy, self.max_block_trans);
+        drop(mempool);
+
+        // random starting nonce, so blocks mined on the same parent in the same instant differ
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap().as_millis().max(median_time_past as u128 + 1);
+        let mut header = Header::new(parent, rand::random(), ts,
+                &difficulty, &content.merkle_root());
+        while !mining_base(&mut header, difficulty.clone()) {}
+        let block = Block::new(header, content);
+        self.found(block.clone());
+        Some(block)
+    }
+
     #[cfg(any(test, test_utilities))]
     fn change_difficulty(&mut self, new_difficulty: &H256) {
         let mut blockchain = self.blockchain.lock().unwrap();
@@ -224,7 +486,7 @@ impl Context {
 // Perforn mining for MINING_STEP here
 pub fn mining_base(header: &mut Header, difficulty: H256) -> bool {
     for _ in 0..MINING_STEP {
-        if header.hash() < difficulty {
+        if header.hash().meets_difficulty(&difficulty) {
             return true;
         }
         header.change_nonce();
@@ -235,14 +497,21 @@ pub fn mining_base(header: &mut Header, difficulty: H256) -> bool {
 #[cfg(any(test, test_utilities))]
 pub mod tests {
     use crate::miner;
+    use crate::blockchain::{BlockError, CheckPolicy, InsertOutcome};
     use crate::crypto::hash::H256;
     use crate::helper::*;
 
     use std::time;
     use std::thread;
     use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-    use crate::config::{BLOCK_SIZE_LIMIT, EASIEST_DIF};
+    use crate::config::{BLOCK_SIZE_LIMIT, EASIEST_DIF, MINER_IDLE_BACKOFF};
+    use crossbeam::channel;
+    use crate::crypto::key_pair;
+    use std::sync::Arc;
     use crate::spread::Spreader;
+    use crate::events::Event;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::sync::Mutex;
 
     #[test]
     fn test_miner() {
@@ -275,6 +544,264 @@ pub mod tests {
         assert_eq!(miner::MINING_STEP, miner.nonce);
     }
 
+    #[test]
+    fn test_template_kept_across_steps() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17443);
+        let (_server_handle, mut miner, _, _blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        miner.change_difficulty(&gen_difficulty_array(256).into());
+        miner.handle_control_signal(miner::ControlSignal::Start(0));  // subscribes to mempool events
+        mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction());
+
+        // unchanged content: same timestamp, nonce keeps advancing
+        assert!(!miner.mining());
+        let (header, content) = miner.template.clone().unwrap();
+        thread::sleep(time::Duration::from_millis(5));
+        assert!(!miner.mining());
+        let (next_header, next_content) = miner.template.clone().unwrap();
+        assert_eq!(header.timestamp, next_header.timestamp);
+        assert_eq!(content.get_trans_hashes(), next_content.get_trans_hashes());
+        assert_eq!(2 * miner::MINING_STEP, miner.nonce);
+
+        // new transaction: template rebuilt from nonce 0
+        mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction());
+        assert!(!miner.mining());
+        let (rebuilt, content) = miner.template.clone().unwrap();
+        assert!(rebuilt.timestamp > header.timestamp);
+        assert_eq!(3, content.trans.len());
+        assert_eq!(miner::MINING_STEP, miner.nonce);
+
+        // without a mempool event, the content isn't even created again
+        miner.events = channel::never();
+        mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction());
+        assert!(!miner.mining());
+        assert_eq!(3, miner.template.as_ref().unwrap().1.trans.len());
+        assert_eq!(2 * miner::MINING_STEP, miner.nonce);
+
+        // nonce space used up: a later timestamp, nonce from 0 again
+        miner.nonce = u32::MAX - 10;
+        assert!(!miner.mining());
+        let (refreshed, _) = miner.template.clone().unwrap();
+        assert!(refreshed.timestamp > rebuilt.timestamp);
+        assert_eq!(miner::MINING_STEP, miner.nonce);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17464);
+        let (server, _, _, blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (mut miner, handle) = miner::new(server, blockchain.clone(), mempool.clone(),
+                                             Arc::new(key_pair::random()));
+        miner.change_difficulty(&gen_difficulty_array(256).into());
+        mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction());
+        let steps_per_second = |miner: &mut miner::Context| {
+            let start = time::Instant::now();
+            let mut steps = 0;
+            while start.elapsed() < time::Duration::from_millis(500) {
+                assert!(!miner.mining());
+                steps += 1;
+            }
+            steps as f64 / start.elapsed().as_secs_f64()
+        };
+        let unthrottled = steps_per_second(&mut miner);
+
+        // a quarter of a core makes about a quarter of the attempts
+        handle.set_throttle(25);
+        miner.handle_control_signal(miner.control_chan.try_recv().unwrap());
+        let throttled = steps_per_second(&mut miner);
+        assert!(throttled < unthrottled * 0.4, "{} vs {} steps/s", throttled, unthrottled);
+        assert!(throttled > unthrottled * 0.1, "{} vs {} steps/s", throttled, unthrottled);
+
+        // out of range percents are clamped, 100 lifts the throttle
+        handle.set_throttle(0);
+        miner.handle_control_signal(miner.control_chan.try_recv().unwrap());
+        assert_eq!(1, miner.throttle);
+        handle.set_throttle(150);
+        miner.handle_control_signal(miner.control_chan.try_recv().unwrap());
+        assert_eq!(100, miner.throttle);
+    }
+
+    #[test]
+    fn test_block_accepted_metrics() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17447);
+        let (_server_handle, mut miner, _, blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        let events = blockchain.lock().unwrap().subscribe();
+        let key = key_pair::random();
+        for fee in 1..=3 {
+            let tran = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+                                                            vec![generate_random_txoutput()], fee);
+            assert!(mempool.lock().unwrap().add_with_check(&tran));
+        }
+
+        let tip = blockchain.lock().unwrap().tip();
+        let block = miner.mine_on(&tip).unwrap();
+        let accepted: Vec<Event> = events.try_iter().filter(|e| match e {
+            Event::BlockAccepted { .. } => true,
+            _ => false,
+        }).collect();
+        assert_eq!(vec![Event::BlockAccepted {
+            hash: block.hash,
+            height: 1,
+            tx_count: 4,
+            total_fees: 6,
+            block_bytes: bincode::serialize(&block).unwrap().len(),
+        }], accepted);
+    }
+
+    #[test]
+    fn test_mine_on() {
+        /*
+         * structure:
+         * genesis <- block_a <- block_b
+         *               ^
+         *               ------ fork_1 <- fork_1_1
+         *               ------ fork_2
+         */
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17441);
+        let (_server_handle, mut miner, _, blockchain, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        assert!(miner.mine_on(&generate_random_hash()).is_none());
+        let genesis = blockchain.lock().unwrap().tip();
+        let block_a = miner.mine_on(&genesis).unwrap();
+        let block_b = miner.mine_on(&block_a.hash).unwrap();
+        assert_eq!(block_b.hash, blockchain.lock().unwrap().tip());
+
+        // two competing blocks on the same non-tip parent
+        let fork_1 = miner.mine_on(&block_a.hash).unwrap();
+        let fork_2 = miner.mine_on(&block_a.hash).unwrap();
+        assert_ne!(fork_1.hash, fork_2.hash);
+        let chain = blockchain.lock().unwrap();
+        assert_eq!(block_b.hash, chain.tip());
+        let mut side_tips: Vec<H256> = chain.side_branch_tips().into_iter().map(|(h, _)| h).collect();
+        side_tips.sort();
+        let mut forks = vec![fork_1.hash, fork_2.hash];
+        forks.sort();
+        assert_eq!(forks, side_tips);
+        drop(chain);
+
+        // extending one of them takes over
+        let fork_1_1 = miner.mine_on(&fork_1.hash).unwrap();
+        assert_eq!(fork_1_1.hash, blockchain.lock().unwrap().tip());
+    }
+
+    #[test]
+    fn test_payout_rotation() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17422);
+        let (_server_handle, mut miner, _, blockchain, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let difficulty: H256 = gen_difficulty_array(0).into();
+        miner.change_difficulty(&difficulty);
+        let addrs = vec![generate_random_h160(), generate_random_h160(), generate_random_h160()];
+        miner.set_payout_addrs(addrs.clone());
+
+        for i in 0..6 {
+            assert!(miner.mining());
+            let chain = blockchain.lock().unwrap();
+            let block = chain.get_block(&chain.tip()).unwrap();
+            assert_eq!(i + 2, chain.length());
+            let coinbase = &block.content.trans[0].transaction;
+            assert_eq!(addrs[i % addrs.len()], coinbase.outputs[0].rec_address);
+        }
+    }
+
+    #[test]
+    fn test_idle_on_empty_mempool() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17406);
+        let (server, _, _, blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        let (miner_ctx, miner_handle) = miner::new(server, blockchain.clone(), mempool.clone(),
+                                                   Arc::new(key_pair::random()));
+        miner_ctx.start();
+        miner_handle.start(0);
+
+        // no busy mining of empty blocks, the loop only wakes up once per backoff
+        thread::sleep(time::Duration::from_millis(100));
+        let wakeups = miner_handle.wakeups();
+        thread::sleep(time::Duration::from_millis(300));
+        assert!(miner_handle.wakeups() - wakeups <= 1, "{} wakeups while idle", miner_handle.wakeups() - wakeups);
+        assert_eq!(1, blockchain.lock().unwrap().length());
+
+        // wake up well before backoff ends
+        let start = time::Instant::now();
+        let tran = generate_random_signed_transaction();
+        assert!(mempool.lock().unwrap().add_with_check(&tran));
+        loop {
+            if blockchain.lock().unwrap().length() > 1 {
+                break;
+            }
+            assert!(start.elapsed() < time::Duration::from_millis(MINER_IDLE_BACKOFF / 2));
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        let chain = blockchain.lock().unwrap();
+        let block = chain.get_block(&chain.tip()).unwrap();
+        assert!(block.content.get_trans_hashes().contains(&tran.hash));
+        miner_handle.exit();
+    }
+
+    #[test]
+    fn test_paused_unsubscribed() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17469);
+        let (server, _, _, blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (mut miner, handle) = miner::new(server, blockchain.clone(), mempool.clone(),
+                                             Arc::new(key_pair::random()));
+        let add_trans = |n: usize| {
+            for _ in 0..n {
+                assert!(mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction()));
+            }
+        };
+
+        // events don't pile up before the first start
+        add_trans(10);
+        assert_eq!(0, miner.events.len());
+        handle.start(0);
+        miner.handle_control_signal(miner.control_chan.try_recv().unwrap());
+        add_trans(10);
+        assert_eq!(10, miner.events.len());
+
+        // nor while paused
+        handle.pause();
+        miner.handle_control_signal(miner.control_chan.try_recv().unwrap());
+        add_trans(10);
+        assert_eq!(0, miner.events.len());
+        assert!(miner.events.try_recv().is_err());
+
+        // a paused miner thread stays asleep while transactions arrive
+        miner.start();
+        thread::sleep(time::Duration::from_millis(50));
+        let wakeups = handle.wakeups();
+        add_trans(10);
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(wakeups, handle.wakeups());
+        assert_eq!(1, blockchain.lock().unwrap().length());
+        handle.exit();
+    }
+
+    #[test]
+    fn test_min_peers() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17451);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17452);
+        let (server_1, _, _, blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, _, _, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        let (mut miner_ctx, miner_handle) = miner::new(server_1, blockchain.clone(), mempool.clone(),
+                                                       Arc::new(key_pair::random()));
+        miner_ctx.set_min_peers(1);
+        miner_ctx.start();
+        miner_handle.start(0);
+        assert!(mempool.lock().unwrap().add_with_check(&generate_random_signed_transaction()));
+
+        // isolated, nothing is mined
+        thread::sleep(time::Duration::from_millis(300));
+        assert_eq!(1, blockchain.lock().unwrap().length());
+
+        // mining begins once connected
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        let start = time::Instant::now();
+        while blockchain.lock().unwrap().length() == 1 {
+            assert!(start.elapsed() < time::Duration::from_millis(3 * MINER_IDLE_BACKOFF));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        miner_handle.exit();
+    }
+
     #[test]
     fn test_block_relay() {
         let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17011);
@@ -284,9 +811,9 @@ pub mod tests {
         let (_server_1, mut miner_ctx_1, _, blockchain_1, _mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
         let (server_2, mut miner_ctx_2, _, blockchain_2, _mempool_2, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
         let (server_3, mut miner_ctx_3, _, blockchain_3, _mempool_3, _, _) = new_server_env(p2p_addr_3, Spreader::Default, false);
-        blockchain_1.lock().unwrap().set_check_trans(false);
-        blockchain_2.lock().unwrap().set_check_trans(false);
-        blockchain_3.lock().unwrap().set_check_trans(false);
+        blockchain_1.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain_2.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain_3.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
 
         // bilateral connection!!
         let peers_1 = vec![p2p_addr_1];
@@ -356,7 +883,7 @@ pub mod tests {
         // test get missing parent
         let mut chain_1 = blockchain_1.lock().unwrap();
         let new_block_1 = generate_mined_block(&chain_1.tip(), &difficulty);
-        chain_1.insert(&new_block_1);
+        chain_1.insert(&new_block_1).unwrap();
         drop(chain_1);
         assert_eq!(5, blockchain_1.lock().unwrap().length());
         assert_eq!(4, blockchain_2.lock().unwrap().length());
@@ -373,10 +900,64 @@ pub mod tests {
         let mut chain_1 = blockchain_1.lock().unwrap();
         let wrong_difficulty: H256 = gen_difficulty_array(1).into();
         let wrong_block = generate_mined_block(&chain_1.tip(), &wrong_difficulty);
-        assert!(!chain_1.insert_with_check(&wrong_block));
-        assert!(!chain_1.insert_with_check(&new_block_1));
+        assert_eq!(Err(BlockError::DifficultyMismatch), chain_1.insert_with_check(&wrong_block));
+        assert_eq!(Err(BlockError::AlreadyExists), chain_1.insert_with_check(&new_block_1));
         let correct_difficulty: H256 = gen_difficulty_array(EASIEST_DIF).into();
         let correct_block = generate_mined_block(&chain_1.tip(), &correct_difficulty);
-        assert!(chain_1.insert_with_check(&correct_block));
+        assert_eq!(Ok(InsertOutcome::Extended), chain_1.insert_with_check(&correct_block));
+    }
+
+    struct CaptureLogger;
+
+    lazy_static! {
+        // (thread, level, message) of miner logs
+        static ref CAPTURED: Mutex<Vec<(thread::ThreadId, Level, String)>> = Mutex::new(vec![]);
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == miner::LOG_TARGET
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                CAPTURED.lock().unwrap().push((thread::current().id(), record.level(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_log_levels() {
+        static LOGGER: CaptureLogger = CaptureLogger;
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Trace);
+        }
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17421);
+        let (_server, mut miner_ctx, _, blockchain, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        let captured = || -> Vec<(Level, String)> {
+            CAPTURED.lock().unwrap().iter()
+                .filter(|(id, _, _)| *id == thread::current().id())
+                .map(|(_, level, msg)| (*level, msg.clone()))
+                .collect()
+        };
+
+        // routine mining is below info
+        for _ in 0..3 {
+            let chain = blockchain.lock().unwrap();
+            let block = generate_mined_block(&chain.tip(), &chain.difficulty());
+            drop(chain);
+            miner_ctx.found(block);
+        }
+        let logs = captured();
+        assert_eq!(3, logs.iter().filter(|(_, msg)| msg.starts_with("Mined a block")).count());
+        assert!(logs.iter().all(|(level, _)| *level > Level::Info));
+
+        // shutdown is significant
+        miner_ctx.handle_control_signal(miner::ControlSignal::Exit);
+        let logs = captured();
+        assert!(logs.iter().any(|(level, msg)| *level == Level::Info && msg == "Miner shutting down"));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::SystemTime;
+
+// Addresses of peers we have connected to, saved to disk so they can be reconnected after restart
+pub struct AddrMan {
+    addrs: HashMap<SocketAddr, u64>,  // address -> time(ms since epoch) of the last successful connection
+    capacity: usize,
+    max_age: u64,  // time(ms) after which an address is considered stale
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+impl AddrMan {
+    pub fn new(capacity: usize, max_age: u64) -> Self {
+        Self {
+            addrs: HashMap::new(),
+            capacity,
+            max_age,
+        }
+    }
+
+    // Remember a successfully-connected peer, dropping the least recently seen beyond capacity
+    pub fn record(&mut self, addr: SocketAddr) {
+        self.addrs.insert(addr, now_ms());
+        self.expire();
+    }
+
+    // Known addresses, most recently seen first
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &u64)> = self.addrs.iter().collect();
+        addrs.sort_by(|x, y| y.1.cmp(x.1).then(x.0.cmp(y.0)));
+        addrs.into_iter().map(|(addr, _)| *addr).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    // Write all addresses to a file, return the number of addresses written
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let addrs: Vec<(&SocketAddr, &u64)> = self.addrs.iter().collect();
+        let writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(writer, &addrs)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(addrs.len())
+    }
+
+    // Read addresses saved by save_to, dropping stale ones; return the number of addresses kept
+    pub fn load_from<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let reader = BufReader::new(File::open(path)?);
+        let addrs: Vec<(SocketAddr, u64)> = bincode::deserialize_from(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for (addr, last_seen) in addrs.into_iter() {
+            let seen = self.addrs.entry(addr).or_insert(last_seen);
+            *seen = std::cmp::max(*seen, last_seen);
+        }
+        self.expire();
+        Ok(self.addrs.len())
+    }
+
+    // Drop stale addresses, then the least recently seen ones beyond capacity
+    fn expire(&mut self) {
+        let now = now_ms();
+        let max_age = self.max_age;
+        self.addrs.retain(|_, last_seen| now.saturating_sub(*last_seen) <= max_age);
+        if self.addrs.len() > self.capacity {
+            let keep: Vec<SocketAddr> = self.addrs().into_iter().take(self.capacity).collect();
+            self.addrs.retain(|addr, _| keep.contains(addr));
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::helper::*;
+    use crate::spread::Spreader;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_expire() {
+        let addr = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let mut addrman = AddrMan::new(2, 1000);
+        addrman.record(addr(1111));
+        addrman.addrs.insert(addr(2222), now_ms() - 10);
+        assert_eq!(vec![addr(1111), addr(2222)], addrman.addrs());
+
+        // the least recently seen is dropped beyond capacity
+        addrman.record(addr(3333));
+        assert_eq!(2, addrman.len());
+        assert!(!addrman.addrs().contains(&addr(2222)));
+
+        // stale address is dropped
+        addrman.addrs.insert(addr(1111), now_ms() - 2000);
+        addrman.record(addr(3333));
+        assert_eq!(vec![addr(3333)], addrman.addrs());
+    }
+
+    #[test]
+    fn test_reconnect_after_restart() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17426);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17427);
+        let p2p_addr_3 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17428);
+        let unreachable = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17429);
+        let (_server_1, _, _, _, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, _, _, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        let path = std::env::temp_dir().join(format!("peers_{}.dat", generate_random_str()));
+
+        // only peers actually connected to are recorded
+        let mut addrman = AddrMan::new(10, 60000);
+        for addr in connect_peers(&server_2, &vec![p2p_addr_1, unreachable]) {
+            addrman.record(addr);
+        }
+        assert_eq!(1, addrman.save_to(&path).unwrap());
+        drop(server_2);
+
+        // restarted node knows nothing but the saved file
+        let (server_3, _, _, _, _, _, _) = new_server_env(p2p_addr_3, Spreader::Default, false);
+        let mut addrman = AddrMan::new(10, 60000);
+        assert_eq!(1, addrman.load_from(&path).unwrap());
+        assert_eq!(vec![p2p_addr_1], connect_peers(&server_3, &addrman.addrs()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::crypto::hash::H256;
+
+// Bounded record of block hashes requested from peers that haven't arrived yet,
+// so the same hash announced by many peers is fetched only once
+pub struct InFlight {
+    requested: HashMap<H256, Instant>,
+    order: VecDeque<(H256, Instant)>,  // oldest request first, may hold entries already cleared
+    capacity: usize,
+    timeout: Duration,
+}
+
+impl InFlight {
+    pub fn new(capacity: usize, timeout_ms: u64) -> Self {
+        Self {
+            requested: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            timeout: Duration::from_millis(timeout_ms),
+        }
+    }
+
+    // Mark hashes as requested, return those not already in flight (or whose request timed out)
+    pub fn request(&mut self, hashes: Vec<H256>) -> Vec<H256> {
+        let now = Instant::now();
+        let mut to_get = vec![];
+        for hash in hashes.into_iter() {
+            if let Some(since) = self.requested.get(&hash) {
+                if now.duration_since(*since) < self.timeout {
+                    continue;
+                }
+            }
+            self.requested.insert(hash.clone(), now);
+            self.order.push_back((hash.clone(), now));
+            to_get.push(hash);
+        }
+        self.evict();
+        to_get
+    }
+
+    // Clear a hash once its block arrives
+    pub fn arrived(&mut self, hash: &H256) {
+        self.requested.remove(hash);
+    }
+
+    pub fn len(&self) -> usize {
+        self.requested.len()
+    }
+
+    // Drop the oldest requests beyond capacity
+    fn evict(&mut self) {
+        while self.requested.len() > self.capacity || self.order.len() > 2 * self.capacity {
+            match self.order.pop_front() {
+                Some((hash, since)) => {
+                    if self.requested.get(&hash) == Some(&since) {
+                        self.requested.remove(&hash);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::helper::generate_random_hash;
+
+    use std::thread;
+
+    #[test]
+    fn test_in_flight() {
+        let mut in_flight = InFlight::new(2, 100);
+        let (h1, h2, h3) = (generate_random_hash(), generate_random_hash(), generate_random_hash());
+        assert_eq!(vec![h1, h2], in_flight.request(vec![h1, h2]));
+        assert!(in_flight.request(vec![h1, h2]).is_empty());
+
+        // arrived block can be requested again
+        in_flight.arrived(&h1);
+        assert_eq!(vec![h1], in_flight.request(vec![h1]));
+
+        // the oldest request is dropped beyond capacity
+        assert_eq!(vec![h3], in_flight.request(vec![h3]));
+        assert_eq!(2, in_flight.len());
+        assert_eq!(vec![h2], in_flight.request(vec![h2]));
+
+        // timed out requests are retried
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(vec![h3], in_flight.request(vec![h3]));
+    }
+}
@@ -1,10 +1,22 @@
 use serde::{Serialize, Deserialize};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{Read, Write};
 
-use crate::block::Block;
+use crate::block::{Block, Header, State};
 use crate::crypto::hash::{H256, H160};
 use crate::transaction::SignedTransaction;
+use crate::config::MAX_MESSAGE_BYTES;
 use ring::signature::ED25519_PUBLIC_KEY_LEN;
 
+// Feature bits exchanged in the Version handshake
+pub const FEATURE_COMPRESSION: u64 = 1;
+
+pub const FEATURE_HEADERS: u64 = 2;  // new blocks are announced by Headers instead of NewBlockHashes
+
+pub const LOCAL_FEATURES: u64 = FEATURE_COMPRESSION | FEATURE_HEADERS;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Ping(String),
@@ -18,4 +30,181 @@ pub enum Message {
     NewPeers(Vec<(H160, Box<[u8; ED25519_PUBLIC_KEY_LEN]>, u16)>),
     Introduce((H160, Box<[u8; ED25519_PUBLIC_KEY_LEN]>, u16)),
     NewDandelionTransactions(Vec<SignedTransaction>),
+    GetSnapshot(H256),
+    Snapshot(Block, State),
+    Version(u64, H256),  // feature bits supported by the sender, hash of its genesis header
+    Compressed(Vec<u8>),  // deflated bincode of another message
+    Reject(String),  // reason the sender is dropping the connection
+    Headers(Vec<Header>),  // headers of new blocks, for peers supporting FEATURE_HEADERS
+    GetMempool,  // ask for the hashes of pending transactions, answered by NewTransactionHashes
+    FeeFilter(u64),  // minimum fee rate(fee per byte) of transactions the sender wants announced
+    SendCompact(bool),  // true: push new blocks to the sender unsolicited (high-bandwidth), false: announce only
+    GetHeaders(Vec<H256>),  // block locator of the sender, answered by Headers of the blocks it lacks
+    // bincode tells variants apart by index, new variants go here at the end
+}
+
+impl Message {
+    // Whether this message carries blocks or transactions, which are worth compressing
+    pub fn is_compressible(&self) -> bool {
+        match self {
+            Message::Blocks(_) | Message::Transactions(_) | Message::NewDandelionTransactions(_) => true,
+            _ => false,
+        }
+    }
+
+    // Wrap a serialized message into a Compressed message
+    pub fn compress(raw: &[u8]) -> Message {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(raw).unwrap();
+        Message::Compressed(encoder.finish().unwrap())
+    }
+
+    // Unwrap a Compressed message, other messages are returned as they are
+    // Data inflating beyond MAX_MESSAGE_BYTES is rejected
+    pub fn decompress(self) -> Result<Message, Box<bincode::ErrorKind>> {
+        match self {
+            Message::Compressed(data) => {
+                let mut raw = Vec::new();
+                DeflateDecoder::new(&data[..]).take(MAX_MESSAGE_BYTES as u64 + 1).read_to_end(&mut raw)?;
+                if raw.len() > MAX_MESSAGE_BYTES {
+                    return Err(Box::new(bincode::ErrorKind::SizeLimit));
+                }
+                bincode::deserialize(&raw)
+            }
+            msg => Ok(msg),
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::Content;
+    use crate::config::COMPRESSION_THRESHOLD;
+    use crate::crypto::key_pair;
+    use crate::crypto::hash::Hashable;
+    use crate::helper::*;
+    use crate::network::server;
+    use crate::mempool::MemPool;
+    use crate::lock_order::OrderedMutex;
+    use crate::spread::Spreader;
+    use crate::transaction::{TxInput, TxOutput};
+
+    use crossbeam::channel as cbchannel;
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    // A block full of transactions from the same account, like a busy wallet would produce
+    fn generate_large_block() -> Block {
+        let key = key_pair::random();
+        let funding = generate_random_hash();
+        let rec_addr = generate_random_h160();
+        let mut content = Content::new();
+        for i in 0..200 {
+            let input = TxInput::new(funding.clone(), i);
+            let output = TxOutput::new(rec_addr.clone(), 10);
+            content.add_tran(generate_signed_transaction(&key, vec![input], vec![output]));
+        }
+        let header = generate_random_header(&generate_random_hash(), &content);
+        Block::new(header, content)
+    }
+
+    #[test]
+    fn test_variant_indices() {
+        // peers running other versions decode messages by these indices, they must never change
+        let index = |msg: Message| -> u32 {
+            bincode::deserialize(&bincode::serialize(&msg).unwrap()[..4]).unwrap()
+        };
+        let key = || Box::new([0u8; ED25519_PUBLIC_KEY_LEN]);
+        assert_eq!(0, index(Message::Ping(String::new())));
+        assert_eq!(1, index(Message::Pong(String::new())));
+        assert_eq!(2, index(Message::NewBlockHashes(vec![])));
+        assert_eq!(3, index(Message::GetBlocks(vec![])));
+        assert_eq!(4, index(Message::Blocks(vec![])));
+        assert_eq!(5, index(Message::NewTransactionHashes(vec![])));
+        assert_eq!(6, index(Message::GetTransactions(vec![])));
+        assert_eq!(7, index(Message::Transactions(vec![])));
+        assert_eq!(8, index(Message::NewPeers(vec![])));
+        assert_eq!(9, index(Message::Introduce((generate_random_h160(), key(), 0))));
+        assert_eq!(10, index(Message::NewDandelionTransactions(vec![])));
+        assert_eq!(11, index(Message::GetSnapshot(generate_random_hash())));
+        assert_eq!(12, index(Message::Snapshot(Block::genesis(), State::new())));
+        assert_eq!(13, index(Message::Version(0, generate_random_hash())));
+        assert_eq!(14, index(Message::Compressed(vec![])));
+        assert_eq!(15, index(Message::Reject(String::new())));
+        assert_eq!(16, index(Message::Headers(vec![])));
+        assert_eq!(17, index(Message::GetMempool));
+        assert_eq!(18, index(Message::FeeFilter(0)));
+        assert_eq!(19, index(Message::SendCompact(false)));
+        assert_eq!(20, index(Message::GetHeaders(vec![])));
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let block = generate_large_block();
+        let msg = Message::Blocks(vec![block.clone()]);
+        assert!(msg.is_compressible());
+        let raw = bincode::serialize(&msg).unwrap();
+        assert!(raw.len() > COMPRESSION_THRESHOLD);
+
+        let compressed = bincode::serialize(&Message::compress(&raw)).unwrap();
+        assert!(compressed.len() < raw.len());
+
+        let decoded: Message = bincode::deserialize(&compressed).unwrap();
+        match decoded.decompress().unwrap() {
+            Message::Blocks(blocks) => {
+                assert_eq!(1, blocks.len());
+                assert_eq!(block.hash(), blocks[0].hash());
+                assert_eq!(block.content.trans.len(), blocks[0].content.trans.len());
+            }
+            m => panic!("expect Blocks, got {:?}", m),
+        }
+        // uncompressed messages pass through
+        assert!(matches!(Message::Ping("a".to_string()).decompress(), Ok(Message::Ping(_))));
+        assert!(Message::Compressed(vec![1, 2, 3]).decompress().is_err());
+    }
+
+    #[test]
+    fn test_compress_on_wire() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17407);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17408);
+        let (sender_1, receiver_1) = cbchannel::unbounded();
+        let (sender_2, receiver_2) = cbchannel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (ctx_1, _server_1, _) = server::new(p2p_addr_1, sender_1, Spreader::Default, mempool.clone()).unwrap();
+        let (ctx_2, server_2, _) = server::new(p2p_addr_2, sender_2, Spreader::Default, mempool.clone()).unwrap();
+        ctx_1.start().unwrap();
+        ctx_2.start().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+        let peer_1 = server_2.connect(p2p_addr_1).unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+
+        // before the handshake is processed, server_2 doesn't know server_1 supports compression
+        let block = generate_large_block();
+        let raw_len = bincode::serialize(&Message::Blocks(vec![block.clone()])).unwrap().len();
+        peer_1.write(Message::Blocks(vec![block.clone()]));
+        let (_, _) = receiver_1.recv_timeout(time::Duration::from_secs(1)).unwrap();  // Version
+        let (msg, _) = receiver_1.recv_timeout(time::Duration::from_secs(1)).unwrap();
+        assert_eq!(raw_len, msg.len());
+
+        // what a worker does on server_2 when receiving server_1's Version
+        let (msg, _) = receiver_2.recv_timeout(time::Duration::from_secs(1)).unwrap();
+        match bincode::deserialize(&msg).unwrap() {
+            Message::Version(features, _) => peer_1.set_features(features),
+            m => panic!("expect Version, got {:?}", m),
+        }
+        assert!(peer_1.supports(FEATURE_COMPRESSION));
+
+        peer_1.write(Message::Blocks(vec![block.clone()]));
+        let (msg, _) = receiver_1.recv_timeout(time::Duration::from_secs(1)).unwrap();
+        assert!(msg.len() < raw_len);
+        let msg: Message = bincode::deserialize(&msg).unwrap();
+        assert!(matches!(msg, Message::Compressed(_)));
+        match msg.decompress().unwrap() {
+            Message::Blocks(blocks) => assert_eq!(block.hash(), blocks[0].hash()),
+            m => panic!("expect Blocks, got {:?}", m),
+        }
+    }
 }
@@ -2,4 +2,6 @@ pub mod message;
 pub mod peer;
 pub mod server;
 pub mod worker;
+pub mod inflight;
 pub mod estimator;
+pub mod addrman;
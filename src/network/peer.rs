@@ -1,10 +1,13 @@
 use super::message;
+use crate::config::{COMPRESSION_THRESHOLD, MAX_MESSAGE_BYTES};
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
 use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 enum DecodeState {
     Length,
@@ -45,6 +48,11 @@ impl ReadContext {
                         DecodeState::Length => {
                             let message_length =
                                 u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                            // refuse before allocating for the payload
+                            if message_length as usize > MAX_MESSAGE_BYTES {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                    format!("message length {} exceeds limit {}", message_length, MAX_MESSAGE_BYTES)));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
                             self.msg_length = message_length as usize;
@@ -159,6 +167,7 @@ pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
     key: usize,
+    high_bandwidth_peers: Arc<AtomicUsize>,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
@@ -186,6 +195,10 @@ pub fn new(
         write_queue: write_sender,
         addr,
         key,
+        features: Arc::new(AtomicU64::new(0)),
+        fee_filter: Arc::new(AtomicU64::new(0)),
+        high_bandwidth: Arc::new(AtomicBool::new(false)),
+        high_bandwidth_peers,
     };
     let ctx = Context {
         addr,
@@ -218,14 +231,56 @@ pub struct Handle {
     pub addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
     pub key: usize,
+    features: Arc<AtomicU64>,  // feature bits the peer announced in its Version message
+    fee_filter: Arc<AtomicU64>,  // minimum fee rate the peer announced in its FeeFilter message, 0 if none
+    high_bandwidth: Arc<AtomicBool>,  // the peer asked by SendCompact for new blocks pushed unsolicited
+    high_bandwidth_peers: Arc<AtomicUsize>,  // shared by the peers of a server, number of them with high_bandwidth set
 }
 
 impl Handle {
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
+        let mut buffer = bincode::serialize(&msg).unwrap();
+        if msg.is_compressible() && buffer.len() > COMPRESSION_THRESHOLD
+            && self.supports(message::FEATURE_COMPRESSION) {
+            let compressed = bincode::serialize(&message::Message::compress(&buffer)).unwrap();
+            trace!("Compressed message for peer {} from {} to {} bytes", self.addr, buffer.len(), compressed.len());
+            if compressed.len() < buffer.len() {
+                buffer = compressed;
+            }
+        }
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
+
+    pub fn set_features(&self, features: u64) {
+        self.features.store(features, Ordering::SeqCst);
+    }
+
+    pub fn supports(&self, feature: u64) -> bool {
+        self.features.load(Ordering::SeqCst) & feature != 0
+    }
+
+    pub fn set_fee_filter(&self, min_fee_rate: u64) {
+        self.fee_filter.store(min_fee_rate, Ordering::SeqCst);
+    }
+
+    pub fn fee_filter(&self) -> u64 {
+        self.fee_filter.load(Ordering::SeqCst)
+    }
+
+    pub fn set_high_bandwidth(&self, high_bandwidth: bool) {
+        if self.high_bandwidth.swap(high_bandwidth, Ordering::SeqCst) != high_bandwidth {
+            if high_bandwidth {
+                self.high_bandwidth_peers.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.high_bandwidth_peers.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn is_high_bandwidth(&self) -> bool {
+        self.high_bandwidth.load(Ordering::SeqCst)
+    }
 }
@@ -2,14 +2,22 @@ use super::message;
 use super::peer::{self, ReadResult, WriteResult};
 use crate::spread;
 use crate::mempool::MemPool;
+use crate::lock_order::OrderedMutex;
+use crate::block::{Block, Header};
+use crate::crypto::hash::H256;
 
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
+use std::collections::HashMap;
 use std::sync::mpsc;
-use std::sync::{Mutex, Arc};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time;
+
+use crate::config::{CONNECT_TIMEOUT, INV_BATCH_SIZE, INV_FLUSH_INTERVAL};
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
@@ -18,11 +26,13 @@ pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
     spread_type: spread::Spreader,
-    mempool: Arc<Mutex<MemPool>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
 ) -> std::io::Result<(Context, Handle, spread::Context)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
+    let high_bandwidth_peers = Arc::new(AtomicUsize::new(0));
     let handle = Handle {
-        control_chan: control_signal_sender,
+        control_chan: control_signal_sender.clone(),
+        high_bandwidth_peers: high_bandwidth_peers.clone(),
     };
     let (spreader, spread_ctx) = spread::get_spreader(spread_type, mempool, handle.clone());
     let ctx = Context {
@@ -31,8 +41,16 @@ pub fn new(
         addr,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
+        control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
         spreader,
+        connect_timeout: CONNECT_TIMEOUT,
+        genesis: Block::genesis().header.hash(),
+        features: message::LOCAL_FEATURES,
+        pending_invs: HashMap::new(),
+        pending_inv_count: 0,
+        inv_deadline: None,
+        high_bandwidth_peers,
     };
     Ok((ctx, handle, spread_ctx))
 }
@@ -43,15 +61,39 @@ pub struct Context {
     addr: std::net::SocketAddr,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
+    control_sender: channel::Sender<ControlSignal>,  // for connecting threads to hand streams back
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
     spreader: Box<dyn spread::Spreading + Send>,
+    connect_timeout: u64,  // time(ms) to wait for an outgoing connection
+    genesis: H256,  // hash of our genesis header, announced in Version so peers on another network hang up
+    features: u64,  // feature bits announced in Version
+    pending_invs: HashMap<Option<usize>, Vec<H256>>,  // transaction hashes waiting to be announced, by source peer
+    pending_inv_count: usize,
+    inv_deadline: Option<time::Instant>,  // when pending transaction hashes are announced
+    high_bandwidth_peers: Arc<AtomicUsize>,  // number of peers that asked for new blocks pushed unsolicited
 }
 
 impl Context {
-    /// Start a new server context.
+    // Set the time(ms) to wait for an outgoing connection before giving up
+    pub fn set_connect_timeout(&mut self, connect_timeout: u64) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    // Set the hash of the genesis header announced to peers, see Blockchain::genesis_id
+    pub fn set_genesis(&mut self, genesis: H256) {
+        self.genesis = genesis;
+    }
+
+    // Set the feature bits announced to peers, LOCAL_FEATURES by default
+    pub fn set_features(&mut self, features: u64) {
+        self.features = features;
+    }
+
+    /// Start a new server context. The address is bound before returning, so peers can connect at once.
     pub fn start(mut self) -> std::io::Result<()> {
+        let server = net::TcpListener::bind(&self.addr)?;
         thread::spawn(move || {
-            self.listen().unwrap_or_else(|e| {
+            self.listen(server).unwrap_or_else(|e| {
                 error!("P2P server error: {}", e);
             });
         });
@@ -86,7 +128,7 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction, key)?;
+        let (ctx, handle) = peer::new(stream, direction, key, self.high_bandwidth_peers.clone())?;
 
         // register the writer queue
         self.poll.register(
@@ -95,6 +137,8 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
+        // announce our features and network, the peer checks them on its Version handler
+        handle.write(message::Message::Version(self.features, self.genesis));
 
         // insert the context and return the handle
         vacant.insert(ctx);
@@ -104,11 +148,45 @@ impl Context {
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+    /// Connect to a peer in a separate thread, so a slow peer doesn't stall the event loop.
+    /// The connected stream is handed back through the control channel and registered there.
+    fn connect(&mut self, req: ConnectRequest) {
+        if self.is_own_addr(&req.addr) {
+            warn!("Refusing to connect to own address {}", req.addr);
+            let _ = req.result_chan.send(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is this node's own address", req.addr),
+            )));
+            return;
+        }
+        debug!("Establishing connection to peer {}", req.addr);
+        let timeout = time::Duration::from_millis(self.connect_timeout);
+        let control_chan = self.control_sender.clone();
+        thread::spawn(move || {
+            match std::net::TcpStream::connect_timeout(&req.addr, timeout) {
+                Ok(stream) => {
+                    if control_chan.send(ControlSignal::RegisterOutgoing(stream, req)).is_err() {
+                        warn!("P2P server dropped before connection was registered");
+                    }
+                }
+                Err(e) => {
+                    let _ = req.result_chan.send(Err(e));
+                }
+            }
+        });
+    }
+
+    /// Whether addr is the address this server listens at
+    fn is_own_addr(&self, addr: &std::net::SocketAddr) -> bool {
+        if addr.port() != self.addr.port() {
+            return false;
+        }
+        // a server bound to all interfaces is also reached through loopback and its own ip
+        addr.ip() == self.addr.ip() || (self.addr.ip().is_unspecified() && addr.ip().is_loopback())
+    }
+
+    /// Register a connected outgoing stream
+    fn register_outgoing(&mut self, stream: std::net::TcpStream) -> std::io::Result<peer::Handle> {
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -135,13 +213,21 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
-                req.result_chan.send(handle).unwrap();
+                self.connect(req);
+            }
+            ControlSignal::RegisterOutgoing(stream, req) => {
+                trace!("Processing RegisterOutgoing command");
+                let handle = self.register_outgoing(stream);
+                // the caller of connect_async may have given up waiting
+                let _ = req.result_chan.send(handle);
             }
             ControlSignal::BroadcastMessage(msg, src_peer_key) => {
                 trace!("Processing BroadcastMessage command");
                 match msg {
-                    message::Message::NewTransactionHashes(_) | message::Message::NewDandelionTransactions(_) => {
+                    message::Message::NewTransactionHashes(hashes) => {
+                        self.queue_inv(hashes, src_peer_key);
+                    }
+                    message::Message::NewDandelionTransactions(_) => {
                         // only set delay for this message
                         self.spreader.spread(&self.peers, &self.peer_list, msg, src_peer_key);
                     }
@@ -153,10 +239,93 @@ impl Context {
                     }
                 }
             }
+            ControlSignal::RelayBlocks(blocks, src_peer_key) => {
+                trace!("Processing RelayBlocks command");
+                let headers: Vec<Header> = blocks.iter().map(|b| b.header.clone()).collect();
+                self.relay(headers, Some(blocks), src_peer_key);
+            }
+            ControlSignal::RelayHeaders(headers, src_peer_key) => {
+                trace!("Processing RelayHeaders command");
+                self.relay(headers, None, src_peer_key);
+            }
+            ControlSignal::Disconnect(peer_id) => {
+                trace!("Processing Disconnect command");
+                // the peer may be gone already, or its slot reused by a newer connection
+                if let Some(peer) = self.peers.get_mut(peer_id) {
+                    info!("Disconnecting peer {}", peer.addr);
+                    // send what was queued before hanging up, e.g. the reason for it
+                    let _ = peer.writer.write();
+                    self.remove_peer(peer_id);
+                }
+            }
+            ControlSignal::PeerCount(result_chan) => {
+                let _ = result_chan.send(self.peer_list.len());
+            }
+            ControlSignal::Shutdown(_) => unreachable!("handled by the event loop"),
         }
         Ok(())
     }
 
+    // Queue transaction hashes to announce, so hashes arriving close together go out in one message
+    // after INV_FLUSH_INTERVAL, or at once when a batch is full
+    fn queue_inv(&mut self, hashes: Vec<H256>, src_peer_key: Option<usize>) {
+        if hashes.is_empty() {
+            return;
+        }
+        self.pending_inv_count += hashes.len();
+        self.pending_invs.entry(src_peer_key).or_insert_with(Vec::new).extend(hashes);
+        if self.inv_deadline.is_none() {
+            self.inv_deadline = Some(time::Instant::now() + time::Duration::from_millis(INV_FLUSH_INTERVAL));
+        }
+        if self.pending_inv_count >= INV_BATCH_SIZE {
+            self.flush_invs();
+        }
+    }
+
+    // Announce pending transaction hashes, at most INV_BATCH_SIZE in a message
+    fn flush_invs(&mut self) {
+        for (src_peer_key, hashes) in self.pending_invs.drain() {
+            for batch in hashes.chunks(INV_BATCH_SIZE) {
+                let msg = message::Message::NewTransactionHashes(batch.to_vec());
+                self.spreader.spread(&self.peers, &self.peer_list, msg, src_peer_key);
+            }
+        }
+        self.pending_inv_count = 0;
+        self.inv_deadline = None;
+    }
+
+    /// Announce new blocks to every peer but the source: whole blocks to high-bandwidth peers when we have
+    /// them, headers to peers supporting them and hashes to the others
+    fn relay(&self, headers: Vec<Header>, blocks: Option<Vec<Block>>, src_peer_key: Option<usize>) {
+        let hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        for peer_id in &self.peer_list {
+            if Some(*peer_id) == src_peer_key {
+                continue;
+            }
+            let handle = &self.peers[*peer_id].handle;
+            // there is no compact encoding yet, high-bandwidth peers get whole blocks
+            match &blocks {
+                Some(blocks) if handle.is_high_bandwidth() => {
+                    handle.write(message::Message::Blocks(blocks.clone()));
+                }
+                _ if handle.supports(message::FEATURE_HEADERS) => {
+                    handle.write(message::Message::Headers(headers.clone()));
+                }
+                _ => {
+                    handle.write(message::Message::NewBlockHashes(hashes.clone()));
+                }
+            }
+        }
+    }
+
+    /// Remove a peer from the connections set, closing its socket
+    fn remove_peer(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        peer.handle.set_high_bandwidth(false);  // no longer counted
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -180,9 +349,7 @@ impl Context {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -192,8 +359,16 @@ impl Context {
                 }
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
-                    // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    // we just received a full message, drop it if workers can't keep up
+                    match self.new_msg_chan.try_send((m, peer.handle.clone())) {
+                        Ok(()) => {}
+                        Err(cbchannel::TrySendError::Full(_)) => {
+                            warn!("Worker queue is full, dropping message from peer {}", peer.addr);
+                        }
+                        Err(cbchannel::TrySendError::Disconnected(_)) => {
+                            warn!("Worker queue detached, dropping message from peer {}", peer.addr);
+                        }
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -203,9 +378,7 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.remove_peer(peer_id);
                         break;
                     }
                 }
@@ -240,9 +413,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -262,9 +433,7 @@ impl Context {
                     // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -272,10 +441,8 @@ impl Context {
     }
 
     /// The main event loop of the server.
-    fn listen(&mut self) -> std::io::Result<()> {
-        // bind server to passed addr and register to the poll
-        let server = net::TcpListener::bind(&self.addr)?;
-
+    fn listen(&mut self, server: net::TcpListener) -> std::io::Result<()> {
+        // register the bound server to the poll
         // token for new incoming connection
         const INCOMING: mio::Token = mio::Token(std::usize::MAX - 1);
         self.poll.register(
@@ -300,7 +467,14 @@ impl Context {
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            // wake up to announce pending transaction hashes
+            let timeout = self.inv_deadline.map(|d| d.saturating_duration_since(time::Instant::now()));
+            self.poll.poll(&mut events, timeout)?;
+            if let Some(deadline) = self.inv_deadline {
+                if time::Instant::now() >= deadline {
+                    self.flush_invs();
+                }
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -310,6 +484,15 @@ impl Context {
                         loop {
                             // get the new control singal from the channel
                             match self.control_chan.try_recv() {
+                                Ok(ControlSignal::Shutdown(done)) => {
+                                    info!("P2P server shutting down, disconnecting all peers");
+                                    self.peers.clear();
+                                    self.peer_list.clear();
+                                    self.poll.deregister(&server)?;
+                                    drop(server);
+                                    let _ = done.send(());
+                                    return Ok(());
+                                }
                                 Ok(req) => {
                                     self.process_control(req).unwrap();
                                 }
@@ -369,6 +552,10 @@ impl Context {
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
+                                // the peer may have been dropped while messages to it were queued
+                                if !self.peers.contains(peer_id) {
+                                    continue;
+                                }
                                 self.register_write_interest(peer_id)?;
                             }
                             _ => unreachable!(),
@@ -383,11 +570,18 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    high_bandwidth_peers: Arc<AtomicUsize>,  // shared with the server context and its peers
 }
 
 impl Handle {
+    // Connect to a peer, blocking until connected or the connect timeout passes
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        let (sender, receiver) = cbchannel::unbounded();
+        self.connect_async(addr).recv().unwrap()
+    }
+
+    // Start connecting to a peer and return at once, the result arrives on the returned channel
+    pub fn connect_async(&self, addr: std::net::SocketAddr) -> cbchannel::Receiver<std::io::Result<peer::Handle>> {
+        let (sender, receiver) = cbchannel::bounded(1);
         let request = ConnectRequest {
             addr,
             result_chan: sender,
@@ -395,7 +589,7 @@ impl Handle {
         self.control_chan
             .send(ControlSignal::ConnectNewPeer(request))
             .unwrap();
-        receiver.recv().unwrap()
+        receiver
     }
 
     pub fn broadcast(&self, msg: message::Message, src_peer_key: Option<usize>) {
@@ -403,11 +597,63 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg, src_peer_key))
             .unwrap();
     }
+
+    // Relay new blocks to every peer but the source: pushed to high-bandwidth peers, announced to the others
+    // by headers to peers supporting them and by hashes otherwise
+    pub fn relay_blocks(&self, blocks: Vec<Block>, src_peer_key: Option<usize>) {
+        self.control_chan
+            .send(ControlSignal::RelayBlocks(blocks, src_peer_key))
+            .unwrap();
+    }
+
+    // Relay new blocks by their headers alone, for when no peer is high-bandwidth and the bodies are not needed
+    pub fn relay_headers(&self, headers: Vec<Header>, src_peer_key: Option<usize>) {
+        self.control_chan
+            .send(ControlSignal::RelayHeaders(headers, src_peer_key))
+            .unwrap();
+    }
+
+    // Whether some peer asked by SendCompact for new blocks pushed unsolicited
+    pub fn has_high_bandwidth_peers(&self) -> bool {
+        self.high_bandwidth_peers.load(Ordering::SeqCst) > 0
+    }
+
+    // Hang up on a peer after flushing messages already queued to it
+    pub fn disconnect(&self, peer_key: usize) {
+        self.control_chan
+            .send(ControlSignal::Disconnect(peer_key))
+            .unwrap();
+    }
+
+    // Close all connections and stop listening, blocking until done.
+    // Workers reading from this server exit once it is gone
+    pub fn shutdown(&self) {
+        let (sender, receiver) = cbchannel::bounded(1);
+        if self.control_chan.send(ControlSignal::Shutdown(sender)).is_err() {
+            return;  // already shut down
+        }
+        let _ = receiver.recv();
+    }
+
+    // Number of connected peers
+    pub fn peer_count(&self) -> usize {
+        let (sender, receiver) = cbchannel::bounded(1);
+        self.control_chan
+            .send(ControlSignal::PeerCount(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message, Option<usize>),
+    RegisterOutgoing(std::net::TcpStream, ConnectRequest),
+    RelayBlocks(Vec<Block>, Option<usize>),
+    RelayHeaders(Vec<Header>, Option<usize>),
+    Disconnect(usize),
+    PeerCount(cbchannel::Sender<usize>),
+    Shutdown(cbchannel::Sender<()>),
 }
 
 struct ConnectRequest {
@@ -418,13 +664,218 @@ struct ConnectRequest {
 #[cfg(any(test, test_utilities))]
 pub mod tests {
     use mio_extras::channel;
+    use crossbeam::channel as cbchannel;
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use crate::lock_order::OrderedMutex;
+    use std::thread;
+    use std::time;
 
     use super::Handle;
+    use crate::mempool::MemPool;
+    use crate::network::message::Message;
+    use crate::spread::Spreader;
+    use crate::helper::generate_random_hash;
+    use crate::config::{INV_BATCH_SIZE, INV_FLUSH_INTERVAL};
 
     pub fn fake_server_handle() -> Handle {
         let (control_signal_sender, _) = channel::channel();
         Handle {
             control_chan: control_signal_sender,
+            high_bandwidth_peers: Arc::new(AtomicUsize::new(0)),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bounded_worker_queue() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17401);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17402);
+        let capacity = 4;
+        // no worker is consuming server_1's queue
+        let (sender_1, receiver_1) = cbchannel::bounded(capacity);
+        let (sender_2, _receiver_2) = cbchannel::bounded(capacity);
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (ctx_1, _server_1, _) = super::new(p2p_addr_1, sender_1, Spreader::Default, mempool.clone()).unwrap();
+        let (ctx_2, server_2, _) = super::new(p2p_addr_2, sender_2, Spreader::Default, mempool.clone()).unwrap();
+        ctx_1.start().unwrap();
+        ctx_2.start().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+        server_2.connect(p2p_addr_1).unwrap();
+
+        for i in 0..100 {
+            server_2.broadcast(Message::Ping(i.to_string()), None);
+        }
+        thread::sleep(time::Duration::from_millis(200));
+        assert_eq!(capacity, receiver_1.len());
+        // the handshake comes first
+        let (msg, _) = receiver_1.recv().unwrap();
+        let msg: Message = bincode::deserialize(&msg).unwrap();
+        assert!(matches!(msg, Message::Version(_, _)));
+        for _ in 1..capacity {
+            let (msg, _) = receiver_1.recv().unwrap();
+            let msg: Message = bincode::deserialize(&msg).unwrap();
+            assert!(matches!(msg, Message::Ping(_)));
+        }
+
+        // connection still works once workers catch up
+        server_2.broadcast(Message::Ping("last".to_string()), None);
+        thread::sleep(time::Duration::from_millis(100));
+        let (msg, _) = receiver_1.try_recv().unwrap();
+        let msg: Message = bincode::deserialize(&msg).unwrap();
+        assert!(matches!(msg, Message::Ping(ref s) if s == "last"));
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17418);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17419);
+        // a listener that never accepts, once its backlog is full new connections hang
+        let unreachable = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17420);
+        let _listener = std::net::TcpListener::bind(unreachable).unwrap();
+        let mut backlog = vec![];
+        while backlog.len() < 4096 {
+            match std::net::TcpStream::connect_timeout(&unreachable, time::Duration::from_millis(100)) {
+                Ok(stream) => backlog.push(stream),
+                Err(_) => break,
+            }
+        }
+        let (sender_1, receiver_1) = cbchannel::unbounded();
+        let (sender_2, _receiver_2) = cbchannel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (ctx_1, _server_1, _) = super::new(p2p_addr_1, sender_1, Spreader::Default, mempool.clone()).unwrap();
+        let (mut ctx_2, server_2, _) = super::new(p2p_addr_2, sender_2, Spreader::Default, mempool.clone()).unwrap();
+        ctx_2.set_connect_timeout(300);
+        ctx_1.start().unwrap();
+        ctx_2.start().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+
+        let start = time::Instant::now();
+        let pending = server_2.connect_async(unreachable);
+
+        // other peers can connect and messages flow meanwhile
+        server_2.connect(p2p_addr_1).unwrap();
+        server_2.broadcast(Message::Ping("hi".to_string()), None);
+        loop {
+            let (msg, _) = receiver_1.recv_timeout(time::Duration::from_millis(200)).unwrap();
+            let msg: Message = bincode::deserialize(&msg).unwrap();
+            if matches!(msg, Message::Ping(ref s) if s == "hi") {
+                break;
+            }
+        }
+
+        let result = pending.recv_timeout(time::Duration::from_secs(2)).unwrap();
+        assert!(result.is_err());
+        assert!(start.elapsed() >= time::Duration::from_millis(300));
+        assert!(start.elapsed() < time::Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_self_connection() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17423);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17424);
+        let (sender_1, receiver_1) = cbchannel::unbounded();
+        let (sender_2, _receiver_2) = cbchannel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (ctx_1, server_1, _) = super::new(p2p_addr_1, sender_1, Spreader::Default, mempool.clone()).unwrap();
+        let (ctx_2, server_2, _) = super::new(p2p_addr_2, sender_2, Spreader::Default, mempool.clone()).unwrap();
+        ctx_1.start().unwrap();
+        ctx_2.start().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+
+        match server_1.connect(p2p_addr_1) {
+            Err(e) => assert_eq!(std::io::ErrorKind::InvalidInput, e.kind()),
+            Ok(_) => panic!("self-connection accepted"),
+        }
+        server_2.connect(p2p_addr_1).unwrap();
+
+        // broadcasts aren't echoed back to ourselves
+        server_1.broadcast(Message::Ping("self".to_string()), None);
+        server_2.broadcast(Message::Ping("peer".to_string()), None);
+        thread::sleep(time::Duration::from_millis(100));
+        let pings: Vec<String> = receiver_1.try_iter()
+            .filter_map(|(msg, _)| match bincode::deserialize(&msg).unwrap() {
+                Message::Ping(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec!["peer".to_string()], pings);
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        use std::io::{Read, Write};
+
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17430);
+        let (sender_1, receiver_1) = cbchannel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (ctx_1, _server_1, _) = super::new(p2p_addr_1, sender_1, Spreader::Default, mempool).unwrap();
+        ctx_1.start().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+
+        // a frame claiming 4GB is refused from its length alone
+        let mut attacker = std::net::TcpStream::connect(p2p_addr_1).unwrap();
+        attacker.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        attacker.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            match attacker.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,  // handshake sent before the frame
+                Err(e) => {
+                    assert_ne!(std::io::ErrorKind::WouldBlock, e.kind(), "peer not dropped");
+                    assert_ne!(std::io::ErrorKind::TimedOut, e.kind(), "peer not dropped");
+                    break;
+                }
+            }
+        }
+
+        // well-formed frames from other peers still get through
+        let mut honest = std::net::TcpStream::connect(p2p_addr_1).unwrap();
+        let payload = bincode::serialize(&Message::Ping("ok".to_string())).unwrap();
+        honest.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+        honest.write_all(&payload).unwrap();
+        let (msg, _) = receiver_1.recv_timeout(time::Duration::from_secs(2)).unwrap();
+        let msg: Message = bincode::deserialize(&msg).unwrap();
+        assert!(matches!(msg, Message::Ping(ref s) if s == "ok"));
+    }
+
+    #[test]
+    fn test_inv_batching() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17465);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17466);
+        let (sender_1, receiver_1) = cbchannel::unbounded();
+        let (sender_2, _receiver_2) = cbchannel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (ctx_1, _server_1, _) = super::new(p2p_addr_1, sender_1, Spreader::Default, mempool.clone()).unwrap();
+        let (ctx_2, server_2, spread_ctx_2) = super::new(p2p_addr_2, sender_2, Spreader::Default, mempool.clone()).unwrap();
+        ctx_1.start().unwrap();
+        ctx_2.start().unwrap();
+        spread_ctx_2.start();
+        server_2.connect(p2p_addr_1).unwrap();
+        let announced = || {
+            thread::sleep(time::Duration::from_millis(INV_FLUSH_INTERVAL + 200));
+            let mut announced = vec![];
+            for (msg, _) in receiver_1.try_iter() {
+                if let Message::NewTransactionHashes(hashes) = bincode::deserialize(&msg).unwrap() {
+                    announced.push(hashes);
+                }
+            }
+            announced
+        };
+
+        // transactions arriving one by one are announced together
+        let hashes: Vec<_> = (0..5).map(|_| generate_random_hash()).collect();
+        for hash in hashes.iter() {
+            server_2.broadcast(Message::NewTransactionHashes(vec![*hash]), None);
+        }
+        assert_eq!(vec![hashes], announced());
+
+        // a message carries at most a full batch
+        let hashes: Vec<_> = (0..INV_BATCH_SIZE + 1).map(|_| generate_random_hash()).collect();
+        server_2.broadcast(Message::NewTransactionHashes(hashes.clone()), None);
+        let batches = announced();
+        assert_eq!(vec![INV_BATCH_SIZE, 1], batches.iter().map(|b| b.len()).collect::<Vec<_>>());
+        assert_eq!(hashes, batches.concat());
+    }
+}
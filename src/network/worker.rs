@@ -1,67 +1,121 @@
-use crossbeam::channel;
+use crossbeam::atomic::AtomicCell;
+use crossbeam::channel::{self, select};
 use log::{debug, warn};
 
 use std::thread;
+use std::time;
 use std::sync::{Arc, Mutex};
 
 use super::message::Message;
 use super::peer;
+use super::inflight::InFlight;
 use crate::network::server::Handle as ServerHandle;
-use crate::blockchain::Blockchain;
+use crate::block::{Block, Header};
+use crate::blockchain::{Blockchain, InsertOutcome, ValidationTip};
+use crate::config::{BLOCK_REQUEST_TIMEOUT, FUTURE_BLOCK_RETRY_INTERVAL, MAX_HEADERS_RESULTS, MAX_IN_FLIGHT_BLOCKS,
+                    MAX_ORPHAN_FETCH_DEPTH, MEMPOOL_SYNC_LIMIT};
 use crate::crypto::hash::{H256, Hashable, H160};
 use crate::mempool::MemPool;
+use crate::lock_order::OrderedMutex;
 use crate::peers::Peers;
 
 use ring::signature::ED25519_PUBLIC_KEY_LEN;
 
+enum ControlSignal {
+    Exit,
+}
+
 #[derive(Clone)]
 pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<MemPool>>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    validation_tip: Arc<AtomicCell<ValidationTip>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
     peers_info: Arc<Mutex<Peers>>,
     self_addr: H160,
     self_pub_key: Box<[u8; ED25519_PUBLIC_KEY_LEN]>,
     self_port: u16,
     supernode: bool,
+    in_flight: Arc<Mutex<InFlight>>,  // blocks requested from peers, shared by all workers
+    orphan_fetch_depth: usize,  // orphans deeper than this don't get their missing ancestors fetched
+    fee_filter: u64,  // minimum fee rate asked of peers by FeeFilter on connect, 0 for none
+    high_bandwidth: bool,  // ask peers by SendCompact on connect to push new blocks unsolicited
 }
 
 pub fn new(
     num_worker: usize,
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<MemPool>>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
     peers_info: Arc<Mutex<Peers>>,
     self_addr: H160,
     self_pub_key: Box<[u8; ED25519_PUBLIC_KEY_LEN]>,
     self_port: u16,
 ) -> Context {
+    let validation_tip = blockchain.lock_or_recover().validation_tip();
     Context {
         msg_chan: msg_src,
         num_worker,
         server,
         blockchain,
+        validation_tip,
         mempool,
         peers_info,
         self_addr,
         self_pub_key,
         self_port,
         supernode: false,
+        in_flight: Arc::new(Mutex::new(InFlight::new(MAX_IN_FLIGHT_BLOCKS, BLOCK_REQUEST_TIMEOUT))),
+        orphan_fetch_depth: MAX_ORPHAN_FETCH_DEPTH,
+        fee_filter: 0,
+        high_bandwidth: false,
+    }
+}
+
+pub struct Handle {
+    /// Channel for sending signal to the worker threads
+    control_chan: channel::Sender<ControlSignal>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Handle {
+    // Signal all worker threads to exit once done with the message at hand, and wait for them
+    pub fn shutdown(self) {
+        for _ in self.threads.iter() {
+            // fails only if all threads already exited, e.g. the server was shut down first
+            let _ = self.control_chan.send(ControlSignal::Exit);
+        }
+        for thread in self.threads.into_iter() {
+            if thread.join().is_err() {
+                warn!("Worker thread panicked");
+            }
+        }
     }
 }
 
 impl Context {
-    pub fn start(self) {
+    // Spawn the worker threads; they exit on Handle::shutdown or when the server is gone
+    pub fn start(self) -> Handle {
+        let (control_tx, control_rx) = channel::unbounded();
         let num_worker = self.num_worker;
+        let mut threads = vec![];
         for i in 0..num_worker {
             let cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+            let control_chan = control_rx.clone();
+            threads.push(thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || {
+                    cloned.worker_loop(i, control_chan);
+                    warn!("Worker thread {} exited", i);
+                })
+                .unwrap());
+        }
+        Handle {
+            control_chan: control_tx,
+            threads,
         }
     }
 
@@ -69,12 +123,198 @@ impl Context {
         self.supernode = true;
     }
 
-    fn worker_loop(&self) {
+    pub fn set_orphan_fetch_depth(&mut self, depth: usize) {
+        self.orphan_fetch_depth = depth;
+    }
+
+    // Ask peers not to announce transactions paying less than min_fee_rate(fee per byte), set before start
+    pub fn set_fee_filter(&mut self, min_fee_rate: u64) {
+        self.fee_filter = min_fee_rate;
+    }
+
+    // Ask peers to push new blocks instead of announcing them, trading bandwidth for latency; set before start
+    pub fn set_high_bandwidth(&mut self, high_bandwidth: bool) {
+        self.high_bandwidth = high_bandwidth;
+    }
+
+    // Check a header on the current tip against validation_tip, without locking blockchain.
+    // Headers on another parent pass, they are checked once blockchain is locked
+    fn fits_tip(&self, header: &Header) -> bool {
+        let tip = self.validation_tip.load();
+        header.parent != tip.tip
+            || (header.difficulty == tip.next_difficulty && header.timestamp > tip.median_time_past)
+    }
+
+    // Insert blocks into blockchain and update mempool by the ones moving the tip. Return the hashes of blocks
+    // to relay, and the missing parents of orphans among them to fetch
+    fn insert_blocks<'a>(&self, blockchain: &mut Blockchain, mempool: &mut MemPool,
+                         blocks: impl Iterator<Item = &'a Block>) -> (Vec<H256>, Vec<H256>) {
+        let old_tip = blockchain.tip();
+        let mut new_hashes = Vec::<H256>::new();
+        let mut missing_parents = Vec::<H256>::new();
+        for b in blocks {
+            match blockchain.insert_with_check(b) {
+                Ok(InsertOutcome::Extended) | Ok(InsertOutcome::Reorg { .. }) => {
+                    if !self.supernode {
+                        let conflicts = blockchain.mempool_conflicts(&b.hash, mempool);
+                        mempool.remove_trans(&b.content.get_trans_hashes());
+                        mempool.remove_conflicts(&conflicts);
+                    }
+                    new_hashes.push(b.hash.clone());
+                }
+                Ok(outcome) => {
+                    debug!("Block {:?} accepted but not relayed: {:?}", b.hash, outcome);
+                }
+                Err(e) => {
+                    debug!("Block {:?} rejected: {:?}", b.hash, e);
+                }
+            }
+            if let Some(parent_hash) = self.orphan_fetch(blockchain, &b.hash) {
+                if !missing_parents.contains(&parent_hash) {
+                    missing_parents.push(parent_hash);
+                }
+            }
+        }
+        // orphans connected by these blocks may have moved the tip further
+        let new_tip = blockchain.tip();
+        if new_tip != old_tip && !self.supernode {
+            let disconnected = blockchain.disconnected_trans(&old_tip);
+            let tip_state = blockchain.tip_block_state();
+            mempool.set_tip_state(tip_state.clone());
+            if !disconnected.is_empty() {
+                let n = mempool.apply_reorg(&disconnected, &tip_state);
+                debug!("Put {} transactions of disconnected blocks back into mempool", n);
+            }
+            let n = mempool.update_tip(blockchain.get_block_count(), blockchain.tip_header().timestamp);
+            if n > 0 {
+                debug!("Put {} transactions reaching their lock time into mempool", n);
+            }
+        }
+        if new_tip != old_tip && !new_hashes.contains(&new_tip) {
+            new_hashes.push(new_tip);
+        }
+        (new_hashes, missing_parents)
+    }
+
+    // Relay blocks just inserted, cloning whole blocks only when some peer wants them pushed
+    fn relay_new_blocks(&self, blockchain: &Blockchain, hashes: &[H256], src_peer_key: Option<usize>) {
+        if hashes.is_empty() {
+            return;
+        }
+        if self.server.has_high_bandwidth_peers() {
+            let blocks: Vec<Block> = hashes.iter().filter_map(|h| blockchain.get_block(h)).collect();
+            self.server.relay_blocks(blocks, src_peer_key);
+        } else {
+            let headers: Vec<Header> = hashes.iter().filter_map(|h| blockchain.get_header(h)).collect();
+            self.server.relay_headers(headers, src_peer_key);
+        }
+    }
+
+    // Insert held blocks the clock has caught up with, without waiting for other blocks to arrive
+    fn retry_future_blocks(&self) {
+        let mut blockchain = match self.blockchain.lock_healthy() {
+            Ok(blockchain) => blockchain,
+            Err(e) => {
+                warn!("Can't retry held blocks: {:?}", e);
+                return;
+            }
+        };
+        let mut mempool = match self.mempool.lock_healthy() {
+            Ok(mempool) => mempool,
+            Err(e) => {
+                warn!("Can't retry held blocks: {:?}", e);
+                return;
+            }
+        };
+        let due = blockchain.take_due_future_blocks();
+        if due.is_empty() {
+            return;
+        }
+        debug!("Retry {} blocks held for their timestamp", due.len());
+        let (new_hashes, missing_parents) = self.insert_blocks(&mut blockchain, &mut mempool, due.iter());
+        drop(mempool);
+        self.relay_new_blocks(&blockchain, &new_hashes, None);
+        drop(blockchain);
+        // whoever sent the orphans among them is unknown by now, ask every peer
+        let missing_parents = self.in_flight.lock().unwrap().request(missing_parents);
+        if missing_parents.len() > 0 {
+            self.server.broadcast(Message::GetBlocks(missing_parents), None);
+        }
+    }
+
+    // Missing ancestor of an orphan to fetch, None if it isn't orphan or is too deep
+    fn orphan_fetch(&self, blockchain: &Blockchain, hash: &H256) -> Option<H256> {
+        let parent_hash = blockchain.missing_parent(hash)?;
+        let depth = blockchain.orphan_depth(hash);
+        if depth > self.orphan_fetch_depth {
+            warn!("Orphan {:?} is {} blocks above missing {:?}, stop fetching", hash, depth, parent_hash);
+            return None;
+        }
+        Some(parent_hash)
+    }
+
+    // Ask a peer for announced blocks we don't have yet and haven't requested elsewhere
+    fn request_blocks(&self, peer: &peer::Handle, hashes: Vec<H256>) {
+        let blockchain = self.blockchain.lock_or_recover();
+        // blocks already in the chain, e.g. our own mined block echoed back by a peer, are neither fetched
+        // nor relayed again
+        let hashes: Vec<H256> = hashes.into_iter().filter(|h| !blockchain.exist(h)).collect();
+        if hashes.is_empty() {
+            return;
+        }
+        // buffered orphans among them need their missing ancestors instead
+        let mut to_get: Vec<H256> = hashes.iter()
+                    .filter_map(|h| self.orphan_fetch(&blockchain, h))
+                    .collect();
+        to_get.extend(hashes.into_iter());
+        drop(blockchain);
+        let to_get = self.in_flight.lock().unwrap().request(to_get);
+        if to_get.len() > 0 {
+            peer.write(Message::GetBlocks(to_get));
+        }
+    }
+
+    fn worker_loop(&self, index: usize, mut control_chan: channel::Receiver<ControlSignal>) {
+        // one worker is enough to retry held blocks
+        let retry = if index == 0 {
+            channel::tick(time::Duration::from_millis(FUTURE_BLOCK_RETRY_INTERVAL))
+        } else {
+            channel::never()
+        };
         loop {
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
+            let (msg, peer) = select! {
+                recv(self.msg_chan) -> msg => match msg {
+                    Ok(msg) => msg,
+                    Err(_) => return,  // the server is gone
+                },
+                recv(control_chan) -> signal => match signal {
+                    Ok(ControlSignal::Exit) => return,
+                    Err(_) => {
+                        // handle dropped without shutdown, keep working until the server is gone
+                        control_chan = channel::never();
+                        continue;
+                    }
+                },
+                recv(retry) -> _ => {
+                    self.retry_future_blocks();
+                    continue;
+                }
+            };
             let peer_key = peer.key;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Failed to decode message from peer {}: {}", peer.addr, e);
+                    continue;
+                }
+            };
+            let msg = match msg.decompress() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Failed to decompress message from peer {}: {}", peer.addr, e);
+                    continue;
+                }
+            };
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -86,19 +326,48 @@ impl Context {
                 Message::NewBlockHashes(hashes) => {
                     //Check whether the hashes are already in blockchain; if not,sending GetBlocks to ask for them.
                     debug!("NewBlockHashes message received!!: {:?}", hashes);
-                    let blockchain = self.blockchain.lock().unwrap();
-                    let to_get: Vec<H256> = hashes.into_iter()
-                                .filter(|h| !blockchain.exist(h))
-                                .collect();
-                    drop(blockchain);
-                    if to_get.len() > 0 {
-                        peer.write(Message::GetBlocks(to_get));
+                    self.request_blocks(&peer, hashes);
+                }
+                Message::Headers(headers) => {
+                    //Same as NewBlockHashes, but blocks whose header lacks proof of work are never fetched
+                    debug!("Headers message received: {} headers", headers.len());
+                    let mut hashes = vec![];
+                    let mut accepted = vec![];
+                    for header in headers.into_iter() {
+                        let hash = header.hash();
+                        if !hash.meets_difficulty(&header.difficulty) {
+                            warn!("Header {:?} from peer {} lacks proof of work", hash, peer.addr);
+                        } else if !self.fits_tip(&header) {
+                            warn!("Header {:?} from peer {} doesn't fit our tip", hash, peer.addr);
+                        } else {
+                            hashes.push(hash);
+                            accepted.push(header);
+                        }
+                    }
+                    // they tell how far the peer's chain goes before their blocks arrive
+                    if let Ok(mut blockchain) = self.blockchain.lock_healthy() {
+                        blockchain.note_headers(&accepted);
+                    }
+                    self.request_blocks(&peer, hashes);
+                }
+                Message::GetHeaders(locator) => {
+                    //Answer with headers of longest-chain after the first locator hash we recognize
+                    debug!("GetHeaders message received: {} locator hashes", locator.len());
+                    if locator.first() == Some(&self.validation_tip.load().tip) {
+                        continue;  // the peer is synced, nothing to answer
+                    }
+                    let slices = self.blockchain.lock_or_recover()
+                        .get_block_headers_for_sync(&locator, MAX_HEADERS_RESULTS);
+                    // headers are shared under the lock and only copied into the message after it
+                    let headers: Vec<Header> = slices.iter().flat_map(|s| s.iter().cloned()).collect();
+                    if headers.len() > 0 {
+                        peer.write(Message::Headers(headers));
                     }
                 }
                 Message::GetBlocks(hashes) => {
                     //Check whether the hashes are already in blockchain; if yes,sending the corresponding blocks thru Blocks.
                     debug!("GetBlocks message received: {:?}", hashes);
-                    let blocks = self.blockchain.lock().unwrap().get_blocks(&hashes);
+                    let blocks = self.blockchain.lock_or_recover().get_blocks(&hashes);
                     if blocks.len() > 0 {
                         peer.write(Message::Blocks(blocks));
                     }
@@ -106,33 +375,57 @@ impl Context {
                 Message::Blocks(blocks) => {
                     //Insert the blocks into blockchain if not already in it; also ask for missing parent blocks
                     debug!("Blocks message received!!");
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut mempool = self.mempool.lock().unwrap();
-                    let mut new_hashes = Vec::<H256>::new();
-                    let mut missing_parents = Vec::<H256>::new();
-                    for b in blocks.iter() {
-                        if blockchain.insert_with_check(b) {
-                            if !self.supernode {
-                                mempool.remove_trans(&b.content.get_trans_hashes());
-                            }
-                            new_hashes.push(b.hash.clone());
-                        }
-                        if let Some(parent_hash) = blockchain.missing_parent(&b.hash) {
-                            missing_parents.push(parent_hash);
+                    // blocks on our tip with a wrong difficulty or timestamp are dropped before locking blockchain
+                    let checked: Vec<&Block> = blocks.iter().filter(|b| self.fits_tip(&b.header)).collect();
+                    if checked.is_empty() {
+                        debug!("Blocks from peer {} rejected: none fits our tip", peer.addr);
+                        let mut in_flight = self.in_flight.lock().unwrap();
+                        for b in blocks.iter() {
+                            in_flight.arrived(&b.hash);
                         }
+                        continue;
                     }
+                    let mut blockchain = match self.blockchain.lock_healthy() {
+                        Ok(blockchain) => blockchain,
+                        Err(e) => {
+                            warn!("Dropping {} blocks from peer {}: {:?}", blocks.len(), peer.addr, e);
+                            continue;
+                        }
+                    };
+                    let mut mempool = match self.mempool.lock_healthy() {
+                        Ok(mempool) => mempool,
+                        Err(e) => {
+                            warn!("Dropping {} blocks from peer {}: {:?}", blocks.len(), peer.addr, e);
+                            continue;
+                        }
+                    };
+                    // blocks held for a future timestamp are tried again before the new ones
+                    let due = blockchain.take_due_future_blocks();
+                    let (new_hashes, missing_parents) = self.insert_blocks(&mut blockchain, &mut mempool,
+                                                                           due.iter().chain(checked.into_iter()));
+                    drop(mempool);
+                    self.relay_new_blocks(&blockchain, &new_hashes, Some(peer_key));
                     drop(blockchain);
+                    let mut in_flight = self.in_flight.lock().unwrap();
+                    for b in blocks.iter() {
+                        in_flight.arrived(&b.hash);
+                    }
+                    let missing_parents = in_flight.request(missing_parents);
+                    drop(in_flight);
                     if missing_parents.len() > 0 {
                         peer.write(Message::GetBlocks(missing_parents));
                     }
-                    if new_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewBlockHashes(new_hashes), Some(peer_key));
-                    }
                 }
                 Message::NewTransactionHashes(hashes) => {
                     //Check whether the transactions are already in mempool/blockchain; if not,sending GetTransactions to ask for them.
                     debug!("NewTransactionHashes message received: {:?}", hashes);
-                    let mut mempool  = self.mempool.lock().unwrap();
+                    let mut mempool = match self.mempool.lock_healthy() {
+                        Ok(mempool) => mempool,
+                        Err(e) => {
+                            warn!("Ignoring transaction hashes from peer {}: {:?}", peer.addr, e);
+                            continue;
+                        }
+                    };
                     if self.supernode {
                         for h in hashes.iter() {
                             mempool.insert_ts_and_addr(h.clone(), peer.addr.clone());
@@ -150,7 +443,13 @@ impl Context {
                     debug!("NewDandelionTxHashes message received: {:?}", trans);
                     if self.supernode {
                         for t in trans.iter() {
-                            let mut mempool = self.mempool.lock().unwrap();
+                            let mut mempool = match self.mempool.lock_healthy() {
+                                Ok(mempool) => mempool,
+                                Err(e) => {
+                                    warn!("Dropping dandelion transactions from peer {}: {:?}", peer.addr, e);
+                                    break;
+                                }
+                            };
                             mempool.insert_ts_and_addr(t.hash(), peer.addr.clone());
                             mempool.add_with_check(t);
                         }
@@ -161,15 +460,34 @@ impl Context {
                 Message::GetTransactions(hashes) => {
                     //Check whether the hashes are already in mempool; if yes,sending the corresponding transactions thru Transactions.
                     debug!("GetTransactions message received: {:?}", hashes);
-                    let trans = self.mempool.lock().unwrap().get_trans(&hashes);
+                    let trans = self.mempool.lock_or_recover().get_trans(&hashes);
                     if trans.len() > 0 {
                         peer.write(Message::Transactions(trans));
                     }
                 }
+                Message::GetMempool => {
+                    //Announce pending transactions to a newly-connected peer, it asks for the ones it misses
+                    debug!("GetMempool message received");
+                    let mempool = self.mempool.lock_or_recover();
+                    let mut hashes = mempool.hashes(MEMPOOL_SYNC_LIMIT);
+                    if peer.fee_filter() > 0 {
+                        hashes = mempool.filter_fee_rate(hashes, peer.fee_filter());
+                    }
+                    drop(mempool);
+                    if hashes.len() > 0 {
+                        peer.write(Message::NewTransactionHashes(hashes));
+                    }
+                }
                 Message::Transactions(trans) => {
                     //Add the transactions into mempool if not already in it and passing signature check
                     debug!("Transactions message received!!");
-                    let mut mempool = self.mempool.lock().unwrap();
+                    let mut mempool = match self.mempool.lock_healthy() {
+                        Ok(mempool) => mempool,
+                        Err(e) => {
+                            warn!("Dropping {} transactions from peer {}: {:?}", trans.len(), peer.addr, e);
+                            continue;
+                        }
+                    };
                     let mut new_hashes = Vec::<H256>::new();
                     for t in trans.iter() {
                         if mempool.add_with_check(t) {
@@ -208,7 +526,7 @@ impl Context {
                     let pub_key = content.1.clone();
                     let port = content.2;
                     debug!("Server {:?} receive IntroduceAddr {:?}!!", self.self_addr, addr);
-                    let blockchain = self.blockchain.lock().unwrap();
+                    let blockchain = self.blockchain.lock_or_recover();
                     let mut peers_info = self.peers_info.lock().unwrap();
 
                     if !peers_info.contains(&addr) {
@@ -223,7 +541,480 @@ impl Context {
 
                     peer.write(Message::NewBlockHashes(blockchain.hash_chain()));
                 }
+                Message::GetSnapshot(hash) => {
+                    debug!("GetSnapshot message received: {:?}", hash);
+                    if let Some((block, state)) = self.blockchain.lock_or_recover().snapshot(&hash) {
+                        peer.write(Message::Snapshot(block, state));
+                    }
+                }
+                Message::Snapshot(block, state) => {
+                    //Install state at the trusted checkpoint; buffered orphans above it get connected
+                    debug!("Snapshot message received: {:?}", block.hash);
+                    let mut blockchain = match self.blockchain.lock_healthy() {
+                        Ok(blockchain) => blockchain,
+                        Err(e) => {
+                            warn!("Dropping snapshot {:?} from peer {}: {:?}", block.hash, peer.addr, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = blockchain.install_snapshot(&block, state) {
+                        warn!("Snapshot {:?} rejected: {:?}", block.hash, e);
+                    }
+                }
+                Message::Version(features, genesis) => {
+                    debug!("Version message received, features {:#x}", features);
+                    let local_genesis = self.blockchain.lock_or_recover().genesis_id();
+                    if genesis != local_genesis {
+                        let reason = format!("genesis mismatch: peer is on {:?}, we are on {:?}", genesis, local_genesis);
+                        warn!("Dropping peer {}: {}", peer.addr, reason);
+                        peer.write(Message::Reject(reason));
+                        self.server.disconnect(peer.key);
+                    } else {
+                        peer.set_features(features);
+                        // before GetMempool, so the answer is already filtered
+                        if self.fee_filter > 0 {
+                            peer.write(Message::FeeFilter(self.fee_filter));
+                        }
+                        if self.high_bandwidth {
+                            peer.write(Message::SendCompact(true));
+                        }
+                        peer.write(Message::GetMempool);
+                    }
+                }
+                Message::SendCompact(high_bandwidth) => {
+                    debug!("SendCompact message received: {}", high_bandwidth);
+                    peer.set_high_bandwidth(high_bandwidth);
+                }
+                Message::FeeFilter(min_fee_rate) => {
+                    debug!("FeeFilter message received: {}", min_fee_rate);
+                    peer.set_fee_filter(min_fee_rate);
+                }
+                Message::Reject(reason) => {
+                    warn!("Peer {} rejected us: {}", peer.addr, reason);
+                }
+                Message::Compressed(_) => {
+                    warn!("Nested compressed message from peer {}", peer.addr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::blockchain::CheckPolicy;
+    use crate::crypto::key_pair;
+    use crate::helper::*;
+    use crate::lock_order::OrderedMutex;
+    use crate::network::message::{FEATURE_COMPRESSION, LOCAL_FEATURES};
+    use crate::network::server;
+    use crate::spread::Spreader;
+    use crate::transaction::{TxInput, TxOutput};
+
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+    use std::time;
+
+    #[test]
+    fn test_dedup_block_requests() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17414);
+        let (_server, _, _, blockchain, _, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+        let genesis = blockchain.lock().unwrap().genesis_id();
+
+        // three bare peers announcing the same unknown block
+        let mut peers = vec![];
+        for port in 17415..17418 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+            let (sender, receiver) = channel::unbounded();
+            let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+            let (mut ctx, handle, _) = server::new(addr, sender, Spreader::Default, mempool).unwrap();
+            ctx.set_genesis(genesis);
+            ctx.start().unwrap();
+            peers.push((handle, receiver));
+        }
+        thread::sleep(time::Duration::from_millis(100));
+        let links: Vec<peer::Handle> = peers.iter().map(|(h, _)| h.connect(p2p_addr).unwrap()).collect();
+        thread::sleep(time::Duration::from_millis(100));
+
+        let hash = generate_random_hash();
+        let announcers: Vec<_> = links.into_iter().map(|link| {
+            let hash = hash.clone();
+            thread::spawn(move || link.write(Message::NewBlockHashes(vec![hash])))
+        }).collect();
+        for a in announcers {
+            a.join().unwrap();
+        }
+        thread::sleep(time::Duration::from_millis(300));
+
+        let mut fetches = 0;
+        for (_, receiver) in peers.iter() {
+            for (msg, _) in receiver.try_iter() {
+                if let Message::GetBlocks(hashes) = bincode::deserialize(&msg).unwrap() {
+                    assert_eq!(vec![hash.clone()], hashes);
+                    fetches += 1;
+                }
             }
         }
+        assert_eq!(1, fetches);
+    }
+
+    #[test]
+    fn test_own_block_echoed_back() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17444);
+        let (_server, mut miner, _, blockchain, _, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+        let genesis = blockchain.lock().unwrap().genesis_id();
+
+        // a peer that will echo our block back, and one watching what we relay
+        let mut peers = vec![];
+        for port in 17445..17447 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+            let (sender, receiver) = channel::unbounded();
+            let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+            let (mut ctx, handle, _) = server::new(addr, sender, Spreader::Default, mempool).unwrap();
+            ctx.set_genesis(genesis);
+            ctx.start().unwrap();
+            peers.push((handle, receiver));
+        }
+        thread::sleep(time::Duration::from_millis(100));
+        let links: Vec<peer::Handle> = peers.iter().map(|(h, _)| h.connect(p2p_addr).unwrap()).collect();
+        thread::sleep(time::Duration::from_millis(100));
+
+        let tip = blockchain.lock().unwrap().tip();
+        let block = miner.mine_on(&tip).unwrap();
+        thread::sleep(time::Duration::from_millis(200));
+        for (_, receiver) in peers.iter() {
+            assert!(receiver.try_iter().count() > 0, "mined block not relayed");
+        }
+
+        links[0].write(Message::NewBlockHashes(vec![block.hash.clone()]));
+        links[0].write(Message::Headers(vec![block.header.clone()]));
+        links[0].write(Message::Blocks(vec![block.clone()]));
+        thread::sleep(time::Duration::from_millis(300));
+
+        // neither fetched nor relayed again
+        for (_, receiver) in peers.iter() {
+            for (msg, _) in receiver.try_iter() {
+                match bincode::deserialize(&msg).unwrap() {
+                    Message::GetBlocks(hashes) => panic!("own block fetched again: {:?}", hashes),
+                    Message::NewBlockHashes(hashes) => panic!("own block relayed again: {:?}", hashes),
+                    Message::Headers(headers) => panic!("own block relayed again: {} headers", headers.len()),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(2, blockchain.lock().unwrap().length());
+    }
+
+    #[test]
+    fn test_fetch_orphan_ancestors() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17431);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17432);
+        let (server_1, _, _, blockchain_1, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (_server_2, _, _, blockchain_2, _, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        blockchain_1.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain_2.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+
+        // node 1 has a chain node 2 never heard of
+        let mut chain = blockchain_1.lock().unwrap();
+        let mut tip_block = None;
+        for _ in 0..5 {
+            let block = generate_mined_block(&chain.tip(), &chain.difficulty());
+            chain.insert_with_check(&block).unwrap();
+            tip_block = Some(block);
+        }
+        drop(chain);
+        let tip_block = tip_block.unwrap();
+
+        // receiving only the tip, node 2 fetches its ancestors one by one from the sender
+        let link = server_1.connect(p2p_addr_2).unwrap();
+        link.write(Message::Blocks(vec![tip_block.clone()]));
+        let start = time::Instant::now();
+        while blockchain_2.lock().unwrap().tip() != tip_block.hash {
+            assert!(start.elapsed() < time::Duration::from_secs(3), "parent chain not installed");
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        let chain_2 = blockchain_2.lock().unwrap();
+        assert_eq!(blockchain_1.lock().unwrap().hash_chain(), chain_2.hash_chain());
+        assert!(chain_2.orphan_hashes().is_empty());
+    }
+
+    #[test]
+    fn test_reject_other_network() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17433);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17434);
+        let (server_1, _, _, blockchain_1, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+
+        // a bare peer announcing the default genesis, whose difficulty differs from node 1's
+        let (sender, receiver) = channel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (ctx_2, server_2, _) = server::new(p2p_addr_2, sender, Spreader::Default, mempool).unwrap();
+        ctx_2.start().unwrap();
+        assert_ne!(Blockchain::new().genesis_id(), blockchain_1.lock().unwrap().genesis_id());
+        thread::sleep(time::Duration::from_millis(100));
+        server_2.connect(p2p_addr_1).unwrap();
+
+        // node 1 tells why before hanging up
+        let start = time::Instant::now();
+        let reason = loop {
+            let (msg, _) = receiver.recv_timeout(time::Duration::from_secs(2)).expect("no Reject received");
+            if let Message::Reject(reason) = bincode::deserialize(&msg).unwrap() {
+                break reason;
+            }
+        };
+        assert!(reason.contains("genesis mismatch"), "unclear reason: {}", reason);
+        while server_1.peer_count() > 0 || server_2.peer_count() > 0 {
+            assert!(start.elapsed() < time::Duration::from_secs(3), "connection not dropped");
+            thread::sleep(time::Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_relay_headers_or_hashes() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17435);
+        let (_server, _, _, blockchain, _, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        let genesis = blockchain.lock().unwrap().genesis_id();
+
+        // bare peers: a block source, a legacy peer and a headers-capable peer
+        let mut peers = vec![];
+        for (port, features) in [(17436, LOCAL_FEATURES), (17437, FEATURE_COMPRESSION), (17438, LOCAL_FEATURES)].iter() {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), *port);
+            let (sender, receiver) = channel::unbounded();
+            let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+            let (mut ctx, handle, _) = server::new(addr, sender, Spreader::Default, mempool).unwrap();
+            ctx.set_genesis(genesis);
+            ctx.set_features(*features);
+            ctx.start().unwrap();
+            peers.push((handle, receiver));
+        }
+        thread::sleep(time::Duration::from_millis(100));
+        let links: Vec<peer::Handle> = peers.iter().map(|(h, _)| h.connect(p2p_addr).unwrap()).collect();
+        thread::sleep(time::Duration::from_millis(100));
+
+        let chain = blockchain.lock().unwrap();
+        let block = generate_mined_block(&chain.tip(), &chain.difficulty());
+        drop(chain);
+        links[0].write(Message::Blocks(vec![block.clone()]));
+        thread::sleep(time::Duration::from_millis(300));
+        assert_eq!(block.hash, blockchain.lock().unwrap().tip());
+
+        let relayed = |receiver: &channel::Receiver<(Vec<u8>, peer::Handle)>| -> Vec<Message> {
+            receiver.try_iter()
+                .map(|(msg, _)| bincode::deserialize(&msg).unwrap())
+                .filter(|msg| matches!(msg, Message::NewBlockHashes(_) | Message::Headers(_)))
+                .collect()
+        };
+        assert!(relayed(&peers[0].1).is_empty());
+        match &relayed(&peers[1].1)[..] {
+            [Message::NewBlockHashes(hashes)] => assert_eq!(&vec![block.hash], hashes),
+            m => panic!("expect NewBlockHashes, got {:?}", m),
+        }
+        match &relayed(&peers[2].1)[..] {
+            [Message::Headers(headers)] => assert_eq!(vec![block.hash], headers.iter().map(|h| h.hash()).collect::<Vec<_>>()),
+            m => panic!("expect Headers, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_high_bandwidth_relay() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17456);
+        let (_server, _, _, blockchain, _, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        let genesis = blockchain.lock().unwrap().genesis_id();
+
+        // bare peers: a block source, a high-bandwidth peer and a low-bandwidth peer
+        let mut peers = vec![];
+        for port in 17457..17460 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+            let (sender, receiver) = channel::unbounded();
+            let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+            let (mut ctx, handle, _) = server::new(addr, sender, Spreader::Default, mempool).unwrap();
+            ctx.set_genesis(genesis);
+            ctx.start().unwrap();
+            peers.push((handle, receiver));
+        }
+        thread::sleep(time::Duration::from_millis(100));
+        let links: Vec<peer::Handle> = peers.iter().map(|(h, _)| h.connect(p2p_addr).unwrap()).collect();
+        links[1].write(Message::SendCompact(true));
+        links[2].write(Message::SendCompact(false));
+        thread::sleep(time::Duration::from_millis(100));
+
+        let chain = blockchain.lock().unwrap();
+        let block = generate_mined_block(&chain.tip(), &chain.difficulty());
+        drop(chain);
+        links[0].write(Message::Blocks(vec![block.clone()]));
+        thread::sleep(time::Duration::from_millis(300));
+        assert_eq!(block.hash, blockchain.lock().unwrap().tip());
+
+        let relayed = |receiver: &channel::Receiver<(Vec<u8>, peer::Handle)>| -> Vec<Message> {
+            receiver.try_iter()
+                .map(|(msg, _)| bincode::deserialize::<Message>(&msg).unwrap().decompress().unwrap())
+                .filter(|msg| matches!(msg, Message::Blocks(_) | Message::NewBlockHashes(_) | Message::Headers(_)))
+                .collect()
+        };
+        assert!(relayed(&peers[0].1).is_empty());
+        match &relayed(&peers[1].1)[..] {
+            [Message::Blocks(blocks)] => assert_eq!(vec![block.hash], blocks.iter().map(|b| b.hash).collect::<Vec<_>>()),
+            m => panic!("expect Blocks, got {:?}", m),
+        }
+        match &relayed(&peers[2].1)[..] {
+            [Message::Headers(headers)] => assert_eq!(vec![block.hash], headers.iter().map(|h| h.hash()).collect::<Vec<_>>()),
+            m => panic!("expect Headers, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_reject_by_validation_tip() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17467);
+        let (_server, _, _, blockchain, _, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+        let genesis = blockchain.lock().unwrap().genesis_id();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17468);
+        let (sender, receiver) = channel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (mut ctx, handle, _) = server::new(addr, sender, Spreader::Default, mempool).unwrap();
+        ctx.set_genesis(genesis);
+        ctx.start().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+        let link = handle.connect(p2p_addr).unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+
+        // blocks on tip with a wrong difficulty keep no worker waiting for the blockchain lock
+        let chain = blockchain.lock().unwrap();
+        let tip = chain.tip();
+        for _ in 0..8 {
+            let content = generate_random_content();
+            let block = Block::new(generate_header(&tip, &content, 0, &generate_random_hash()), content);
+            link.write(Message::Blocks(vec![block]));
+        }
+        link.write(Message::GetHeaders(vec![tip]));
+        link.write(Message::Ping("after".to_string()));
+        let pong = loop {
+            let (msg, _) = receiver.recv_timeout(time::Duration::from_secs(2)).expect("workers stuck on blockchain lock");
+            if let Message::Pong(nonce) = bincode::deserialize(&msg).unwrap() {
+                break nonce;
+            }
+        };
+        assert_eq!("after", pong);
+        assert_eq!(tip, chain.tip());
+    }
+
+    #[test]
+    fn test_retry_future_block_on_timer() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17470);
+        let (_server, _, _, blockchain, _, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain.lock().unwrap().set_max_future_drift(0);
+        let genesis = blockchain.lock().unwrap().genesis_id();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17471);
+        let (sender, _receiver) = channel::unbounded();
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let (mut ctx, handle, _) = server::new(addr, sender, Spreader::Default, mempool).unwrap();
+        ctx.set_genesis(genesis);
+        ctx.start().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+        let link = handle.connect(p2p_addr).unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+
+        let chain = blockchain.lock().unwrap();
+        let difficulty = chain.difficulty();
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_millis();
+        let content = generate_random_content();
+        let mut header = Header::new(&chain.tip(), 0, now + 300, &difficulty, &content.merkle_root());
+        drop(chain);
+        assert!(crate::miner::mining_base(&mut header, difficulty));
+        let block = Block::new(header, content);
+        link.write(Message::Blocks(vec![block.clone()]));
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(1, blockchain.lock().unwrap().future_block_count());
+
+        // inserted once due, though no other block arrives
+        thread::sleep(time::Duration::from_millis(200 + FUTURE_BLOCK_RETRY_INTERVAL + 200));
+        let chain = blockchain.lock().unwrap();
+        assert_eq!(0, chain.future_block_count());
+        assert_eq!(block.hash, chain.tip());
+    }
+
+    #[test]
+    fn test_mempool_sync_on_connect() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17448);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17449);
+        let (_server_1, _, _, _, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, _, mempool_2, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        let mut pending = vec![];
+        for _ in 0..5 {
+            let tran = generate_random_signed_transaction();
+            assert!(mempool_1.lock().unwrap().add_with_check(&tran));
+            pending.push(tran.hash);
+        }
+
+        // transactions pending before the connection are pulled by the new node
+        connect_peers(&server_2, &vec![p2p_addr_1]);
+        thread::sleep(time::Duration::from_millis(500));
+        let mempool_2 = mempool_2.lock().unwrap();
+        assert_eq!(5, mempool_2.size());
+        assert!(pending.iter().all(|h| mempool_2.exist(h)));
+        assert_eq!(mempool_1.lock().unwrap().hashes(3), mempool_2.hashes(3));
+    }
+
+    #[test]
+    fn test_fee_filter() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17454);
+        let peer_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17455);
+        let (server, _, _, blockchain, mempool, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+
+        // a bare peer only wants transactions paying at least 2 per byte
+        let (sender, receiver) = channel::unbounded();
+        let (mut ctx, handle, _) = server::new(peer_addr, sender, Spreader::Default,
+                                               Arc::new(OrderedMutex::new(MemPool::new()))).unwrap();
+        ctx.set_genesis(blockchain.lock().unwrap().genesis_id());
+        ctx.start().unwrap();
+        let link = handle.connect(p2p_addr).unwrap();
+        link.write(Message::FeeFilter(2));
+        thread::sleep(time::Duration::from_millis(100));
+
+        let key = key_pair::random();
+        let new_tran = |fee| generate_signed_transaction_with_fee(&key, vec![TxInput::new(generate_random_hash(), 0)],
+            vec![TxOutput::new(generate_random_h160(), 10)], fee);
+        let low = new_tran(10);
+        let high = new_tran(1000);
+        assert!(!low.pays_fee_rate(2) && high.pays_fee_rate(2));
+        assert!(mempool.lock().unwrap().add_with_check(&low));
+        assert!(mempool.lock().unwrap().add_with_check(&high));
+        server.broadcast(Message::NewTransactionHashes(vec![low.hash, high.hash]), None);
+        thread::sleep(time::Duration::from_millis(200));
+        link.write(Message::GetMempool);
+        thread::sleep(time::Duration::from_millis(200));
+
+        // neither relayed nor answered to GetMempool below the filter
+        let mut announced = vec![];
+        for (msg, _) in receiver.try_iter() {
+            if let Message::NewTransactionHashes(hashes) = bincode::deserialize(&msg).unwrap() {
+                announced.push(hashes);
+            }
+        }
+        assert_eq!(vec![vec![high.hash], vec![high.hash]], announced);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let (_msg_tx, msg_rx) = channel::unbounded();
+        let blockchain = Arc::new(OrderedMutex::new(Blockchain::new()));
+        let mempool = Arc::new(OrderedMutex::new(MemPool::new()));
+        let account = Account::new(17450, Arc::new(key_pair::random()));
+        let worker_ctx = new(4, msg_rx, server::tests::fake_server_handle(), blockchain.clone(), mempool,
+                             Arc::new(Mutex::new(Peers::new())), account.addr, account.get_pub_key(), account.port);
+        let handle = worker_ctx.start();
+        assert_eq!(4, handle.threads.len());
+        assert_eq!(5, Arc::strong_count(&blockchain));
+
+        // shutdown returns only once every thread has exited and dropped what it shared
+        let (done_tx, done_rx) = channel::unbounded();
+        thread::spawn(move || {
+            handle.shutdown();
+            done_tx.send(()).unwrap();
+        });
+        done_rx.recv_timeout(time::Duration::from_secs(5)).expect("worker threads still running");
+        assert_eq!(1, Arc::strong_count(&blockchain));
     }
 }
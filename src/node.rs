@@ -0,0 +1,711 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crossbeam::channel::{self, select};
+use log::info;
+use ring::signature::Ed25519KeyPair;
+
+use crate::network::{server, worker};
+use crate::network::server::Handle as ServerHandle;
+use crate::network::message::Message;
+use crate::account::Account;
+use crate::helper;
+use crate::miner;
+use crate::peers::Peers;
+use crate::spread::{self, Spreader};
+use crate::transaction_generator;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, BlockError, InsertOutcome};
+use crate::crypto::hash::{H160, H256};
+use crate::mempool::{Accepted, MemPool, TransactionError};
+use crate::config::{BLOCK_SIZE_LIMIT, LONGPOLL_NEW_TXS, MAX_BLOCK_BYTES};
+use crate::events::Event;
+use crate::difficulty;
+use crate::lock_order::OrderedMutex;
+use crate::transaction::SignedTransaction;
+
+// Whether a transaction is still in mempool or in a block of the longest chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxStatus {
+    Unconfirmed,
+    Confirmed { block: H256, confirmations: usize },
+}
+
+// Work for an external miner on tip: it builds its own coinbase claiming coinbase_value and committing to
+// Content::aux_root_of the transactions, followed by them in this order
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockTemplate {
+    pub parent: H256,
+    pub height: usize,
+    pub difficulty: H256,
+    pub min_timestamp: u64,  // earliest timestamp the block may have, one past the median time past of parent
+    pub coinbase_value: u64,  // block subsidy plus fees of the transactions
+    pub transactions: Vec<SignedTransaction>,
+}
+
+// Node-level operations shared by the API and scripted tests
+#[derive(Clone)]
+pub struct Handle {
+    server: ServerHandle,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
+    dandelion: bool,
+}
+
+pub fn new(server: ServerHandle, blockchain: Arc<OrderedMutex<Blockchain>>, mempool: Arc<OrderedMutex<MemPool>>,
+           dandelion: bool) -> Handle {
+    Handle {
+        server,
+        blockchain,
+        mempool,
+        dandelion,
+    }
+}
+
+impl Handle {
+    // Put a given transaction into mempool and announce it to peers, return why if it is rejected
+    // One held until its lock time is kept without being announced
+    pub fn submit_transaction(&self, tran: &SignedTransaction) -> Result<H256, TransactionError> {
+        let mut mempool = self.mempool.lock().unwrap();
+        let accepted = mempool.accept(tran)?;
+        drop(mempool);
+        if accepted == Accepted::Held {
+            info!("Submitted transaction {:?} is held until its lock time", tran.hash);
+            return Ok(tran.hash.clone());
+        }
+        info!("Submitted transaction {:?} is put into mempool", tran.hash);
+        if self.dandelion {
+            self.server.broadcast(Message::NewDandelionTransactions(vec![tran.clone()]), None);
+        } else {
+            self.server.broadcast(Message::NewTransactionHashes(vec![tran.hash.clone()]), None);
+        }
+        return Ok(tran.hash.clone());
+    }
+
+    // Transaction with a given hash from mempool, or else from the longest chain, with its status
+    pub fn get_raw_transaction(&self, hash: &H256) -> Option<(SignedTransaction, TxStatus)> {
+        let unconfirmed = self.mempool.lock().unwrap().get_trans(&vec![hash.clone()]).pop();
+        if let Some(tran) = unconfirmed {
+            return Some((tran, TxStatus::Unconfirmed));
+        }
+        let (tran, block, confirmations) = self.blockchain.lock().unwrap().get_transaction(hash)?;
+        return Some((tran, TxStatus::Confirmed { block, confirmations }));
+    }
+
+    // Hash of the block at a given height of the longest chain
+    pub fn get_block_hash(&self, height: usize) -> Option<H256> {
+        self.blockchain.lock().unwrap().get_block_by_index(height).map(|b| b.hash)
+    }
+
+    // Height of the tip of the longest chain
+    pub fn get_block_count(&self) -> usize {
+        self.blockchain.lock().unwrap().get_block_count()
+    }
+
+    // Whether the node is still syncing, so its tip shouldn't be trusted yet
+    pub fn is_initial_block_download(&self) -> bool {
+        self.blockchain.lock().unwrap().is_initial_block_download()
+    }
+
+    // Hash of the tip of the longest chain
+    pub fn get_best_block_hash(&self) -> H256 {
+        self.blockchain.lock().unwrap().tip()
+    }
+
+    // Balance of an address including unconfirmed transactions: confirmed coins not spent by mempool
+    // transactions, plus mempool outputs to the address not spent by other mempool transactions
+    pub fn available_balance(&self, addr: &H160) -> u64 {
+        let blockchain = self.blockchain.lock().unwrap();
+        let state = blockchain.tip_block_state();
+        let confirmed = blockchain.balance(addr);
+        drop(blockchain);
+
+        let mempool = self.mempool.lock().unwrap();
+        let mut incoming = 0u64;
+        let mut pending: HashMap<(H256, u32), u64> = HashMap::new();
+        for (hash, tran) in mempool.transactions.iter() {
+            for (index, output) in tran.transaction.outputs.iter().enumerate() {
+                if output.rec_address == *addr {
+                    pending.insert((hash.clone(), index as u32), output.val);
+                    incoming += output.val;
+                }
+            }
+        }
+        let mut outgoing = 0u64;
+        for tran in mempool.transactions.values() {
+            for input in tran.transaction.inputs.iter() {
+                let outpoint = (input.pre_hash.clone(), input.index);
+                if let Some(val) = pending.get(&outpoint) {
+                    outgoing += val;
+                } else if let Some((val, owner)) = state.get(&outpoint) {
+                    if *owner == *addr {
+                        outgoing += val;
+                    }
+                }
+            }
+        }
+        return (confirmed + incoming).saturating_sub(outgoing);
+    }
+
+    // Difficulty of the next block on tip, as a multiple of the easiest target's
+    pub fn get_difficulty(&self) -> f64 {
+        let target = self.blockchain.lock().unwrap().difficulty();
+        difficulty::block_work(&target) as f64 / difficulty::block_work(&difficulty::max_target()) as f64
+    }
+
+    // Estimated network hashes per second over the last `window` blocks of the longest chain
+    pub fn get_network_hashps(&self, window: usize) -> Option<f64> {
+        self.blockchain.lock().unwrap().network_hashps(window)
+    }
+
+    // Template of the next block on tip from the current mempool
+    pub fn get_block_template(&self) -> BlockTemplate {
+        let blockchain = self.blockchain.lock().unwrap();
+        let parent = blockchain.tip();
+        let height = blockchain.get_block_count() + 1;
+        let difficulty = blockchain.difficulty();
+        let min_timestamp = blockchain.median_time_past(&parent) + 1;
+        let mempool = self.mempool.lock().unwrap();
+        drop(blockchain);
+        let transactions = mempool.select_transactions(BLOCK_SIZE_LIMIT, MAX_BLOCK_BYTES);
+        drop(mempool);
+        let fees: u64 = transactions.iter().map(|t| t.transaction.fee).sum();
+        BlockTemplate {
+            parent,
+            height,
+            difficulty,
+            min_timestamp,
+            coinbase_value: Blockchain::block_subsidy(height) + fees,
+            transactions,
+        }
+    }
+
+    // Long-poll for a template replacing one on `parent`: wait until tip moves away from it or LONGPOLL_NEW_TXS
+    // transactions enter mempool, at most for timeout, then return the template
+    pub fn wait_block_template(&self, parent: &H256, timeout: Duration) -> BlockTemplate {
+        // subscribe before looking at tip, so a block arriving in between isn't missed
+        let blocks = self.blockchain.lock().unwrap().subscribe();
+        let trans = self.mempool.lock().unwrap().subscribe();
+        let deadline = Instant::now() + timeout;
+        let mut new_trans = 0;
+        while self.get_best_block_hash() == *parent && new_trans < LONGPOLL_NEW_TXS {
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(d) => d,
+                None => break,
+            };
+            select! {
+                recv(blocks) -> _ => {}
+                recv(trans) -> event => {
+                    if let Ok(Event::TxAdded(_)) = event {
+                        new_trans += 1;
+                    }
+                }
+                default(remaining) => break,
+            }
+        }
+        self.get_block_template()
+    }
+
+    // Insert a given block into blockchain and announce it if it moves the tip, return its hash if accepted
+    pub fn submit_block(&self, block: &Block) -> Result<H256, BlockError> {
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let old_tip = blockchain.tip();
+        let outcome = blockchain.insert_with_check(block)?;
+        info!("Submitted block {:?} is accepted: {:?}", block.hash, outcome);
+        match outcome {
+            InsertOutcome::Extended | InsertOutcome::Reorg { .. } => {
+                let mut mempool = self.mempool.lock().unwrap();
+                let conflicts = blockchain.mempool_conflicts(&block.hash, &mempool);
+                let disconnected = blockchain.disconnected_trans(&old_tip);
+                let tip_state = blockchain.tip_block_state();
+                mempool.set_tip_state(tip_state.clone());
+                if !disconnected.is_empty() {
+                    mempool.apply_reorg(&disconnected, &tip_state);
+                }
+                let (tip_height, tip_time) = (blockchain.get_block_count(), blockchain.tip_header().timestamp);
+                drop(blockchain);
+                mempool.remove_trans(&block.content.get_trans_hashes());
+                mempool.remove_conflicts(&conflicts);
+                mempool.update_tip(tip_height, tip_time);
+                drop(mempool);
+                self.server.relay_blocks(vec![block.clone()], None);
+            }
+            InsertOutcome::SideBranch | InsertOutcome::Orphaned | InsertOutcome::AlreadyHave => {}
+        }
+        return Ok(block.hash.clone());
+    }
+}
+
+// A whole node: p2p server, workers, transaction generator and miner, owned together so they
+// are started and shut down in order
+pub struct Node {
+    server_ctx: Option<server::Context>,
+    spreader_ctx: Option<spread::Context>,
+    worker_ctx: Option<worker::Context>,
+    generator_ctx: Option<transaction_generator::Context>,
+    miner_ctx: Option<miner::Context>,
+    server: ServerHandle,
+    generator: transaction_generator::Handle,
+    miner: miner::Handle,
+    worker: Option<worker::Handle>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
+    peers: Arc<Mutex<Peers>>,
+    account: Arc<Account>,
+    known_peers: Vec<SocketAddr>,  // bootstrap peers connected to on start
+    dandelion: bool,
+    started: bool,
+}
+
+impl Node {
+    // Wire up all parts of a node listening at addr, nothing runs before start
+    pub fn new(addr: SocketAddr, spreader_type: Spreader, num_worker: usize, queue_capacity: usize,
+               blockchain: Arc<OrderedMutex<Blockchain>>, mempool: Arc<OrderedMutex<MemPool>>,
+               key_pair: Arc<Ed25519KeyPair>) -> io::Result<Node> {
+        let dandelion = spreader_type == Spreader::Dandelion || spreader_type == Spreader::DandelionPlus;
+        let (msg_tx, msg_rx) = channel::bounded(queue_capacity);
+        let (mut server_ctx, server, spreader_ctx) = server::new(addr, msg_tx, spreader_type, mempool.clone())?;
+        server_ctx.set_genesis(blockchain.lock().unwrap().genesis_id());
+
+        let peers = Arc::new(Mutex::new(Peers::new()));
+        let account = Arc::new(Account::new(addr.port(), key_pair.clone()));
+        let worker_ctx = worker::new(num_worker, msg_rx, server.clone(), blockchain.clone(), mempool.clone(),
+                                     peers.clone(), account.addr, account.get_pub_key(), account.port);
+        let (generator_ctx, generator) = transaction_generator::new(server.clone(), mempool.clone(),
+            blockchain.clone(), peers.clone(), account.clone(), dandelion);
+        let (miner_ctx, miner) = miner::new(server.clone(), blockchain.clone(), mempool.clone(), key_pair);
+
+        Ok(Node {
+            server_ctx: Some(server_ctx),
+            spreader_ctx: Some(spreader_ctx),
+            worker_ctx: Some(worker_ctx),
+            generator_ctx: Some(generator_ctx),
+            miner_ctx: Some(miner_ctx),
+            server,
+            generator,
+            miner,
+            worker: None,
+            blockchain,
+            mempool,
+            peers,
+            account,
+            known_peers: vec![],
+            dandelion,
+            started: false,
+        })
+    }
+
+    // Peers to connect to on start, set before start
+    pub fn set_known_peers(&mut self, known_peers: Vec<SocketAddr>) {
+        self.known_peers = known_peers;
+    }
+
+    // See server::Context::set_connect_timeout, set before start
+    pub fn set_connect_timeout(&mut self, connect_timeout: u64) {
+        if let Some(ctx) = self.server_ctx.as_mut() {
+            ctx.set_connect_timeout(connect_timeout);
+        }
+    }
+
+    // See miner::Context::set_max_block_trans, set before start
+    pub fn set_max_block_trans(&mut self, max_block_trans: usize) {
+        if let Some(ctx) = self.miner_ctx.as_mut() {
+            ctx.set_max_block_trans(max_block_trans);
+        }
+    }
+
+    // See miner::Context::set_log_fees, set before start
+    pub fn set_log_fees(&mut self, log_fees: bool) {
+        if let Some(ctx) = self.miner_ctx.as_mut() {
+            ctx.set_log_fees(log_fees);
+        }
+    }
+
+    // See miner::Context::set_min_peers, set before start
+    pub fn set_min_peers(&mut self, min_peers: usize) {
+        if let Some(ctx) = self.miner_ctx.as_mut() {
+            ctx.set_min_peers(min_peers);
+        }
+    }
+
+    // See worker::Context::set_fee_filter, set before start
+    pub fn set_fee_filter(&mut self, min_fee_rate: u64) {
+        if let Some(ctx) = self.worker_ctx.as_mut() {
+            ctx.set_fee_filter(min_fee_rate);
+        }
+    }
+
+    // See worker::Context::set_high_bandwidth, set before start
+    pub fn set_high_bandwidth(&mut self, high_bandwidth: bool) {
+        if let Some(ctx) = self.worker_ctx.as_mut() {
+            ctx.set_high_bandwidth(high_bandwidth);
+        }
+    }
+
+    // Bind the p2p server, connect to known peers, then start workers, transaction generator and miner.
+    // The miner starts paused. Return the known peers actually connected to
+    pub fn start(&mut self) -> io::Result<Vec<SocketAddr>> {
+        let server_ctx = match self.server_ctx.take() {
+            Some(ctx) => ctx,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "node already started")),
+        };
+        server_ctx.start()?;
+        self.started = true;
+        self.spreader_ctx.take().unwrap().start();
+        let connected = helper::connect_peers(&self.server, &self.known_peers);
+        self.worker = Some(self.worker_ctx.take().unwrap().start());
+        self.generator_ctx.take().unwrap().start();
+        self.miner_ctx.take().unwrap().start();
+        info!("Node started at {:?}, connected to {} peers", self.account.addr, connected.len());
+        Ok(connected)
+    }
+
+    // Stop miner and transaction generator, then the p2p server and the workers,
+    // blocking until all connections are closed and worker threads have exited
+    pub fn shutdown(self) {
+        if !self.started {
+            return;
+        }
+        self.miner.exit();
+        self.generator.exit();
+        self.server.shutdown();
+        if let Some(worker) = self.worker {
+            worker.shutdown();
+        }
+        info!("Node {:?} shut down", self.account.addr);
+    }
+
+    // Node-level operations, e.g. for the API
+    pub fn handle(&self) -> Handle {
+        new(self.server.clone(), self.blockchain.clone(), self.mempool.clone(), self.dandelion)
+    }
+
+    pub fn server(&self) -> &ServerHandle {
+        &self.server
+    }
+
+    pub fn miner(&self) -> &miner::Handle {
+        &self.miner
+    }
+
+    pub fn transaction_generator(&self) -> &transaction_generator::Handle {
+        &self.generator
+    }
+
+    pub fn blockchain(&self) -> &Arc<OrderedMutex<Blockchain>> {
+        &self.blockchain
+    }
+
+    pub fn mempool(&self) -> &Arc<OrderedMutex<MemPool>> {
+        &self.mempool
+    }
+
+    pub fn peers(&self) -> &Arc<Mutex<Peers>> {
+        &self.peers
+    }
+
+    pub fn account(&self) -> &Arc<Account> {
+        &self.account
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::blockchain::CheckPolicy;
+    use crate::helper::*;
+    use crate::spread::Spreader;
+    use crate::block::ParseBlockError;
+    use crate::crypto::key_pair;
+    use crate::block::Content;
+    use crate::config::{COINBASE_REWARD, INV_FLUSH_INTERVAL};
+    use crate::transaction::{LockTime, TxInput, TxOutput};
+
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+    use std::time;
+    use std::thread;
+
+    #[test]
+    fn test_submit_transaction() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17403);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17404);
+
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (server_2, _, _, _, mempool_2, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        connect_peers(&server_1, &vec![p2p_addr_2]);
+        thread::sleep(time::Duration::from_millis(100));
+
+        let node = new(server_1, blockchain_1, mempool_1.clone(), false);
+        let tran = generate_random_signed_transaction();
+        assert_eq!(Ok(tran.hash), node.submit_transaction(&tran));
+        assert_eq!(Err(TransactionError::AlreadyExists), node.submit_transaction(&tran));
+        assert!(mempool_1.lock().unwrap().exist(&tran.hash));
+
+        // held until its lock time, not announced meanwhile
+        let locked = generate_signed_locked_transaction(&key_pair::random(), vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], LockTime::Height(100));
+        assert_eq!(Ok(locked.hash), node.submit_transaction(&locked));
+        assert!(mempool_1.lock().unwrap().is_non_final(&locked.hash));
+
+        thread::sleep(time::Duration::from_millis(INV_FLUSH_INTERVAL + 100));
+        assert!(mempool_2.lock().unwrap().exist(&tran.hash));
+        assert!(!mempool_2.lock().unwrap().is_non_final(&locked.hash));
+        drop(server_2);
+    }
+
+    #[test]
+    fn test_submit_transaction_rejected() {
+        let p2p_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17453);
+        let (server, _, _, blockchain, mempool, _, _) = new_server_env(p2p_addr, Spreader::Default, false);
+        let node = new(server, blockchain, mempool.clone(), false);
+        let key = key_pair::random();
+        let input = TxInput::new(generate_random_hash(), 0);
+        let output = TxOutput::new(generate_random_h160(), 10);
+        let tran = generate_signed_transaction_with_fee(&key, vec![input.clone()], vec![output.clone()], 10);
+        assert_eq!(Ok(tran.hash), node.submit_transaction(&tran));
+
+        let mut forged = generate_random_signed_transaction();
+        forged.signature = vec![0u8; 64].into_boxed_slice();
+        assert_eq!(Err(TransactionError::InvalidSignature), node.submit_transaction(&forged));
+
+        let conflicting = generate_signed_transaction_with_fee(&key, vec![input], vec![output], 10);
+        assert_eq!(Err(TransactionError::DoubleSpend), node.submit_transaction(&conflicting));
+
+        let dust = generate_signed_transaction(&key, vec![TxInput::new(generate_random_hash(), 0)],
+            vec![TxOutput::new(generate_random_h160(), 0)]);
+        assert_eq!(Err(TransactionError::NonStandard), node.submit_transaction(&dust));
+
+        // full by bytes: a lower fee rate than every pool transaction is evicted right away
+        let cheap = generate_signed_transaction(&key, vec![TxInput::new(generate_random_hash(), 0)],
+            vec![TxOutput::new(generate_random_h160(), 10)]);
+        mempool.lock().unwrap().set_max_bytes(tran.size());
+        assert_eq!(Err(TransactionError::FeeTooLow), node.submit_transaction(&cheap));
+        assert!(mempool.lock().unwrap().exist(&tran.hash));
+
+        mempool.lock().unwrap().set_max_count(1);
+        let other = generate_random_signed_transaction();
+        assert_eq!(Err(TransactionError::PoolFull), node.submit_transaction(&other));
+
+        mempool.lock().unwrap().set_max_count(10);
+        mempool.lock().unwrap().set_max_bytes(tran.size() * 10);
+        mempool.lock().unwrap().set_policy(Some(Box::new(|_| false)));
+        let other = generate_random_signed_transaction();
+        assert_eq!(Err(TransactionError::PolicyRejected), node.submit_transaction(&other));
+        assert_eq!(1, mempool.lock().unwrap().size());
+    }
+
+    #[test]
+    fn test_submit_raw_block() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17409);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17410);
+
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let (_server_2, _, _, blockchain_2, _, _, _) = new_server_env(p2p_addr_2, Spreader::Default, false);
+        connect_peers(&server_1, &vec![p2p_addr_2]);
+        thread::sleep(time::Duration::from_millis(100));
+        blockchain_1.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        blockchain_2.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+
+        let block = {
+            let chain = blockchain_1.lock().unwrap();
+            generate_mined_block(&chain.tip(), &chain.difficulty())
+        };
+        let raw = block.to_hex();
+        let parsed = Block::from_hex(&raw).unwrap();
+        assert_eq!(block, parsed);
+        assert_eq!(block.content.get_trans_hashes(), parsed.content.get_trans_hashes());
+        assert_eq!(Err(ParseBlockError::InvalidHex), Block::from_hex("xyz"));
+        assert_eq!(Err(ParseBlockError::InvalidEncoding), Block::from_hex(&raw[..20]));
+
+        let node = new(server_1, blockchain_1.clone(), mempool_1, false);
+        assert_eq!(Ok(block.hash.clone()), node.submit_block(&parsed));
+        assert_eq!(block.hash, blockchain_1.lock().unwrap().tip());
+        assert_eq!(Err(BlockError::AlreadyExists), node.submit_block(&parsed));
+
+        // peers fetch the announced block
+        thread::sleep(time::Duration::from_millis(200));
+        assert_eq!(block.hash, blockchain_2.lock().unwrap().tip());
+    }
+
+    #[test]
+    fn test_block_hash_queries() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17413);
+        let (server, _, _, blockchain, mempool, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+        let node = new(server, blockchain.clone(), mempool, false);
+
+        let genesis_hash = Block::genesis().hash;
+        assert_eq!(Some(genesis_hash.clone()), node.get_block_hash(0));
+        assert_eq!(genesis_hash, node.get_best_block_hash());
+
+        let mut main_chain = vec![genesis_hash.clone()];
+        for _ in 0..3 {
+            let chain = blockchain.lock().unwrap();
+            let block = generate_mined_block(&chain.tip(), &chain.difficulty());
+            drop(chain);
+            node.submit_block(&block).unwrap();
+            main_chain.push(block.hash);
+        }
+        // side branch blocks are not canonical
+        let difficulty = blockchain.lock().unwrap().difficulty();
+        let side = generate_mined_block(&main_chain[1], &difficulty);
+        node.submit_block(&side).unwrap();
+
+        assert_eq!(main_chain[3], node.get_best_block_hash());
+        for (height, hash) in main_chain.iter().enumerate() {
+            assert_eq!(Some(hash.clone()), node.get_block_hash(height));
+        }
+        assert_eq!(Some(genesis_hash), node.get_block_hash(0));
+        assert_eq!(None, node.get_block_hash(4));
+    }
+
+    #[test]
+    fn test_available_balance() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17442);
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let node = new(server_1, blockchain_1.clone(), mempool_1, false);
+        let key = key_pair::random();
+        let addr = address_of(&key);
+        let other = generate_random_h160();
+
+        let block = {
+            let chain = blockchain_1.lock().unwrap();
+            let content = Content::new_with_trans(&vec![
+                generate_signed_coinbase_transaction_with_reward(&key, COINBASE_REWARD)]);
+            Block::new(generate_header(&chain.tip(), &content, 0, &chain.difficulty()), content)
+        };
+        node.submit_block(&block).unwrap();
+        assert_eq!(COINBASE_REWARD, blockchain_1.lock().unwrap().balance(&addr));
+        assert_eq!(COINBASE_REWARD, node.available_balance(&addr));
+
+        // spending the mined coin leaves only the change
+        let coin = TxInput::new(block.content.trans[0].hash, 0);
+        let spend = generate_signed_transaction(&key, vec![coin],
+            vec![TxOutput::new(other, 20), TxOutput::new(addr, COINBASE_REWARD - 20)]);
+        assert_eq!(Ok(spend.hash), node.submit_transaction(&spend));
+        assert_eq!(COINBASE_REWARD, blockchain_1.lock().unwrap().balance(&addr));
+        assert_eq!(COINBASE_REWARD - 20, node.available_balance(&addr));
+
+        // chained transaction spends the unconfirmed change
+        let change = TxInput::new(spend.hash, 1);
+        let chained = generate_signed_transaction(&key, vec![change],
+            vec![TxOutput::new(other, 10), TxOutput::new(addr, COINBASE_REWARD - 30)]);
+        assert_eq!(Ok(chained.hash), node.submit_transaction(&chained));
+        assert_eq!(COINBASE_REWARD, blockchain_1.lock().unwrap().balance(&addr));
+        assert_eq!(COINBASE_REWARD - 30, node.available_balance(&addr));
+        assert_eq!(30, node.available_balance(&other));
+        assert_eq!(0, blockchain_1.lock().unwrap().balance(&other));
+    }
+
+    #[test]
+    fn test_get_raw_transaction() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17460);
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let node = new(server_1, blockchain_1.clone(), mempool_1, false);
+        let key = key_pair::random();
+        let addr = address_of(&key);
+        let mine = |trans: Vec<SignedTransaction>| {
+            let chain = blockchain_1.lock().unwrap();
+            let content = Content::new_with_trans(&trans);
+            Block::new(generate_header(&chain.tip(), &content, 0, &chain.difficulty()), content)
+        };
+
+        let coinbase = generate_signed_coinbase_transaction_with_reward(&key, COINBASE_REWARD);
+        let block = mine(vec![coinbase]);
+        node.submit_block(&block).unwrap();
+        let coin = TxInput::new(block.content.trans[0].hash, 0);
+        let spend = generate_signed_transaction(&key, vec![coin],
+            vec![TxOutput::new(generate_random_h160(), 20), TxOutput::new(addr, COINBASE_REWARD - 20)]);
+        assert_eq!(None, node.get_raw_transaction(&spend.hash));
+
+        // unconfirmed while in mempool
+        assert_eq!(Ok(spend.hash), node.submit_transaction(&spend));
+        assert_eq!(Some((spend.clone(), TxStatus::Unconfirmed)), node.get_raw_transaction(&spend.hash));
+
+        // confirmed once mined, confirmations grow with the chain
+        let coinbase = generate_signed_coinbase_transaction_with_reward(&key, COINBASE_REWARD);
+        let block = mine(vec![coinbase, spend.clone()]);
+        node.submit_block(&block).unwrap();
+        let status = TxStatus::Confirmed { block: block.hash, confirmations: 1 };
+        assert_eq!(Some((spend.clone(), status)), node.get_raw_transaction(&spend.hash));
+        node.submit_block(&mine(vec![generate_signed_coinbase_transaction_with_reward(&key, COINBASE_REWARD)]))
+            .unwrap();
+        let status = TxStatus::Confirmed { block: block.hash, confirmations: 2 };
+        assert_eq!(Some((spend.clone(), status)), node.get_raw_transaction(&spend.hash));
+    }
+
+    #[test]
+    fn test_block_template_long_poll() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17463);
+        let (server_1, _, _, blockchain_1, mempool_1, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let node = new(server_1, blockchain_1.clone(), mempool_1, false);
+        let tran = generate_random_signed_transaction();
+        assert_eq!(Ok(tran.hash), node.submit_transaction(&tran));
+        let template = node.get_block_template();
+        assert_eq!(blockchain_1.lock().unwrap().tip(), template.parent);
+        assert_eq!(1, template.height);
+        assert_eq!(vec![tran.clone()], template.transactions);
+        assert_eq!(COINBASE_REWARD + tran.transaction.fee, template.coinbase_value);
+
+        // unchanged template is returned after the timeout
+        let start = time::Instant::now();
+        assert_eq!(template, node.wait_block_template(&template.parent, time::Duration::from_millis(200)));
+        assert!(start.elapsed() >= time::Duration::from_millis(200));
+
+        // a new block wakes the long-poll up
+        let poller = node.clone();
+        let parent = template.parent.clone();
+        let start = time::Instant::now();
+        let waiting = thread::spawn(move || poller.wait_block_template(&parent, time::Duration::from_secs(10)));
+        thread::sleep(time::Duration::from_millis(100));
+        let block = {
+            let chain = blockchain_1.lock().unwrap();
+            let content = Content::new_with_trans(&vec![generate_signed_coinbase_transaction(&key_pair::random())]);
+            Block::new(generate_header(&chain.tip(), &content, 0, &chain.difficulty()), content)
+        };
+        node.submit_block(&block).unwrap();
+        let template = waiting.join().unwrap();
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+        assert_eq!(block.hash, template.parent);
+        assert_eq!(2, template.height);
+    }
+
+    #[test]
+    fn test_node_start_shutdown() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17439);
+        let p2p_addr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17440);
+        let new_node = |addr| {
+            Node::new(addr, Spreader::Default, 2, 100, Arc::new(OrderedMutex::new(Blockchain::new())),
+                      Arc::new(OrderedMutex::new(MemPool::new())), Arc::new(key_pair::random())).unwrap()
+        };
+        let mut node_1 = new_node(p2p_addr_1);
+        let mut node_2 = new_node(p2p_addr_2);
+        // bound on return, so node_2 can bootstrap from node_1 right away
+        assert!(node_1.start().unwrap().is_empty());
+        node_2.set_known_peers(vec![p2p_addr_1]);
+        assert_eq!(vec![p2p_addr_1], node_2.start().unwrap());
+        assert!(node_2.start().is_err());
+
+        // nodes talk to each other
+        let tran = generate_random_signed_transaction();
+        assert!(node_1.handle().submit_transaction(&tran).is_ok());
+        let start = time::Instant::now();
+        while !node_2.mempool().lock().unwrap().exist(&tran.hash) {
+            assert!(start.elapsed() < time::Duration::from_secs(3), "transaction not relayed");
+            thread::sleep(time::Duration::from_millis(50));
+        }
+
+        let (sender, receiver) = crossbeam::channel::bounded(1);
+        thread::spawn(move || {
+            node_2.shutdown();
+            node_1.shutdown();
+            sender.send(()).unwrap();
+        });
+        receiver.recv_timeout(time::Duration::from_secs(5)).expect("shutdown timed out");
+        // listeners are closed
+        std::net::TcpListener::bind(p2p_addr_1).unwrap();
+        std::net::TcpListener::bind(p2p_addr_2).unwrap();
+    }
+}
@@ -15,6 +15,7 @@ use chrono;
 use timer::{MessageTimer, Guard};
 use rand_distr::{Exp, Distribution};
 use crate::mempool::MemPool;
+use crate::lock_order::OrderedMutex;
 use crate::crypto::hash::{H256, Hashable};
 
 pub trait Spreading {
@@ -38,7 +39,7 @@ pub enum TimerTask {
     DandelionPlusFailSafeCheck(i64, Vec<H256>),
 }
 
-fn new_base(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (MessageTimer<TimerTask>, Arc<Mutex<HashMap<i64, Guard>>>, Context) {
+fn new_base(mempool: Arc<OrderedMutex<MemPool>>, handle: ServerHandle) -> (MessageTimer<TimerTask>, Arc<Mutex<HashMap<i64, Guard>>>, Context) {
     let (sender, receiver) = channel();
     let timer = MessageTimer::new(sender);
     let guard_map = Arc::new(Mutex::new(HashMap::new()));
@@ -50,7 +51,7 @@ fn new_base(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (MessageTimer
 pub struct Context {
     pub receiver: Receiver<TimerTask>,
     guard_map: Arc<Mutex<HashMap<i64, Guard>>>,
-    mempool: Arc<Mutex<MemPool>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
     server: ServerHandle,
 }
 
@@ -61,7 +62,17 @@ impl Context {
                 Ok(task) => {
                     match task {
                         TimerTask::PeerWrite(nano, handle, msg) => {
-                            handle.write(msg);
+                            match msg {
+                                // transactions below the peer's fee filter are not announced to it
+                                Message::NewTransactionHashes(hashes) if handle.fee_filter() > 0 => {
+                                    let hashes = self.mempool.lock().unwrap()
+                                        .filter_fee_rate(hashes, handle.fee_filter());
+                                    if hashes.len() > 0 {
+                                        handle.write(Message::NewTransactionHashes(hashes));
+                                    }
+                                }
+                                msg => handle.write(msg),
+                            }
                             self.guard_map.lock().unwrap().remove(&nano);
                         }
                         TimerTask::DandelionResetEpoch(nano, target_index) => {
@@ -96,7 +107,8 @@ impl Context {
                         }
                     }
                 }
-                _ => {}
+                // the server owning the timer is gone
+                Err(_) => return,
             }
         }
     }
@@ -129,7 +141,7 @@ impl Spreading for DefaultSpreader {
 }
 
 impl DefaultSpreader {
-    pub fn new(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
+    pub fn new(mempool: Arc<OrderedMutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
         let (timer, guard_map, context) = new_base(mempool, handle);
         (DefaultSpreader { timer, guard_map }, context)
     }
@@ -141,7 +153,7 @@ struct TrickleSpreader {
 }
 
 impl TrickleSpreader {
-    pub fn new(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
+    pub fn new(mempool: Arc<OrderedMutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
         let (timer, guard_map, context) = new_base(mempool, handle);
         (TrickleSpreader { timer, guard_map }, context)
     }
@@ -182,7 +194,7 @@ struct DiffusionSpreader {
 }
 
 impl DiffusionSpreader {
-    pub fn new(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
+    pub fn new(mempool: Arc<OrderedMutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
         let (timer, guard_map, context) = new_base(mempool, handle);
         (DiffusionSpreader { timer, guard_map }, context)
     }
@@ -198,13 +210,13 @@ struct DandelionSpreader {
     timer: MessageTimer<TimerTask>,
     guard_map: Arc<Mutex<HashMap<i64, Guard>>>,
     target_index: Arc<Mutex<usize>>,
-    mempool: Arc<Mutex<MemPool>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
     epoch_period_ms: i64,
     phase_switch_prob: u64,
 }
 
 impl DandelionSpreader {
-    pub fn new(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
+    pub fn new(mempool: Arc<OrderedMutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
         let (timer, guard_map, context) = new_base(mempool.clone(), handle);
         (DandelionSpreader {
             timer, guard_map,
@@ -276,13 +288,13 @@ struct DandelionPlusSpreader {
     guard_map: Arc<Mutex<HashMap<i64, Guard>>>,
     routing_table: Arc<Mutex<HashMap<usize, usize>>>, // One-to-one (inbound, outbound) pairs
     is_diffuser: bool, // true -> diffuser; false -> dandelion-relayer
-    mempool: Arc<Mutex<MemPool>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
     epoch_period_ms: i64,
     is_diffuser_prob: u64,
 }
 
 impl DandelionPlusSpreader {
-    pub fn new(mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
+    pub fn new(mempool: Arc<OrderedMutex<MemPool>>, handle: ServerHandle) -> (Self, Context) {
         let (timer, guard_map, context) = new_base(mempool.clone(), handle);
         (DandelionPlusSpreader {
             timer, guard_map,
@@ -411,7 +423,7 @@ impl Spreading for DandelionPlusSpreader {
     }
 }
 
-pub fn get_spreader(key: Spreader, mempool: Arc<Mutex<MemPool>>, handle: ServerHandle) -> (Box<dyn Spreading + Send>, Context) {
+pub fn get_spreader(key: Spreader, mempool: Arc<OrderedMutex<MemPool>>, handle: ServerHandle) -> (Box<dyn Spreading + Send>, Context) {
     match key {
         Spreader::Default => {
             let (spreader, ctx) = DefaultSpreader::new(mempool, handle);
@@ -449,8 +461,9 @@ mod tests {
     use crate::network::peer;
     use crate::network::message::Message;
     use crate::network::server;
+    use crate::config::INV_FLUSH_INTERVAL;
 
-    fn check_mempools_total_size(mempool_list: &Vec<Arc<Mutex<MemPool>>>, expect_size: usize) {
+    fn check_mempools_total_size(mempool_list: &Vec<Arc<OrderedMutex<MemPool>>>, expect_size: usize) {
         let mut cur = 0;
         for m in mempool_list.iter() {
             cur += m.lock().unwrap().size();
@@ -474,7 +487,8 @@ mod tests {
         connect_peers(&server_3, &peers_2);
 
         generator_1.generating();
-        sleep(time::Duration::from_millis((TRICKLE_GAP_TIME + 100) as u64));
+        // announcements wait INV_FLUSH_INTERVAL for more hashes before trickling
+        sleep(time::Duration::from_millis((TRICKLE_GAP_TIME + 100) as u64 + INV_FLUSH_INTERVAL));
 
         let pool_1 = mempool_1.lock().unwrap();
         let pool_2 = mempool_2.lock().unwrap();
@@ -490,7 +504,7 @@ mod tests {
 
         // after one and a half time, both of 2, 3 will receive the transaction
 
-        sleep(time::Duration::from_millis((TRICKLE_GAP_TIME * 2 + 100) as u64));
+        sleep(time::Duration::from_millis((TRICKLE_GAP_TIME * 2 + 100) as u64 + INV_FLUSH_INTERVAL));
 
         let pool_1 = mempool_1.lock().unwrap();
         let pool_2 = mempool_2.lock().unwrap();
@@ -519,11 +533,14 @@ mod tests {
         // all other servers directly connect to server 1
         let peers_list = vec![p2p_addr_2, p2p_addr_3, p2p_addr_4, p2p_addr_5];
         connect_peers(&server_1, &peers_list);
+        // let the handshakes finish, otherwise the transaction is pulled by GetMempool without diffusion
+        sleep(time::Duration::from_millis(100));
 
         let mempool_list = vec![mempool_2.clone(), mempool_3.clone(),
                                 mempool_4.clone(), mempool_5.clone()];
 
         generator_1.generating();
+        sleep(time::Duration::from_millis(INV_FLUSH_INTERVAL));
         let mut sleep_time = DIFFUSION_BASE_GAP_TIME as f64;
         for i in 1..5 {
             sleep_time *= DIFFUSION_RATE;
@@ -544,7 +561,7 @@ mod tests {
         let vacant = peers.vacant_entry();
         let key: usize = vacant.key();
         let mut peer_list = Vec::<usize>::new();
-        let (peer_ctx, handle) = peer::new(mio_stream, peer::Direction::Outgoing, key).unwrap();
+        let (peer_ctx, handle) = peer::new(mio_stream, peer::Direction::Outgoing, key, Default::default()).unwrap();
         vacant.insert(peer_ctx);
         peer_list.push(key);
         let trans = vec![helper::generate_random_signed_transaction()];
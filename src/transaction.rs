@@ -1,12 +1,15 @@
 use bincode;
 use serde::{Serialize, Deserialize};
 use ring::digest;
-use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, EcdsaKeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters,
+                      ECDSA_P256_SHA256_FIXED};
+use std::cmp::Ordering;
 use std::time::SystemTime;
 use std::str;
 
 use crate::crypto::hash::{Hashable, H256, H160};
-use crate::config::COINBASE_REWARD;
+use crate::config::{DUST_THRESHOLD, MAX_FEE_RATE, WITNESS_SCALE_FACTOR};
 
 ///UTXO model transaction
 #[derive(Eq, PartialEq, Serialize, Deserialize, Debug, Default, Clone, Hash)]
@@ -15,6 +18,20 @@ pub struct SignedTransaction {
     pub hash: H256,
     pub signature: Box<[u8]>,
     pub public_key: Box<[u8]>,
+    pub scheme: SignatureScheme,
+}
+
+// Algorithm of signature & public key, validators verify a transaction by its own scheme
+#[derive(Eq, PartialEq, Serialize, Deserialize, Debug, Clone, Copy, Hash)]
+pub enum SignatureScheme {
+    Ed25519,
+    EcdsaP256,  // ECDSA with P-256 curve and SHA-256
+}
+
+impl Default for SignatureScheme {
+    fn default() -> Self {
+        SignatureScheme::Ed25519
+    }
 }
 
 #[derive(Eq, PartialEq, Serialize, Deserialize, Debug, Default, Clone, Hash)]
@@ -22,6 +39,17 @@ pub struct Transaction {
     pub inputs: Vec<TxInput>,
     pub outputs: Vec<TxOutput>,
     pub ts: u64,  // timestamp to avoid same hash
+    pub fee: u64,  // value left to miner, inputs must cover outputs and fee
+    pub commitment: Option<H256>,  // only in coinbase, commits to the auxiliary root of its block
+    pub replaceable: bool,  // opts in to be replaced in mempool by a conflicting transaction paying more
+    pub lock_time: Option<LockTime>,  // None: final, may be in any block
+}
+
+// Earliest block a transaction may be in, by its height or its timestamp
+#[derive(Eq, PartialEq, Serialize, Deserialize, Debug, Clone, Copy, Hash)]
+pub enum LockTime {
+    Height(usize),
+    Time(u64),  // time(ms since epoch)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,6 +73,15 @@ pub struct TxOutput {
     pub val: u64,        // Value to transfer
 }
 
+// Spending condition of an output. The spender's signature is verified by sign_check,
+// a lock only decides whether the signing key is allowed to spend. Pay-to-pubkey-hash is the only
+// condition, so outputs and UTXO entries keep just the recipient address and the lock is derived
+// from it (TxOutput::lock, State::lock_of) rather than stored as a script
+#[derive(Eq, PartialEq, Debug, Clone, Hash)]
+pub enum Lock {
+    PayToPubkeyHash(H160),  // public key of the spender must hash to the address
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PrintableTxInput {
     pub pre_hash: String,
@@ -70,25 +107,87 @@ impl Hashable for Transaction {
 
 impl Transaction {
     pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Self {
+        Self::new_with_fee(inputs, outputs, 0)
+    }
+
+    pub fn new_with_fee(inputs: Vec<TxInput>, outputs: Vec<TxOutput>, fee: u64) -> Self {
         let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap().as_millis() as u64;
-        Self {inputs: inputs, outputs: outputs, ts: ts}
+        Self {inputs: inputs, outputs: outputs, ts: ts, fee: fee, commitment: None, replaceable: false, lock_time: None}
+    }
+
+    // Whether the lock time allows the transaction in a block of the given height & timestamp(ms)
+    pub fn is_final(&self, height: usize, timestamp: u64) -> bool {
+        match self.lock_time {
+            None => true,
+            Some(LockTime::Height(h)) => height >= h,
+            Some(LockTime::Time(t)) => timestamp >= t,
+        }
     }
 }
 
 impl SignedTransaction {
     pub fn new(transaction: Transaction, signature: Box<[u8]>, public_key: Box<[u8]>) -> Self {
+        Self::new_with_scheme(transaction, signature, public_key, SignatureScheme::Ed25519)
+    }
+
+    pub fn new_with_scheme(transaction: Transaction, signature: Box<[u8]>, public_key: Box<[u8]>,
+                           scheme: SignatureScheme) -> Self {
         Self {
             hash: transaction.hash(),
             transaction: transaction,
             signature: signature,
             public_key: public_key,
+            scheme: scheme,
         }
     }
 
     // Call verify directly
     pub fn sign_check(&self) -> bool {
-        verify(&self.transaction, self.public_key.as_ref(), self.signature.as_ref())
+        verify_with_scheme(self.scheme, &self.transaction, self.public_key.as_ref(), self.signature.as_ref())
+    }
+
+    // Length of serialized transaction
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    // Serialized length of signature & public key, the witness part of a transaction
+    pub fn witness_size(&self) -> usize {
+        (bincode::serialized_size(&self.signature).unwrap() + bincode::serialized_size(&self.public_key).unwrap())
+            as usize
+    }
+
+    // Consensus weight: witness bytes count once, all other bytes WITNESS_SCALE_FACTOR times
+    pub fn weight(&self) -> usize {
+        let witness = self.witness_size();
+        (self.size() - witness) * WITNESS_SCALE_FACTOR + witness
+    }
+
+    // Size that fee rates are computed against, i.e. fee rate = fee / vsize.
+    // Same as size for now, witness data would be discounted here if ever added
+    pub fn vsize(&self) -> usize {
+        self.size()
+    }
+
+    // Compare fee rates exactly by cross multiplication, the only place fee rates are ordered
+    pub fn cmp_fee_rate(&self, other: &SignedTransaction) -> Ordering {
+        (self.transaction.fee as u128 * other.vsize() as u128)
+            .cmp(&(other.transaction.fee as u128 * self.vsize() as u128))
+    }
+
+    // Whether the fee rate is at least min_fee_rate(fee per byte), by cross multiplication as cmp_fee_rate
+    pub fn pays_fee_rate(&self, min_fee_rate: u64) -> bool {
+        (self.transaction.fee as u128) >= min_fee_rate as u128 * self.vsize() as u128
+    }
+
+    // Relay policy on top of validity: no dust outputs and no absurd fee rate.
+    // Only mempool checks it, a block with a non-standard transaction is still valid
+    pub fn is_standard(&self) -> bool {
+        if self.transaction.outputs.iter().any(|o| o.val < DUST_THRESHOLD) {
+            return false;
+        }
+        (self.transaction.fee as u128) <= MAX_FEE_RATE as u128 * self.vsize() as u128
     }
 
     pub fn sender_addr(&self) -> H160 {
@@ -101,16 +200,7 @@ impl SignedTransaction {
            self.transaction.outputs.len() != 1 {
             return false;
         }
-        // check value
-        let output = self.transaction.outputs[0].clone();
-        if output.val != COINBASE_REWARD {
-            return false;
-        }
-        // match address with public_key
-        let addr: H160 = digest::digest(&digest::SHA256, &self.public_key).into();
-        if addr != output.rec_address {
-            return false;
-        }
+        // the reward may go to any address, its amount is checked against the block's subsidy
         true
     }
 }
@@ -165,6 +255,15 @@ impl TxInput {
     }
 }
 
+impl Lock {
+    // Whether a transaction spending the locked output is signed by the key it's locked to
+    pub fn unlocked_by(&self, tran: &SignedTransaction) -> bool {
+        match self {
+            Lock::PayToPubkeyHash(addr) => tran.sender_addr() == *addr,
+        }
+    }
+}
+
 impl TxOutput {
     pub fn new(rec_address: H160, val: u64) -> Self {
         Self {
@@ -172,6 +271,11 @@ impl TxOutput {
             val,
         }
     }
+
+    // Condition a transaction spending this output must meet
+    pub fn lock(&self) -> Lock {
+        Lock::PayToPubkeyHash(self.rec_address)
+    }
 }
 
 /// Create digital signature of a transaction
@@ -180,9 +284,69 @@ pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     key.sign(bytes.as_ref())
 }
 
+/// Create ECDSA P-256 digital signature of a transaction
+pub fn sign_ecdsa(t: &Transaction, key: &EcdsaKeyPair) -> Signature {
+    let bytes = signed_bytes(t, SignatureScheme::EcdsaP256).unwrap();
+    key.sign(&SystemRandom::new(), bytes.as_ref()).unwrap()
+}
+
+/// Create a replacement of a transaction paying extra_fee more, taken from its change output
+/// (the largest output back to the signer). None if the original didn't opt in to replacement,
+/// key isn't the original signer or the change can't cover extra_fee without becoming dust
+pub fn bump_fee(original: &SignedTransaction, extra_fee: u64, key: &Ed25519KeyPair) -> Option<SignedTransaction> {
+    let public_key: Box<[u8]> = key.public_key().as_ref().into();
+    if !original.transaction.replaceable || public_key != original.public_key
+            || original.scheme != SignatureScheme::Ed25519 {
+        return None;
+    }
+    let sender = original.sender_addr();
+    let mut tran = original.transaction.clone();
+    let change = tran.outputs.iter_mut()
+        .filter(|o| o.rec_address == sender)
+        .max_by_key(|o| o.val)?;
+    if change.val < extra_fee.checked_add(DUST_THRESHOLD)? {
+        return None;
+    }
+    change.val -= extra_fee;
+    tran.fee = tran.fee.checked_add(extra_fee)?;
+    let signature: Box<[u8]> = sign(&tran, key).as_ref().into();
+    Some(SignedTransaction::new(tran, signature, public_key))
+}
+
+/// Bytes a signature of the given scheme is made over, None if the transaction can't be serialized.
+/// The scheme tag isn't part of the transaction hash, so schemes other than Ed25519 (whose signatures
+/// predate the tag) sign it along, and a signature is never valid under a scheme it wasn't made for
+fn signed_bytes(t: &Transaction, scheme: SignatureScheme) -> Option<Vec<u8>> {
+    match scheme {
+        SignatureScheme::Ed25519 => bincode::serialize(&t).ok(),
+        SignatureScheme::EcdsaP256 => bincode::serialize(&(scheme, t)).ok(),
+    }
+}
+
+/// Verify digital signature of a transaction with the given scheme (with bytes).
+/// Key and signature come from peers, any malformed one is simply invalid
+pub fn verify_with_scheme(scheme: SignatureScheme, t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
+    match scheme {
+        SignatureScheme::Ed25519 => verify(t, public_key, signature),
+        SignatureScheme::EcdsaP256 => {
+            let bytes = match signed_bytes(t, scheme) {
+                Some(bytes) => bytes,
+                None => return false,
+            };
+            let msg = untrusted::Input::from(bytes.as_ref());
+            let pk = untrusted::Input::from(public_key);
+            let sig = untrusted::Input::from(signature);
+            ECDSA_P256_SHA256_FIXED.verify(pk, msg, sig).is_ok()
+        }
+    }
+}
+
 /// Verify digital signature of a transaction, using public key instead of secret key (with bytes)
 pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
-    let bytes = bincode::serialize(&t).unwrap();
+    let bytes = match signed_bytes(t, SignatureScheme::Ed25519) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
     let msg = untrusted::Input::from(bytes.as_ref());
     let pk = untrusted::Input::from(public_key);
     let sig = untrusted::Input::from(signature);
@@ -212,6 +376,8 @@ pub mod tests {
     use crate::crypto::key_pair;
     use crate::helper::*;
     use crate::config::COINBASE_REWARD;
+    use crate::block::{Block, Content, State};
+    use crate::mempool::MemPool;
 
     #[test]
     fn test_sign_verify() {
@@ -243,6 +409,115 @@ pub mod tests {
         assert!(!verify(&t_2.clone(), st.public_key.clone().as_ref(), st.signature.clone().as_ref()));
     }
 
+    #[test]
+    fn test_signature_scheme() {
+        let ed_key = key_pair::random();
+        let ecdsa_key = key_pair::random_ecdsa();
+        let ed_tran = generate_random_signed_transaction_from_keypair(&ed_key);
+        let ecdsa_tran = generate_ecdsa_signed_transaction(&ecdsa_key, vec![generate_random_txinput()],
+                                                           vec![generate_random_txoutput()]);
+        assert_eq!(SignatureScheme::Ed25519, ed_tran.scheme);
+        assert_eq!(SignatureScheme::EcdsaP256, ecdsa_tran.scheme);
+        assert!(ed_tran.sign_check());
+        assert!(ecdsa_tran.sign_check());
+
+        // verify with wrong scheme
+        let mut wrong = ed_tran.clone();
+        wrong.scheme = SignatureScheme::EcdsaP256;
+        assert!(!wrong.sign_check());
+        let mut wrong = ecdsa_tran.clone();
+        wrong.scheme = SignatureScheme::Ed25519;
+        assert!(!wrong.sign_check());
+
+        // scheme survives serialization
+        let bytes = bincode::serialize(&ecdsa_tran).unwrap();
+        let decoded: SignedTransaction = bincode::deserialize(&bytes).unwrap();
+        assert!(decoded.sign_check());
+    }
+
+    #[test]
+    fn test_scheme_is_signed() {
+        let ecdsa_key = key_pair::random_ecdsa();
+        let tran = Transaction::new(vec![generate_random_txinput()], vec![generate_random_txoutput()]);
+        let public_key: Box<[u8]> = ecdsa_key.public_key().as_ref().into();
+
+        // an ECDSA signature over the bare transaction doesn't carry the scheme, it's rejected
+        let bare = bincode::serialize(&tran).unwrap();
+        let untagged: Box<[u8]> = ecdsa_key.sign(&SystemRandom::new(), bare.as_ref()).unwrap().as_ref().into();
+        let mismatched = SignedTransaction::new_with_scheme(tran.clone(), untagged, public_key.clone(),
+                                                            SignatureScheme::EcdsaP256);
+        assert!(!mismatched.sign_check());
+
+        // one made for the scheme verifies under it only
+        let tagged: Box<[u8]> = sign_ecdsa(&tran, &ecdsa_key).as_ref().into();
+        assert!(verify_with_scheme(SignatureScheme::EcdsaP256, &tran, &public_key, &tagged));
+        assert!(!verify_with_scheme(SignatureScheme::Ed25519, &tran, &public_key, &tagged));
+        let ecdsa_sig = untrusted::Input::from(tagged.as_ref());
+        assert!(ECDSA_P256_SHA256_FIXED.verify(untrusted::Input::from(public_key.as_ref()),
+                                                untrusted::Input::from(bare.as_ref()), ecdsa_sig).is_err());
+    }
+
+    #[test]
+    fn test_mixed_scheme_block() {
+        let ed_key = key_pair::random();
+        let ecdsa_key = key_pair::random_ecdsa();
+        let ecdsa_addr: H160 = digest::digest(&digest::SHA256, ecdsa_key.public_key().as_ref()).into();
+        let ed_addr: H160 = digest::digest(&digest::SHA256, ed_key.public_key().as_ref()).into();
+
+        let coinbase = generate_ecdsa_signed_transaction(&ecdsa_key, Vec::new(),
+                                                         vec![TxOutput::new(ecdsa_addr, COINBASE_REWARD)]);
+        assert!(coinbase.is_coinbase_tran());
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let block_1 = Block::new(generate_header(&generate_random_hash(), &content, 0, &generate_random_hash()), content);
+        let state = block_1.try_generate_state(&State::new(), COINBASE_REWARD).unwrap();
+
+        let transfer = generate_ecdsa_signed_transaction(&ecdsa_key, vec![TxInput::new(coinbase.hash, 0)],
+                                                         vec![TxOutput::new(ed_addr, COINBASE_REWARD)]);
+        let trans = vec![generate_signed_coinbase_transaction(&ed_key), transfer];
+        let content = Content::new_with_trans(&trans);
+        let block_2 = Block::new(generate_header(&block_1.hash, &content, 0, &generate_random_hash()), content);
+        assert!(block_2.validate_signature());
+        let state = block_2.try_generate_state(&state, COINBASE_REWARD).unwrap();
+        assert_eq!(2 * COINBASE_REWARD, state.coins_of(&ed_addr).1);
+        assert_eq!(0, state.coins_of(&ecdsa_addr).1);
+    }
+
+    #[test]
+    fn test_bump_fee() {
+        let key = key_pair::random();
+        let change_addr: H160 = digest::digest(&digest::SHA256, key.public_key().as_ref()).into();
+        let payee = generate_random_txoutput();
+        let original = generate_signed_replaceable_transaction(&key,
+            vec![generate_random_txinput(), generate_random_txinput()], vec![payee.clone(), TxOutput::new(change_addr, 10)], 1);
+        let mut mempool = MemPool::new();
+        // replacement counts fees the inputs are known to pay
+        let outpoints = original.transaction.inputs.iter().map(|i| (i.pre_hash, i.index)).collect();
+        mempool.set_tip_state(generate_random_state(outpoints, vec![(1000, change_addr); 2]));
+        assert!(mempool.add_with_check(&original));
+
+        let bumped = bump_fee(&original, 5, &key).unwrap();
+        assert!(bumped.sign_check());
+        assert_eq!(original.transaction.inputs, bumped.transaction.inputs);
+        assert_eq!(vec![payee, TxOutput::new(change_addr, 5)], bumped.transaction.outputs);
+        assert_eq!(6, bumped.transaction.fee);
+        assert_eq!(Ordering::Greater, bumped.cmp_fee_rate(&original));
+        // the replacement wins over the original in mempool
+        assert!(mempool.add_with_check(&bumped));
+        assert!(!mempool.exist(&original.hash));
+        assert!(!mempool.add_with_check(&original));
+
+        // change must stay above dust, and only the signer can bump
+        assert!(bump_fee(&original, 10, &key).is_none());
+        assert!(bump_fee(&original, 5, &key_pair::random()).is_none());
+        let no_change = generate_signed_replaceable_transaction(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], 1);
+        assert!(bump_fee(&no_change, 1, &key).is_none());
+        // nor a transaction that didn't opt in to replacement
+        let final_tran = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+            vec![TxOutput::new(change_addr, 10)], 1);
+        assert!(bump_fee(&final_tran, 1, &key).is_none());
+    }
+
     #[test]
     fn test_sender_address() {
         let tran = Transaction::new(Vec::new(), Vec::new());
@@ -253,6 +528,50 @@ pub mod tests {
         assert_eq!(h160, signed_tran.sender_addr());
     }
 
+    #[test]
+    fn test_malformed_signature() {
+        let key = key_pair::random();
+        let valid = generate_signed_transaction_with_fee(&key, vec![generate_random_txinput()],
+            vec![generate_random_txoutput()], 1);
+        let garbage: Vec<Box<[u8]>> = vec![Box::new([]), Box::new([0xff; 1]), Box::new([7u8; 31]),
+            Box::new([7u8; 33]), Box::new([0u8; 64]), Box::new([0xab; 4096])];
+        let mut mempool = MemPool::new();
+        for scheme in [SignatureScheme::Ed25519, SignatureScheme::EcdsaP256].iter() {
+            for bytes in garbage.iter() {
+                let bad_key = SignedTransaction::new_with_scheme(valid.transaction.clone(), valid.signature.clone(),
+                    bytes.clone(), *scheme);
+                let bad_sig = SignedTransaction::new_with_scheme(valid.transaction.clone(), bytes.clone(),
+                    valid.public_key.clone(), *scheme);
+                for tran in [bad_key, bad_sig].iter() {
+                    assert!(!tran.sign_check());
+                    assert!(!mempool.add_with_check(tran));
+                }
+            }
+        }
+        assert_eq!(0, mempool.size());
+        assert!(mempool.add_with_check(&valid));
+    }
+
+    #[test]
+    fn test_vsize() {
+        let tran = Transaction::new_with_fee(vec![TxInput::new(H256::default(), 0)],
+            vec![TxOutput::new(H160::default(), 10)], 2);
+        let signed_tran = SignedTransaction::new(tran, Box::new([1u8; 64]), Box::new([2u8; 32]));
+        assert_eq!(247, signed_tran.vsize());
+        let copy: SignedTransaction = bincode::deserialize(&bincode::serialize(&signed_tran).unwrap()).unwrap();
+        assert_eq!(signed_tran.vsize(), copy.vsize());
+    }
+
+    #[test]
+    fn test_weight() {
+        let tran = Transaction::new_with_fee(vec![TxInput::new(H256::default(), 0)],
+            vec![TxOutput::new(H160::default(), 10)], 2);
+        let signed_tran = SignedTransaction::new(tran, Box::new([1u8; 64]), Box::new([2u8; 32]));
+        // length-prefixed signature & public key
+        assert_eq!(8 + 64 + 8 + 32, signed_tran.witness_size());
+        assert_eq!((247 - 112) * 4 + 112, signed_tran.weight());
+    }
+
     #[test]
     fn test_verify_coinbase_tran() {
         let key = key_pair::random();
@@ -269,11 +588,11 @@ pub mod tests {
         let signed_tran = SignedTransaction::new(coinbase_tran.clone(), sig_bytes.clone(), key_bytes.clone());
         assert!(signed_tran.is_coinbase_tran());
 
-        // wrong rec_address
+        // any rec_address, e.g. a rotated payout address
         let txoutput = TxOutput {rec_address: generate_random_h160(), val: COINBASE_REWARD};
         let coinbase_tran = Transaction::new(Vec::new(), vec![txoutput]);
         let signed_tran = SignedTransaction::new(coinbase_tran.clone(), sig_bytes.clone(), key_bytes.clone());
-        assert!(!signed_tran.is_coinbase_tran());
+        assert!(signed_tran.is_coinbase_tran());
 
         // wrong txinput length
         let txoutput = TxOutput {rec_address: h160.clone(), val: COINBASE_REWARD};
@@ -282,11 +601,11 @@ pub mod tests {
         let signed_tran = SignedTransaction::new(coinbase_tran.clone(), sig_bytes.clone(), key_bytes.clone());
         assert!(!signed_tran.is_coinbase_tran());
 
-        // wrong reward
+        // any reward, the amount is checked against the block subsidy
         let txoutput = TxOutput {rec_address: h160.clone(), val: COINBASE_REWARD+1};
         let coinbase_tran = Transaction::new(Vec::new(), vec![txoutput]);
         let signed_tran = SignedTransaction::new(coinbase_tran.clone(), sig_bytes.clone(), key_bytes.clone());
-        assert!(!signed_tran.is_coinbase_tran());
+        assert!(signed_tran.is_coinbase_tran());
 
         // wrong txoutput length - 0
         let coinbase_tran = Transaction::new(Vec::new(), Vec::new());
@@ -1,28 +1,59 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
-use log::info;
+use log::{debug, info};
 use rand::Rng;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 
 use crate::network::server::Handle as ServerHandle;
 use crate::network::message::Message;
 use crate::mempool::MemPool;
+use crate::lock_order::OrderedMutex;
 use crate::helper;
-use crate::crypto::hash::H160;
+use crate::crypto::hash::{H256, H160};
+use crate::transaction::TxInput;
+use crate::events::Event;
 use crate::config::TRANSACTION_GENERATE_INTERVAL;
 use crate::peers::Peers;
 use crate::blockchain::Blockchain;
 use crate::account::Account;
 
+// How fast new transactions are generated, all times in ms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadProfile {
+    Steady(u64),  // one transaction every interval
+    Burst { size: usize, interval: u64 },  // `size` transactions at once, every interval
+    Ramp { start: u64, end: u64, duration: u64 },  // interval moves linearly from start to end over duration, then stays at end
+}
+
+impl LoadProfile {
+    // Number of transactions to generate in one round and the interval after it,
+    // `elapsed` is the time since the profile started
+    fn round(&self, elapsed: u64) -> (usize, u64) {
+        match *self {
+            LoadProfile::Steady(itv) => (1, itv),
+            LoadProfile::Burst { size, interval } => (size, interval),
+            LoadProfile::Ramp { start, end, duration } => {
+                if duration == 0 || elapsed >= duration {
+                    return (1, end);
+                }
+                let (start, end) = (start as i128, end as i128);
+                let itv = start + (end - start) * elapsed as i128 / duration as i128;
+                (1, itv as u64)
+            }
+        }
+    }
+}
+
 enum ControlSignal {
-    Start(u64), // the number controls the interval to generate new tx
+    Start(LoadProfile),
     Exit,
     Paused,
 }
 
 enum OperatingState {
-    Run(u64),
+    Run(LoadProfile),
     Paused,
     ShutDown,
 }
@@ -30,12 +61,16 @@ enum OperatingState {
 pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
+    run_since: time::Instant,  // when the current profile started, for Ramp
     server: ServerHandle,
-    mempool: Arc<Mutex<MemPool>>,
-    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
     peers: Arc<Mutex<Peers>>,
     account: Arc<Account>,
     dandelion: bool,
+    events: Receiver<Event>,
+    spent: HashSet<TxInput>,  // outputs spent by generated but unconfirmed transactions
+    pending: HashMap<H256, Vec<TxInput>>,  // generated transaction -> its inputs
 }
 
 #[derive(Clone)]
@@ -46,23 +81,28 @@ pub struct Handle {
 
 pub fn new(
     server: ServerHandle,
-    mempool: Arc<Mutex<MemPool>>,
-    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<MemPool>>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
     peers: Arc<Mutex<Peers>>,
     account: Arc<Account>,
     dandelion: bool,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let events = mempool.lock().unwrap().subscribe();
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
-        operating_state: OperatingState::Run(TRANSACTION_GENERATE_INTERVAL),
+        operating_state: OperatingState::Run(LoadProfile::Steady(TRANSACTION_GENERATE_INTERVAL)),
+        run_since: time::Instant::now(),
         server,
         mempool,
         blockchain,
         peers,
         account,
         dandelion,
+        events,
+        spent: HashSet::new(),
+        pending: HashMap::new(),
     };
 
     let handle = Handle {
@@ -78,8 +118,12 @@ impl Handle {
     }
 
     pub fn start(&self, itv: u64) {
+        self.start_profile(LoadProfile::Steady(itv));
+    }
+
+    pub fn start_profile(&self, profile: LoadProfile) {
         self.control_chan
-            .send(ControlSignal::Start(itv))
+            .send(ControlSignal::Start(profile))
             .unwrap();
     }
 
@@ -109,9 +153,10 @@ impl Context {
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
         match signal {
-            ControlSignal::Start(i) => {
-                info!("Transaction_generator starting in continuous mode");
-                self.operating_state = OperatingState::Run(i);
+            ControlSignal::Start(profile) => {
+                info!("Transaction_generator starting with load profile {:?}", profile);
+                self.operating_state = OperatingState::Run(profile);
+                self.run_since = time::Instant::now();
             }
             ControlSignal::Exit => {
                 info!("Transaction_generator shutting down");
@@ -147,9 +192,12 @@ impl Context {
                 return;
             }
 
-            self.tx_generating();
-
-            if let OperatingState::Run(i) = self.operating_state {
+            if let OperatingState::Run(profile) = self.operating_state {
+                let elapsed = self.run_since.elapsed().as_millis() as u64;
+                let (size, i) = profile.round(elapsed);
+                for _ in 0..size {
+                    self.tx_generating();
+                }
                 if i != 0 {
                     let sleep_itv = time::Duration::from_millis(i as u64);
                     thread::sleep(sleep_itv);
@@ -161,12 +209,24 @@ impl Context {
     // Generating logic method!
     fn tx_generating(&mut self) {
         // Update state from tip of longest-chain
-        let state = self.blockchain.lock().unwrap().tip_block_state();
+        let mut state = self.blockchain.lock().unwrap().tip_block_state();
+        self.handle_events();
+        // forget outputs already confirmed as spent, and hide the ones our pending trans spend
+        self.spent.retain(|input| state.contains_key(&(input.pre_hash, input.index)));
+        self.pending.retain(|_, inputs| inputs.iter()
+            .any(|input| state.contains_key(&(input.pre_hash, input.index))));
+        for input in self.spent.iter() {
+            state.remove(&(input.pre_hash, input.index));
+        }
         if let Some(rec_addr) = self.random_peer_addr() {
             if let Some(tran) = helper::generate_valid_tran(&state, &self.account, &rec_addr) {
                 let mut mempool = self.mempool.lock().unwrap();
                 if mempool.add_with_check(&tran) {
                     info!("Put a new transaction into client! Now mempool has {} transaction", mempool.size());
+                    for input in tran.transaction.inputs.iter() {
+                        self.spent.insert(input.clone());
+                    }
+                    self.pending.insert(tran.hash.clone(), tran.transaction.inputs.clone());
                     if self.dandelion {
                         let vec_trans = vec![tran];
                         self.server.broadcast(Message::NewDandelionTransactions(vec_trans), None);
@@ -179,6 +239,23 @@ impl Context {
         }
     }
 
+    // Release outputs of generated transactions evicted or replaced in mempool
+    fn handle_events(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            let hash = match event {
+                Event::TxEvicted(hash) => hash,
+                Event::TxReplaced { old, .. } => old,
+                _ => continue,
+            };
+            if let Some(inputs) = self.pending.remove(&hash) {
+                debug!("Generated transaction {:?} left mempool, release its inputs", hash);
+                for input in inputs.iter() {
+                    self.spent.remove(input);
+                }
+            }
+        }
+    }
+
     // Pick single random peer to as receiver of new transaction
     fn random_peer_addr(&self) -> Option<H160> {
         let peers = self.peers.lock().unwrap();
@@ -225,8 +302,11 @@ mod tests {
     use std::thread::sleep;
     use std::time;
 
+    use super::LoadProfile;
     use crate::helper::*;
-    use crate::config::REPEAT_TEST_TIME;
+    use crate::block::{Block, Content};
+    use crate::transaction::TxInput;
+    use crate::config::{REPEAT_TEST_TIME, COINBASE_REWARD, INV_FLUSH_INTERVAL};
     use crate::spread::Spreader;
     use crate::crypto::key_pair;
     use ring::signature::{ED25519_PUBLIC_KEY_LEN, KeyPair};
@@ -247,7 +327,7 @@ mod tests {
         connect_peers(&server_3, &peers_2);
 
         generator_1.generating();
-        sleep(time::Duration::from_millis(100));
+        sleep(time::Duration::from_millis(2 * INV_FLUSH_INTERVAL + 100));
 
         let pool_1 = mempool_1.lock().unwrap();
         let pool_2 = mempool_2.lock().unwrap();
@@ -260,7 +340,7 @@ mod tests {
         drop(pool_3);
 
         generator_2.generating();
-        sleep(time::Duration::from_millis(100));
+        sleep(time::Duration::from_millis(2 * INV_FLUSH_INTERVAL + 100));
 
         let pool_1 = mempool_1.lock().unwrap();
         let pool_2 = mempool_2.lock().unwrap();
@@ -273,7 +353,7 @@ mod tests {
         drop(pool_3);
 
         generator_3.generating();
-        sleep(time::Duration::from_millis(100));
+        sleep(time::Duration::from_millis(2 * INV_FLUSH_INTERVAL + 100));
 
         let pool_1 = mempool_1.lock().unwrap();
         let pool_2 = mempool_2.lock().unwrap();
@@ -303,7 +383,7 @@ mod tests {
         connect_peers(&server_3, &peers_2);
 
         generator_1.generating();
-        sleep(time::Duration::from_millis(100));
+        sleep(time::Duration::from_millis(2 * INV_FLUSH_INTERVAL + 100));
 
         let pool_1 = mempool_1.lock().unwrap();
         let pool_2 = mempool_2.lock().unwrap();
@@ -316,7 +396,7 @@ mod tests {
         drop(pool_3);
 
         generator_3.generating();
-        sleep(time::Duration::from_millis(100));
+        sleep(time::Duration::from_millis(2 * INV_FLUSH_INTERVAL + 100));
 
         let pool_1 = mempool_1.lock().unwrap();
         let pool_2 = mempool_2.lock().unwrap();
@@ -365,4 +445,96 @@ mod tests {
             assert!(addr == peer_addr1 || addr == peer_addr2 || addr == peer_addr3);
         }
     }
+
+    #[test]
+    fn test_release_evicted_tran() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17029);
+        let (_, _, mut generator, blockchain, mempool, peers, account) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let peer_key_pair = key_pair::random();
+        let mut bytes_pub_key: [u8; ED25519_PUBLIC_KEY_LEN] = [0; ED25519_PUBLIC_KEY_LEN];
+        bytes_pub_key[..].copy_from_slice(&peer_key_pair.public_key().as_ref()[..]);
+        peers.lock().unwrap().insert(&generate_random_h160(), Box::new(bytes_pub_key), 1111);
+
+        // give the account one coin
+        let mut chain = blockchain.lock().unwrap();
+        let coinbase = generate_signed_coinbase_transaction(&account.key_pair);
+        let content = Content::new_with_trans(&vec![coinbase.clone()]);
+        let header = generate_header(&chain.tip(), &content, 0, &chain.difficulty());
+        chain.insert(&Block::new(header, content)).unwrap();
+        drop(chain);
+        let coin = TxInput::new(coinbase.hash, 0);
+
+        generator.tx_generating();
+        assert!(generator.spent.contains(&coin));
+        assert_eq!(1, generator.pending.len());
+        assert_eq!(1, mempool.lock().unwrap().size());
+
+        // no coin left to spend
+        generator.tx_generating();
+        assert_eq!(1, mempool.lock().unwrap().size());
+
+        // a confirmed conflicting transaction evicts the generated one
+        let conflict = generate_signed_transaction(&account.key_pair, vec![coin.clone()], Vec::new());
+        mempool.lock().unwrap().remove_conflict_tx_inputs(&Content::new_with_trans(&vec![conflict]));
+        assert!(mempool.lock().unwrap().empty());
+        generator.handle_events();
+        assert!(!generator.spent.contains(&coin));
+        assert!(generator.pending.is_empty());
+
+        generator.tx_generating();
+        assert!(generator.spent.contains(&coin));
+        assert_eq!(1, mempool.lock().unwrap().size());
+    }
+
+    #[test]
+    fn test_burst_profile() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17425);
+        let (server, _, _, blockchain, mempool, peers, account) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        let peer_key_pair = key_pair::random();
+        let mut bytes_pub_key: [u8; ED25519_PUBLIC_KEY_LEN] = [0; ED25519_PUBLIC_KEY_LEN];
+        bytes_pub_key[..].copy_from_slice(&peer_key_pair.public_key().as_ref()[..]);
+        peers.lock().unwrap().insert(&generate_random_h160(), Box::new(bytes_pub_key), 1111);
+
+        // give the account enough coins for three bursts
+        let mut chain = blockchain.lock().unwrap();
+        for i in 0..9 {
+            // distinct rewards, so coinbases created in the same instant don't share a hash
+            let coinbase = generate_signed_coinbase_transaction_with_reward(&account.key_pair, COINBASE_REWARD - i);
+            let content = Content::new_with_trans(&vec![coinbase]);
+            let header = generate_header(&chain.tip(), &content, 0, &chain.difficulty());
+            chain.insert(&Block::new(header, content)).unwrap();
+        }
+        drop(chain);
+
+        let key_pair = account.key_pair.clone();
+        let (ctx, handle) = super::new(server, mempool.clone(), blockchain.clone(), peers, account, false);
+        handle.start_profile(LoadProfile::Burst { size: 3, interval: 400 });
+        ctx.start();
+
+        // mempool grows by a whole burst and stays flat until the next one
+        for step in 1..4 {
+            sleep(time::Duration::from_millis(200));
+            assert_eq!(3 * step, mempool.lock().unwrap().size());
+            sleep(time::Duration::from_millis(200));
+        }
+        handle.exit();
+
+        // all generated transactions fit in one valid block
+        let chain = blockchain.lock().unwrap();
+        let content = mempool.lock().unwrap().create_content(&key_pair, &address_of(&key_pair), COINBASE_REWARD, 100);
+        assert_eq!(10, content.trans.len());
+        let header = generate_header(&chain.tip(), &content, 0, &chain.difficulty());
+        assert_eq!(Ok(()), chain.would_accept(&Block::new(header, content)));
+    }
+
+    #[test]
+    fn test_profile_round() {
+        assert_eq!((1, 100), LoadProfile::Steady(100).round(5000));
+        assert_eq!((4, 300), LoadProfile::Burst { size: 4, interval: 300 }.round(0));
+        let ramp = LoadProfile::Ramp { start: 1000, end: 200, duration: 800 };
+        assert_eq!((1, 1000), ramp.round(0));
+        assert_eq!((1, 600), ramp.round(400));
+        assert_eq!((1, 200), ramp.round(800));
+        assert_eq!((1, 200), ramp.round(10000));
+    }
 }
\ No newline at end of file
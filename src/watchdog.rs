@@ -0,0 +1,155 @@
+use log::{info, warn};
+
+use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use std::time::{self, Instant};
+
+use std::thread;
+use std::sync::Arc;
+
+use crate::blockchain::Blockchain;
+use crate::lock_order::OrderedMutex;
+use crate::crypto::hash::H256;
+use crate::events::{Event, EventBus};
+
+enum ControlSignal {
+    Exit,
+}
+
+pub struct Context {
+    /// Channel for receiving control signal
+    control_chan: Receiver<ControlSignal>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    timeout: u64,  // time(ms) without tip change before alerting
+    events: EventBus,
+    tip: H256,
+    tip_since: Instant,
+    alerted: bool,  // only alert once for the same tip
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending signal to the watchdog thread
+    control_chan: Sender<ControlSignal>,
+}
+
+pub fn new(blockchain: Arc<OrderedMutex<Blockchain>>, timeout: u64) -> (Context, Handle) {
+    let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let tip = blockchain.lock().unwrap().tip();
+
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        blockchain,
+        timeout,
+        events: EventBus::new(),
+        tip,
+        tip_since: Instant::now(),
+        alerted: false,
+    };
+
+    let handle = Handle {
+        control_chan: signal_chan_sender,
+    };
+
+    (ctx, handle)
+}
+
+impl Handle {
+    pub fn exit(&self) {
+        self.control_chan.send(ControlSignal::Exit).unwrap();
+    }
+}
+
+impl Context {
+    // Receive StaleTip events, subscribe before start
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn start(mut self) {
+        let timeout = self.timeout;
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || {
+                self.watchdog_loop();
+            })
+            .unwrap();
+        info!("Stale-tip watchdog started with timeout {}ms", timeout);
+    }
+
+    fn watchdog_loop(&mut self) {
+        let interval = time::Duration::from_millis(std::cmp::max(self.timeout / 4, 1));
+        loop {
+            match self.control_chan.try_recv() {
+                Ok(ControlSignal::Exit) | Err(TryRecvError::Disconnected) => {
+                    info!("Watchdog shutting down");
+                    return;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+            self.check_tip();
+            thread::sleep(interval);
+        }
+    }
+
+    // Record tip change, or alert if tip stays the same for longer than timeout
+    fn check_tip(&mut self) {
+        let tip = self.blockchain.lock().unwrap().tip();
+        if tip != self.tip {
+            self.tip = tip;
+            self.tip_since = Instant::now();
+            self.alerted = false;
+            return;
+        }
+        let idle_ms = self.tip_since.elapsed().as_millis() as u64;
+        if !self.alerted && idle_ms >= self.timeout {
+            warn!("No new block for {}ms, tip is still {:?}", idle_ms, self.tip);
+            self.events.publish(Event::StaleTip { tip: self.tip.clone(), idle_ms });
+            self.alerted = true;
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::blockchain::CheckPolicy;
+    use crate::helper::*;
+    use crate::spread::Spreader;
+
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_stale_tip() {
+        let p2p_addr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 17405);
+        let (_server, _, _, blockchain, _, _, _) = new_server_env(p2p_addr_1, Spreader::Default, false);
+        blockchain.lock().unwrap().set_check_policy(CheckPolicy::SkipAll);
+
+        let (mut ctx, handle) = new(blockchain.clone(), 300);
+        let events = ctx.subscribe();
+        ctx.start();
+
+        // tip keeps changing while mining
+        for _ in 0..4 {
+            thread::sleep(time::Duration::from_millis(100));
+            let mut chain = blockchain.lock().unwrap();
+            let block = generate_mined_block(&chain.tip(), &chain.difficulty());
+            chain.insert_with_check(&block).unwrap();
+        }
+        assert!(events.try_recv().is_err());
+
+        // mining paused
+        let tip = blockchain.lock().unwrap().tip();
+        thread::sleep(time::Duration::from_millis(500));
+        match events.try_recv() {
+            Ok(Event::StaleTip { tip: stale, idle_ms }) => {
+                assert_eq!(tip, stale);
+                assert!(idle_ms >= 300);
+            }
+            e => panic!("expect StaleTip, got {:?}", e),
+        }
+        // alert once for the same tip
+        thread::sleep(time::Duration::from_millis(400));
+        assert!(events.try_recv().is_err());
+        handle.exit();
+    }
+}